@@ -0,0 +1,47 @@
+//! Appending parsed rows to DuckDB, the other half of import time. Each
+//! batch goes through one transaction, as `import::checkpoint` does. Run
+//! with `cargo bench --bench insert`.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use duckdb::Connection;
+use pulezviz::{
+    db,
+    parser::{LogRow, Timestamps, parse_line},
+};
+
+const CORPUS: &str = include_str!("../tests/corpus/ok.log");
+
+/// `n` rows cycling through the corpus.
+fn rows(n: usize) -> Vec<LogRow> {
+    let timestamps = Timestamps::default();
+    let corpus: Vec<LogRow> = CORPUS.lines().map(|l| parse_line(l, &timestamps).unwrap()).collect();
+    corpus.iter().cycle().take(n).cloned().collect()
+}
+
+fn append(c: &mut Criterion) {
+    let mut conn = Connection::open_in_memory().unwrap();
+    db::init_schema(&conn).unwrap();
+
+    let mut group = c.benchmark_group("insert_rows");
+    group.sample_size(20);
+    for batch in [1_000, 10_000, 50_000] {
+        group.throughput(Throughput::Elements(batch as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(batch), &batch, |b, &batch| {
+            b.iter_batched(
+                || rows(batch),
+                |rows| {
+                    let tx = conn.transaction().unwrap();
+                    db::insert_rows(&tx, rows, db::RawStorage::Text).unwrap();
+                    tx.commit().unwrap();
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        // Keep the partitions from growing across batch sizes.
+        db::prune(&conn, "9999-01-01T00:00:00Z").unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, append);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+//! Per-line parsing cost, the ceiling on import speed. Run with
+//! `cargo bench --bench parser`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use pulezviz::{
+    config::{EnrichConfig, ParserConfig},
+    enrich::Pipeline,
+    parser::{Timestamps, parse_line},
+};
+use url::Url;
+
+const CORPUS: &str = include_str!("../tests/corpus/ok.log");
+
+fn corpus_line(needle: &str) -> String {
+    CORPUS.lines().find(|l| l.contains(needle)).expect("corpus has the line").to_string()
+}
+
+/// Lines worth timing separately: the common case, the longest fields, and
+/// the fastest way to fail.
+fn cases() -> Vec<(&'static str, String)> {
+    let huge = format!(
+        r#"192.0.2.10 - sessAAA [15/Feb/2026:00:00:04 +0000] "GET https://search.example.com/results?q={} HTTP/1.1" 200 1 "US" "Mozilla/5.0""#,
+        "climate%20adaptation%20".repeat(400)
+    );
+    vec![
+        ("typical", corpus_line("sCyGAlJG8RoCLDry3ziUL4lk7NXPtMH")),
+        ("referrer", corpus_line("sessBBB")),
+        ("ipv6", corpus_line("2001:db8::")),
+        ("huge_url", huge),
+        ("no_match", "not an EZproxy log line at all".to_string()),
+    ]
+}
+
+fn parse(c: &mut Criterion) {
+    let timestamps = Timestamps::default();
+    let mut group = c.benchmark_group("parse_line");
+    for (name, line) in cases() {
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &line, |b, line| {
+            b.iter(|| parse_line(black_box(line), &timestamps))
+        });
+    }
+    group.finish();
+
+    // The whole corpus at once, for lines per second.
+    let lines: Vec<&str> = CORPUS.lines().collect();
+    let mut group = c.benchmark_group("parse_corpus");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function("ok.log", |b| {
+        b.iter(|| lines.iter().filter(|l| parse_line(black_box(l), &timestamps).is_ok()).count())
+    });
+    group.finish();
+}
+
+fn split_url(c: &mut Criterion) {
+    let mut group = c.benchmark_group("url_split");
+    for (name, url) in [
+        ("plain", "https://www.jstor.org:443/stable/12345"),
+        ("query", "https://search.ebscohost.com/login.aspx?direct=true&db=a9h&AN=123456&site=ehost-live"),
+        ("encoded", "https://www.degruyter.com/search?query=%22Fr%C3%BChneuzeit%22%20Stadt&lang=de"),
+        ("punycode", "https://xn--caf-dma.example.org/r%C3%A9sum%C3%A9/550e8400-e29b-41d4-a716-446655440000"),
+    ] {
+        group.throughput(Throughput::Bytes(url.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), url, |b, url| b.iter(|| Url::parse(black_box(url))));
+    }
+    group.finish();
+}
+
+fn timestamps(c: &mut Criterion) {
+    let strict = Timestamps::default();
+    let lenient = Timestamps::from_config(&ParserConfig {
+        month_locales: vec!["de".to_string(), "fr".to_string()],
+        assume_tz: Some("America/Chicago".to_string()),
+        ..ParserConfig::default()
+    })
+    .unwrap();
+
+    let mut group = c.benchmark_group("timestamp");
+    group.bench_function("ezproxy", |b| b.iter(|| strict.parse(black_box("15/Feb/2026:00:00:04 +0000"))));
+    // The last default format, after two misses.
+    group.bench_function("epoch", |b| b.iter(|| strict.parse(black_box("1771113604"))));
+    group.bench_function("localized_month", |b| b.iter(|| lenient.parse(black_box("15/Mär/2026:00:00:04 +0100"))));
+    group.bench_function("assumed_zone", |b| b.iter(|| lenient.parse(black_box("15/Feb/2026:00:00:04"))));
+    group.finish();
+}
+
+fn enrich(c: &mut Criterion) {
+    let timestamps = Timestamps::default();
+    let pipeline = Pipeline::from_config(&EnrichConfig::default()).unwrap();
+    let row = parse_line(&corpus_line("sessDDD"), &timestamps).unwrap();
+    c.bench_function("enrich_default_stages", |b| {
+        b.iter_batched_ref(|| row.clone(), |row| pipeline.run(row), criterion::BatchSize::SmallInput)
+    });
+}
+
+criterion_group!(benches, parse, split_url, timestamps, enrich);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+#![no_main]
+
+//! Log lines are untrusted input: whatever a client puts in its request or
+//! User-Agent ends up in them. Parsing, and the enrichment stages that run
+//! on every parsed row, must reject garbage without panicking.
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use pulezviz::{
+    config::{EnrichConfig, ParserConfig},
+    enrich::Pipeline,
+    parser::{ParseError, Timestamps, parse_line},
+};
+
+struct Setup {
+    strict: Timestamps,
+    lenient: Timestamps,
+    pipeline: Pipeline,
+}
+
+fn setup() -> &'static Setup {
+    static SETUP: OnceLock<Setup> = OnceLock::new();
+    SETUP.get_or_init(|| {
+        let lenient = ParserConfig {
+            month_locales: ["de", "es", "fr", "it", "nl", "pt"].map(String::from).to_vec(),
+            assume_tz: Some("America/Chicago".to_string()),
+            ..ParserConfig::default()
+        };
+        Setup {
+            strict: Timestamps::default(),
+            lenient: Timestamps::from_config(&lenient).unwrap(),
+            pipeline: Pipeline::from_config(&EnrichConfig::default()).unwrap(),
+        }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let setup = setup();
+    // Seeds are whole log files, like tests/corpus; take them a line at a time.
+    for line in text.lines() {
+        for timestamps in [&setup.strict, &setup.lenient] {
+            let mut row = match parse_line(line, timestamps) {
+                Ok(row) => row,
+                Err(ParseError::BadUrl { row, .. }) => *row,
+                Err(_) => continue,
+            };
+            assert_eq!(row.raw, line);
+            setup.pipeline.run(&mut row);
+        }
+    }
+});
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use duckdb::{Connection, params, params_from_iter};
+
+use crate::db;
+
+/// Most entries `list` returns at once.
+pub const MAX_LISTED: usize = 1000;
+
+/// Record that `caller` asked for `path` with `query` (the filters) and, for
+/// ad-hoc SQL, `body`.
+pub fn record(conn: &Connection, caller: &str, method: &str, path: &str, query: Option<&str>, body: Option<&str>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO access_audit (caller, method, path, query, body) VALUES (?, ?, ?, ?, ?)",
+        params![caller, method, path, query, body],
+    )?;
+    Ok(())
+}
+
+/// Recorded requests, newest first, optionally only `caller`'s and only
+/// from `start` to `end` (RFC 3339).
+pub fn list(
+    conn: &Connection,
+    caller: Option<&str>,
+    start: Option<&str>,
+    end: Option<&str>,
+    limit: usize,
+) -> Result<Vec<serde_json::Value>> {
+    let mut conds = vec!["TRUE".to_string()];
+    let mut args = Vec::new();
+    if let Some(c) = caller {
+        conds.push("caller = ?".into());
+        args.push(c);
+    }
+    if let Some(s) = start {
+        conds.push("created_at >= CAST(? AS TIMESTAMPTZ)".into());
+        args.push(s);
+    }
+    if let Some(e) = end {
+        conds.push("created_at <= CAST(? AS TIMESTAMPTZ)".into());
+        args.push(e);
+    }
+    let table = db::query_table(
+        conn,
+        &format!(
+            "SELECT id, CAST(created_at AS VARCHAR) AS created_at, caller, method, path, query, body \
+             FROM access_audit WHERE {} ORDER BY id DESC LIMIT {}",
+            conds.join(" AND "),
+            limit.min(MAX_LISTED)
+        ),
+        params_from_iter(args),
+    )?;
+    Ok(table.to_objects())
+}
@@ -0,0 +1,208 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Result, anyhow};
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use axum::http::{HeaderMap, Method, header};
+use base64ct::{Base64, Encoding};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuthConfig;
+
+/// Roles are ordered: each one can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Dashboard and aggregate endpoints
+    Viewer,
+    /// Raw request drill-down, ad-hoc SQL and its schema, the endpoints
+    /// that name users or addresses, and job status
+    Analyst,
+    /// Queueing jobs: imports, prunes, exports
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Analyst => "analyst",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Who made a request: a login's username or a token's label. Set on
+/// authenticated requests for handlers that record who did what.
+#[derive(Debug, Clone)]
+pub struct Caller(pub String);
+
+/// What the caller of an authenticated request may do, for handlers like
+/// `/api/dashboard` that serve several routes' data in one response.
+#[derive(Debug, Clone)]
+pub enum Grant {
+    /// A login's role
+    Role(Role),
+    /// An API token's scopes
+    Scopes(Vec<Scope>),
+}
+
+impl Grant {
+    /// Whether a route needing `role` is open to this caller.
+    pub fn allows(&self, role: Role) -> bool {
+        match self {
+            Grant::Role(granted) => *granted >= role,
+            Grant::Scopes(scopes) => scopes.contains(&Scope::for_role(role)),
+        }
+    }
+}
+
+/// Served without credentials when `[public]` is configured, and not at all
+/// otherwise.
+pub const PUBLIC_PATHS: &[&str] = &["/public", "/api/public"];
+
+/// Whether a request is refused outright when `[auth]` is off: it changes
+/// the server's files or database, or runs arbitrary SQL. With no
+/// credentials to check, any web page open in the operator's browser could
+/// otherwise send it to a dashboard on localhost. Grafana's POSTs only read.
+pub fn needs_auth(method: &Method, path: &str) -> bool {
+    method != Method::GET && method != Method::HEAD && !path.starts_with("/grafana/")
+}
+
+/// Role needed for a request. Anything not listed here is an aggregate and
+/// open to viewers.
+pub fn required_role(method: &Method, path: &str) -> Role {
+    if path.starts_with("/api/jobs") {
+        return if method == Method::POST { Role::Admin } else { Role::Analyst };
+    }
+    if path == "/api/requests" || path.starts_with("/api/requests/") || path == "/api/query" || path == "/api/schema" {
+        return Role::Analyst;
+    }
+    if path == "/api/costs" || path.starts_with("/api/pseudonyms") || path == "/api/access_audit" {
+        return Role::Admin;
+    }
+    // Names users or addresses, like the raw requests behind it.
+    if matches!(
+        path,
+        "/api/policy_violations"
+            | "/api/honeytoken_hits"
+            | "/api/peak_windows"
+            | "/api/login_failures"
+            | "/api/turnaways"
+            | "/api/client_types"
+            | "/api/anomalies"
+            | "/api/proxy_abuse"
+    ) {
+        return Role::Analyst;
+    }
+    Role::Viewer
+}
+
+/// What an API token may do. Unlike roles, scopes don't include one another:
+/// a token carries exactly the scopes it was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Dashboard and aggregate endpoints, as for viewers
+    ReadAggregates,
+    /// Raw request drill-down, ad-hoc SQL, endpoints naming users, and job
+    /// status, as for analysts
+    ReadRequests,
+    /// Queueing jobs, as for admins
+    WriteJobs,
+}
+
+pub const SCOPES: &[Scope] = &[Scope::ReadAggregates, Scope::ReadRequests, Scope::WriteJobs];
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadAggregates => "read:aggregates",
+            Scope::ReadRequests => "read:requests",
+            Scope::WriteJobs => "write:jobs",
+        }
+    }
+
+    /// Scope a token needs for a route that requires `role` from a user.
+    pub fn for_role(role: Role) -> Scope {
+        match role {
+            Role::Viewer => Scope::ReadAggregates,
+            Role::Analyst => Scope::ReadRequests,
+            Role::Admin => Scope::WriteJobs,
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        SCOPES.iter().find(|sc| sc.as_str() == s).copied().ok_or_else(|| {
+            let known: Vec<_> = SCOPES.iter().map(|sc| sc.as_str()).collect();
+            anyhow!("unknown scope {:?}, expected one of {}", s, known.join(", "))
+        })
+    }
+}
+
+/// PHC string for `password`, as stored in `auth.users[].password_hash`.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Checks HTTP Basic credentials against the configured users.
+pub struct Authenticator {
+    config: AuthConfig,
+    /// Argon2 is deliberately slow and the dashboard makes a dozen requests
+    /// per load, so verified Authorization headers are remembered until the
+    /// server restarts.
+    verified: Mutex<HashMap<String, (String, Role)>>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Result<Self> {
+        for u in &config.users {
+            PasswordHash::new(&u.password_hash)
+                .map_err(|e| anyhow!("auth user {:?}: bad password_hash: {}", u.name, e))?;
+        }
+        Ok(Authenticator {
+            config,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// User name and role for the request's Basic credentials, if they are
+    /// valid.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<(String, Role)> {
+        let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+        if let Some(hit) = self.verified.lock().expect("auth cache poisoned").get(value) {
+            return Some(hit.clone());
+        }
+
+        let encoded = value.strip_prefix("Basic ")?;
+        let decoded = Base64::decode_vec(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+
+        let user = self.config.users.iter().find(|u| u.name == name)?;
+        let hash = PasswordHash::new(&user.password_hash).ok()?;
+        Argon2::default().verify_password(password.as_bytes(), &hash).ok()?;
+
+        let hit = (user.name.clone(), user.role);
+        self.verified
+            .lock()
+            .expect("auth cache poisoned")
+            .insert(value.to_string(), hit.clone());
+        Some(hit)
+    }
+}
+
+/// The secret from an `Authorization: Bearer` header, if there is one.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ").map(str::trim).filter(|t| !t.is_empty())
+}
@@ -0,0 +1,319 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use duckdb::{Connection, params};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::db;
+
+/// zstd level for `.zst` backups: the database is already compressed
+/// column by column, so higher levels mostly cost time.
+const BACKUP_ZSTD_LEVEL: i32 = 3;
+
+/// Where a backup is written to or a restore read from: a local path or
+/// `s3://bucket/key`. A name ending in `.zst` is zstd-compressed.
+#[derive(Debug, Clone)]
+enum Location {
+    Local(PathBuf),
+    S3 { bucket: String, key: String },
+}
+
+impl Location {
+    fn parse(s: &str) -> Result<Location> {
+        let Some(rest) = s.strip_prefix("s3://") else {
+            return Ok(Location::Local(PathBuf::from(s)));
+        };
+        match rest.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+                Ok(Location::S3 { bucket: bucket.to_string(), key: key.to_string() })
+            }
+            _ => bail!("{:?} must look like s3://bucket/key", s),
+        }
+    }
+}
+
+fn is_compressed(name: &str) -> bool {
+    name.ends_with(".zst")
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupSummary {
+    pub out: String,
+    /// Size of what was written, after compression
+    pub bytes: u64,
+    pub compressed: bool,
+}
+
+/// A sibling of `path` for work in progress, so a failed run never leaves
+/// something that looks finished.
+fn partial(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).with_context(|| format!("remove {}", path.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Copy the database open as `conn` to `out` as a standalone DuckDB file.
+/// The copy is made in one transaction, so it is consistent even while
+/// imports and the dashboard are using the database; copying the live file
+/// itself could catch it half-written or miss what is still in the WAL.
+pub fn backup(conn: &Connection, out: &str) -> Result<BackupSummary> {
+    let location = Location::parse(out)?;
+    let compressed = is_compressed(out);
+    let target = match &location {
+        Location::Local(path) => path.clone(),
+        Location::S3 { key, .. } => {
+            std::env::temp_dir().join(format!("ezvis-backup-{}-{}", std::process::id(), key.replace('/', "_")))
+        }
+    };
+    if let Location::Local(path) = &location
+        && path.exists()
+    {
+        bail!("{} already exists", path.display());
+    }
+
+    let snapshot = partial(&target, ".snapshot");
+    remove_if_exists(&snapshot)?;
+    let res = write_snapshot(conn, &snapshot).and_then(|_| {
+        let done = partial(&target, ".partial");
+        if compressed {
+            compress(&snapshot, &done)?;
+            fs::remove_file(&snapshot)?;
+        } else {
+            fs::rename(&snapshot, &done)?;
+        }
+        fs::rename(&done, &target)?;
+        Ok(())
+    });
+    if let Err(e) = res {
+        let _ = fs::remove_file(&snapshot);
+        let _ = fs::remove_file(partial(&target, ".partial"));
+        return Err(e);
+    }
+    let bytes = target.metadata()?.len();
+
+    if let Location::S3 { bucket, key } = &location {
+        let res = S3::from_env().and_then(|s3| s3.put(bucket, key, &target));
+        fs::remove_file(&target)?;
+        res?;
+    }
+    Ok(BackupSummary { out: out.to_string(), bytes, compressed })
+}
+
+fn write_snapshot(conn: &Connection, snapshot: &Path) -> Result<()> {
+    let source: String = conn.query_row("SELECT current_database()", params![], |r| r.get(0))?;
+    let path = snapshot.to_str().ok_or_else(|| anyhow!("{} is not valid UTF-8", snapshot.display()))?;
+    // An encrypted database is backed up encrypted, with the same key.
+    let options = match db::encryption_key()? {
+        Some(key) => format!(" (ENCRYPTION_KEY '{}')", key.replace('\'', "''")),
+        None => String::new(),
+    };
+    conn.execute_batch(&format!(
+        "ATTACH '{}' AS ezvis_backup{}; COPY FROM DATABASE \"{}\" TO ezvis_backup; DETACH ezvis_backup;",
+        path.replace('\'', "''"),
+        options,
+        source.replace('"', "\"\"")
+    ))?;
+    Ok(())
+}
+
+fn compress(from: &Path, to: &Path) -> Result<()> {
+    let mut r = BufReader::new(File::open(from)?);
+    let mut w = zstd::Encoder::new(BufWriter::new(File::create(to)?), BACKUP_ZSTD_LEVEL)?;
+    io::copy(&mut r, &mut w)?;
+    w.finish()?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreSummary {
+    pub from: String,
+    pub db: String,
+    pub requests: i64,
+}
+
+/// Replace the database at `db_path` with the backup at `from`. The backup
+/// is unpacked and checked beside the database first, and only then moved
+/// over it, so a bad backup leaves the database as it was. An existing
+/// database is only replaced with `force`.
+pub fn restore(from: &str, db_path: &str, force: bool) -> Result<RestoreSummary> {
+    let db = Path::new(db_path);
+    if db.exists() && !force {
+        bail!("{} already exists; pass --force to replace it", db.display());
+    }
+    let location = Location::parse(from)?;
+    let staged = partial(db, ".restoring");
+    remove_if_exists(&staged)?;
+    remove_if_exists(&partial(db, ".restoring.wal"))?;
+
+    let res = (|| -> Result<i64> {
+        match &location {
+            Location::Local(path) => {
+                let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+                unpack(file, &staged, is_compressed(from))?;
+            }
+            Location::S3 { bucket, key } => {
+                let body = S3::from_env()?.get(bucket, key)?;
+                unpack(body, &staged, is_compressed(from))?;
+            }
+        }
+        let conn = db::open_db(staged.to_str().unwrap_or_default())
+            .with_context(|| format!("{} is not a DuckDB database", from))?;
+        // Backups from before a schema change come back up to date.
+        db::init_schema(&conn)?;
+        let requests = conn.query_row("SELECT count(*) FROM requests", params![], |r| r.get(0))?;
+        conn.execute_batch("CHECKPOINT")?;
+        Ok(requests)
+    })();
+    let requests = match res {
+        Ok(n) => n,
+        Err(e) => {
+            let _ = fs::remove_file(&staged);
+            let _ = fs::remove_file(partial(&staged, ".wal"));
+            return Err(e);
+        }
+    };
+
+    // A WAL left by the old database would be replayed onto the new one.
+    remove_if_exists(&partial(db, ".wal"))?;
+    fs::rename(&staged, db).with_context(|| format!("move restored database to {}", db.display()))?;
+    Ok(RestoreSummary { from: from.to_string(), db: db_path.to_string(), requests })
+}
+
+fn unpack(from: impl io::Read, to: &Path, compressed: bool) -> Result<()> {
+    let mut w = BufWriter::new(File::create(to).with_context(|| format!("create {}", to.display()))?);
+    if compressed {
+        io::copy(&mut zstd::Decoder::new(from)?, &mut w)?;
+    } else {
+        io::copy(&mut BufReader::new(from), &mut w)?;
+    }
+    Ok(())
+}
+
+/// Just enough of S3 to put and get one object, signed with Signature
+/// Version 4. Settings come from the standard AWS environment variables;
+/// `AWS_ENDPOINT_URL` points at S3-compatible stores such as MinIO, which
+/// are addressed path-style.
+struct S3 {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    region: String,
+    endpoint: Option<String>,
+}
+
+impl S3 {
+    fn from_env() -> Result<S3> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Ok(S3 {
+            access_key: var("AWS_ACCESS_KEY_ID").ok_or_else(|| anyhow!("AWS_ACCESS_KEY_ID is not set"))?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY").ok_or_else(|| anyhow!("AWS_SECRET_ACCESS_KEY is not set"))?,
+            session_token: var("AWS_SESSION_TOKEN"),
+            region: var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")).unwrap_or_else(|| "us-east-1".to_string()),
+            endpoint: var("AWS_ENDPOINT_URL"),
+        })
+    }
+
+    fn url(&self, bucket: &str, key: &str) -> String {
+        match &self.endpoint {
+            Some(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, uri_encode(key)),
+            None => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, self.region, uri_encode(key)),
+        }
+    }
+
+    /// Headers that sign a request with no query string. The body is left
+    /// unsigned so a large backup can be streamed from disk.
+    fn signed_headers(&self, method: &str, url: &str) -> Result<Vec<(&'static str, String)>> {
+        let parsed = url::Url::parse(url)?;
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{}", parsed.host_str().unwrap_or_default(), port),
+            None => parsed.host_str().unwrap_or_default().to_string(),
+        };
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v.trim())).collect();
+        let signed: Vec<&str> = headers.iter().map(|(k, _)| *k).collect();
+        let signed = signed.join(";");
+        let canonical_request =
+            format!("{}\n{}\n\n{}\n{}\nUNSIGNED-PAYLOAD", method, parsed.path(), canonical_headers, signed);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign =
+            format!("AWS4-HMAC-SHA256\n{}\n{}\n{:x}", amz_date, scope, Sha256::digest(canonical_request.as_bytes()));
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature: String = hmac(&key, string_to_sign.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+
+        headers.retain(|(k, _)| *k != "host");
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed, signature
+            ),
+        ));
+        Ok(headers)
+    }
+
+    fn put(&self, bucket: &str, key: &str, file: &Path) -> Result<()> {
+        let url = self.url(bucket, key);
+        let mut req = ureq::put(&url);
+        for (k, v) in self.signed_headers("PUT", &url)? {
+            req = req.header(k, v);
+        }
+        req.send(File::open(file)?).with_context(|| format!("upload s3://{}/{}", bucket, key))?;
+        Ok(())
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Result<impl io::Read + use<>> {
+        let url = self.url(bucket, key);
+        let mut req = ureq::get(&url);
+        for (k, v) in self.signed_headers("GET", &url)? {
+            req = req.header(k, v);
+        }
+        let res = req.call().with_context(|| format!("download s3://{}/{}", bucket, key))?;
+        Ok(res.into_body().into_reader())
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode an object key as SigV4 expects: everything but
+/// unreserved characters and the `/` between segments.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,285 @@
+use anyhow::{Result, bail};
+use chrono::Duration;
+use duckdb::{Connection, params};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{config::PortsConfig, db};
+
+/// Minimum z-score for an hourly count to be reported as a spike.
+const SPIKE_Z: f64 = 3.0;
+/// Minimum absolute excess over the learned mean, so that quiet hosts going
+/// from 0 to 3 requests are not reported.
+const SPIKE_MIN_EXCESS: f64 = 10.0;
+/// Countries below this share of a subject's traffic are not considered typical.
+const TYPICAL_COUNTRY_SHARE: f64 = 0.01;
+
+#[derive(Debug, Serialize)]
+pub struct BaselineSummary {
+    pub window_start: String,
+    pub window_end: String,
+    pub hosts: i64,
+    pub users: i64,
+}
+
+/// Recompute every baseline table from the `window` of data ending at the
+/// latest imported request. Logs are usually imported after the fact, so the
+/// window is anchored to the data rather than to the wall clock.
+pub fn build(conn: &Connection, window: Duration, ports: &PortsConfig) -> Result<BaselineSummary> {
+    let hours = window.num_hours();
+    if hours < 24 {
+        bail!("baseline window must be at least 24h");
+    }
+
+    let has_data: bool = conn.query_row("SELECT count(*) > 0 FROM requests", params![], |r| r.get(0))?;
+    if !has_data {
+        bail!("no requests imported yet");
+    }
+
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = build_tables(conn, hours, ports);
+    match res {
+        Ok(summary) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(summary)
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+fn build_tables(conn: &Connection, hours: i64, ports: &PortsConfig) -> Result<BaselineSummary> {
+    conn.execute_batch(
+        r#"
+        DELETE FROM baseline_meta;
+        DELETE FROM baseline_host_hourly;
+        DELETE FROM baseline_user_hourly;
+        DELETE FROM baseline_countries;
+        "#,
+    )?;
+
+    conn.execute(
+        r#"
+        INSERT INTO baseline_meta
+        SELECT now(), hi - INTERVAL 1 HOUR * ?, hi, ?
+        FROM (
+          SELECT date_trunc('hour', max(CAST(ts AS TIMESTAMP))) + INTERVAL 1 HOUR AS hi
+          FROM requests
+        )
+        "#,
+        params![hours, hours],
+    )?;
+
+    // Hourly means include the hours in which a subject had no requests at
+    // all: the number of times each slot occurs in the window is the divisor.
+    let host = db::target_host(ports);
+    conn.execute_batch(&format!(
+        r#"
+        INSERT INTO baseline_host_hourly
+        WITH bounds AS (
+          SELECT window_start AS lo, window_end AS hi FROM baseline_meta
+        ),
+        slots AS (
+          SELECT CAST(EXTRACT(dow FROM h) * 24 + EXTRACT(hour FROM h) AS INTEGER) AS slot,
+                 count(*) AS samples
+          FROM bounds, generate_series(lo, hi - INTERVAL 1 HOUR, INTERVAL 1 HOUR) AS g(h)
+          GROUP BY 1
+        ),
+        hourly AS (
+          SELECT {host} AS host, date_trunc('hour', CAST(ts AS TIMESTAMP)) AS h, count(*) AS n
+          FROM requests, bounds
+          WHERE {host} IS NOT NULL AND ts >= lo AND ts < hi
+          GROUP BY 1, 2
+        )
+        SELECT host, slot,
+               sum(n) / samples AS mean,
+               sqrt(greatest(sum(n * n) / samples - pow(sum(n) / samples, 2), 0)) AS stddev,
+               samples
+        FROM hourly
+        JOIN slots ON slot = CAST(EXTRACT(dow FROM h) * 24 + EXTRACT(hour FROM h) AS INTEGER)
+        GROUP BY host, slot, samples;
+
+        INSERT INTO baseline_user_hourly
+        WITH bounds AS (
+          SELECT window_start AS lo, window_end AS hi FROM baseline_meta
+        ),
+        slots AS (
+          SELECT CAST(EXTRACT(hour FROM h) AS INTEGER) AS slot, count(*) AS samples
+          FROM bounds, generate_series(lo, hi - INTERVAL 1 HOUR, INTERVAL 1 HOUR) AS g(h)
+          GROUP BY 1
+        ),
+        hourly AS (
+          SELECT user_or_session, date_trunc('hour', CAST(ts AS TIMESTAMP)) AS h, count(*) AS n
+          FROM requests, bounds
+          WHERE user_or_session IS NOT NULL AND ts >= lo AND ts < hi
+          GROUP BY 1, 2
+        )
+        SELECT user_or_session, slot,
+               sum(n) / samples AS mean,
+               sqrt(greatest(sum(n * n) / samples - pow(sum(n) / samples, 2), 0)) AS stddev,
+               samples
+        FROM hourly
+        JOIN slots ON slot = CAST(EXTRACT(hour FROM h) AS INTEGER)
+        GROUP BY user_or_session, slot, samples;
+        "#
+    ))?;
+
+    for (subject_type, column) in [("host", host), ("user", "user_or_session")] {
+        conn.execute(
+            &format!(
+                r#"
+                INSERT INTO baseline_countries
+                SELECT ?, {column}, country, count(*) AS n,
+                       count(*) / sum(count(*)) OVER (PARTITION BY {column}) AS share
+                FROM requests, baseline_meta
+                WHERE {column} IS NOT NULL AND country IS NOT NULL
+                  AND ts >= window_start AND ts < window_end
+                GROUP BY {column}, country
+                QUALIFY share >= ?
+                "#
+            ),
+            params![subject_type, TYPICAL_COUNTRY_SHARE],
+        )?;
+    }
+
+    let summary = conn.query_row(
+        r#"
+        SELECT CAST(window_start AS VARCHAR), CAST(window_end AS VARCHAR),
+               (SELECT count(DISTINCT host) FROM baseline_host_hourly),
+               (SELECT count(DISTINCT user_or_session) FROM baseline_user_hourly)
+        FROM baseline_meta
+        "#,
+        params![],
+        |r| {
+            Ok(BaselineSummary {
+                window_start: r.get(0)?,
+                window_end: r.get(1)?,
+                hosts: r.get(2)?,
+                users: r.get(3)?,
+            })
+        },
+    )?;
+    Ok(summary)
+}
+
+/// Compare hourly activity in `[start, end]` against the learned baselines.
+/// Without bounds, the last 24 hours of imported data are checked.
+pub fn anomalies(
+    conn: &Connection,
+    start: Option<&str>,
+    end: Option<&str>,
+    ports: &PortsConfig,
+) -> Result<serde_json::Value> {
+    let built: bool = conn.query_row("SELECT count(*) > 0 FROM baseline_meta", params![], |r| r.get(0))?;
+    if !built {
+        bail!("no baseline available; run `ezvis baseline build` first");
+    }
+
+    let bounds = r#"
+        bounds AS (
+          SELECT COALESCE(CAST(CAST(? AS TIMESTAMPTZ) AS TIMESTAMP),
+                          max(CAST(ts AS TIMESTAMP)) - INTERVAL 24 HOUR) AS lo,
+                 COALESCE(CAST(CAST(? AS TIMESTAMPTZ) AS TIMESTAMP),
+                          max(CAST(ts AS TIMESTAMP))) AS hi
+          FROM requests
+        )
+    "#;
+
+    let mut spikes = Vec::new();
+    let host = db::target_host(ports);
+    let spike_queries = [
+        (
+            "host",
+            format!(
+                r#"
+                SELECT {host}, date_trunc('hour', CAST(ts AS TIMESTAMP)) AS h, count(*) AS n
+                FROM requests, bounds
+                WHERE {host} IS NOT NULL AND ts >= lo AND ts <= hi
+                GROUP BY 1, 2
+                "#
+            ),
+            "baseline_host_hourly b ON b.host = hourly.subject
+               AND b.hour_of_week = CAST(EXTRACT(dow FROM h) * 24 + EXTRACT(hour FROM h) AS INTEGER)",
+        ),
+        (
+            "user",
+            r#"
+            SELECT user_or_session, date_trunc('hour', CAST(ts AS TIMESTAMP)) AS h, count(*) AS n
+            FROM requests, bounds
+            WHERE user_or_session IS NOT NULL AND ts >= lo AND ts <= hi
+            GROUP BY 1, 2
+            "#
+            .to_string(),
+            "baseline_user_hourly b ON b.user_or_session = hourly.subject
+               AND b.hour_of_day = CAST(EXTRACT(hour FROM h) AS INTEGER)",
+        ),
+    ];
+
+    for (kind, hourly, join) in spike_queries {
+        let sql = format!(
+            r#"
+            WITH {bounds},
+            hourly(subject, h, n) AS ({hourly})
+            SELECT subject, CAST(h AS VARCHAR), n, b.mean, b.stddev,
+                   (n - b.mean) / greatest(b.stddev, 1.0) AS z
+            FROM hourly JOIN {join}
+            WHERE n >= b.mean + ? AND (n - b.mean) / greatest(b.stddev, 1.0) >= ?
+            ORDER BY z DESC
+            LIMIT 50
+            "#
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt.query(params![start, end, SPIKE_MIN_EXCESS, SPIKE_Z])?;
+        while let Some(r) = rows.next()? {
+            let subject: String = r.get(0)?;
+            let t: String = r.get(1)?;
+            let n: i64 = r.get(2)?;
+            let mean: f64 = r.get(3)?;
+            let stddev: f64 = r.get(4)?;
+            let z: f64 = r.get(5)?;
+            spikes.push(json!({
+                "kind": kind,
+                "subject": subject,
+                "t": t,
+                "n": n,
+                "mean": mean,
+                "stddev": stddev,
+                "z": z,
+            }));
+        }
+    }
+
+    // Users seen from a country that made up none of their baseline traffic.
+    let sql = format!(
+        r#"
+        WITH {bounds}
+        SELECT r.user_or_session, r.country, count(*) AS n
+        FROM requests r, bounds
+        WHERE r.user_or_session IS NOT NULL AND r.country IS NOT NULL
+          AND r.ts >= lo AND r.ts <= hi
+          AND r.user_or_session IN (
+            SELECT subject FROM baseline_countries WHERE subject_type = 'user'
+          )
+          AND NOT EXISTS (
+            SELECT 1 FROM baseline_countries c
+            WHERE c.subject_type = 'user' AND c.subject = r.user_or_session AND c.country = r.country
+          )
+        GROUP BY 1, 2
+        ORDER BY n DESC
+        LIMIT 50
+        "#
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params![start, end])?;
+    let mut countries = Vec::new();
+    while let Some(r) = rows.next()? {
+        let user: String = r.get(0)?;
+        let country: String = r.get(1)?;
+        let n: i64 = r.get(2)?;
+        countries.push(json!({"user": user, "country": country, "n": n}));
+    }
+
+    Ok(json!({ "spikes": spikes, "unusual_countries": countries }))
+}
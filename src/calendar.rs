@@ -0,0 +1,113 @@
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+use serde_json::json;
+
+use crate::{
+    config::{CalendarConfig, PeriodKind},
+    duration,
+    parser::AssumedTz,
+};
+
+/// Keys accepted in `calendar.service_hours`.
+pub const WEEKDAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Opening and closing time of a `service_hours` entry, or None when the
+/// day is `closed`. A closing time of `24:00` means open until midnight.
+fn parse_hours(s: &str) -> Result<Option<(String, String)>> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("closed") {
+        return Ok(None);
+    }
+    let bad = || anyhow!("expected \"HH:MM-HH:MM\" or \"closed\", got {:?}", s);
+    let (open, close) = s.split_once('-').ok_or_else(bad)?;
+    let (open, close) = (open.trim(), close.trim());
+    let open_t = NaiveTime::parse_from_str(open, "%H:%M").map_err(|_| bad())?;
+    if close != "24:00" {
+        let close_t = NaiveTime::parse_from_str(close, "%H:%M").map_err(|_| bad())?;
+        if close_t <= open_t {
+            bail!("closing time {} is not after opening time {}", close, open);
+        }
+    }
+    // Normalised so the dashboard can compare them as strings.
+    Ok(Some((open_t.format("%H:%M").to_string(), close.to_string())))
+}
+
+/// Reject periods that end before they start and malformed service hours,
+/// so mistakes show up at startup rather than as missing shading.
+pub fn validate(cfg: &CalendarConfig) -> Result<()> {
+    for p in &cfg.periods {
+        if p.end < p.start {
+            bail!("calendar period {:?} ends before it starts", p.name);
+        }
+    }
+    for (day, hours) in &cfg.service_hours {
+        if !WEEKDAYS.contains(&day.as_str()) {
+            bail!("calendar.service_hours: unknown day {:?}, expected one of {}", day, WEEKDAYS.join(", "));
+        }
+        parse_hours(hours).map_err(|e| anyhow!("calendar.service_hours.{}: {}", day, e))?;
+    }
+    timezone(cfg)?;
+    Ok(())
+}
+
+/// `calendar.timezone`, UTC when unset.
+fn timezone(cfg: &CalendarConfig) -> Result<AssumedTz> {
+    Ok(match &cfg.timezone {
+        Some(tz) => AssumedTz::parse(tz).context("calendar.timezone")?,
+        None => AssumedTz::parse("UTC")?,
+    })
+}
+
+/// What `?range=` takes, for error messages.
+pub const RANGES: &str = "24h, 7d, 30d, another duration, or semester";
+
+/// The `start` and `end` (None: up to now) that `?range=` stands for at
+/// `now`. Days and weeks cover today and the days before it from midnight
+/// in `calendar.timezone`, so `7d` is a week of whole days; hours and
+/// shorter run back from now, so `24h` is a sliding day. `semester` is the `term` period under way,
+/// or else the last one to have started.
+pub fn resolve_range(cfg: &CalendarConfig, range: &str, now: DateTime<Utc>) -> Result<(DateTime<Utc>, Option<DateTime<Utc>>)> {
+    let tz = timezone(cfg)?;
+    let today = tz.date(now);
+    if range.trim().eq_ignore_ascii_case("semester") {
+        let term = cfg
+            .periods
+            .iter()
+            .filter(|p| p.kind == PeriodKind::Term && p.start <= today)
+            .max_by_key(|p| p.start)
+            .ok_or_else(|| anyhow!("semester needs a [[calendar.periods]] entry of kind \"term\" that has started"))?;
+        let end = (term.end < today).then(|| tz.start_of(term.end + Days::new(1)) - chrono::Duration::microseconds(1));
+        return Ok((tz.start_of(term.start), end));
+    }
+    let length = duration::parse_duration(range).map_err(|e| anyhow!("{e}; use {RANGES}"))?;
+    if length <= chrono::Duration::zero() {
+        bail!("{:?} is empty; use {}", range, RANGES);
+    }
+    let start = if range.trim().ends_with(['d', 'w']) {
+        today.checked_sub_days(Days::new(length.num_days() as u64 - 1)).map(|d| tz.start_of(d))
+    } else {
+        now.checked_sub_signed(length)
+    };
+    Ok((start.ok_or_else(|| anyhow!("{:?} reaches too far back", range))?, None))
+}
+
+/// Periods overlapping `start..=end` (all of them when a bound is missing)
+/// and the weekly service hours, with closed days as null.
+pub fn overlay(cfg: &CalendarConfig, start: Option<NaiveDate>, end: Option<NaiveDate>) -> Result<serde_json::Value> {
+    let periods: Vec<_> = cfg
+        .periods
+        .iter()
+        .filter(|p| start.is_none_or(|s| p.end >= s) && end.is_none_or(|e| p.start <= e))
+        .collect();
+
+    let mut hours = serde_json::Map::new();
+    for (day, spec) in &cfg.service_hours {
+        let value = match parse_hours(spec)? {
+            Some((open, close)) => json!({ "open": open, "close": close }),
+            None => serde_json::Value::Null,
+        };
+        hours.insert(day.clone(), value);
+    }
+
+    Ok(json!({ "periods": periods, "service_hours": hours }))
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Duration};
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, db, sessions};
+
+/// Hosts and users listed for each window.
+const DOMINANT_N: usize = 3;
+
+/// Longest window `peak_windows` takes; beyond a day the daily series
+/// answers the question.
+const MAX_WINDOW: Duration = Duration::days(1);
+
+/// Most points in a concurrency series; longer ranges are summarised in
+/// buckets of several minutes.
+const MAX_POINTS: i64 = 1440;
+
+/// Bucket sizes, in minutes, a concurrency series may use.
+const RESOLUTIONS: &[i64] = &[1, 5, 10, 15, 30, 60, 120, 180, 360, 720, 1440];
+
+const MINUTE_US: i64 = 60_000_000;
+
+/// Reject window lengths `peak_windows` doesn't take.
+pub fn check_window(window: Duration) -> Result<()> {
+    if window < Duration::seconds(1) || window > MAX_WINDOW {
+        bail!("window must be between 1s and 1d");
+    }
+    Ok(())
+}
+
+/// The `top` busiest windows of length `window` among the requests matching
+/// `filter`, busiest first, each with the hosts and users (or IPs, without
+/// one) behind most of its traffic. Windows are aligned to the clock in
+/// UTC, so 5-minute windows run 10:00-10:05, 10:05-10:10, and so on.
+pub fn peak_windows(
+    conn: &Connection,
+    window: Duration,
+    top: usize,
+    filter: &str,
+    args: &[String],
+    ports: &PortsConfig,
+) -> Result<serde_json::Value> {
+    check_window(window)?;
+    let us = window.num_microseconds().unwrap_or(i64::MAX);
+    let bucket = format!("epoch_us(ts) // {us}");
+    let host = db::target_host(ports);
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT {bucket} AS w, count(*) AS n,
+               round(COALESCE(sum(bytes), 0) / 1024.0 / 1024.0, 1) AS mb,
+               count(DISTINCT COALESCE(user_or_session, remote_addr)) AS users
+        FROM requests
+        WHERE {filter}
+        GROUP BY 1
+        ORDER BY n DESC, w
+        LIMIT {top}
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut windows = Vec::new();
+    while let Some(r) = rows.next()? {
+        let w: i64 = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let mb: f64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        windows.push((w, n, mb, users));
+    }
+    if windows.is_empty() {
+        return Ok(json!({ "window_seconds": window.num_seconds(), "windows": [] }));
+    }
+
+    // One pass over the chosen windows for both breakdowns.
+    let chosen = windows.iter().map(|w| w.0.to_string()).collect::<Vec<_>>().join(", ");
+    let dominant = |column: &str| -> Result<Vec<(i64, String, i64)>> {
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {bucket} AS w, {column} AS v, count(*) AS n
+            FROM requests
+            WHERE {filter} AND {bucket} IN ({chosen}) AND {column} IS NOT NULL
+            GROUP BY 1, 2
+            QUALIFY row_number() OVER (PARTITION BY w ORDER BY n DESC, v) <= {DOMINANT_N}
+            ORDER BY w, n DESC, v
+            "#
+        ))?;
+        let rows = stmt.query_map(params_from_iter(args), |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+        Ok(rows.collect::<duckdb::Result<_>>()?)
+    };
+    let hosts = dominant(host)?;
+    let users = dominant("COALESCE(user_or_session, remote_addr)")?;
+
+    let seconds = (us as f64 / 1_000_000.0).max(1.0);
+    let out: Vec<_> = windows
+        .iter()
+        .map(|&(w, n, mb, user_count)| {
+            let start = DateTime::from_timestamp_micros(w * us).unwrap_or_default();
+            let top_hosts: Vec<_> =
+                hosts.iter().filter(|h| h.0 == w).map(|(_, host, n)| json!({ "host": host, "n": n })).collect();
+            let top_users: Vec<_> =
+                users.iter().filter(|u| u.0 == w).map(|(_, who, n)| json!({ "who": who, "n": n })).collect();
+            json!({
+                "start": start.to_rfc3339(),
+                "end": (start + window).to_rfc3339(),
+                "requests": n,
+                "requests_per_second": (n as f64 / seconds * 100.0).round() / 100.0,
+                "mb": mb,
+                "users": user_count,
+                "top_hosts": top_hosts,
+                "top_users": top_users,
+            })
+        })
+        .collect();
+    Ok(json!({ "window_seconds": window.num_seconds(), "windows": out }))
+}
+
+/// Estimated concurrent sessions per minute among the requests matching
+/// `filter`. A session is a user's (or, without one, an IP's) requests with
+/// gaps of at most `sessions::SESSION_GAP_SECS`, across every platform as
+/// EZproxy counts them, and is active in each minute from its first request
+/// to its last. EZproxy keeps a session open until it times out, so the
+/// counts err low. Ranges longer than `MAX_POINTS` minutes come back in
+/// wider buckets, each with its peak and mean minute; `peak` and `p95` are
+/// over single minutes whatever the resolution. `hosts` counts the distinct
+/// hostnames requested, which is what EZproxy's `MaxVirtualHosts` caps.
+pub fn concurrency(conn: &Connection, filter: &str, args: &[String]) -> Result<serde_json::Value> {
+    let gap_us = sessions::SESSION_GAP_SECS * 1_000_000;
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        WITH r AS (
+          SELECT COALESCE(user_or_session, remote_addr) AS who, epoch_us(ts) AS t
+          FROM requests
+          WHERE {filter}
+        ),
+        marked AS (
+          SELECT *, CASE WHEN t - lag(t) OVER (PARTITION BY who ORDER BY t) <= {gap_us} THEN 0 ELSE 1 END AS new_session
+          FROM r
+        ),
+        numbered AS (
+          SELECT *, sum(new_session) OVER (PARTITION BY who ORDER BY t ROWS UNBOUNDED PRECEDING) AS session_no
+          FROM marked
+        )
+        SELECT min(t) // {MINUTE_US}, max(t) // {MINUTE_US}
+        FROM numbered
+        GROUP BY who, session_no
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    // Per minute, sessions starting minus sessions ended the minute before.
+    let mut deltas: BTreeMap<i64, i64> = BTreeMap::new();
+    let mut sessions = 0;
+    while let Some(r) = rows.next()? {
+        let first: i64 = r.get(0)?;
+        let last: i64 = r.get(1)?;
+        *deltas.entry(first).or_default() += 1;
+        *deltas.entry(last + 1).or_default() -= 1;
+        sessions += 1;
+    }
+    let hosts: i64 = conn.query_row(
+        &format!("SELECT count(DISTINCT host) FROM requests WHERE {filter}"),
+        params_from_iter(args),
+        |r| r.get(0),
+    )?;
+    let (Some(&from), Some(&to)) = (deltas.keys().next(), deltas.keys().next_back()) else {
+        return Ok(json!({ "resolution_minutes": 1, "sessions": 0, "hosts": hosts, "peak": null, "p95": null, "series": [] }));
+    };
+
+    // `to` is the minute after the last session ends.
+    let span = to - from;
+    let needed = (span + MAX_POINTS - 1) / MAX_POINTS;
+    let resolution = RESOLUTIONS.iter().copied().find(|&r| r >= needed).unwrap_or(needed);
+    let mut counts = Vec::with_capacity(span as usize);
+    let mut series = Vec::new();
+    let (mut current, mut peak, mut peak_at) = (0i64, 0i64, from);
+    let (mut bucket_peak, mut bucket_sum, mut bucket_minutes) = (0i64, 0i64, 0i64);
+    for minute in from..to {
+        current += deltas.get(&minute).copied().unwrap_or_default();
+        counts.push(current);
+        if current > peak {
+            peak = current;
+            peak_at = minute;
+        }
+        bucket_peak = bucket_peak.max(current);
+        bucket_sum += current;
+        bucket_minutes += 1;
+        // Buckets are aligned to the clock, so the first may be short.
+        if (minute + 1).rem_euclid(resolution) == 0 || minute == to - 1 {
+            let start = (minute - minute.rem_euclid(resolution)).max(from);
+            series.push(json!({
+                "t": DateTime::from_timestamp_micros(start * MINUTE_US).unwrap_or_default().to_rfc3339(),
+                "peak": bucket_peak,
+                "mean": (bucket_sum as f64 / bucket_minutes as f64 * 10.0).round() / 10.0,
+            }));
+            (bucket_peak, bucket_sum, bucket_minutes) = (0, 0, 0);
+        }
+    }
+    counts.sort_unstable();
+    let p95 = counts[((counts.len() - 1) as f64 * 0.95).round() as usize];
+
+    Ok(json!({
+        "resolution_minutes": resolution,
+        "sessions": sessions,
+        "hosts": hosts,
+        "peak": { "n": peak, "t": DateTime::from_timestamp_micros(peak_at * MINUTE_US).unwrap_or_default().to_rfc3339() },
+        "p95": p95,
+        "series": series,
+    }))
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result, anyhow, bail};
+use plotters::{
+    coord::{Shift, ranged1d::SegmentValue},
+    prelude::*,
+};
+
+use crate::ui::Theme;
+
+/// Panels `/api/chart/{name}.png` can draw, with the title shown on each.
+pub const CHARTS: &[(&str, &str)] = &[
+    ("requests_over_time", "Requests per hour"),
+    ("bandwidth_over_time", "Bandwidth (MB per hour)"),
+    ("top_hosts", "Top hosts"),
+    ("top_countries", "Top countries"),
+    ("status_codes", "Status codes"),
+    ("user_agents", "Browsers"),
+];
+
+/// Bars drawn by the ranked charts; the rest are left out, as on the dashboard.
+const MAX_BARS: usize = 15;
+
+fn color(hex: &str) -> RGBColor {
+    let n = u32::from_str_radix(hex.trim_start_matches('#'), 16).unwrap_or(0);
+    RGBColor((n >> 16) as u8, (n >> 8) as u8, n as u8)
+}
+
+/// `YYYY-MM-DD HH:..` -> `MM-DD HH:00`.
+fn hour_label(t: &str) -> String {
+    t.get(5..13).map(|s| format!("{}:00", s)).unwrap_or_else(|| t.to_string())
+}
+
+/// `(label, value)` pairs from the array `key` of a panel's JSON.
+fn points(panel: &serde_json::Value, key: &str, label: &str, value: &str) -> Vec<(String, f64)> {
+    panel[key]
+        .as_array()
+        .map(|rows| {
+            rows.iter()
+                .map(|r| {
+                    let label = match &r[label] {
+                        serde_json::Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    (label, r[value].as_f64().unwrap_or(0.0))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Status codes grouped by class, as the dashboard's doughnut shows them.
+fn status_classes(panel: &serde_json::Value, theme: &Theme) -> Vec<(String, f64, RGBColor)> {
+    let mut groups = [0.0; 5];
+    for (status, n) in points(panel, "status", "status", "n") {
+        let class = status.parse::<i64>().map(|s| s / 100).unwrap_or(0);
+        groups[if (2..=5).contains(&class) { class as usize - 2 } else { 4 }] += n;
+    }
+    ["2xx", "3xx", "4xx", "5xx", "Other"]
+        .iter()
+        .zip(groups)
+        .zip(theme.status)
+        .map(|((label, n), c)| (label.to_string(), n, color(c)))
+        .collect()
+}
+
+/// Draw the panel `name` from its JSON and encode it as a PNG.
+pub fn render_png(name: &str, panel: &serde_json::Value, theme: &Theme, width: u32, height: u32) -> Result<Vec<u8>> {
+    let title = CHARTS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, title)| *title)
+        .ok_or_else(|| anyhow!("no chart named {:?}", name))?;
+
+    let mut pixels = vec![0u8; width as usize * height as usize * 3];
+    {
+        let root = BitMapBackend::with_buffer(&mut pixels, (width, height)).into_drawing_area();
+        root.fill(&color(theme.surface)).map_err(draw_error)?;
+        let accent = color(theme.accent);
+        match name {
+            "requests_over_time" => time_series(&root, title, theme, points(panel, "series", "t", "n"), false)?,
+            "bandwidth_over_time" => time_series(&root, title, theme, points(panel, "series", "t", "mb"), true)?,
+            "top_hosts" | "top_countries" | "user_agents" => {
+                let (key, label) = match name {
+                    "top_hosts" => ("hosts", "host"),
+                    "top_countries" => ("countries", "country"),
+                    _ => ("browsers", "browser"),
+                };
+                let bars = points(panel, key, label, "n").into_iter().map(|(l, n)| (l, n, accent)).collect();
+                ranked_bars(&root, title, theme, bars)?
+            }
+            "status_codes" => ranked_bars(&root, title, theme, status_classes(panel, theme))?,
+            _ => bail!("no renderer for chart {:?}", name),
+        }
+        root.present().map_err(draw_error)?;
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut w| w.write_image_data(&pixels))
+        .context("encode PNG")?;
+    Ok(out)
+}
+
+fn draw_error<E: std::fmt::Display>(e: E) -> anyhow::Error {
+    anyhow!("draw chart: {}", e)
+}
+
+/// Hourly values left to right, as a line or as bars.
+fn time_series(
+    root: &DrawingArea<BitMapBackend<'_>, Shift>,
+    title: &str,
+    theme: &Theme,
+    series: Vec<(String, f64)>,
+    bars: bool,
+) -> Result<()> {
+    let text = color(theme.text);
+    let accent = color(theme.accent);
+    let max = series.iter().map(|p| p.1).fold(0.0, f64::max).max(1.0) * 1.1;
+    let len = series.len().max(1);
+
+    let mut chart = ChartBuilder::on(root)
+        .caption(title, ("sans-serif", 20).into_font().color(&text))
+        .margin(15)
+        .x_label_area_size(30)
+        .y_label_area_size(60)
+        .build_cartesian_2d(0..len, 0.0..max)
+        .map_err(draw_error)?;
+    chart
+        .configure_mesh()
+        .light_line_style(TRANSPARENT)
+        .bold_line_style(color(theme.muted).mix(0.3))
+        .axis_style(color(theme.muted))
+        .label_style(("sans-serif", 12).into_font().color(&text))
+        .x_labels(6)
+        .x_label_formatter(&|i| series.get(*i).map(|p| hour_label(&p.0)).unwrap_or_default())
+        .y_label_formatter(&|v| v.to_string())
+        .draw()
+        .map_err(draw_error)?;
+
+    if bars {
+        chart
+            .draw_series(series.iter().enumerate().map(|(i, p)| {
+                Rectangle::new([(i, 0.0), (i + 1, p.1)], accent.mix(0.7).filled())
+            }))
+            .map_err(draw_error)?;
+    } else {
+        chart
+            .draw_series(LineSeries::new(series.iter().enumerate().map(|(i, p)| (i, p.1)), accent.stroke_width(2)))
+            .map_err(draw_error)?;
+    }
+    Ok(())
+}
+
+/// Horizontal bars, largest at the top.
+fn ranked_bars(
+    root: &DrawingArea<BitMapBackend<'_>, Shift>,
+    title: &str,
+    theme: &Theme,
+    mut bars: Vec<(String, f64, RGBColor)>,
+) -> Result<()> {
+    let text = color(theme.text);
+    bars.truncate(MAX_BARS);
+    let n = bars.len().max(1);
+    let max = bars.iter().map(|b| b.1).fold(0.0, f64::max).max(1.0) * 1.1;
+    let label_width = bars.iter().map(|b| b.0.chars().count()).max().unwrap_or(0).clamp(4, 32) as u32 * 8;
+
+    let mut chart = ChartBuilder::on(root)
+        .caption(title, ("sans-serif", 20).into_font().color(&text))
+        .margin(15)
+        .x_label_area_size(30)
+        .y_label_area_size(label_width)
+        // Segmented ranges include their end, so this is `n` rows.
+        .build_cartesian_2d(0.0..max, (0..n - 1).into_segmented())
+        .map_err(draw_error)?;
+    // Row `i` from the bottom holds bar `n - 1 - i`.
+    let label_of = |v: &SegmentValue<usize>| match v {
+        SegmentValue::CenterOf(i) if *i < bars.len() => {
+            let label = &bars[bars.len() - 1 - i].0;
+            if label.chars().count() > 32 { format!("{}…", label.chars().take(31).collect::<String>()) } else { label.clone() }
+        }
+        _ => String::new(),
+    };
+    chart
+        .configure_mesh()
+        .disable_y_mesh()
+        .light_line_style(TRANSPARENT)
+        .bold_line_style(color(theme.muted).mix(0.3))
+        .axis_style(color(theme.muted))
+        .label_style(("sans-serif", 12).into_font().color(&text))
+        .y_labels(n)
+        .y_label_formatter(&label_of)
+        .x_label_formatter(&|v| v.to_string())
+        .draw()
+        .map_err(draw_error)?;
+
+    let rows = bars.len();
+    chart
+        .draw_series(bars.iter().enumerate().map(|(rank, (_, v, c))| {
+            let row = rows - 1 - rank;
+            let mut bar = Rectangle::new(
+                [(0.0, SegmentValue::Exact(row)), (*v, SegmentValue::Exact(row + 1))],
+                c.mix(0.8).filled(),
+            );
+            bar.set_margin(3, 3, 0, 0);
+            bar
+        }))
+        .map_err(draw_error)?;
+    Ok(())
+}
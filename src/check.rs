@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use croner::Cron;
+use duckdb::{Connection, params};
+use serde::Serialize;
+
+use crate::{
+    auth, calendar, clients,
+    config::Config,
+    db, destinations, duration, enrich, honeytokens, parser, policy, siem, ui,
+    web::{parse_query_timeout, parse_scheme_port, titles_cte},
+};
+
+/// Something in a config file that would fail or misbehave once it ran.
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    /// 1-based line of the file it was found on, where that can be told
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// Everything wrong with the config file at `path`, rather than only the
+/// first thing `serve` or an import would trip over: TOML syntax and
+/// unknown keys, then referrer patterns, the title list query, GeoIP and
+/// client type files, schedules, logins, policies, and the other sections
+/// checked at startup. Fails only when the file can't be read.
+pub fn check_file(path: &str) -> Result<Vec<Issue>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read config {}", path))?;
+    let config: Config = match toml::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => {
+            let line = e.span().map(|s| text[..s.start].matches('\n').count() + 1);
+            return Ok(vec![Issue { line, message: e.message().to_string() }]);
+        }
+    };
+    Ok(check(&config)
+        .into_iter()
+        .map(|(needle, message)| Issue { line: line_of(&text, &needle), message })
+        .collect())
+}
+
+/// First line of `text` mentioning `needle`, as a key or a quoted value.
+fn line_of(text: &str, needle: &str) -> Option<usize> {
+    let quoted = format!("{:?}", needle);
+    let literal = format!("'{}'", needle);
+    text.lines()
+        .position(|l| {
+            let l = l.trim_start();
+            l.contains(&quoted) || l.contains(&literal) || l.starts_with(needle)
+        })
+        .map(|i| i + 1)
+}
+
+/// Issues with an already parsed config, each with the key or value that
+/// locates it in the file.
+pub fn check(config: &Config) -> Vec<(String, String)> {
+    let mut issues = Vec::new();
+    let mut report = |needle: &str, message: String| issues.push((needle.to_string(), message));
+
+    if let Err(e) = parse_query_timeout(&config.server.query_timeout) {
+        report("query_timeout", format!("{:#}", e));
+    }
+    if config.server.max_queries == 0 {
+        report("max_queries", "server.max_queries must be at least 1".to_string());
+    }
+    if ui::theme(&config.ui.default_theme).is_none() {
+        report("default_theme", format!("unknown ui.default_theme {:?}", config.ui.default_theme));
+    }
+    for entry in &config.ports.expected {
+        if parse_scheme_port(entry).is_none() {
+            report(entry, format!("ports.expected entries look like \"https:8443\", got {:?}", entry));
+        }
+    }
+    for system in &config.referrers {
+        let checked = Connection::open_in_memory().map_err(Into::into).and_then(|conn| db::check_regex(&conn, &system.pattern));
+        if let Err(e) = checked {
+            report(&system.pattern, format!("invalid pattern for referrer system {:?}: {}", system.name, e));
+        }
+    }
+    if let Err(e) = calendar::validate(&config.calendar) {
+        report("[calendar", format!("{:#}", e));
+    }
+    if let Err(e) = destinations::Rules::new(&config.destinations, &config.licenses) {
+        report("[destinations]", format!("{:#}", e));
+    }
+    if let Err(e) = parser::Timestamps::from_config(&config.parser) {
+        report("[parser]", format!("{:#}", e));
+    }
+
+    let geoip_missing = config.enrich.geoip.as_ref().filter(|g| !Path::new(&g.database).is_file());
+    if let Some(g) = geoip_missing {
+        report(&g.database, format!("enrich.geoip.database {} does not exist", g.database));
+    } else if let Err(e) = enrich::Pipeline::from_config(&config.enrich) {
+        report("stages", format!("{:#}", e));
+    }
+
+    if let Some(titles) = &config.titles {
+        let run = || -> Result<i64> {
+            let conn = Connection::open_in_memory()?;
+            let n = conn.query_row(&format!("SELECT count(*) FROM ({})", titles_cte(Some(titles))), params![], |r| r.get(0))?;
+            Ok(n)
+        };
+        if let Err(e) = run() {
+            // DuckDB follows its message with the query text.
+            let e = e.to_string();
+            let first = e.lines().next().unwrap_or_default();
+            report(&titles.path, format!("titles list {} can't be read: {}", titles.path, first));
+        }
+    }
+    for path in &config.client_types.lists {
+        if !Path::new(path).is_file() {
+            report(path, format!("client type list {} does not exist", path));
+        }
+    }
+    if config.client_types.lists.iter().all(|p| Path::new(p).is_file())
+        && let Err(e) = clients::load(&config.client_types)
+    {
+        report("[client_types]", format!("{:#}", e));
+    }
+
+    if let Some(a) = &config.auth
+        && let Err(e) = auth::Authenticator::new(a.clone())
+    {
+        report("[[auth.users]]", format!("{:#}", e));
+    }
+    if let Err(e) = policy::validate(&config.policies) {
+        report("[[policies]]", format!("{:#}", e));
+    }
+    if let Err(e) = honeytokens::validate(&config.honeytokens) {
+        report("[[honeytokens]]", format!("{:#}", e));
+    }
+    if let Some(s) = &config.siem
+        && let Err(e) = siem::validate(s)
+    {
+        report("[siem]", format!("{:#}", e));
+    }
+
+    let mut schedules = vec![
+        ("export.schedule", config.export.as_ref().and_then(|e| e.schedule.as_ref())),
+        ("federation.schedule", config.federation.as_ref().and_then(|f| f.schedule.as_ref())),
+    ];
+    schedules.extend(config.schedules.iter().map(|s| ("schedules.schedule", Some(&s.schedule))));
+    for (key, schedule) in schedules {
+        if let Some(s) = schedule
+            && let Err(e) = Cron::new(s).parse()
+        {
+            report(s, format!("{} {:?} is not a valid cron expression: {}", key, s, e));
+        }
+    }
+    if let Some(fed) = &config.federation
+        && let Err(e) = duration::parse_duration(&fed.window)
+    {
+        report("[federation]", format!("federation.window: {:#}", e));
+    }
+    issues
+}
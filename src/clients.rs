@@ -0,0 +1,115 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    net::IpAddr,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::config::ClientTypesConfig;
+
+/// Client types a network can be tagged with. Addresses in no listed network
+/// are reported as `unknown`.
+pub const CLIENT_TYPES: &[&str] = &["campus", "residential", "hosting", "vpn"];
+
+/// Networks keyed by prefix length, so a lookup is one hash probe per
+/// distinct prefix length instead of a scan of the whole list.
+#[derive(Debug, Default)]
+pub struct NetworkList {
+    v4: BTreeMap<u8, HashMap<u32, &'static str>>,
+    v6: BTreeMap<u8, HashMap<u128, &'static str>>,
+}
+
+fn parse_type(s: &str) -> Result<&'static str> {
+    let s = s.trim().to_ascii_lowercase();
+    CLIENT_TYPES
+        .iter()
+        .find(|t| **t == s)
+        .copied()
+        .ok_or_else(|| anyhow!("unknown client type {:?}, expected one of {}", s, CLIENT_TYPES.join(", ")))
+}
+
+fn mask_v4(addr: u32, len: u8) -> u32 {
+    if len == 0 { 0 } else { addr & (u32::MAX << (32 - len)) }
+}
+
+fn mask_v6(addr: u128, len: u8) -> u128 {
+    if len == 0 { 0 } else { addr & (u128::MAX << (128 - len)) }
+}
+
+impl NetworkList {
+    /// Add a network in CIDR notation; a bare address is a single host.
+    /// When the same network is listed twice the later entry wins.
+    pub fn insert(&mut self, cidr: &str, kind: &'static str) -> Result<()> {
+        let cidr = cidr.trim();
+        let (addr, len) = match cidr.split_once('/') {
+            Some((a, l)) => (a, Some(l.parse::<u8>().with_context(|| format!("bad prefix in {:?}", cidr))?)),
+            None => (cidr, None),
+        };
+        match addr.parse::<IpAddr>().with_context(|| format!("bad network {:?}", cidr))? {
+            IpAddr::V4(a) => {
+                let len = len.unwrap_or(32);
+                if len > 32 {
+                    bail!("bad prefix in {:?}", cidr);
+                }
+                self.v4.entry(len).or_default().insert(mask_v4(a.into(), len), kind);
+            }
+            IpAddr::V6(a) => {
+                let len = len.unwrap_or(128);
+                if len > 128 {
+                    bail!("bad prefix in {:?}", cidr);
+                }
+                self.v6.entry(len).or_default().insert(mask_v6(a.into(), len), kind);
+            }
+        }
+        Ok(())
+    }
+
+    /// Type of the most specific network containing `addr`. IPv4-mapped
+    /// IPv6 addresses are matched as the IPv4 addresses they carry.
+    pub fn classify(&self, addr: &str) -> Option<&'static str> {
+        match addr.parse::<IpAddr>().ok()?.to_canonical() {
+            IpAddr::V4(a) => {
+                let a = u32::from(a);
+                self.v4.iter().rev().find_map(|(len, nets)| nets.get(&mask_v4(a, *len)).copied())
+            }
+            IpAddr::V6(a) => {
+                let a = u128::from(a);
+                self.v6.iter().rev().find_map(|(len, nets)| nets.get(&mask_v6(a, *len)).copied())
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+/// Build the network list from `[client_types]`: each list file is a CSV of
+/// `network,type` rows (header optional), then the inline `campus` networks,
+/// which replace any list entry for exactly the same network.
+pub fn load(cfg: &ClientTypesConfig) -> Result<NetworkList> {
+    let mut list = NetworkList::default();
+    for path in &cfg.lists {
+        let text = fs::read_to_string(path).with_context(|| format!("read client type list {}", path))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((network, kind)) = line.split_once(',') else {
+                bail!("{}:{}: expected network,type", path, i + 1);
+            };
+            if i == 0 && network.trim().eq_ignore_ascii_case("network") {
+                continue;
+            }
+            let kind = parse_type(kind.split(',').next().unwrap_or_default())
+                .with_context(|| format!("{}:{}", path, i + 1))?;
+            list.insert(network, kind).with_context(|| format!("{}:{}", path, i + 1))?;
+        }
+    }
+    for network in &cfg.campus {
+        list.insert(network, "campus").context("client_types.campus")?;
+    }
+    Ok(list)
+}
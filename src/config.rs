@@ -0,0 +1,676 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Role;
+
+/// Loaded from the working directory when `--config` is not given.
+pub const DEFAULT_PATH: &str = "ezvis.toml";
+
+/// Site configuration from `ezvis.toml`. Every section is optional so that a
+/// missing file behaves exactly like an empty one.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub export: Option<ExportConfig>,
+    pub server: ServerConfig,
+    pub ui: UiConfig,
+    pub titles: Option<TitlesConfig>,
+    /// Extra referrer systems, checked before the built-in ones
+    pub referrers: Vec<ReferrerSystem>,
+    pub client_types: ClientTypesConfig,
+    pub ports: PortsConfig,
+    pub destinations: DestinationsConfig,
+    pub federation: Option<FederationConfig>,
+    /// Unauthenticated `/public` stats page; off without this section
+    pub public: Option<PublicConfig>,
+    pub privacy: PrivacyConfig,
+    pub auth: Option<AuthConfig>,
+    pub calendar: CalendarConfig,
+    pub parser: ParserConfig,
+    pub enrich: EnrichConfig,
+    /// Rules checked by `ezvis policy check`
+    pub policies: Vec<PolicyConfig>,
+    /// Bait URLs no patron has reason to visit, alerted on as they are imported
+    pub honeytokens: Vec<HoneytokenConfig>,
+    /// Where alerts, and any requests picked out, are forwarded; off without
+    /// this section
+    pub siem: Option<SiemConfig>,
+    /// Simultaneous-user limit per platform (host, as `target_host` gives
+    /// it), for `/api/license_pressure`
+    pub licenses: BTreeMap<String, u32>,
+    /// Jobs queued on a timetable by `serve` or `ezvis cron`
+    pub schedules: Vec<ScheduleConfig>,
+}
+
+/// A background job queued each time `schedule` fires, e.g.
+/// `schedule = "0 3 * * *"`, `kind = "import"`, `path = "..."`; the fields
+/// besides `schedule` are those `POST /api/jobs` takes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleConfig {
+    /// Cron expression (minute hour day-of-month month day-of-week, local time)
+    pub schedule: String,
+    #[serde(flatten)]
+    pub job: crate::jobs::JobSpec,
+}
+
+/// Scheduled export of monthly usage tables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Cron expression (minute hour day-of-month month day-of-week, local time)
+    pub schedule: Option<String>,
+    /// Directory the CSV files are written to
+    #[serde(default = "default_export_dir")]
+    pub dir: String,
+    /// URL that receives the same tables as a JSON POST
+    pub webhook: Option<String>,
+    /// Rows in the top-platforms table
+    #[serde(default = "default_top_platforms")]
+    pub top_platforms: i64,
+    /// Secret for signing integrity export manifests
+    pub signing_key: Option<String>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig {
+            schedule: None,
+            dir: default_export_dir(),
+            webhook: None,
+            top_platforms: default_top_platforms(),
+            signing_key: None,
+        }
+    }
+}
+
+/// What the public stats page shows. Counts for fewer than `min_users`
+/// distinct users are left out.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublicConfig {
+    /// Heading of the page, e.g. the library's name
+    pub title: String,
+    /// Months shown, up to and including the newest
+    pub months: u32,
+    /// Platforms in the ranking
+    pub top_platforms: u32,
+    pub min_users: u32,
+}
+
+impl Default for PublicConfig {
+    fn default() -> Self {
+        PublicConfig {
+            title: "E-resource usage".to_string(),
+            months: 12,
+            top_platforms: 10,
+            min_users: 10,
+        }
+    }
+}
+
+/// Limits on looking at individuals, as some institutions' policies require.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// Whole days, counting today, that endpoints naming users or IPs leave
+    /// out; 0 for none. Aggregate endpoints still cover them.
+    pub embargo_days: u32,
+    /// Fewest distinct users a breakdown may show a bucket for; 0 for no
+    /// minimum
+    pub min_group_users: u32,
+    /// What happens to buckets below `min_group_users`
+    pub small_groups: SmallGroups,
+    /// Fewest distinct users a department or affiliation may be shown for;
+    /// smaller ones are pooled. `min_group_users` raises it.
+    pub min_attribute_users: u32,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        PrivacyConfig { embargo_days: 0, min_group_users: 0, small_groups: SmallGroups::Suppress, min_attribute_users: 5 }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmallGroups {
+    /// Leave them out
+    #[default]
+    Suppress,
+    /// Keep them with their counts rounded to the nearest multiple of
+    /// `min_group_users`
+    Round,
+}
+
+impl PrivacyConfig {
+    /// Start of the embargo: midnight UTC `embargo_days - 1` days before
+    /// today, so requests from then on are withheld. None without an embargo.
+    pub fn cutoff(&self) -> Option<DateTime<Utc>> {
+        if self.embargo_days == 0 {
+            return None;
+        }
+        let today = Utc::now().date_naive();
+        let first = today - Days::new(u64::from(self.embargo_days) - 1);
+        Some(first.and_time(NaiveTime::MIN).and_utc())
+    }
+
+    /// Fewest users behind any group of a breakdown by user attribute.
+    pub fn attribute_floor(&self) -> u32 {
+        self.min_attribute_users.max(self.min_group_users)
+    }
+}
+
+/// HTTP behaviour of `serve`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Gzip or Brotli responses for clients that accept them
+    pub compression: bool,
+    /// Longest a panel, chart, or `/api/query` call may run its SQL, e.g.
+    /// `30s` or `2m`; `0s` for no limit. Exports are never cut off.
+    pub query_timeout: String,
+    /// Queries run at once; further requests wait for one to finish
+    pub max_queries: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { compression: true, query_timeout: "30s".to_string(), max_queries: 4 }
+    }
+}
+
+/// Dashboard appearance.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// Theme used until a visitor picks another: light, dark, or colorblind
+    pub default_theme: String,
+    /// Language used when neither `?lang=` nor Accept-Language match: en, es, fr
+    pub default_language: String,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        UiConfig {
+            default_theme: "light".to_string(),
+            default_language: "en".to_string(),
+        }
+    }
+}
+
+/// Title list used to name ISSNs in `/api/top_issns`. Defaults fit a plain
+/// `issn,title` CSV; for a KBART file use
+/// `issn_columns = ["print_identifier", "online_identifier"]` and
+/// `title_column = "publication_title"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TitlesConfig {
+    /// CSV or TSV file with a header row
+    pub path: String,
+    #[serde(default = "default_issn_columns")]
+    pub issn_columns: Vec<String>,
+    #[serde(default = "default_title_column")]
+    pub title_column: String,
+}
+
+/// Networks used to tag client IPs in `/api/client_types`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTypesConfig {
+    /// CSV files of `network,type` rows, e.g. exported from an ASN or
+    /// IP-intelligence database; type is campus, residential, hosting, or vpn
+    pub lists: Vec<String>,
+    /// The institution's own networks, tagged campus
+    pub campus: Vec<String>,
+}
+
+/// Which hosts `/api/proxy_abuse` counts as licensed resources and which it
+/// flags, on top of its built-in list of streaming, social media, and other
+/// sites no library licenses.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DestinationsConfig {
+    /// Host patterns (`*` and `?`) of licensed resources, never flagged;
+    /// hosts in `[licenses]` count too
+    pub allowed: Vec<String>,
+    /// Host patterns always flagged
+    pub denied: Vec<String>,
+    /// Flag every host not in `allowed`, not just the ones that look wrong
+    pub strict: bool,
+}
+
+/// Scheme/port combinations `/api/ports` treats as normal, in addition to
+/// http on 80 and https on 443, and whether hosts are told apart by port.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortsConfig {
+    /// Entries like `"https:8443"` or `"http:2048"`
+    pub expected: Vec<String>,
+    /// Count a host on a port other than its scheme's default apart from
+    /// the same name on 80 or 443, as `host:port`, in host analytics
+    pub split_hosts: bool,
+}
+
+/// Academic calendar and opening hours, shaded behind the dashboard's time
+/// series.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalendarConfig {
+    pub periods: Vec<CalendarPeriod>,
+    /// Weekday (`mon` .. `sun`) to `"08:00-22:00"` or `"closed"`; days left
+    /// out are not shaded
+    pub service_hours: BTreeMap<String, String>,
+    /// Zone whose midnights `?range=` counts days from, e.g.
+    /// "America/Chicago" [default: UTC]
+    pub timezone: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarPeriod {
+    pub name: String,
+    pub kind: PeriodKind,
+    pub start: NaiveDate,
+    /// Inclusive
+    pub end: NaiveDate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeriodKind {
+    Term,
+    Break,
+    Exam,
+    Holiday,
+}
+
+/// How log lines are read; see `parser::Timestamps`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParserConfig {
+    /// chrono formats tried in order for the bracketed timestamp, or
+    /// "rfc3339"; "%s" is epoch seconds
+    pub timestamp_formats: Vec<String>,
+    /// Locales whose month names may appear in place of English ones:
+    /// de, es, fr, it, nl, pt
+    pub month_locales: Vec<String>,
+    /// Zone for timestamps logged without an offset, e.g. "America/Chicago"
+    /// or "+01:00"; also repairs two-digit years. Unset, such lines are
+    /// rejected.
+    pub assume_tz: Option<String>,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            timestamp_formats: ["%d/%b/%Y:%H:%M:%S %z", "rfc3339", "%s"].map(String::from).to_vec(),
+            month_locales: Vec::new(),
+            assume_tz: None,
+        }
+    }
+}
+
+/// Stages every imported row passes through, in order; see `enrich`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichConfig {
+    /// Stage names: identifiers, user_agent, path_template, request_kind,
+    /// target_host, geoip, anonymize, scrub, truncate
+    pub stages: Vec<String>,
+    pub geoip: Option<GeoipConfig>,
+    pub anonymize: Option<AnonymizeConfig>,
+    pub request_kind: RequestKindConfig,
+    pub target_host: Option<TargetHostConfig>,
+    pub scrub: ScrubConfig,
+    pub truncate: TruncateConfig,
+}
+
+impl Default for EnrichConfig {
+    fn default() -> Self {
+        EnrichConfig {
+            stages: ["identifiers", "user_agent", "path_template", "request_kind"].map(String::from).to_vec(),
+            geoip: None,
+            anonymize: None,
+            request_kind: RequestKindConfig::default(),
+            target_host: None,
+            scrub: ScrubConfig::default(),
+            truncate: TruncateConfig::default(),
+        }
+    }
+}
+
+/// What the `request_kind` stage sorts requests by. Each extension list
+/// given replaces the built-in one; paths with an extension in neither are
+/// page views.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestKindConfig {
+    /// Full text a patron downloads, tagged `content`
+    pub content_extensions: Vec<String>,
+    /// Page furniture, tagged `asset`
+    pub asset_extensions: Vec<String>,
+    /// EZproxy's own host names, e.g. login.ezproxy.myuni.edu; its login,
+    /// logout, menu, and connect pages on them are tagged `proxy`. Empty
+    /// matches those pages on any host.
+    pub proxy_hosts: Vec<String>,
+}
+
+impl Default for RequestKindConfig {
+    fn default() -> Self {
+        RequestKindConfig {
+            content_extensions: crate::enrich::CONTENT_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            asset_extensions: crate::sessions::ASSET_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            proxy_hosts: Vec::new(),
+        }
+    }
+}
+
+/// Query parameters the `scrub` stage blanks out of URLs. Giving `params`
+/// replaces the built-in list.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrubConfig {
+    /// Parameter names, compared without regard to case; `*` matches any
+    /// run of characters and `?` any one
+    pub params: Vec<String>,
+    /// Also scrub any value holding an email address, whatever its name
+    pub emails: bool,
+    /// What a scrubbed value is replaced with
+    pub replacement: String,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            params: crate::enrich::SCRUB_PARAMS.iter().map(|p| p.to_string()).collect(),
+            emails: true,
+            replacement: "REDACTED".to_string(),
+        }
+    }
+}
+
+/// Longest values the `truncate` stage stores, in bytes, counting the
+/// `…` it ends a cut value with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TruncateConfig {
+    /// For `url`, `query`, and `referrer`
+    pub max_url: usize,
+    /// For `path` and `path_template`
+    pub max_path: usize,
+}
+
+impl Default for TruncateConfig {
+    fn default() -> Self {
+        TruncateConfig { max_url: 2048, max_path: 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoipConfig {
+    /// MaxMind-format country or city database, e.g. GeoLite2-Country.mmdb
+    pub database: String,
+    /// Replace the country EZproxy logged instead of only filling blanks
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetHostConfig {
+    /// Proxy host names that vendor hosts are rewritten under with
+    /// proxy-by-hostname, e.g. ezproxy.myuni.edu
+    pub suffixes: Vec<String>,
+    /// Proxy-by-port assignments, port to vendor host, e.g.
+    /// `2050 = "www.jstor.org"`
+    #[serde(default)]
+    pub ports: BTreeMap<String, String>,
+    /// CSV files of `port,host` rows (header optional), read before `ports`
+    #[serde(default)]
+    pub port_maps: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnonymizeConfig {
+    /// Secret for pseudonymizing usernames; keep it to get the same
+    /// pseudonym for the same user across imports
+    pub key: String,
+    /// Network bits of IPv4 addresses kept; the rest are zeroed
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+/// Consortium roll-up: pull daily aggregates (never raw rows) from member
+/// instances and show them side by side.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FederationConfig {
+    /// Cron expression for pulls (local time); without it, pull via a
+    /// `federation_pull` job
+    pub schedule: Option<String>,
+    /// How many days back each pull refreshes, e.g. 35d
+    #[serde(default = "default_federation_window")]
+    pub window: String,
+    #[serde(default)]
+    pub members: Vec<FederationMember>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FederationMember {
+    /// Label used for attribution in combined views
+    pub name: String,
+    /// Base URL of the member's ezvis, e.g. https://ezvis.member.edu
+    pub url: String,
+    /// `read:aggregates` API token, for members that have `[auth]` set up
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Dashboard and API logins. Without this section nothing is protected, so
+/// only bind to localhost.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub users: Vec<AuthUser>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthUser {
+    pub name: String,
+    /// Argon2 PHC string from `ezvis auth hash-password`
+    pub password_hash: String,
+    pub role: Role,
+}
+
+/// A rule traffic is held to. Breaches are recorded in the alerts table,
+/// one per user (or IP, without a user or for login failures) and day.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub name: String,
+    pub kind: PolicyKind,
+    /// max_countries_per_day: most countries a user may appear from in a day;
+    /// max_login_failures_per_day: most refused logins from one IP in a day
+    pub max: Option<u32>,
+    /// blocked_countries and allowed_countries: country codes as logged
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// Users the rule doesn't apply to, e.g. a monitoring account
+    #[serde(default)]
+    pub exempt_users: Vec<String>,
+}
+
+/// A URL seeded where only someone harvesting links would find it, e.g. in
+/// a page a credential reseller scrapes. Every request for it is recorded
+/// in the alerts table with high severity, one per user (or IP) and day.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoneytokenConfig {
+    pub name: String,
+    /// The URL as logged, or a path when it starts with `/`; `*` and `?`
+    /// are wildcards
+    pub url: String,
+}
+
+/// A SIEM that alerts, and requests matching `requests`, are sent to after
+/// each import and policy check, and by `ezvis siem forward`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiemConfig {
+    #[serde(default)]
+    pub kind: SiemKind,
+    /// `udp://host:514` or `tcp://host:514` for syslog; the HEC endpoint
+    /// (`.../services/collector/event`) for Splunk; the cluster's base URL
+    /// for Elastic or OpenSearch
+    pub url: String,
+    /// Syslog message body; Splunk and Elastic always get JSON
+    #[serde(default)]
+    pub format: SiemFormat,
+    /// Splunk HEC token, or Elastic API key
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Elastic index prefix; alerts go to `<index>-alerts`, requests to
+    /// `<index>-requests`
+    #[serde(default = "default_siem_index")]
+    pub index: String,
+    /// SQL condition selecting requests to forward as well, e.g.
+    /// "status IN (401, 403)"; none without it
+    #[serde(default)]
+    pub requests: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SiemKind {
+    #[default]
+    Syslog,
+    /// Splunk HTTP Event Collector
+    Splunk,
+    /// Elastic or OpenSearch `_bulk` API
+    Elastic,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SiemFormat {
+    /// ArcSight Common Event Format
+    #[default]
+    Cef,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyKind {
+    /// A user seen from more than `max` countries in one day
+    MaxCountriesPerDay,
+    /// Any request from one of `countries`
+    BlockedCountries,
+    /// Any request from a known country not in `countries`
+    AllowedCountries,
+    /// An IP refused at the login form more than `max` times in one day
+    MaxLoginFailuresPerDay,
+}
+
+/// Names a discovery system by a case-insensitive regular expression
+/// matched against the full referrer URL.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReferrerSystem {
+    pub name: String,
+    pub pattern: String,
+}
+
+/// Discovery systems recognized without any configuration.
+pub const DEFAULT_REFERRER_SYSTEMS: &[(&str, &str)] = &[
+    ("Primo", r"primo|exlibrisgroup\.com|/discovery/(search|fulldisplay)"),
+    ("Summon", r"summon\.(serialssolutions|proquest)\.com"),
+    ("EDS", r"eds\.[a-z]+\.ebscohost\.com|search\.ebscohost\.com"),
+    ("Google Scholar", r"scholar\.google\."),
+    ("LibGuides", r"libguides|springshare"),
+];
+
+impl Config {
+    /// Configured referrer systems followed by the built-in ones, in match
+    /// order.
+    pub fn referrer_systems(&self) -> Vec<ReferrerSystem> {
+        self.referrers
+            .iter()
+            .cloned()
+            .chain(DEFAULT_REFERRER_SYSTEMS.iter().map(|(name, pattern)| ReferrerSystem {
+                name: name.to_string(),
+                pattern: pattern.to_string(),
+            }))
+            .collect()
+    }
+}
+
+fn default_issn_columns() -> Vec<String> {
+    vec!["issn".to_string()]
+}
+
+fn default_title_column() -> String {
+    "title".to_string()
+}
+
+fn default_federation_window() -> String {
+    "35d".to_string()
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    48
+}
+
+fn default_siem_index() -> String {
+    "ezvis".to_string()
+}
+
+fn default_export_dir() -> String {
+    "exports".to_string()
+}
+
+fn default_top_platforms() -> i64 {
+    50
+}
+
+/// The file `load` reads: `path` if given, else `DEFAULT_PATH` if it
+/// exists, else none.
+pub fn resolve_path(path: Option<&str>) -> Option<&str> {
+    match path {
+        Some(p) => Some(p),
+        None if Path::new(DEFAULT_PATH).exists() => Some(DEFAULT_PATH),
+        None => None,
+    }
+}
+
+/// The config a long-running `serve` uses, replaced whole when the file is
+/// reloaded. Read it afresh for each piece of work rather than holding on.
+pub type Shared = Arc<RwLock<Arc<Config>>>;
+
+/// Load the config at `path`, or `ezvis.toml` if it exists. An explicitly
+/// requested file must exist; the default one is optional.
+pub fn load(path: Option<&str>) -> Result<Config> {
+    let Some(path) = resolve_path(path) else {
+        return Ok(Config::default());
+    };
+
+    let text = std::fs::read_to_string(path).with_context(|| format!("read config {}", path))?;
+    toml::from_str(&text).with_context(|| format!("parse config {}", path))
+}
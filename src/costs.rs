@@ -0,0 +1,137 @@
+use anyhow::{Context, Result, bail};
+use duckdb::{Connection, params, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, db, downloads, sessions};
+
+/// Split one CSV line into fields, honouring double-quoted fields with
+/// doubled quotes inside, as spreadsheets write them.
+pub(crate) fn split_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.into_iter().map(|f| f.trim().to_string()).collect()
+}
+
+/// `"$12,500.00"` -> 12500.0. Currency symbols and thousands separators
+/// are what spreadsheets export; the figures are taken as one currency.
+fn parse_cost(s: &str) -> Option<f64> {
+    let digits: String = s.chars().filter(|c| c.is_ascii_digit() || *c == '.' || *c == '-').collect();
+    digits.parse().ok().filter(|c: &f64| c.is_finite() && *c >= 0.0)
+}
+
+/// Load annual platform costs from CSV text with a header naming `platform`,
+/// `year`, and `cost` columns; others, such as a vendor or fund code, are
+/// ignored. A platform is the host usage is counted under, as `target_host`
+/// gives it. Rows replace any cost already loaded for the same platform and
+/// year, so a corrected file can be loaded again. Nothing is stored if any
+/// row is malformed. Returns the number of rows loaded.
+pub fn load_csv(conn: &Connection, text: &str) -> Result<usize> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    let (_, header) = lines.next().context("empty costs file")?;
+    let header: Vec<String> = split_line(header.trim_start_matches('\u{feff}')).iter().map(|h| h.to_lowercase()).collect();
+    let find = |name: &str| header.iter().position(|h| h == name).with_context(|| format!("costs file has no {} column", name));
+    let (platform_at, year_at, cost_at) = (find("platform")?, find("year")?, find("cost")?);
+
+    let mut rows = Vec::new();
+    for (i, line) in lines {
+        let fields = split_line(line);
+        let field = |at: usize| fields.get(at).map(String::as_str).unwrap_or_default();
+        let platform = field(platform_at).to_lowercase();
+        if platform.is_empty() {
+            bail!("line {}: no platform", i + 1);
+        }
+        let year: i32 = field(year_at).parse().with_context(|| format!("line {}: bad year {:?}", i + 1, field(year_at)))?;
+        let cost = parse_cost(field(cost_at)).with_context(|| format!("line {}: bad cost {:?}", i + 1, field(cost_at)))?;
+        rows.push((platform, year, cost));
+    }
+
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = (|| -> Result<()> {
+        for (platform, year, cost) in &rows {
+            conn.execute(
+                r#"
+                INSERT INTO platform_costs (host, year, cost) VALUES (?, ?, ?)
+                ON CONFLICT (host, year) DO UPDATE SET cost = excluded.cost, updated_at = now()
+                "#,
+                params![platform, year, cost],
+            )?;
+        }
+        Ok(())
+    })();
+    match res {
+        Ok(()) => conn.execute_batch("COMMIT")?,
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    Ok(rows.len())
+}
+
+/// Cost per download and per session for every platform and year with a
+/// loaded cost, counting the requests matching `filter` in that calendar
+/// year. Downloads are counted as `/api/downloads` counts them, and sessions
+/// per platform as `/api/license_pressure` does. Years without usage have
+/// no cost per use rather than an infinite one.
+pub fn cost_per_use(conn: &Connection, filter: &str, args: &[String], ports: &PortsConfig) -> Result<serde_json::Value> {
+    let costed = format!("{filter} AND lower({}) IN (SELECT host FROM platform_costs)", db::target_host(ports));
+    let sql = format!(
+        r#"
+        WITH {downloads},
+        {sessions},
+        dl AS (
+          SELECT lower(host) AS host, year(day) AS year, count(*) AS n FROM d GROUP BY 1, 2
+        ),
+        ss AS (
+          SELECT host, year(make_timestamp(first_us)) AS year, count(*) AS n FROM platform_sessions GROUP BY 1, 2
+        )
+        SELECT c.host, c.year, c.cost,
+               COALESCE(dl.n, 0) AS downloads,
+               COALESCE(ss.n, 0) AS sessions,
+               round(c.cost / NULLIF(dl.n, 0), 2) AS cost_per_download,
+               round(c.cost / NULLIF(ss.n, 0), 2) AS cost_per_session
+        FROM platform_costs c
+        LEFT JOIN dl ON dl.host = c.host AND dl.year = c.year
+        LEFT JOIN ss ON ss.host = c.host AND ss.year = c.year
+        ORDER BY c.year DESC, cost_per_download DESC NULLS FIRST, c.host
+        "#,
+        downloads = downloads::cte(&costed, ports),
+        sessions = sessions::platform_cte(&costed, ports),
+    );
+    // The filter appears once in each CTE.
+    let bind: Vec<&String> = args.iter().chain(args.iter()).collect();
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(bind))?;
+    let mut platforms = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let year: i32 = r.get(1)?;
+        let cost: f64 = r.get(2)?;
+        let downloads: i64 = r.get(3)?;
+        let sessions: i64 = r.get(4)?;
+        let cost_per_download: Option<f64> = r.get(5)?;
+        let cost_per_session: Option<f64> = r.get(6)?;
+        platforms.push(json!({
+            "host": host,
+            "year": year,
+            "cost": cost,
+            "downloads": downloads,
+            "sessions": sessions,
+            "cost_per_download": cost_per_download,
+            "cost_per_session": cost_per_session,
+        }));
+    }
+    Ok(json!({ "platforms": platforms }))
+}
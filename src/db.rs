@@ -1,54 +1,868 @@
-use anyhow::Result;
-use duckdb::{params, Connection};
-use crate::parser::LogRow;
+use std::collections::{BTreeMap, HashSet};
 
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use duckdb::{params, Connection, Params, types::Value};
+use crate::{config::PortsConfig, parser::LogRow, seen};
+
+/// Result of an ad-hoc query with its column names, for writing out as
+/// CSV or JSON without a struct per query.
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+impl Table {
+    /// Rows as JSON objects keyed by column name.
+    pub fn to_objects(&self) -> Vec<serde_json::Value> {
+        self.rows
+            .iter()
+            .map(|row| {
+                let obj = self.columns.iter().cloned().zip(row.iter().cloned()).collect();
+                serde_json::Value::Object(obj)
+            })
+            .collect()
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = self.columns.join(",");
+        out.push('\n');
+        for row in &self.rows {
+            out.push_str(&row.iter().map(|v| csv_quote(&plain_field(v))).collect::<Vec<_>>().join(","));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// A value as text, with NULL as an empty string.
+pub fn plain_field(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// One CSV field, quoted when it needs to be.
+pub fn csv_quote(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// One TSV field. TSV has no quoting, so tabs and line breaks become spaces.
+pub fn tsv_quote(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+
+fn json_value(v: Value) -> serde_json::Value {
+    match v {
+        Value::Null => serde_json::Value::Null,
+        Value::Boolean(b) => b.into(),
+        Value::TinyInt(n) => n.into(),
+        Value::SmallInt(n) => n.into(),
+        Value::Int(n) => n.into(),
+        Value::BigInt(n) => n.into(),
+        Value::HugeInt(n) => (n as f64).into(),
+        Value::UTinyInt(n) => n.into(),
+        Value::USmallInt(n) => n.into(),
+        Value::UInt(n) => n.into(),
+        Value::UBigInt(n) => n.into(),
+        Value::Float(n) => n.into(),
+        Value::Double(n) => n.into(),
+        Value::Text(s) => s.into(),
+        other => format!("{:?}", other).into(),
+    }
+}
+
+/// Run `sql` and collect every row. Cast timestamps to VARCHAR in the query;
+/// only plain numbers, booleans, and text map cleanly to JSON.
+pub fn query_table<P: Params>(conn: &Connection, sql: &str, params: P) -> Result<Table> {
+    Ok(query_table_capped(conn, sql, params, usize::MAX)?.0)
+}
+
+/// Run `sql` and hand each row to `f` as it is read, without holding the
+/// result in memory. `f` returns false to stop early.
+pub fn for_each_row<P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+    mut f: impl FnMut(&[serde_json::Value]) -> Result<bool>,
+) -> Result<()> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(params)?;
+    let mut row = Vec::new();
+    while let Some(r) = rows.next()? {
+        row.clear();
+        for i in 0..r.as_ref().column_count() {
+            row.push(json_value(r.get::<_, Value>(i)?));
+        }
+        if !f(&row)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Like `query_table`, but stops after `max_rows`. The flag is true when
+/// rows were left unread.
+pub fn query_table_capped<P: Params>(conn: &Connection, sql: &str, params: P, max_rows: usize) -> Result<(Table, bool)> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut out = Vec::new();
+    let mut truncated = false;
+    {
+        let mut rows = stmt.query(params)?;
+        while let Some(r) = rows.next()? {
+            if out.len() == max_rows {
+                truncated = true;
+                break;
+            }
+            let n = r.as_ref().column_count();
+            let mut row = Vec::with_capacity(n);
+            for i in 0..n {
+                row.push(json_value(r.get::<_, Value>(i)?));
+            }
+            out.push(row);
+        }
+    }
+    let table = Table {
+        columns: stmt.column_names(),
+        rows: out,
+    };
+    Ok((table, truncated))
+}
+
+/// Environment variable holding the key of an encrypted database.
+pub const KEY_ENV: &str = "EZVIS_DB_KEY";
+/// Environment variable naming a file that holds the key instead, e.g. a
+/// systemd credential.
+pub const KEY_FILE_ENV: &str = "EZVIS_DB_KEY_FILE";
+
+/// Catalog an encrypted database is attached as.
+const ENCRYPTED_ALIAS: &str = "ezvis";
+
+/// The database key from `EZVIS_DB_KEY` or the file named by
+/// `EZVIS_DB_KEY_FILE`; `None` when the database isn't encrypted.
+pub fn encryption_key() -> Result<Option<String>> {
+    if let Some(key) = std::env::var(KEY_ENV).ok().filter(|k| !k.is_empty()) {
+        return Ok(Some(key));
+    }
+    let Some(file) = std::env::var(KEY_FILE_ENV).ok().filter(|f| !f.is_empty()) else {
+        return Ok(None);
+    };
+    let key = std::fs::read_to_string(&file).with_context(|| format!("read {} from {}", KEY_FILE_ENV, file))?;
+    let key = key.trim().to_string();
+    if key.is_empty() {
+        bail!("{} names {}, which is empty", KEY_FILE_ENV, file);
+    }
+    Ok(Some(key))
+}
+
+pub fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Compile `pattern` as `regexp_matches` will, with DuckDB's RE2 rather
+/// than the `regex` crate, whose syntax differs in places.
+pub fn check_regex(conn: &Connection, pattern: &str) -> Result<()> {
+    conn.query_row("SELECT regexp_matches('', ?, 'i')", params![pattern], |_| Ok(()))?;
+    Ok(())
+}
+
+/// DuckDB's built-in crypto only reads encrypted files; writing them
+/// securely takes OpenSSL, which comes with the httpfs extension.
+fn load_openssl(conn: &Connection) -> Result<()> {
+    if conn.execute_batch("LOAD httpfs").is_ok() {
+        return Ok(());
+    }
+    conn.execute_batch("INSTALL httpfs; LOAD httpfs").context(
+        "writing an encrypted database needs DuckDB's httpfs extension, which could not be loaded or installed; \
+         install it once on a machine with network access (INSTALL httpfs) and copy ~/.duckdb/extensions over",
+    )?;
+    Ok(())
+}
+
+/// Attach the database at `path` with DuckDB's AES encryption and make it
+/// the default catalog, so queries needn't know it is attached. Its WAL and
+/// any temporary files spilled to disk are encrypted too.
+fn open_encrypted(path: &str, key: &str, read_only: bool) -> Result<Connection> {
+    let conn = Connection::open_in_memory()?;
+    if !read_only {
+        load_openssl(&conn)?;
+    }
+    let mode = if read_only { ", READ_ONLY" } else { "" };
+    conn.execute_batch(&format!(
+        "ATTACH {} AS {ENCRYPTED_ALIAS} (ENCRYPTION_KEY {}{mode}); USE {ENCRYPTED_ALIAS};",
+        sql_string(path),
+        sql_string(key)
+    ))
+    .with_context(|| format!("open {} with {}: wrong key, or not an encrypted database?", path, KEY_ENV))?;
+    Ok(conn)
+}
+
+/// Waits between attempts, in milliseconds, while the database is busy.
+const BUSY_BACKOFF_MS: &[u64] = &[25, 50, 100, 200, 400];
+
+/// Whether `message` (an error with its causes, as `{:#}` prints it) says
+/// another process holds the database file's lock, as it does for the
+/// length of an import, or that a write collided with another one. Both
+/// clear up by themselves.
+pub fn is_busy(message: &str) -> bool {
+    message.contains("Could not set lock on file") || message.contains("write-write conflict")
+}
+
+/// `f`, tried again with growing waits while it fails because the database
+/// is busy, for a little under a second in all.
+pub fn retry_busy<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    for wait in BUSY_BACKOFF_MS {
+        match f() {
+            Err(e) if is_busy(&format!("{:#}", e)) => std::thread::sleep(std::time::Duration::from_millis(*wait)),
+            res => return res,
+        }
+    }
+    f()
+}
+
+/// Open the database at `path`, decrypting it with `encryption_key` when
+/// one is set. While another process has it open, keeps trying briefly.
 pub fn open_db(path: &str) -> Result<Connection> {
-    Ok(Connection::open(path)?)
+    let key = encryption_key()?;
+    retry_busy(|| match &key {
+        Some(key) => open_encrypted(path, key, false),
+        None => Ok(Connection::open(path)?),
+    })
+}
+
+/// Connection for user-supplied SQL: writes fail, and so does anything that
+/// touches the filesystem or network (read_csv, COPY, ATTACH, ...).
+pub fn open_read_only(path: &str) -> Result<Connection> {
+    retry_busy(|| open_read_only_once(path))
+}
+
+fn open_read_only_once(path: &str) -> Result<Connection> {
+    if let Some(key) = encryption_key()? {
+        // External access is needed for the ATTACH itself, so it is shut
+        // off afterwards, and the settings locked so SQL can't reopen it.
+        let conn = open_encrypted(path, &key, true)?;
+        conn.execute_batch("SET enable_external_access = false; SET lock_configuration = true;")?;
+        return Ok(conn);
+    }
+    let config = duckdb::Config::default()
+        .access_mode(duckdb::AccessMode::ReadOnly)?
+        .enable_external_access(false)?;
+    Ok(Connection::open_with_flags(path, config)?)
+}
+
+/// Rewrite the unencrypted database at `path` encrypted with `key`. The
+/// encrypted copy is written beside it and moved over it once complete.
+pub fn encrypt(path: &str, key: &str) -> Result<()> {
+    let staged = format!("{}.encrypting", path);
+    for leftover in [staged.clone(), format!("{}.wal", staged)] {
+        if std::path::Path::new(&leftover).exists() {
+            std::fs::remove_file(&leftover)?;
+        }
+    }
+    {
+        let conn = Connection::open_in_memory()?;
+        load_openssl(&conn)?;
+        conn.execute_batch(&format!(
+            "ATTACH {} AS plain; CHECKPOINT plain; ATTACH {} AS encrypted (ENCRYPTION_KEY {}); \
+             COPY FROM DATABASE plain TO encrypted; DETACH encrypted; DETACH plain;",
+            sql_string(path),
+            sql_string(&staged),
+            sql_string(key)
+        ))
+        .with_context(|| format!("copy {} into an encrypted database", path))?;
+    }
+    // Checkpointed above, so this holds nothing the copy lacks; left in
+    // place it would be replayed onto the new file.
+    let wal = format!("{}.wal", path);
+    if std::path::Path::new(&wal).exists() {
+        std::fs::remove_file(&wal)?;
+    }
+    std::fs::rename(&staged, path)?;
+    Ok(())
 }
 
 pub fn init_schema(conn: &Connection) -> Result<()> {
+    let fill_seen = seen::missing(conn)?;
     conn.execute_batch(
         r#"
-        CREATE TABLE IF NOT EXISTS requests (
-          ts TIMESTAMPTZ,
-          remote_addr TEXT,
-          identd TEXT,
+        CREATE SEQUENCE IF NOT EXISTS jobs_id_seq;
+        CREATE TABLE IF NOT EXISTS jobs (
+          id BIGINT PRIMARY KEY DEFAULT nextval('jobs_id_seq'),
+          kind TEXT NOT NULL,
+          spec TEXT NOT NULL,
+          status TEXT NOT NULL,
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          started_at TIMESTAMPTZ,
+          finished_at TIMESTAMPTZ,
+          result TEXT,
+          error TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS baseline_meta (
+          built_at TIMESTAMPTZ,
+          window_start TIMESTAMP,
+          window_end TIMESTAMP,
+          window_hours INTEGER
+        );
+
+        CREATE TABLE IF NOT EXISTS baseline_host_hourly (
+          host TEXT,
+          hour_of_week INTEGER,
+          mean DOUBLE,
+          stddev DOUBLE,
+          samples INTEGER
+        );
+
+        CREATE TABLE IF NOT EXISTS baseline_user_hourly (
           user_or_session TEXT,
-          method TEXT,
-          url TEXT,
-          scheme TEXT,
+          hour_of_day INTEGER,
+          mean DOUBLE,
+          stddev DOUBLE,
+          samples INTEGER
+        );
+
+        CREATE TABLE IF NOT EXISTS baseline_countries (
+          subject_type TEXT,
+          subject TEXT,
+          country TEXT,
+          n BIGINT,
+          share DOUBLE
+        );
+
+        CREATE TABLE IF NOT EXISTS federation_daily (
+          member TEXT,
+          day DATE,
           host TEXT,
-          port INTEGER,
-          path TEXT,
+          requests BIGINT,
+          users BIGINT,
+          mb DOUBLE,
+          pulled_at TIMESTAMPTZ
+        );
+
+        CREATE SEQUENCE IF NOT EXISTS api_tokens_id_seq;
+        CREATE TABLE IF NOT EXISTS api_tokens (
+          id BIGINT PRIMARY KEY DEFAULT nextval('api_tokens_id_seq'),
+          name TEXT,
+          token_hash TEXT NOT NULL UNIQUE,
+          scopes TEXT NOT NULL,
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          last_used_at TIMESTAMPTZ,
+          revoked_at TIMESTAMPTZ
+        );
+
+        CREATE SEQUENCE IF NOT EXISTS export_manifests_id_seq;
+        CREATE TABLE IF NOT EXISTS export_manifests (
+          id BIGINT PRIMARY KEY DEFAULT nextval('export_manifests_id_seq'),
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          filters TEXT NOT NULL,
+          row_count BIGINT,
+          chain_sha256 TEXT,
+          signature TEXT,
+          completed_at TIMESTAMPTZ
+        );
+
+        CREATE SEQUENCE IF NOT EXISTS alerts_id_seq;
+        CREATE TABLE IF NOT EXISTS alerts (
+          id BIGINT PRIMARY KEY DEFAULT nextval('alerts_id_seq'),
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          -- What raised it; 'policy' for policy checks, 'honeytoken' for
+          -- requests to a honeytoken
+          source TEXT NOT NULL,
+          -- Policy or honeytoken name
+          rule TEXT NOT NULL,
+          -- User, or IP for requests without one
+          subject TEXT NOT NULL,
+          day DATE NOT NULL,
+          -- JSON object with the specifics, e.g. the countries seen
+          detail TEXT,
+          UNIQUE (source, rule, subject, day)
+        );
+        -- high for honeytoken hits
+        ALTER TABLE alerts ADD COLUMN IF NOT EXISTS severity TEXT DEFAULT 'medium';
+
+        CREATE SEQUENCE IF NOT EXISTS imports_id_seq;
+        CREATE TABLE IF NOT EXISTS imports (
+          id BIGINT PRIMARY KEY DEFAULT nextval('imports_id_seq'),
+          path TEXT NOT NULL,
+          started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          finished_at TIMESTAMPTZ,
+          ok BIGINT,
+          bad BIGINT,
+          skipped BIGINT,
+          -- Share of the file's lines imported; 1 unless --sample was used
+          sample_rate DOUBLE NOT NULL DEFAULT 1,
+          -- Where an unfinished import got to, and which file it was reading
+          bytes_done BIGINT NOT NULL DEFAULT 0,
+          lines_done BIGINT NOT NULL DEFAULT 0,
+          fingerprint TEXT,
+          -- JSON object of lines that failed to parse, by reason
+          parse_failures TEXT
+        );
+        ALTER TABLE imports ADD COLUMN IF NOT EXISTS sample_rate DOUBLE DEFAULT 1;
+        ALTER TABLE imports ADD COLUMN IF NOT EXISTS bytes_done BIGINT DEFAULT 0;
+        ALTER TABLE imports ADD COLUMN IF NOT EXISTS lines_done BIGINT DEFAULT 0;
+        ALTER TABLE imports ADD COLUMN IF NOT EXISTS fingerprint TEXT;
+        ALTER TABLE imports ADD COLUMN IF NOT EXISTS parse_failures TEXT;
+
+        CREATE TABLE IF NOT EXISTS platform_costs (
+          -- Lowercased, as target_host gives it
+          host TEXT NOT NULL,
+          year INTEGER NOT NULL,
+          cost DOUBLE NOT NULL,
+          updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          PRIMARY KEY (host, year)
+        );
+
+        CREATE SEQUENCE IF NOT EXISTS access_audit_id_seq;
+        CREATE TABLE IF NOT EXISTS access_audit (
+          id BIGINT PRIMARY KEY DEFAULT nextval('access_audit_id_seq'),
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          -- Login or token label
+          caller TEXT NOT NULL,
+          method TEXT NOT NULL,
+          path TEXT NOT NULL,
+          -- Query string, i.e. the filters
           query TEXT,
-          http_version TEXT,
-          status INTEGER,
-          bytes BIGINT,
-          country TEXT,
-          user_agent TEXT,
-          raw TEXT
+          -- Body of an ad-hoc SQL request
+          body TEXT
+        );
+
+        CREATE SEQUENCE IF NOT EXISTS pseudonym_resolutions_id_seq;
+        CREATE TABLE IF NOT EXISTS pseudonym_resolutions (
+          id BIGINT PRIMARY KEY DEFAULT nextval('pseudonym_resolutions_id_seq'),
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+          -- Login or token label of whoever asked
+          caller TEXT NOT NULL,
+          pseudonym TEXT NOT NULL,
+          reason TEXT NOT NULL,
+          -- Usernames tried, and whether one matched; never which
+          candidates BIGINT NOT NULL,
+          matched BOOLEAN NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS users (
+          -- Lowercased, matched against lower(user_or_session)
+          user_id TEXT PRIMARY KEY,
+          department TEXT,
+          status TEXT,
+          affiliation TEXT,
+          updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+
+        -- Earliest and latest request per host and user, kept up by imports;
+        -- see seen.rs
+        CREATE TABLE IF NOT EXISTS host_seen (
+          -- target_host, else host
+          host TEXT PRIMARY KEY,
+          first_seen TIMESTAMPTZ NOT NULL,
+          last_seen TIMESTAMPTZ NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS user_seen (
+          user_or_session TEXT PRIMARY KEY,
+          first_seen TIMESTAMPTZ NOT NULL,
+          last_seen TIMESTAMPTZ NOT NULL
         );
 
-        CREATE INDEX IF NOT EXISTS idx_requests_ts ON requests(ts);
-        CREATE INDEX IF NOT EXISTS idx_requests_host ON requests(host);
-        CREATE INDEX IF NOT EXISTS idx_requests_status ON requests(status);
-        CREATE INDEX IF NOT EXISTS idx_requests_country ON requests(country);
+        -- How far each stream (alerts, requests) has been sent to [siem]
+        CREATE TABLE IF NOT EXISTS siem_cursor (
+          stream TEXT PRIMARY KEY,
+          -- Microseconds since the epoch of the last event sent
+          forwarded_to BIGINT NOT NULL
+        );
         "#,
     )?;
+    init_requests(conn)?;
+    if fill_seen {
+        seen::rebuild(conn)?;
+    }
+    Ok(())
+}
+
+/// Columns of every `requests` partition, in order: the appender is
+/// positional, so columns added later go at the end.
+pub const REQUEST_COLUMNS: &[(&str, &str)] = &[
+    ("ts", "TIMESTAMPTZ"),
+    ("remote_addr", "TEXT"),
+    ("identd", "TEXT"),
+    ("user_or_session", "TEXT"),
+    ("method", "TEXT"),
+    ("url", "TEXT"),
+    ("scheme", "TEXT"),
+    ("host", "TEXT"),
+    ("port", "INTEGER"),
+    ("path", "TEXT"),
+    ("query", "TEXT"),
+    ("http_version", "TEXT"),
+    ("status", "INTEGER"),
+    ("bytes", "BIGINT"),
+    ("country", "TEXT"),
+    ("user_agent", "TEXT"),
+    ("raw", "TEXT"),
+    ("issn", "TEXT"),
+    ("isbn", "TEXT"),
+    ("referrer", "TEXT"),
+    ("browser", "TEXT"),
+    ("path_template", "TEXT"),
+    ("raw_zstd", "BLOB"),
+    ("request_kind", "TEXT"),
+    ("target_host", "TEXT"),
+    ("tls_protocol", "TEXT"),
+    ("tls_cipher", "TEXT"),
+    ("url_sha256", "TEXT"),
+    ("host_unicode", "TEXT"),
+    ("file_ext", "TEXT"),
+];
+
+/// Columns indexed in every partition.
+const REQUEST_INDEXES: &[&str] = &["ts", "host", "status", "country", "issn", "file_ext"];
+
+/// `parser::file_ext` in SQL, to fill `file_ext` in rows imported before it.
+const FILE_EXT_OF_PATH: &str = r"nullif(lower(regexp_extract(path, '\.([A-Za-z0-9]{1,10})$', 1)), '')";
+
+/// Columns derived from others, filled in existing rows as they are added.
+const DERIVED_COLUMNS: &[(&str, &str)] = &[("file_ext", FILE_EXT_OF_PATH)];
+
+/// Matches the names of monthly partitions, e.g. `requests_2026_02`.
+pub const PARTITION_PATTERN: &str = r"^requests_\d{4}_\d{2}$";
+
+/// Partition holding requests from the UTC month of `ts`.
+pub fn partition_for(ts: DateTime<Utc>) -> String {
+    format!("requests_{:04}_{:02}", ts.year(), ts.month())
+}
+
+/// Start of a partition's month and of the month after it.
+fn partition_bounds(name: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (year, month) = name.strip_prefix("requests_")?.split_once('_')?;
+    let (year, month): (i32, u32) = (year.parse().ok()?, month.parse().ok()?);
+    let start = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+    let end = if month == 12 {
+        Utc.with_ymd_and_hms(year + 1, 1, 1, 0, 0, 0)
+    } else {
+        Utc.with_ymd_and_hms(year, month + 1, 1, 0, 0, 0)
+    };
+    Some((start, end.single()?))
+}
+
+/// Monthly partitions of `requests`, oldest first.
+pub fn partitions(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT table_name FROM duckdb_tables() WHERE schema_name = 'main' AND regexp_matches(table_name, ?) ORDER BY table_name",
+    )?;
+    let names = stmt.query_map(params![PARTITION_PATTERN], |r| r.get(0))?;
+    Ok(names.collect::<duckdb::Result<Vec<String>>>()?)
+}
+
+/// Create a partition, with its indexes, if it doesn't exist yet.
+fn create_partition(conn: &Connection, name: &str) -> Result<()> {
+    let defs: Vec<String> = REQUEST_COLUMNS.iter().map(|(c, t)| format!("{c} {t}")).collect();
+    let mut sql = format!("CREATE TABLE IF NOT EXISTS {name} ({});", defs.join(", "));
+    for col in REQUEST_INDEXES {
+        sql.push_str(&format!("CREATE INDEX IF NOT EXISTS idx_{name}_{col} ON {name}({col});"));
+    }
+    conn.execute_batch(&sql)?;
+    Ok(())
+}
+
+/// Indexes on `requests` partitions other than the ones `create_partition`
+/// makes: left over from columns no longer indexed, or added by hand.
+pub fn orphaned_indexes(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT index_name, table_name FROM duckdb_indexes() WHERE schema_name = 'main' AND regexp_matches(table_name, ?) ORDER BY index_name",
+    )?;
+    let indexes = stmt
+        .query_map(params![PARTITION_PATTERN], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+        .collect::<duckdb::Result<Vec<_>>>()?;
+    Ok(indexes
+        .into_iter()
+        .filter(|(index, table)| !REQUEST_INDEXES.iter().any(|col| *index == format!("idx_{table}_{col}")))
+        .map(|(index, _)| index)
+        .collect())
+}
+
+/// Point the `requests` view at the partitions there are now. With none, it
+/// is an empty relation of the right shape.
+fn rebuild_view(conn: &Connection) -> Result<()> {
+    let parts = partitions(conn)?;
+    let body = if parts.is_empty() {
+        let cols: Vec<String> = REQUEST_COLUMNS.iter().map(|(c, t)| format!("CAST(NULL AS {t}) AS {c}")).collect();
+        format!("SELECT {} WHERE false", cols.join(", "))
+    } else {
+        parts.iter().map(|p| format!("SELECT * FROM {p}")).collect::<Vec<_>>().join(" UNION ALL ")
+    };
+    conn.execute_batch(&format!("CREATE OR REPLACE VIEW requests AS {body}"))?;
     Ok(())
 }
 
-pub fn insert_rows(conn: &mut Connection, rows: impl Iterator<Item = LogRow>) -> Result<(u64, u64)> {
+/// Set up `requests` as a view over monthly partitions, so old months can
+/// be dropped whole and time-bounded queries skip the rest by their `ts`
+/// zone maps. A `requests` table from before partitioning is split into
+/// partitions and replaced by the view; partitions from before a column
+/// was added get the column.
+fn init_requests(conn: &Connection) -> Result<()> {
+    let legacy: bool = conn.query_row(
+        "SELECT count(*) > 0 FROM duckdb_tables() WHERE schema_name = 'main' AND table_name = 'requests'",
+        params![],
+        |r| r.get(0),
+    )?;
+    if legacy {
+        migrate_unpartitioned(conn)?;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT table_name, column_name FROM duckdb_columns() WHERE schema_name = 'main' AND regexp_matches(table_name, ?)",
+    )?;
+    let existing = stmt
+        .query_map(params![PARTITION_PATTERN], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+        .collect::<duckdb::Result<HashSet<_>>>()?;
+    for part in partitions(conn)? {
+        for (col, ty) in REQUEST_COLUMNS {
+            if !existing.contains(&(part.clone(), col.to_string())) {
+                conn.execute_batch(&format!("ALTER TABLE {part} ADD COLUMN {col} {ty}"))?;
+                if let Some((_, expr)) = DERIVED_COLUMNS.iter().find(|(c, _)| c == col) {
+                    conn.execute_batch(&format!("UPDATE {part} SET {col} = {expr}"))?;
+                }
+                if REQUEST_INDEXES.contains(col) {
+                    conn.execute_batch(&format!("CREATE INDEX IF NOT EXISTS idx_{part}_{col} ON {part}({col})"))?;
+                }
+            }
+        }
+    }
+    rebuild_view(conn)
+}
+
+/// Move the rows of an unpartitioned `requests` table into partitions and
+/// drop it, all in one transaction.
+fn migrate_unpartitioned(conn: &Connection) -> Result<()> {
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = (|| -> Result<()> {
+        // Columns added after the first release, for tables created before them.
+        let mut sql = String::new();
+        for (col, ty) in REQUEST_COLUMNS {
+            sql.push_str(&format!("ALTER TABLE requests ADD COLUMN IF NOT EXISTS {col} {ty};"));
+        }
+        for (col, expr) in DERIVED_COLUMNS {
+            sql.push_str(&format!("UPDATE requests SET {col} = COALESCE({col}, {expr});"));
+        }
+        conn.execute_batch(&sql)?;
+
+        let months: Vec<String> = {
+            let mut stmt = conn.prepare(&format!("SELECT DISTINCT {MONTH_OF_TS} FROM requests WHERE ts IS NOT NULL"))?;
+            let months = stmt.query_map(params![], |r| r.get(0))?;
+            months.collect::<duckdb::Result<_>>()?
+        };
+        let cols: Vec<&str> = REQUEST_COLUMNS.iter().map(|(c, _)| *c).collect();
+        let cols = cols.join(", ");
+        for month in &months {
+            let part = format!("requests_{month}");
+            create_partition(conn, &part)?;
+            conn.execute(
+                &format!("INSERT INTO {part} SELECT {cols} FROM requests WHERE {MONTH_OF_TS} = ?"),
+                params![month],
+            )?;
+        }
+        // Rows without a timestamp can't be placed; none are ever imported.
+        conn.execute_batch("DROP TABLE requests")?;
+        Ok(())
+    })();
+    match res {
+        Ok(()) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+/// `YYYY_MM` of a row's `ts` in UTC, whatever the session time zone.
+const MONTH_OF_TS: &str = "strftime(make_timestamp(epoch_us(ts)), '%Y_%m')";
+
+/// Requests that count toward headline metrics: GET/POST with a non-empty
+/// body. Kept in step with `LogRow::is_noise`, its import-time equivalent.
+pub const SIGNAL_CONDITION: &str = "method IN ('GET', 'POST') AND COALESCE(bytes, 0) > 0";
+
+/// The vendor host a request was for: `target_host` where the stage of that
+/// name un-rewrote a proxy-by-hostname name, else `host`. Host analytics
+/// group and filter by this. With `ports.split_hosts`, a port other than
+/// the scheme's default follows it, as in `example.org:8443`; `port` is
+/// only set for those.
+pub fn target_host(ports: &PortsConfig) -> &'static str {
+    if ports.split_hosts {
+        "(COALESCE(target_host, host) || COALESCE(':' || port, ''))"
+    } else {
+        "COALESCE(target_host, host)"
+    }
+}
+
+/// Requests for vendor resources rather than EZproxy's own login and menu
+/// pages, per the `request_kind` stage. Untagged rows count as resources.
+pub const RESOURCE_CONDITION: &str = "request_kind IS DISTINCT FROM 'proxy'";
+
+/// Requests that aren't page furniture, per the `request_kind` stage. Rows
+/// it hasn't tagged count as page views.
+pub const PAGE_VIEW_CONDITION: &str = "request_kind IS DISTINCT FROM 'asset'";
+
+/// Changes whenever data behind the aggregates does: an import finishes, a
+/// prune deletes rows, a baseline is rebuilt, federation figures are
+/// pulled, a policy check records violations, or costs or user attributes
+/// are loaded. Cheap
+/// enough to check on every request.
+pub fn data_version(conn: &Connection) -> Result<String> {
+    Ok(conn.query_row(
+        r#"
+        SELECT concat_ws('|',
+          (SELECT CAST(max(finished_at) AS VARCHAR) FROM imports),
+          (SELECT CAST(count(*) AS VARCHAR) FROM requests),
+          (SELECT CAST(max(built_at) AS VARCHAR) FROM baseline_meta),
+          (SELECT CAST(max(pulled_at) AS VARCHAR) FROM federation_daily),
+          (SELECT CAST(max(updated_at) AS VARCHAR) FROM alerts),
+          (SELECT CAST(max(updated_at) AS VARCHAR) FROM platform_costs),
+          (SELECT CAST(max(updated_at) AS VARCHAR) FROM users))
+        "#,
+        params![],
+        |r| r.get(0),
+    )?)
+}
+
+/// Delete requests before `cutoff` (RFC 3339). Returns the number removed.
+/// Months entirely before the cutoff are dropped whole; only the month it
+/// falls in is deleted from row by row.
+pub fn prune(conn: &Connection, cutoff: &str) -> Result<usize> {
+    let cutoff_at = DateTime::parse_from_rfc3339(cutoff)?.with_timezone(&Utc);
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = (|| -> Result<usize> {
+        let mut removed = 0;
+        let mut dropped = false;
+        for part in partitions(conn)? {
+            let Some((start, end)) = partition_bounds(&part) else {
+                continue;
+            };
+            if end <= cutoff_at {
+                removed +=
+                    conn.query_row(&format!("SELECT count(*) FROM {part}"), params![], |r| r.get::<_, i64>(0))? as usize;
+                conn.execute_batch(&format!("DROP TABLE {part}"))?;
+                dropped = true;
+            } else if start < cutoff_at {
+                removed += conn.execute(&format!("DELETE FROM {part} WHERE ts < CAST(? AS TIMESTAMPTZ)"), params![cutoff])?;
+            }
+        }
+        if dropped {
+            rebuild_view(conn)?;
+        }
+        seen::forget_users(conn, cutoff)?;
+        Ok(removed)
+    })();
+    match res {
+        Ok(removed) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(removed)
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+/// How `insert_rows` keeps each row's original log line. Plain text roughly
+/// doubles the size of the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RawStorage {
+    /// As text in `raw`
+    #[default]
+    Text,
+    /// Not at all; `raw` and `raw_zstd` are both NULL
+    Omit,
+    /// zstd-compressed in `raw_zstd`, with `raw` NULL
+    Zstd,
+}
+
+/// zstd level for `RawStorage::Zstd`; higher levels gain little on lines
+/// this short.
+const RAW_ZSTD_LEVEL: i32 = 3;
+
+impl RawStorage {
+    /// Values for the `raw` and `raw_zstd` columns.
+    pub fn encode(self, raw: &str) -> Result<(Option<&str>, Option<Vec<u8>>)> {
+        Ok(match self {
+            RawStorage::Text => (Some(raw), None),
+            RawStorage::Omit => (None, None),
+            RawStorage::Zstd => (None, Some(zstd::encode_all(raw.as_bytes(), RAW_ZSTD_LEVEL)?)),
+        })
+    }
+
+    /// How a stored row's line was kept, from which columns are set.
+    pub fn of(raw: Option<&str>, raw_zstd: Option<&[u8]>) -> RawStorage {
+        match (raw, raw_zstd) {
+            (_, Some(_)) => RawStorage::Zstd,
+            (Some(_), None) => RawStorage::Text,
+            (None, None) => RawStorage::Omit,
+        }
+    }
+}
+
+/// A stored row's original line from its `raw` and `raw_zstd` columns, or
+/// `None` when it was imported without one.
+pub fn decode_raw(raw: Option<String>, raw_zstd: Option<&[u8]>) -> Result<Option<String>> {
+    match (raw, raw_zstd) {
+        (Some(raw), _) => Ok(Some(raw)),
+        (None, Some(z)) => Ok(Some(String::from_utf8(zstd::decode_all(z)?)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+pub fn insert_rows(conn: &Connection, rows: impl IntoIterator<Item = LogRow>, raw: RawStorage) -> Result<(u64, u64)> {
     let mut ok: u64 = 0;
     let mut bad: u64 = 0;
-    let rows_vec: Vec<LogRow> = rows.collect();
-    let total = rows_vec.len();
-    println!("Processing {} log entries...", total);
+    // Rows go to their month's partition, which is created on first use.
+    let mut by_part: BTreeMap<String, Vec<(usize, LogRow)>> = BTreeMap::new();
+    for (idx, r) in rows.into_iter().enumerate() {
+        by_part.entry(partition_for(r.ts.with_timezone(&Utc))).or_default().push((idx, r));
+    }
+    let existing = partitions(conn)?;
+    let mut created = false;
+    for part in by_part.keys() {
+        if !existing.contains(part) {
+            create_partition(conn, part)?;
+            created = true;
+        }
+    }
+    if created {
+        rebuild_view(conn)?;
+    }
+
+    for (part, rows) in by_part {
+        append_partition(conn, &part, rows, raw, &mut ok, &mut bad)?;
+    }
+    Ok((ok, bad))
+}
+
+fn append_partition(
+    conn: &Connection,
+    part: &str,
+    rows: Vec<(usize, LogRow)>,
+    raw: RawStorage,
+    ok: &mut u64,
+    bad: &mut u64,
+) -> Result<()> {
     // Use DuckDB's appender for much faster bulk inserts
     // This is the recommended way for bulk loading in DuckDB
-    let mut appender = conn.appender("requests")?;
-    for (idx, r) in rows_vec.iter().enumerate() {
-        let ts = r.ts.to_rfc3339();
+    let mut appender = conn.appender(part)?;
+    for (idx, r) in rows {
+        // The appender reads the string as a plain TIMESTAMP and drops any
+        // offset, so hand it UTC.
+        let ts = r.ts.with_timezone(&Utc).to_rfc3339();
+        let (raw_text, raw_zstd) = raw.encode(&r.raw)?;
 
         let res = appender.append_row(params![
             ts,
@@ -67,26 +881,32 @@ pub fn insert_rows(conn: &mut Connection, rows: impl Iterator<Item = LogRow>) ->
             r.bytes,
             &r.country,
             &r.user_agent,
-            &r.raw
+            raw_text,
+            &r.issn,
+            &r.isbn,
+            &r.referrer,
+            &r.browser,
+            &r.path_template,
+            raw_zstd,
+            &r.request_kind,
+            &r.target_host,
+            // TLS details only come from a transfer log; see `transfer::import`.
+            None::<String>,
+            None::<String>,
+            &r.url_sha256,
+            &r.host_unicode,
+            &r.file_ext
         ]);
 
         match res {
-            Ok(_) => ok += 1,
+            Ok(_) => *ok += 1,
             Err(e) => {
-                bad += 1;
+                *bad += 1;
                 eprintln!("Row {} failed: {}", idx + 1, e);
             }
         }
-        
-        if (idx + 1) % 10000 == 0 {
-            println!("  Processed {} / {} entries ({} ok, {} failed)", idx + 1, total, ok, bad);
-        }
     }
-    
-    // Flush the appender
-    let _ = appender.flush();
-    
-    println!("Import complete!");
-    
-    Ok((ok, bad))
+
+    appender.flush()?;
+    Ok(())
 }
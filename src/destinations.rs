@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+use crate::{
+    config::{DestinationsConfig, PortsConfig},
+    db, parser,
+    watch::wildcard_match,
+};
+
+/// Hosts no library licenses, by why: what open-proxy abuse mostly goes
+/// to. Patterns as in `[destinations]`.
+const KNOWN_HOSTS: &[(&str, &[&str])] = &[
+    (
+        "streaming",
+        &[
+            "youtube.com", "*.youtube.com", "*.googlevideo.com", "netflix.com", "*.netflix.com", "*.nflxvideo.net",
+            "twitch.tv", "*.twitch.tv", "*.ttvnw.net", "*.spotify.com", "*.hulu.com", "*.disneyplus.com",
+            "*.primevideo.com",
+        ],
+    ),
+    (
+        "social_media",
+        &[
+            "facebook.com", "*.facebook.com", "*.fbcdn.net", "instagram.com", "*.instagram.com", "*.cdninstagram.com",
+            "tiktok.com", "*.tiktok.com", "twitter.com", "*.twitter.com", "x.com", "reddit.com", "*.reddit.com",
+        ],
+    ),
+    // Someone checking which address the proxy gives them.
+    ("ip_check", &["api.ipify.org", "ifconfig.me", "*.whatismyipaddress.com", "icanhazip.com", "ipinfo.io"]),
+    ("web_search", &["www.google.com", "www.bing.com", "duckduckgo.com"]),
+];
+
+/// File extensions of audio and video, whole files or stream segments.
+const MEDIA_EXTENSIONS: &[&str] = &["m3u8", "m4s", "ts", "mpd", "mp4", "webm", "flv", "mp3", "aac"];
+
+/// Share of a host's requests that must be media for a host in neither
+/// list to be flagged as `media`. Licensed video platforms belong in
+/// `allowed`.
+pub const MEDIA_MIN_SHARE: f64 = 0.5;
+
+/// Hosts and offenders listed; the totals count all of them.
+const MAX_LISTED: usize = 50;
+
+/// `[destinations]`, lowercased, with the `[licenses]` platforms allowed.
+pub struct Rules {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+    strict: bool,
+}
+
+impl Rules {
+    pub fn new(cfg: &DestinationsConfig, licenses: &BTreeMap<String, u32>) -> Result<Rules> {
+        let normalize = |patterns: &[String], key: &str| -> Result<Vec<String>> {
+            patterns
+                .iter()
+                .map(|p| match p.trim().to_lowercase() {
+                    p if p.is_empty() => bail!("destinations.{} has an empty host pattern", key),
+                    p => Ok(p),
+                })
+                .collect()
+        };
+        let mut allowed = normalize(&cfg.allowed, "allowed")?;
+        allowed.extend(licenses.keys().map(|h| h.to_lowercase()));
+        Ok(Rules { allowed, denied: normalize(&cfg.denied, "denied")?, strict: cfg.strict })
+    }
+
+    /// Why `host` looks like abuse, if it does; `media_share` is the share
+    /// of its requests for audio or video. Allowed hosts never are.
+    pub fn flag(&self, host: &str, media_share: f64) -> Option<&'static str> {
+        let (name, _) = parser::split_port(host);
+        let matches = |patterns: &[String]| patterns.iter().any(|p| wildcard_match(p, name));
+        if matches(&self.allowed) {
+            return None;
+        }
+        if matches(&self.denied) {
+            return Some("denied");
+        }
+        if let Some((why, _)) = KNOWN_HOSTS.iter().find(|(_, hosts)| hosts.iter().any(|p| wildcard_match(p, name))) {
+            return Some(why);
+        }
+        if media_share >= MEDIA_MIN_SHARE {
+            return Some("media");
+        }
+        self.strict.then_some("unlisted")
+    }
+}
+
+/// Requests among those matching `filter` to hosts `rules` flags: each such
+/// host with why, and the users (or IPs, without one) sending the most
+/// through the proxy to them, busiest first.
+pub fn offenders(
+    conn: &Connection,
+    rules: &Rules,
+    filter: &str,
+    args: &[String],
+    ports: &PortsConfig,
+) -> Result<serde_json::Value> {
+    let host = db::target_host(ports);
+    let media = MEDIA_EXTENSIONS.iter().map(|e| format!("'{e}'")).collect::<Vec<_>>().join(", ");
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT {host} AS host, count(*) AS n,
+               count(*) FILTER (WHERE file_ext IN ({media})) AS media,
+               round(COALESCE(sum(bytes), 0) / 1024.0 / 1024.0, 1) AS mb,
+               count(DISTINCT COALESCE(user_or_session, remote_addr)) AS users
+        FROM requests
+        WHERE host IS NOT NULL AND {filter}
+        GROUP BY 1
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut flagged = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let media: i64 = r.get(2)?;
+        if let Some(why) = rules.flag(&host, media as f64 / n.max(1) as f64) {
+            let mb: f64 = r.get(3)?;
+            let users: i64 = r.get(4)?;
+            flagged.push((host, why, n, mb, users));
+        }
+    }
+    flagged.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    let requests: i64 = flagged.iter().map(|f| f.2).sum();
+    let hosts: Vec<_> = flagged
+        .iter()
+        .take(MAX_LISTED)
+        .map(|(host, why, n, mb, users)| json!({ "host": host, "reason": why, "requests": n, "mb": mb, "users": users }))
+        .collect();
+    if flagged.is_empty() {
+        return Ok(json!({ "requests": 0, "flagged_hosts": 0, "hosts": hosts, "offenders": [] }));
+    }
+
+    let placeholders = vec!["?"; flagged.len()].join(", ");
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT COALESCE(user_or_session, remote_addr) AS who, count(*) AS n,
+               round(COALESCE(sum(bytes), 0) / 1024.0 / 1024.0, 1) AS mb,
+               count(DISTINCT {host}) AS hosts,
+               array_to_string(list(DISTINCT {host} ORDER BY {host})[1:5], ',') AS sample_hosts,
+               CAST(min(CAST(ts AS TIMESTAMP)) AS VARCHAR) AS first_seen,
+               CAST(max(CAST(ts AS TIMESTAMP)) AS VARCHAR) AS last_seen
+        FROM requests
+        WHERE {filter} AND {host} IN ({placeholders})
+        GROUP BY 1
+        ORDER BY n DESC, who
+        LIMIT {MAX_LISTED}
+        "#
+    ))?;
+    let bind: Vec<String> = args.iter().cloned().chain(flagged.iter().map(|f| f.0.clone())).collect();
+    let mut rows = stmt.query(params_from_iter(&bind))?;
+    let mut offenders = Vec::new();
+    while let Some(r) = rows.next()? {
+        let who: Option<String> = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let mb: f64 = r.get(2)?;
+        let host_count: i64 = r.get(3)?;
+        let sample_hosts: Option<String> = r.get(4)?;
+        let sample_hosts: Vec<String> = sample_hosts
+            .unwrap_or_default()
+            .split(',')
+            .filter(|h| !h.is_empty())
+            .map(String::from)
+            .collect();
+        let first_seen: String = r.get(5)?;
+        let last_seen: String = r.get(6)?;
+        offenders.push(json!({
+            "user_or_ip": who,
+            "requests": n,
+            "mb": mb,
+            "hosts": host_count,
+            "sample_hosts": sample_hosts,
+            "first_seen": first_seen,
+            "last_seen": last_seen,
+        }));
+    }
+    Ok(json!({ "requests": requests, "flagged_hosts": flagged.len(), "hosts": hosts, "offenders": offenders }))
+}
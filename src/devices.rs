@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::Result;
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::enrich::browser_family;
+
+/// Devices listed per user; the estimate still counts all of them.
+pub const MAX_LISTED: usize = 50;
+
+/// Operating system family from a user agent, coarse enough that browser
+/// and OS updates don't look like a new device.
+pub fn os_family(ua: &str) -> &'static str {
+    if ua.contains("iPhone") || ua.contains("iPad") || ua.contains("iPod") {
+        "iOS"
+    } else if ua.contains("Android") {
+        "Android"
+    } else if ua.contains("CrOS") {
+        "ChromeOS"
+    } else if ua.contains("Windows") {
+        "Windows"
+    } else if ua.contains("Macintosh") || ua.contains("Mac OS X") {
+        "macOS"
+    } else if ua.contains("Linux") {
+        "Linux"
+    } else {
+        "Other"
+    }
+}
+
+/// The /24 (IPv4) or /48 (IPv6) an address is in, so a device keeps its
+/// identity while DHCP or a carrier moves it around one network. Anything
+/// that isn't an address is returned as is.
+pub fn network(addr: &str) -> String {
+    match addr.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+        Ok(IpAddr::V4(v4)) => format!("{}/24", Ipv4Addr::from(u32::from(v4) & 0xffff_ff00)),
+        Ok(IpAddr::V6(v6)) => format!("{}/48", Ipv6Addr::from(u128::from(v6) & (u128::MAX << 80))),
+        Err(_) => addr.to_string(),
+    }
+}
+
+/// Short id for a browser family, OS, and network.
+fn device_id(browser: &str, os: &str, network: &str) -> String {
+    let digest = Sha256::digest(format!("{}\n{}\n{}", browser, os, network));
+    digest[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Approximate devices behind the requests matching `filter`, meant for one
+/// user: each distinct browser family, OS, and network counts as one. One
+/// patron on a laptop, phone, and tablet comes out near three; a shared
+/// credential, in the dozens. Busiest first, at most `MAX_LISTED`.
+pub fn estimate(conn: &Connection, filter: &str, args: &[String]) -> Result<serde_json::Value> {
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT COALESCE(user_agent, ''), remote_addr, count(*),
+               CAST(min(ts) AS VARCHAR), CAST(max(ts) AS VARCHAR)
+        FROM requests
+        WHERE {filter}
+        GROUP BY 1, 2
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    // Timestamps share a format, so they compare as strings.
+    let mut devices: HashMap<(&str, &str, String), (i64, String, String)> = HashMap::new();
+    while let Some(r) = rows.next()? {
+        let ua: String = r.get(0)?;
+        let addr: String = r.get(1)?;
+        let (n, first, last): (i64, String, String) = (r.get(2)?, r.get(3)?, r.get(4)?);
+        let key = (browser_family(&ua), os_family(&ua), network(&addr));
+        let entry = devices.entry(key).or_insert_with(|| (0, first.clone(), last.clone()));
+        entry.0 += n;
+        if first < entry.1 {
+            entry.1 = first;
+        }
+        if last > entry.2 {
+            entry.2 = last;
+        }
+    }
+
+    let mut list: Vec<_> = devices.into_iter().collect();
+    list.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(&b.0)));
+    let estimated = list.len();
+    let out: Vec<_> = list
+        .into_iter()
+        .take(MAX_LISTED)
+        .map(|((browser, os, network), (requests, first_seen, last_seen))| {
+            json!({
+                "device": device_id(browser, os, &network),
+                "browser": browser,
+                "os": os,
+                "network": network,
+                "requests": requests,
+                "first_seen": first_seen,
+                "last_seen": last_seen,
+            })
+        })
+        .collect();
+    Ok(json!({ "estimated_devices": estimated, "devices": out }))
+}
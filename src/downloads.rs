@@ -0,0 +1,102 @@
+use anyhow::Result;
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, db, enrich::CONTENT_EXTENSIONS};
+
+/// Platforms and users listed; the totals still count all of them.
+const MAX_LISTED: usize = 25;
+
+/// CTE `d(host, who, path, day)` with one row per download among the
+/// requests matching `filter`. A download is a successful request the
+/// `request_kind` stage tagged `content`; rows it hasn't tagged are judged
+/// by the built-in extensions. PDF viewers fetch a file in many ranged
+/// requests, so one user fetching the same file on the same day counts once.
+pub fn cte(filter: &str, ports: &PortsConfig) -> String {
+    let extensions = CONTENT_EXTENSIONS.join("|");
+    let host = db::target_host(ports);
+    format!(
+        r#"
+        d AS (
+          SELECT DISTINCT {host} AS host, COALESCE(user_or_session, remote_addr) AS who, path,
+                 CAST(CAST(ts AS TIMESTAMP) AS DATE) AS day
+          FROM requests
+          WHERE {host} IS NOT NULL AND status BETWEEN 200 AND 299
+            AND COALESCE(request_kind = 'content',
+                         regexp_matches(lower(COALESCE(path, '')), '\.({extensions})$'))
+            AND {filter}
+        )
+        "#
+    )
+}
+
+/// Full-text downloads among the requests matching `filter`, overall, per
+/// platform, per user, and per day; see `cte`.
+pub fn analyze(conn: &Connection, filter: &str, args: &[String], ports: &PortsConfig) -> Result<serde_json::Value> {
+    let base = format!("WITH {}", cte(filter, ports));
+
+    let (downloads, users, platforms): (i64, i64, i64) = conn.query_row(
+        &format!("{base} SELECT count(*), count(DISTINCT who), count(DISTINCT host) FROM d"),
+        params_from_iter(args),
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )?;
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT host, count(*) AS downloads, count(DISTINCT who) AS users
+        FROM d GROUP BY 1 ORDER BY downloads DESC, host
+        LIMIT {MAX_LISTED}
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut by_platform = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let downloads: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        by_platform.push(json!({"host": host, "downloads": downloads, "users": users}));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT who, count(*) AS downloads, count(DISTINCT host) AS platforms
+        FROM d GROUP BY 1 ORDER BY downloads DESC, who
+        LIMIT {MAX_LISTED}
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut by_user = Vec::new();
+    while let Some(r) = rows.next()? {
+        let who: String = r.get(0)?;
+        let downloads: i64 = r.get(1)?;
+        let platforms: i64 = r.get(2)?;
+        by_user.push(json!({"user_or_ip": who, "downloads": downloads, "platforms": platforms}));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT CAST(day AS VARCHAR), count(*) AS downloads, count(DISTINCT who) AS users
+        FROM d GROUP BY 1 ORDER BY 1
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut trend = Vec::new();
+    while let Some(r) = rows.next()? {
+        let day: String = r.get(0)?;
+        let downloads: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        trend.push(json!({"day": day, "downloads": downloads, "users": users}));
+    }
+
+    Ok(json!({
+        "downloads": downloads,
+        "users": users,
+        "platforms": platforms,
+        "by_platform": by_platform,
+        "by_user": by_user,
+        "trend": trend,
+    }))
+}
@@ -0,0 +1,54 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+/// Parse a compact duration such as `90d`, `24h`, `5m`, `2w`, or `30s`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("duration {:?} is missing a unit (s, m, h, d, w)", s))?;
+    let (num, unit) = s.split_at(split);
+    let n: i64 = num
+        .parse()
+        .map_err(|_| anyhow!("duration {:?} must start with a number", s))?;
+
+    let d = match unit {
+        "s" => Duration::try_seconds(n),
+        "m" => Duration::try_minutes(n),
+        "h" => Duration::try_hours(n),
+        "d" => Duration::try_days(n),
+        "w" => Duration::try_weeks(n),
+        _ => bail!("duration {:?} has unknown unit {:?} (use s, m, h, d, w)", s, unit),
+    };
+    d.ok_or_else(|| anyhow!("duration {:?} is out of range", s))
+}
+
+/// The forms `parse_time` takes, for error messages.
+pub const TIME_FORMATS: &str =
+    "RFC 3339 (2026-02-01T09:00:00Z), a date (2026-02-01, midnight UTC), or a duration before now (-7d, -24h)";
+
+/// Parse a point in time: RFC 3339, a date or date and time without an
+/// offset (taken as UTC), or a duration before `now` such as `-7d`.
+pub fn parse_time(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Some(ago) = s.strip_prefix('-') {
+        let ago = parse_duration(ago).map_err(|e| anyhow!("{e}; accepted: {TIME_FORMATS}"))?;
+        return now.checked_sub_signed(ago).ok_or_else(|| anyhow!("time {:?} is out of range", s));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    // As DuckDB prints a TIMESTAMPTZ, e.g. `2026-02-01 09:00:00+00`.
+    if let Ok(t) = DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f%#z") {
+        return Ok(t.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(t.and_utc());
+        }
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_time(NaiveTime::MIN).and_utc());
+    }
+    bail!("{:?} is not a time; accepted: {}", s, TIME_FORMATS)
+}
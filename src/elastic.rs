@@ -0,0 +1,217 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use chrono::DateTime;
+use duckdb::params_from_iter;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::db;
+
+/// API key for `export elastic`, kept out of the command line and so out of
+/// shell history and `ps`.
+pub const API_KEY_ENV: &str = "EZVIS_ELASTIC_API_KEY";
+
+/// Rows per `_bulk` request.
+pub const BATCH: usize = 5000;
+
+/// Columns not shipped: the original line is bulky and, for a parsed
+/// event, redundant.
+pub const SKIPPED_COLUMNS: &[&str] = &["raw", "raw_zstd"];
+
+/// Where rows go, and how to authenticate.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// Cluster base URL, e.g. https://elastic.example.edu:9200
+    pub url: String,
+    pub api_key: Option<String>,
+}
+
+impl Target {
+    fn agent(&self) -> ureq::Agent {
+        ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(300)))
+            .http_status_as_error(false)
+            .build()
+            .into()
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.url.trim_end_matches('/'), path)
+    }
+
+    fn auth(&self) -> Option<String> {
+        self.api_key.as_ref().map(|k| format!("ApiKey {}", k))
+    }
+
+    /// Send newline-delimited `_bulk` actions, failing if any item failed.
+    pub fn bulk(&self, body: String) -> Result<()> {
+        let url = self.endpoint("_bulk");
+        let mut req = self.agent().post(&url).content_type("application/x-ndjson");
+        if let Some(auth) = self.auth() {
+            req = req.header("Authorization", auth);
+        }
+        let mut res = req.send(body).with_context(|| format!("post {}", url))?;
+        let status = res.status();
+        let res: serde_json::Value = res.body_mut().read_json().with_context(|| format!("read reply from {}", url))?;
+        if !status.is_success() {
+            bail!("{} answered {}: {}", url, status, res["error"]);
+        }
+        if res["errors"].as_bool() == Some(true) {
+            let first = res["items"]
+                .as_array()
+                .and_then(|items| items.iter().find_map(|i| i.as_object()?.values().next()?.get("error")))
+                .cloned()
+                .unwrap_or_default();
+            bail!("bulk request partly failed: {}", first);
+        }
+        Ok(())
+    }
+
+    /// Create `index` with `mappings` unless it exists. Returns whether it
+    /// was created; an existing index keeps the mappings it has.
+    pub fn ensure_index(&self, index: &str, mappings: &serde_json::Value) -> Result<bool> {
+        let url = self.endpoint(index);
+        let agent = self.agent();
+        let mut head = agent.head(&url);
+        if let Some(auth) = self.auth() {
+            head = head.header("Authorization", auth);
+        }
+        match head.call().with_context(|| format!("check {}", url))?.status().as_u16() {
+            200 => return Ok(false),
+            404 => {}
+            status => bail!("{} answered {} to a HEAD request", url, status),
+        }
+        let mut put = agent.put(&url);
+        if let Some(auth) = self.auth() {
+            put = put.header("Authorization", auth);
+        }
+        let mut res = put.send_json(json!({ "mappings": mappings })).with_context(|| format!("create {}", url))?;
+        if !res.status().is_success() {
+            let body = res.body_mut().read_to_string().unwrap_or_default();
+            bail!("creating {} answered {}: {}", url, res.status(), body);
+        }
+        Ok(true)
+    }
+}
+
+/// Elasticsearch field type for a `requests` column of SQL type `ty`.
+fn field_type(col: &str, ty: &str) -> serde_json::Value {
+    match (col, ty) {
+        // Truncated by the anonymize stage, but still addresses.
+        ("remote_addr", _) => json!({ "type": "ip", "ignore_malformed": true }),
+        // Searched by words as well as filtered on whole.
+        ("url" | "user_agent" | "referrer", _) => {
+            json!({ "type": "text", "fields": { "keyword": { "type": "keyword", "ignore_above": 2048 } } })
+        }
+        (_, "TIMESTAMPTZ") => json!({ "type": "date" }),
+        (_, "INTEGER") => json!({ "type": "integer" }),
+        (_, "BIGINT") => json!({ "type": "long" }),
+        _ => json!({ "type": "keyword", "ignore_above": 2048 }),
+    }
+}
+
+/// Index mappings for exported requests: one field per column, plus
+/// `@timestamp` for Kibana.
+pub fn mappings() -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    properties.insert("@timestamp".into(), json!({ "type": "date" }));
+    for (col, ty) in db::REQUEST_COLUMNS.iter().filter(|(c, _)| !SKIPPED_COLUMNS.contains(c)) {
+        properties.insert(col.to_string(), field_type(col, ty));
+    }
+    // Columns added later are kept in _source of an older index, unindexed,
+    // rather than rejected.
+    json!({ "dynamic": false, "properties": properties })
+}
+
+/// Append the `_bulk` action indexing a request's fields, keyed by column,
+/// to `body`. The ID is a hash of the fields, so sending the same request
+/// again replaces it rather than adding a copy.
+pub fn push_document(body: &mut String, index: &str, mut doc: serde_json::Map<String, serde_json::Value>) {
+    if let Some(ts) = doc.get("ts").cloned() {
+        doc.insert("@timestamp".into(), ts);
+    }
+    let doc = serde_json::Value::Object(doc).to_string();
+    let id = format!("{:x}", Sha256::digest(doc.as_bytes()));
+    body.push_str(&json!({ "index": { "_index": index, "_id": id } }).to_string());
+    body.push('\n');
+    body.push_str(&doc);
+    body.push('\n');
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportSummary {
+    pub index: String,
+    /// Whether the index was created, with our mappings, by this run
+    pub created: bool,
+    pub rows: u64,
+}
+
+/// Ship every request between `start` (inclusive) and `end` (exclusive),
+/// either of which may be left open, to `index`. Each document's ID is a
+/// hash of its fields, so exporting the same range again replaces rows
+/// rather than duplicating them.
+pub fn export(
+    conn: &duckdb::Connection,
+    target: &Target,
+    index: &str,
+    start: Option<&str>,
+    end: Option<&str>,
+    mut progress: impl FnMut(u64),
+) -> Result<ExportSummary> {
+    let created = target.ensure_index(index, &mappings())?;
+
+    let cols: Vec<(&str, &str)> =
+        db::REQUEST_COLUMNS.iter().copied().filter(|(c, _)| !SKIPPED_COLUMNS.contains(c)).collect();
+    let select = cols
+        .iter()
+        .map(|(c, ty)| if *ty == "TIMESTAMPTZ" { format!("epoch_us({c})") } else { c.to_string() })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut conds = vec!["true"];
+    let mut args = Vec::new();
+    for (cond, arg) in [("ts >= CAST(? AS TIMESTAMPTZ)", start), ("ts < CAST(? AS TIMESTAMPTZ)", end)] {
+        if let Some(arg) = arg {
+            conds.push(cond);
+            args.push(arg);
+        }
+    }
+
+    let mut body = String::new();
+    let mut batched = 0;
+    let mut rows = 0;
+    db::for_each_row(
+        conn,
+        &format!("SELECT {select} FROM requests WHERE {} ORDER BY ts", conds.join(" AND ")),
+        params_from_iter(args),
+        |row| {
+            let mut doc = serde_json::Map::new();
+            for ((col, ty), value) in cols.iter().zip(row) {
+                let value = match (ty, value.as_i64()) {
+                    (&"TIMESTAMPTZ", Some(us)) => {
+                        DateTime::from_timestamp_micros(us).map(|t| json!(t.to_rfc3339())).unwrap_or_default()
+                    }
+                    _ => value.clone(),
+                };
+                if !value.is_null() {
+                    doc.insert(col.to_string(), value);
+                }
+            }
+            push_document(&mut body, index, doc);
+            batched += 1;
+            if batched == BATCH {
+                target.bulk(std::mem::take(&mut body))?;
+                rows += batched as u64;
+                batched = 0;
+                progress(rows);
+            }
+            Ok(true)
+        },
+    )?;
+    if batched > 0 {
+        target.bulk(body)?;
+        rows += batched as u64;
+    }
+    Ok(ExportSummary { index: index.to_string(), created, rows })
+}
@@ -0,0 +1,846 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Mutex, OnceLock},
+};
+
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, appender_params_from_iter, params, types::Value};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    config::{AnonymizeConfig, EnrichConfig, GeoipConfig, RequestKindConfig, ScrubConfig, TargetHostConfig, TruncateConfig},
+    db,
+    parser::{self, LogRow},
+    seen,
+    watch::wildcard_match,
+};
+
+/// One step between parsing a line and storing it. A stage sees only the
+/// row, never the file or the database, so the same code serves imports
+/// and backfills of stored rows.
+pub trait Enricher: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Columns of `requests` the stage writes, which a backfill updates
+    fn columns(&self) -> &'static [&'static str];
+    fn enrich(&self, row: &mut LogRow);
+    /// Values the stage has blanked out since last asked, by what they were
+    /// (a query parameter name, say); most stages blank nothing
+    fn take_scrubbed(&self) -> BTreeMap<String, u64> {
+        BTreeMap::new()
+    }
+    /// A URL from another log rewritten as the stage rewrites `url`, so it
+    /// can be matched against stored requests; most stages leave it be
+    fn stored_url(&self, _url: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Stage names accepted in `enrich.stages`.
+pub const STAGES: &[&str] =
+    &["identifiers", "user_agent", "path_template", "request_kind", "target_host", "geoip", "anonymize", "scrub", "truncate"];
+
+/// Build the stage called `name`. A new enrichment only needs an arm here.
+fn stage(name: &str, cfg: &EnrichConfig) -> Result<Box<dyn Enricher>> {
+    Ok(match name {
+        "identifiers" => Box::new(Identifiers),
+        "user_agent" => Box::new(UserAgent),
+        "path_template" => Box::new(PathTemplate),
+        "request_kind" => Box::new(RequestKind::new(&cfg.request_kind)?),
+        "target_host" => {
+            let hosts = cfg
+                .target_host
+                .as_ref()
+                .ok_or_else(|| anyhow!("the target_host stage needs an [enrich.target_host] section"))?;
+            Box::new(TargetHost::new(hosts)?)
+        }
+        "geoip" => {
+            let geoip = cfg.geoip.as_ref().ok_or_else(|| anyhow!("the geoip stage needs an [enrich.geoip] section"))?;
+            Box::new(Geoip::open(geoip)?)
+        }
+        "anonymize" => {
+            let anon = cfg
+                .anonymize
+                .as_ref()
+                .ok_or_else(|| anyhow!("the anonymize stage needs an [enrich.anonymize] section"))?;
+            Box::new(Anonymize::new(anon)?)
+        }
+        "scrub" => Box::new(Scrub::new(&cfg.scrub)?),
+        "truncate" => Box::new(Truncate::new(&cfg.truncate)?),
+        other => bail!("unknown enrich stage {:?}, expected one of {}", other, STAGES.join(", ")),
+    })
+}
+
+/// The configured stages, run in order on every row.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Enricher>>,
+}
+
+impl Pipeline {
+    pub fn from_config(cfg: &EnrichConfig) -> Result<Pipeline> {
+        let mut stages: Vec<Box<dyn Enricher>> = Vec::new();
+        for name in &cfg.stages {
+            if stages.iter().any(|s| s.name() == name) {
+                bail!("enrich stage {:?} is listed twice", name);
+            }
+            stages.push(stage(name, cfg).with_context(|| format!("enrich stage {}", name))?);
+        }
+        Ok(Pipeline { stages })
+    }
+
+    pub fn run(&self, row: &mut LogRow) {
+        for s in &self.stages {
+            s.enrich(row);
+        }
+    }
+
+    /// Values blanked out by any stage since the last call, by parameter
+    /// name.
+    pub fn take_scrubbed(&self) -> BTreeMap<String, u64> {
+        let mut out = BTreeMap::new();
+        for (name, n) in self.stages.iter().flat_map(|s| s.take_scrubbed()) {
+            *out.entry(name).or_default() += n;
+        }
+        out
+    }
+
+    /// `url` as the stages would store it. Truncation is left out: a cut URL
+    /// is matched by its `url_sha256` instead.
+    pub fn stored_url(&self, url: &str) -> String {
+        self.stages.iter().fold(url.to_string(), |url, s| s.stored_url(&url).unwrap_or(url))
+    }
+
+    /// Just the named stages, in the order given, for a backfill.
+    pub fn only(cfg: &EnrichConfig, names: &[String]) -> Result<Pipeline> {
+        let cfg = EnrichConfig { stages: names.to_vec(), ..cfg.clone() };
+        Pipeline::from_config(&cfg)
+    }
+
+    /// Columns written by any stage, each once.
+    fn columns(&self) -> Vec<&'static str> {
+        let mut out: Vec<&'static str> = Vec::new();
+        for c in self.stages.iter().flat_map(|s| s.columns()) {
+            if !out.contains(c) {
+                out.push(c);
+            }
+        }
+        out
+    }
+}
+
+/// Stored columns read back into a `LogRow` for a backfill, in field order.
+/// `raw_zstd` comes last, for `db::decode_raw`.
+const STORED_COLUMNS: &str = "remote_addr, identd, user_or_session, method, url, scheme, host, port, path, \
+     query, http_version, status, bytes, country, user_agent, raw, issn, isbn, referrer, browser, path_template, \
+     request_kind, target_host, url_sha256, host_unicode, file_ext, raw_zstd";
+
+/// A stored row with its `raw` and `raw_zstd` columns as read; `row.raw` is
+/// left empty until they are decoded.
+type StoredRow = (i64, LogRow, Option<String>, Option<Vec<u8>>);
+
+fn stored_row(r: &duckdb::Row<'_>) -> duckdb::Result<StoredRow> {
+    let ts_ms: i64 = r.get(1)?;
+    // Stored as UTC; no stage looks at the timestamp anyway.
+    let ts = DateTime::<Utc>::from_timestamp_millis(ts_ms).unwrap_or_default().fixed_offset();
+    let row = LogRow {
+        ts,
+        remote_addr: r.get(2)?,
+        identd: r.get(3)?,
+        user_or_session: r.get(4)?,
+        method: r.get(5)?,
+        url: r.get(6)?,
+        scheme: r.get(7)?,
+        host: r.get(8)?,
+        port: r.get(9)?,
+        path: r.get(10)?,
+        query: r.get(11)?,
+        http_version: r.get(12)?,
+        status: r.get(13)?,
+        bytes: r.get(14)?,
+        country: r.get(15)?,
+        user_agent: r.get(16)?,
+        raw: String::new(),
+        issn: r.get(18)?,
+        isbn: r.get(19)?,
+        referrer: r.get(20)?,
+        browser: r.get(21)?,
+        path_template: r.get(22)?,
+        request_kind: r.get(23)?,
+        target_host: r.get(24)?,
+        url_sha256: r.get(25)?,
+        host_unicode: r.get(26)?,
+        file_ext: r.get(27)?,
+    };
+    Ok((r.get(0)?, row, r.get(17)?, r.get(28)?))
+}
+
+fn column_value(row: &LogRow, column: &str) -> Value {
+    let text = match column {
+        "issn" => row.issn.clone(),
+        "isbn" => row.isbn.clone(),
+        "browser" => row.browser.clone(),
+        "path_template" => row.path_template.clone(),
+        "request_kind" => row.request_kind.clone(),
+        "target_host" => row.target_host.clone(),
+        "country" => row.country.clone(),
+        "remote_addr" => Some(row.remote_addr.clone()),
+        "user_or_session" => row.user_or_session.clone(),
+        "raw" => Some(row.raw.clone()),
+        "url" => Some(row.url.clone()),
+        "path" => row.path.clone(),
+        "url_sha256" => row.url_sha256.clone(),
+        "query" => row.query.clone(),
+        "referrer" => row.referrer.clone(),
+        other => unreachable!("no stage writes {}", other),
+    };
+    text.map(Value::Text).unwrap_or(Value::Null)
+}
+
+/// Re-run `pipeline` over stored rows matching `filter` (SQL, e.g.
+/// `country IS NULL`), `batch` rows per transaction, calling `progress` with
+/// rows done and rows matched after each. Returns the number of rows
+/// rewritten. A rewritten `raw` is stored the way the row's line already
+/// was, per `db::RawStorage`.
+pub fn backfill(
+    conn: &Connection,
+    pipeline: &Pipeline,
+    filter: &str,
+    batch: usize,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<u64> {
+    let columns = pipeline.columns();
+    let total: i64 = conn.query_row(&format!("SELECT count(*) FROM requests WHERE {filter}"), params![], |r| r.get(0))?;
+
+    let mut defs: Vec<String> = columns.iter().map(|c| format!("{} TEXT", c)).collect();
+    let mut sets: Vec<String> = columns.iter().map(|c| format!("{c} = u.{c}")).collect();
+    let raw_at = columns.iter().position(|c| *c == "raw");
+    if raw_at.is_some() {
+        defs.push("raw_zstd BLOB".to_string());
+        sets.push("raw_zstd = u.raw_zstd".to_string());
+    }
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE TEMP TABLE enrich_updates (rid BIGINT, {})",
+        defs.join(", ")
+    ))?;
+    let sets = sets.join(", ");
+
+    // Partition by partition, since rowids are only unique within a table.
+    let mut done = 0u64;
+    for part in db::partitions(conn)? {
+        let update = format!("UPDATE {part} SET {sets} FROM enrich_updates u WHERE {part}.rowid = u.rid");
+        // Paged by rowid rather than OFFSET: a stage may change the very
+        // column `filter` tests, which would shift an offset under us.
+        let select = format!(
+            "SELECT rowid, epoch_ms(CAST(ts AS TIMESTAMP)), {STORED_COLUMNS} FROM {part} \
+             WHERE rowid > ? AND ({filter}) ORDER BY rowid LIMIT {batch}"
+        );
+
+        let mut last = -1i64;
+        loop {
+            let rows = {
+                let mut stmt = conn.prepare(&select)?;
+                let rows = stmt.query_map(params![last], stored_row)?;
+                rows.collect::<duckdb::Result<Vec<_>>>()?
+            };
+            let Some((rid, ..)) = rows.last() else {
+                break;
+            };
+            last = *rid;
+            let n = rows.len();
+
+            conn.execute_batch("BEGIN TRANSACTION")?;
+            {
+                let mut appender = conn.appender_to_catalog_and_db("enrich_updates", "temp", "main")?;
+                for (rid, mut row, raw, raw_zstd) in rows {
+                    let storage = db::RawStorage::of(raw.as_deref(), raw_zstd.as_deref());
+                    row.raw = db::decode_raw(raw, raw_zstd.as_deref())?.unwrap_or_default();
+                    pipeline.run(&mut row);
+                    let mut values: Vec<Value> = std::iter::once(Value::BigInt(rid))
+                        .chain(columns.iter().map(|c| column_value(&row, c)))
+                        .collect();
+                    if let Some(at) = raw_at {
+                        // `column_value` gave the text; keep it the way it was kept.
+                        let (text, blob) = storage.encode(&row.raw)?;
+                        values[1 + at] = text.map_or(Value::Null, |t| Value::Text(t.to_string()));
+                        values.push(blob.map_or(Value::Null, Value::Blob));
+                    }
+                    appender.append_row(appender_params_from_iter(values))?;
+                }
+                appender.flush()?;
+            }
+            conn.execute_batch(&format!("{update}; DELETE FROM enrich_updates; COMMIT"))?;
+
+            done += n as u64;
+            progress(done, total as u64);
+        }
+    }
+    conn.execute_batch("DROP TABLE enrich_updates")?;
+    // A pseudonymized user or an un-rewritten host leaves the old name there.
+    if done > 0 && columns.iter().any(|c| matches!(*c, "user_or_session" | "target_host")) {
+        seen::rebuild(conn)?;
+    }
+    Ok(done)
+}
+
+/// ISSNs and ISBNs found in the path or query string.
+struct Identifiers;
+
+impl Enricher for Identifiers {
+    fn name(&self) -> &'static str {
+        "identifiers"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["issn", "isbn"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        if let Some(path) = row.path.as_deref() {
+            row.issn = parser::extract_issn(path, row.query.as_deref());
+            row.isbn = parser::extract_isbn(path, row.query.as_deref());
+        }
+    }
+}
+
+/// Browser family, by the same rules `/api/user_agents` applies to rows
+/// imported without this stage.
+struct UserAgent;
+
+pub(crate) fn browser_family(ua: &str) -> &'static str {
+    if ua.contains("Chrome") && !ua.contains("Edg") {
+        "Chrome"
+    } else if ua.contains("Firefox") {
+        "Firefox"
+    } else if ua.contains("Safari") && !ua.contains("Chrome") {
+        "Safari"
+    } else if ua.contains("Edg") {
+        "Edge"
+    } else if ua.contains("Opera") {
+        "Opera"
+    } else if ua.contains("bot") || ua.contains("Bot") {
+        "Bot"
+    } else {
+        "Other"
+    }
+}
+
+impl Enricher for UserAgent {
+    fn name(&self) -> &'static str {
+        "user_agent"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["browser"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        row.browser = row.user_agent.as_deref().map(|ua| browser_family(ua).to_string());
+    }
+}
+
+/// The path with record numbers, UUIDs, and hashes replaced by `{id}`, so
+/// `/article/123` and `/article/456` count as one page.
+struct PathTemplate;
+
+fn is_id_segment(seg: &str) -> bool {
+    if seg.is_empty() {
+        return false;
+    }
+    if seg.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    // UUIDs and hex digests: long, hex and hyphens only, with a digit
+    seg.len() >= 16
+        && seg.bytes().all(|b| b.is_ascii_hexdigit() || b == b'-')
+        && seg.bytes().any(|b| b.is_ascii_digit())
+}
+
+fn path_template(path: &str) -> String {
+    path.split('/').map(|seg| if is_id_segment(seg) { "{id}" } else { seg }).collect::<Vec<_>>().join("/")
+}
+
+impl Enricher for PathTemplate {
+    fn name(&self) -> &'static str {
+        "path_template"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["path_template"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        row.path_template = row.path.as_deref().map(path_template);
+    }
+}
+
+/// Whether a request is full text a patron downloaded (`content`), page
+/// furniture fetched along with a page (`asset`), one of EZproxy's own
+/// login and menu pages (`proxy`), or a vendor page itself (`page_view`). Vendor platforms load hundreds of scripts, stylesheets,
+/// and images per page, which would otherwise drown out the page views in
+/// every count.
+struct RequestKind {
+    content: Vec<String>,
+    asset: Vec<String>,
+    /// Lowercase; empty to recognize EZproxy's pages on any host
+    proxy_hosts: Vec<String>,
+}
+
+/// Extensions tagged `content` unless `[enrich.request_kind]` says otherwise.
+pub const CONTENT_EXTENSIONS: &[&str] = &["pdf", "epub"];
+
+/// Directories platforms serve furniture from, whatever the file type.
+const ASSET_DIRS: &[&str] = &["static", "assets", "_next", "fonts"];
+
+/// First path segments of EZproxy's own pages: the login form, logout, the
+/// database menu, and the `/connect` starting point.
+const PROXY_PATHS: &[&str] = &["login", "logout", "menu", "connect"];
+
+impl RequestKind {
+    fn new(cfg: &RequestKindConfig) -> Result<RequestKind> {
+        let normalize = |list: &[String]| -> Result<Vec<String>> {
+            list.iter()
+                .map(|e| {
+                    let ext = e.trim().trim_start_matches('.').to_ascii_lowercase();
+                    if ext.is_empty() || ext.contains('/') {
+                        bail!("{:?} is not a file extension", e);
+                    }
+                    Ok(ext)
+                })
+                .collect()
+        };
+        let content = normalize(&cfg.content_extensions)?;
+        let asset = normalize(&cfg.asset_extensions)?;
+        if let Some(both) = content.iter().find(|e| asset.contains(e)) {
+            bail!("extension {:?} is in both content_extensions and asset_extensions", both);
+        }
+        let proxy_hosts = cfg.proxy_hosts.iter().map(|h| h.trim().trim_end_matches('.').to_ascii_lowercase()).collect();
+        Ok(RequestKind { content, asset, proxy_hosts })
+    }
+
+    /// Content wins over an asset directory, so a PDF served from
+    /// `/assets/` still counts as a download.
+    fn classify(&self, host: Option<&str>, path: &str) -> &'static str {
+        let path = path.to_ascii_lowercase();
+        let ext = parser::file_ext(&path);
+        let ext = ext.as_deref();
+        let first = path.split('/').find(|s| !s.is_empty()).unwrap_or_default();
+        let proxy_host = self.proxy_hosts.is_empty()
+            || host.is_some_and(|h| self.proxy_hosts.iter().any(|p| p.eq_ignore_ascii_case(h)));
+        if proxy_host && PROXY_PATHS.contains(&first) {
+            "proxy"
+        } else if ext.is_some_and(|ext| self.content.iter().any(|e| e == ext)) {
+            "content"
+        } else if ext.is_some_and(|ext| self.asset.iter().any(|e| e == ext))
+            || path.split('/').any(|s| ASSET_DIRS.contains(&s))
+        {
+            "asset"
+        } else {
+            "page_view"
+        }
+    }
+}
+
+impl Enricher for RequestKind {
+    fn name(&self) -> &'static str {
+        "request_kind"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["request_kind"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        row.request_kind = Some(self.classify(row.host.as_deref(), row.path.as_deref().unwrap_or_default()).to_string());
+    }
+}
+
+/// The vendor host behind a proxy-by-hostname name, so that
+/// `www-jstor-org.ezproxy.myuni.edu` counts as `www.jstor.org`. EZproxy
+/// turns the host's dots into hyphens and doubles the hyphens it had. With
+/// proxy by port, requests to the proxy's own name on a mapped port count as
+/// the vendor host assigned that port. Other hosts are kept as they are.
+struct TargetHost {
+    /// Each with a leading dot, lowercase
+    suffixes: Vec<String>,
+    /// Proxy-by-port assignments, lowercase
+    ports: HashMap<i32, String>,
+}
+
+impl TargetHost {
+    fn new(cfg: &TargetHostConfig) -> Result<TargetHost> {
+        if cfg.suffixes.is_empty() {
+            bail!("enrich.target_host.suffixes is empty");
+        }
+        let suffixes = cfg.suffixes.iter().map(|s| format!(".{}", parser::ascii_host(s.trim().trim_start_matches('.')))).collect();
+        let mut ports = HashMap::new();
+        for path in &cfg.port_maps {
+            let text = fs::read_to_string(path).with_context(|| format!("read port map {}", path))?;
+            for (i, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let Some((port, host)) = line.split_once(',') else {
+                    bail!("{}:{}: expected port,host", path, i + 1);
+                };
+                if i == 0 && port.trim().eq_ignore_ascii_case("port") {
+                    continue;
+                }
+                let (port, host) = port_entry(port, host).with_context(|| format!("{}:{}", path, i + 1))?;
+                ports.insert(port, host);
+            }
+        }
+        for (port, host) in &cfg.ports {
+            let (port, host) = port_entry(port, host).context("enrich.target_host.ports")?;
+            ports.insert(port, host);
+        }
+        Ok(TargetHost { suffixes, ports })
+    }
+
+    fn target(&self, host: &str, port: Option<i32>) -> String {
+        let host = host.to_ascii_lowercase();
+        let label = self.suffixes.iter().find_map(|s| host.strip_suffix(s.as_str()));
+        match label {
+            // The proxy's own name, or a host that is already a vendor's
+            Some(label) if !label.is_empty() && !label.contains('.') => {
+                label.split("--").map(|part| part.replace('-', ".")).collect::<Vec<_>>().join("-")
+            }
+            _ if self.suffixes.iter().any(|s| s[1..] == host) => {
+                port.and_then(|p| self.ports.get(&p)).cloned().unwrap_or(host)
+            }
+            _ => host,
+        }
+    }
+}
+
+/// One port assignment; the host may be given as a URL.
+fn port_entry(port: &str, host: &str) -> Result<(i32, String)> {
+    let port: u16 = port.trim().parse().map_err(|_| anyhow!("port {:?} is not a port number", port.trim()))?;
+    let host = host.split(',').next().unwrap_or_default().trim();
+    let host = host.split_once("://").map_or(host, |(_, rest)| rest);
+    let host = parser::ascii_host(host.split(['/', ':']).next().unwrap_or_default());
+    if host.is_empty() {
+        bail!("port {} has no host", port);
+    }
+    Ok((i32::from(port), host))
+}
+
+impl Enricher for TargetHost {
+    fn name(&self) -> &'static str {
+        "target_host"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["target_host"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        row.target_host = row.host.as_deref().map(|h| self.target(h, row.port));
+    }
+}
+
+/// Country from a MaxMind database, for logs without `%{ezproxy-country}`
+/// or where it was logged blank.
+struct Geoip {
+    reader: maxminddb::Reader<Vec<u8>>,
+    overwrite: bool,
+}
+
+impl Geoip {
+    fn open(cfg: &GeoipConfig) -> Result<Geoip> {
+        let reader = maxminddb::Reader::open_readfile(&cfg.database).with_context(|| format!("open {}", cfg.database))?;
+        Ok(Geoip { reader, overwrite: cfg.overwrite })
+    }
+}
+
+impl Enricher for Geoip {
+    fn name(&self) -> &'static str {
+        "geoip"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["country"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        if row.country.is_some() && !self.overwrite {
+            return;
+        }
+        let Ok(ip) = row.remote_addr.parse::<IpAddr>() else {
+            return;
+        };
+        // Addresses the database doesn't cover keep what was logged.
+        if let Ok(found) = self.reader.lookup::<maxminddb::geoip2::Country>(ip)
+            && let Some(code) = found.country.and_then(|c| c.iso_code)
+        {
+            row.country = Some(code.to_string());
+        }
+    }
+}
+
+/// The anonymize stage's pseudonym for `user` under `key`.
+pub fn pseudonym(key: &str, user: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(user.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("anon-{}", hex)
+}
+
+/// Pseudonymous usernames and truncated IPs, for sites that may not keep
+/// personal data. The same key gives the same pseudonym, so sessions and
+/// unique-user counts still work. Rewrites `raw` to match.
+struct Anonymize {
+    key: String,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+}
+
+impl Anonymize {
+    fn new(cfg: &AnonymizeConfig) -> Result<Anonymize> {
+        if cfg.key.is_empty() {
+            bail!("enrich.anonymize.key is empty");
+        }
+        if cfg.ipv4_prefix > 32 || cfg.ipv6_prefix > 128 {
+            bail!("enrich.anonymize prefixes are at most 32 (IPv4) and 128 (IPv6) bits");
+        }
+        Ok(Anonymize { key: cfg.key.clone(), ipv4_prefix: cfg.ipv4_prefix, ipv6_prefix: cfg.ipv6_prefix })
+    }
+
+    fn truncate(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX.checked_shl(32 - self.ipv4_prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(128 - self.ipv6_prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        }
+    }
+}
+
+impl Enricher for Anonymize {
+    fn name(&self) -> &'static str {
+        "anonymize"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["remote_addr", "user_or_session", "raw"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        if let Ok(ip) = row.remote_addr.parse::<IpAddr>() {
+            let masked = self.truncate(ip).to_string();
+            row.raw = row.raw.replacen(&row.remote_addr, &masked, 1);
+            row.remote_addr = masked;
+        }
+        if let Some(user) = row.user_or_session.take() {
+            let pseudonym = pseudonym(&self.key, &user);
+            // The username is the third field of the line; only replace it
+            // there, not wherever the same text shows up in the URL.
+            if let Some(at) = row.raw.find(&format!(" {} [", user)) {
+                row.raw.replace_range(at + 1..at + 1 + user.len(), &pseudonym);
+            }
+            row.user_or_session = Some(pseudonym);
+        }
+    }
+}
+
+/// Query parameters the `scrub` stage blanks by default: sessions, tokens,
+/// credentials, and the patron identifiers some platforms pass along.
+pub const SCRUB_PARAMS: &[&str] = &[
+    "sid", "*session*", "*token*", "auth", "ticket", "jwt", "*password*", "passwd", "*secret*", "apikey", "api_key",
+    "signature", "email", "*mail", "user", "username", "userid", "user_id", "patron*", "barcode",
+];
+
+/// Values of sensitive query parameters replaced in `url`, `query`,
+/// `referrer`, and `raw`, so session IDs, tokens, and addresses never reach
+/// the database. Only values are touched; the names stay, so
+/// `/api/top_query_params` still shows what was there.
+struct Scrub {
+    params: Vec<String>,
+    emails: bool,
+    replacement: String,
+    scrubbed: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Scrub {
+    fn new(cfg: &ScrubConfig) -> Result<Scrub> {
+        let params = cfg.params.iter().map(|p| p.trim().to_lowercase()).collect::<Vec<_>>();
+        if let Some(p) = params.iter().find(|p| p.is_empty() || p.contains(['&', '='])) {
+            bail!("{:?} is not a query parameter name", p);
+        }
+        if cfg.replacement.contains(['&', '=', '#', '"']) || cfg.replacement.contains(char::is_whitespace) {
+            bail!("enrich.scrub.replacement {:?} would change how the URL reads", cfg.replacement);
+        }
+        Ok(Scrub { params, emails: cfg.emails, replacement: cfg.replacement.clone(), scrubbed: Mutex::default() })
+    }
+
+    fn sensitive(&self, name: &str, value: &str) -> bool {
+        static EMAIL: OnceLock<regex::Regex> = OnceLock::new();
+        let email = EMAIL.get_or_init(|| {
+            regex::Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}").expect("regex compiles")
+        });
+        self.params.iter().any(|p| wildcard_match(p, name)) || (self.emails && email.is_match(value))
+    }
+
+    /// `query` with sensitive values replaced, and what was replaced by
+    /// name; `None` when nothing was.
+    fn scrub_query(&self, query: &str, counts: &mut BTreeMap<String, u64>) -> Option<String> {
+        let mut changed = false;
+        let pairs: Vec<String> = query
+            .split('&')
+            .map(|pair| {
+                let Some((name, value)) = pair.split_once('=') else {
+                    return pair.to_string();
+                };
+                // Names and values as the platform meant them, for matching.
+                let decoded = url::form_urlencoded::parse(pair.as_bytes()).next();
+                let (dname, dvalue) = decoded.map(|(n, v)| (n.to_lowercase(), v.into_owned())).unwrap_or_default();
+                if value.is_empty() || value == self.replacement || !self.sensitive(&dname, &dvalue) {
+                    return pair.to_string();
+                }
+                changed = true;
+                *counts.entry(dname).or_default() += 1;
+                format!("{}={}", name, self.replacement)
+            })
+            .collect();
+        changed.then(|| pairs.join("&"))
+    }
+
+    /// `url` with its query string scrubbed, or `None` when that changed
+    /// nothing.
+    fn scrub_url(&self, url: &str, counts: &mut BTreeMap<String, u64>) -> Option<String> {
+        let (base, rest) = url.split_once('?')?;
+        let (query, fragment) = match rest.split_once('#') {
+            Some((q, f)) => (q, Some(f)),
+            None => (rest, None),
+        };
+        let query = self.scrub_query(query, counts)?;
+        Some(match fragment {
+            Some(f) => format!("{}?{}#{}", base, query, f),
+            None => format!("{}?{}", base, query),
+        })
+    }
+}
+
+impl Enricher for Scrub {
+    fn name(&self) -> &'static str {
+        "scrub"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["url", "query", "referrer", "raw"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        let mut counts = BTreeMap::new();
+        if let Some(url) = self.scrub_url(&row.url, &mut counts) {
+            row.raw = row.raw.replacen(&row.url, &url, 1);
+            row.url = url;
+            // The parsed query is the URL's, so it changes the same way.
+            row.query = row.query.as_deref().map(|q| self.scrub_query(q, &mut BTreeMap::new()).unwrap_or_else(|| q.to_string()));
+        }
+        if let Some(referrer) = &row.referrer
+            && let Some(scrubbed) = self.scrub_url(referrer, &mut counts)
+        {
+            // The referrer is the line's last field.
+            if let Some(at) = row.raw.rfind(referrer.as_str()) {
+                row.raw.replace_range(at..at + referrer.len(), &scrubbed);
+            }
+            row.referrer = Some(scrubbed);
+        }
+        if !counts.is_empty() {
+            let mut scrubbed = self.scrubbed.lock().expect("scrub counts poisoned");
+            for (name, n) in counts {
+                *scrubbed.entry(name).or_default() += n;
+            }
+        }
+    }
+
+    fn take_scrubbed(&self) -> BTreeMap<String, u64> {
+        std::mem::take(&mut *self.scrubbed.lock().expect("scrub counts poisoned"))
+    }
+
+    fn stored_url(&self, url: &str) -> Option<String> {
+        self.scrub_url(url, &mut BTreeMap::new())
+    }
+}
+
+/// Ends a value the `truncate` stage cut short.
+pub const TRUNCATION_MARKER: &str = "…";
+
+/// `s` cut to at most `max` bytes, ending in `TRUNCATION_MARKER`, or `None`
+/// when it already fits.
+fn truncated(s: &str, max: usize) -> Option<String> {
+    if s.len() <= max {
+        return None;
+    }
+    let mut end = max - TRUNCATION_MARKER.len();
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(format!("{}{}", &s[..end], TRUNCATION_MARKER))
+}
+
+/// URLs and paths cut to a storable length. Some platforms put whole
+/// search states or SAML assertions in the URL, tens of KB per request,
+/// which bloat the database and every payload that lists paths. A cut URL
+/// keeps a hash of the whole one in `url_sha256`, so requests for the same
+/// long URL can still be told apart from different ones. Rewrites `raw` to
+/// match.
+struct Truncate {
+    max_url: usize,
+    max_path: usize,
+}
+
+impl Truncate {
+    fn new(cfg: &TruncateConfig) -> Result<Truncate> {
+        let least = TRUNCATION_MARKER.len() + 8;
+        if cfg.max_url < least || cfg.max_path < least {
+            bail!("enrich.truncate lengths must be at least {} bytes", least);
+        }
+        Ok(Truncate { max_url: cfg.max_url, max_path: cfg.max_path })
+    }
+}
+
+impl Enricher for Truncate {
+    fn name(&self) -> &'static str {
+        "truncate"
+    }
+
+    fn columns(&self) -> &'static [&'static str] {
+        &["url", "query", "path", "path_template", "referrer", "url_sha256", "raw"]
+    }
+
+    fn enrich(&self, row: &mut LogRow) {
+        if let Some(url) = truncated(&row.url, self.max_url) {
+            row.url_sha256 = Some(crate::integrity::raw_hash(&row.url));
+            row.raw = row.raw.replacen(&row.url, &url, 1);
+            row.url = url;
+        }
+        if let Some(referrer) = &row.referrer
+            && let Some(cut) = truncated(referrer, self.max_url)
+        {
+            if let Some(at) = row.raw.rfind(referrer.as_str()) {
+                row.raw.replace_range(at..at + referrer.len(), &cut);
+            }
+            row.referrer = Some(cut);
+        }
+        for (value, max) in [
+            (&mut row.query, self.max_url),
+            (&mut row.path, self.max_path),
+            (&mut row.path_template, self.max_path),
+        ] {
+            if let Some(cut) = value.as_deref().and_then(|v| truncated(v, max)) {
+                *value = Some(cut);
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{Datelike, Local, Months, NaiveDate};
+use duckdb::{Connection, params};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::{ExportConfig, PortsConfig},
+    db::{self, Table},
+};
+
+#[derive(Debug, Serialize)]
+pub struct ExportSummary {
+    pub month: String,
+    pub files: Vec<String>,
+    pub webhook: Option<String>,
+}
+
+/// `YYYY-MM` of the month before today, the usual target of a scheduled run.
+pub fn previous_month() -> String {
+    let first = Local::now().date_naive().with_day(1).expect("day 1 exists");
+    (first - Months::new(1)).format("%Y-%m").to_string()
+}
+
+fn month_bounds(month: &str) -> Result<(NaiveDate, NaiveDate)> {
+    let start = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(|_| anyhow!("month must look like 2026-02, got {:?}", month))?;
+    Ok((start, start + Months::new(1)))
+}
+
+/// Top platforms, daily bandwidth, and daily unique users for one calendar
+/// month (UTC), in the shape of the monthly e-resources spreadsheet.
+pub fn usage_tables(
+    conn: &Connection,
+    month: &str,
+    top_platforms: i64,
+    ports: &PortsConfig,
+) -> Result<Vec<(&'static str, Table)>> {
+    let (start, end) = month_bounds(month)?;
+    let (start, end) = (start.to_string(), end.to_string());
+
+    let platforms = db::query_table(
+        conn,
+        &format!(
+            r#"
+            SELECT {host} AS platform,
+                   count(*) AS requests,
+                   round(sum(COALESCE(bytes, 0)) / 1024.0 / 1024.0, 1) AS mb,
+                   count(DISTINCT user_or_session) AS users
+            FROM requests
+            WHERE {host} IS NOT NULL
+              AND ts >= CAST(? AS TIMESTAMP) AND ts < CAST(? AS TIMESTAMP)
+            GROUP BY 1
+            ORDER BY requests DESC
+            LIMIT ?
+            "#,
+            host = db::target_host(ports)
+        ),
+        params![start, end, top_platforms],
+    )?;
+
+    let bandwidth = db::query_table(
+        conn,
+        r#"
+        SELECT CAST(CAST(CAST(ts AS TIMESTAMP) AS DATE) AS VARCHAR) AS day,
+               count(*) AS requests,
+               round(sum(COALESCE(bytes, 0)) / 1024.0 / 1024.0, 1) AS mb
+        FROM requests
+        WHERE ts >= CAST(? AS TIMESTAMP) AND ts < CAST(? AS TIMESTAMP)
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+        params![start, end],
+    )?;
+
+    let users = db::query_table(
+        conn,
+        r#"
+        SELECT CAST(CAST(CAST(ts AS TIMESTAMP) AS DATE) AS VARCHAR) AS day,
+               count(DISTINCT user_or_session) AS users,
+               count(DISTINCT remote_addr) AS ips
+        FROM requests
+        WHERE ts >= CAST(? AS TIMESTAMP) AND ts < CAST(? AS TIMESTAMP)
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+        params![start, end],
+    )?;
+
+    Ok(vec![("platforms", platforms), ("bandwidth", bandwidth), ("users", users)])
+}
+
+/// Write `<dir>/<month>-<table>.csv` for each usage table and, when a
+/// webhook is configured, POST the same tables as JSON.
+pub fn export_month(conn: &Connection, month: &str, cfg: &ExportConfig, ports: &PortsConfig) -> Result<ExportSummary> {
+    let tables = usage_tables(conn, month, cfg.top_platforms, ports)?;
+
+    fs::create_dir_all(&cfg.dir).with_context(|| format!("create {}", cfg.dir))?;
+    let mut files = Vec::new();
+    for (name, table) in &tables {
+        let path = Path::new(&cfg.dir).join(format!("{}-{}.csv", month, name));
+        fs::write(&path, table.to_csv()).with_context(|| format!("write {}", path.display()))?;
+        files.push(path.display().to_string());
+    }
+
+    if let Some(url) = &cfg.webhook {
+        let mut body = json!({ "month": month });
+        for (name, table) in &tables {
+            body[*name] = table.to_objects().into();
+        }
+        ureq::post(url)
+            .send_json(&body)
+            .with_context(|| format!("post export to {}", url))?;
+    }
+
+    Ok(ExportSummary {
+        month: month.to_string(),
+        files,
+        webhook: cfg.webhook.clone(),
+    })
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use duckdb::{Connection, Params, params};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    config::{FederationConfig, PortsConfig},
+    db::{self, Table},
+    duration,
+};
+
+/// Per-day, per-host totals: all a member ever shares with the consortium.
+pub fn member_summary<P: Params>(conn: &Connection, cond: &str, params: P, ports: &PortsConfig) -> Result<Table> {
+    db::query_table(
+        conn,
+        &format!(
+            r#"
+            SELECT CAST(CAST(CAST(ts AS TIMESTAMP) AS DATE) AS VARCHAR) AS day,
+                   {host} AS host,
+                   count(*) AS requests,
+                   count(DISTINCT user_or_session) AS users,
+                   round(sum(COALESCE(bytes, 0)) / 1024.0 / 1024.0, 1) AS mb
+            FROM requests
+            WHERE {host} IS NOT NULL AND {cond}
+            GROUP BY 1, 2
+            ORDER BY 1, 2
+            "#,
+            host = db::target_host(ports)
+        ),
+        params,
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct DailyRow {
+    day: String,
+    host: String,
+    requests: i64,
+    users: i64,
+    mb: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryResponse {
+    rows: Vec<DailyRow>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PullSummary {
+    pub since: String,
+    pub members: Vec<serde_json::Value>,
+}
+
+/// Replace the stored window of one member's rows with what it returned.
+fn store(conn: &Connection, member: &str, since: &str, rows: &[DailyRow]) -> Result<()> {
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = (|| -> Result<()> {
+        conn.execute(
+            "DELETE FROM federation_daily WHERE member = ? AND day >= CAST(? AS DATE)",
+            params![member, since],
+        )?;
+        let mut stmt = conn.prepare(
+            "INSERT INTO federation_daily VALUES (?, CAST(? AS DATE), ?, ?, ?, ?, now())",
+        )?;
+        for r in rows {
+            stmt.execute(params![member, r.day, r.host, r.requests, r.users, r.mb])?;
+        }
+        Ok(())
+    })();
+    match res {
+        Ok(()) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+/// Fetch the last `window` of daily aggregates from every member. A member
+/// that can't be reached keeps its previous rows; the others still update.
+pub fn pull(conn: &Connection, cfg: &FederationConfig) -> Result<PullSummary> {
+    let window = duration::parse_duration(&cfg.window)?;
+    let since = (Utc::now() - window).date_naive().to_string();
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(120)))
+        .build()
+        .into();
+
+    let mut members = Vec::new();
+    for m in &cfg.members {
+        let url = format!("{}/api/federation/summary", m.url.trim_end_matches('/'));
+        let mut req = agent.get(&url).query("start", &since);
+        if let Some(token) = &m.token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        let res = req
+            .call()
+            .and_then(|mut r| r.body_mut().read_json::<SummaryResponse>())
+            .with_context(|| format!("fetch {}", url))
+            .and_then(|s| store(conn, &m.name, &since, &s.rows).map(|_| s.rows.len()));
+        match res {
+            Ok(n) => members.push(json!({"member": m.name, "rows": n})),
+            Err(e) => {
+                eprintln!("federation: {}: {:#}", m.name, e);
+                members.push(json!({"member": m.name, "error": format!("{:#}", e)}));
+            }
+        }
+    }
+
+    Ok(PullSummary { since, members })
+}
+
+/// Combined view over everything pulled so far: totals per member, a daily
+/// series per member, and the top hosts with each member's share.
+pub fn overview(conn: &Connection) -> Result<serde_json::Value> {
+    let members = db::query_table(
+        conn,
+        r#"
+        SELECT member,
+               CAST(sum(requests) AS BIGINT) AS requests,
+               round(sum(mb), 1) AS mb,
+               CAST(min(day) AS VARCHAR) AS first_day,
+               CAST(max(day) AS VARCHAR) AS last_day,
+               CAST(max(pulled_at) AS VARCHAR) AS pulled_at
+        FROM federation_daily
+        GROUP BY 1 ORDER BY requests DESC
+        "#,
+        params![],
+    )?;
+
+    let daily = db::query_table(
+        conn,
+        r#"
+        SELECT CAST(day AS VARCHAR) AS day, member, CAST(sum(requests) AS BIGINT) AS requests
+        FROM federation_daily
+        GROUP BY 1, 2 ORDER BY 1, 2
+        "#,
+        params![],
+    )?;
+
+    let hosts = db::query_table(
+        conn,
+        r#"
+        SELECT host, member, CAST(sum(requests) AS BIGINT) AS requests
+        FROM federation_daily
+        WHERE host IN (
+          SELECT host FROM federation_daily GROUP BY 1 ORDER BY sum(requests) DESC LIMIT 15
+        )
+        GROUP BY 1, 2 ORDER BY 1, 2
+        "#,
+        params![],
+    )?;
+
+    Ok(json!({
+        "members": members.to_objects(),
+        "daily": daily.to_objects(),
+        "top_hosts": hosts.to_objects(),
+    }))
+}
@@ -0,0 +1,207 @@
+use anyhow::{Result, bail};
+use chrono::{Datelike, Days, Duration, NaiveDate};
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+/// Longest projection `compute` makes; a linear trend says little about
+/// next year's term.
+const MAX_HORIZON: Duration = Duration::days(365);
+
+/// Days of history fitted, counting back from the last day with data.
+const MAX_HISTORY_DAYS: i64 = 365;
+
+/// Two of each weekday, so every weekday's effect is estimated from more
+/// than one day.
+const MIN_HISTORY_DAYS: i64 = 14;
+
+/// The bands are 95% prediction intervals.
+const CONFIDENCE: f64 = 0.95;
+const Z: f64 = 1.96;
+
+/// Intercept, trend, and six weekday offsets from Monday.
+const COEFFICIENTS: usize = 8;
+
+/// Reject horizons `compute` doesn't take.
+pub fn check_horizon(horizon: Duration) -> Result<()> {
+    if horizon < Duration::days(1) || horizon > MAX_HORIZON {
+        bail!("horizon must be between 1d and 365d");
+    }
+    Ok(())
+}
+
+/// Daily requests and bandwidth among the requests matching `filter`,
+/// projected `horizon` (rounded up to whole days) past the last day with
+/// data. Each metric is fitted, by least squares, to a linear trend plus an
+/// offset for each day of the week over up to a year of history, and each
+/// projected day comes with a 95% prediction interval. Days in UTC; days
+/// without requests inside the history count as zero, so gaps in the logs
+/// drag the fit down.
+pub fn compute(conn: &Connection, horizon: Duration, filter: &str, args: &[String]) -> Result<serde_json::Value> {
+    check_horizon(horizon)?;
+    let horizon_days = (horizon.num_seconds() + 86_399) / 86_400;
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT CAST(CAST(CAST(ts AS TIMESTAMP) AS DATE) AS VARCHAR) AS d,
+               count(*) AS n,
+               COALESCE(sum(bytes), 0) / 1024.0 / 1024.0 AS mb
+        FROM requests
+        WHERE {filter}
+        GROUP BY 1
+        ORDER BY 1
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut daily: Vec<(NaiveDate, f64, f64)> = Vec::new();
+    while let Some(r) = rows.next()? {
+        let day: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let mb: f64 = r.get(2)?;
+        daily.push((day.parse()?, n as f64, mb));
+    }
+
+    let empty = |reason: String| {
+        json!({ "horizon_days": horizon_days, "history": null, "requests": null, "bandwidth_mb": null, "reason": reason })
+    };
+    let Some(&(last, ..)) = daily.last() else {
+        return Ok(empty("no requests match".into()));
+    };
+    let first = daily[0].0.max(last - Days::new(MAX_HISTORY_DAYS as u64 - 1));
+    let days = (last - first).num_days() + 1;
+    if days < MIN_HISTORY_DAYS {
+        return Ok(empty(format!("{days} days of history; a forecast needs at least {MIN_HISTORY_DAYS}")));
+    }
+
+    let mut requests = vec![0.0; days as usize];
+    let mut mb = vec![0.0; days as usize];
+    for &(day, n, m) in daily.iter().filter(|d| d.0 >= first) {
+        let i = (day - first).num_days() as usize;
+        requests[i] = n;
+        mb[i] = m;
+    }
+
+    let future: Vec<NaiveDate> = (1..=horizon_days as u64).map(|i| last + Days::new(i)).collect();
+    let metric = |y: &[f64], decimals: i32| -> Result<serde_json::Value> {
+        let fit = Fit::new(first, y)?;
+        let scale = 10f64.powi(decimals);
+        let round = |v: f64| (v.max(0.0) * scale).round() / scale;
+        let points: Vec<_> = future
+            .iter()
+            .map(|&day| {
+                let (value, se) = fit.predict(day);
+                json!({
+                    "day": day.to_string(),
+                    "value": round(value),
+                    "low": round(value - Z * se),
+                    "high": round(value + Z * se),
+                })
+            })
+            .collect();
+        // The trend is per day, so small, and gets two more decimals.
+        let trend = (fit.beta[1] * scale * 100.0).round() / scale / 100.0;
+        Ok(json!({ "trend_per_day": trend, "forecast": points }))
+    };
+
+    Ok(json!({
+        "horizon_days": horizon_days,
+        "confidence": CONFIDENCE,
+        "history": { "start": first.to_string(), "end": last.to_string(), "days": days },
+        "requests": metric(&requests, 0)?,
+        "bandwidth_mb": metric(&mb, 1)?,
+    }))
+}
+
+/// A least-squares fit of trend and weekday offsets to one daily series.
+struct Fit {
+    first: NaiveDate,
+    beta: [f64; COEFFICIENTS],
+    /// (X'X)^-1, for the variance of a prediction
+    inverse: [[f64; COEFFICIENTS]; COEFFICIENTS],
+    /// Residual standard deviation
+    sigma: f64,
+}
+
+impl Fit {
+    fn new(first: NaiveDate, y: &[f64]) -> Result<Self> {
+        let mut xtx = [[0.0; COEFFICIENTS]; COEFFICIENTS];
+        let mut xty = [0.0; COEFFICIENTS];
+        for (i, &v) in y.iter().enumerate() {
+            let x = regressors(first, first + Days::new(i as u64));
+            for r in 0..COEFFICIENTS {
+                xty[r] += x[r] * v;
+                for c in 0..COEFFICIENTS {
+                    xtx[r][c] += x[r] * x[c];
+                }
+            }
+        }
+        let inverse = invert(xtx)?;
+        let mut beta = [0.0; COEFFICIENTS];
+        for (r, b) in beta.iter_mut().enumerate() {
+            *b = (0..COEFFICIENTS).map(|c| inverse[r][c] * xty[c]).sum();
+        }
+        let rss: f64 = y
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| {
+                let x = regressors(first, first + Days::new(i as u64));
+                let fitted: f64 = x.iter().zip(&beta).map(|(x, b)| x * b).sum();
+                (v - fitted).powi(2)
+            })
+            .sum();
+        let sigma = (rss / (y.len() - COEFFICIENTS) as f64).sqrt();
+        Ok(Fit { first, beta, inverse, sigma })
+    }
+
+    /// The fitted value on `day` and the standard error of a new
+    /// observation there.
+    fn predict(&self, day: NaiveDate) -> (f64, f64) {
+        let x = regressors(self.first, day);
+        let value = x.iter().zip(&self.beta).map(|(x, b)| x * b).sum();
+        let leverage: f64 =
+            (0..COEFFICIENTS).map(|r| (0..COEFFICIENTS).map(|c| x[r] * self.inverse[r][c] * x[c]).sum::<f64>()).sum();
+        (value, self.sigma * (1.0 + leverage).sqrt())
+    }
+}
+
+/// Intercept, days since `first`, and a dummy for each weekday but Monday.
+fn regressors(first: NaiveDate, day: NaiveDate) -> [f64; COEFFICIENTS] {
+    let mut x = [0.0; COEFFICIENTS];
+    x[0] = 1.0;
+    x[1] = (day - first).num_days() as f64;
+    let weekday = day.weekday().num_days_from_monday() as usize;
+    if weekday > 0 {
+        x[1 + weekday] = 1.0;
+    }
+    x
+}
+
+/// Gauss-Jordan elimination with partial pivoting.
+fn invert(mut m: [[f64; COEFFICIENTS]; COEFFICIENTS]) -> Result<[[f64; COEFFICIENTS]; COEFFICIENTS]> {
+    let mut inv = [[0.0; COEFFICIENTS]; COEFFICIENTS];
+    for (i, row) in inv.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    for col in 0..COEFFICIENTS {
+        let pivot = (col..COEFFICIENTS).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs())).unwrap_or(col);
+        if m[pivot][col].abs() < 1e-9 {
+            bail!("history too short to fit a weekly pattern");
+        }
+        m.swap(col, pivot);
+        inv.swap(col, pivot);
+        let p = m[col][col];
+        for c in 0..COEFFICIENTS {
+            m[col][c] /= p;
+            inv[col][c] /= p;
+        }
+        for r in 0..COEFFICIENTS {
+            if r != col {
+                let f = m[r][col];
+                for c in 0..COEFFICIENTS {
+                    m[r][c] -= f * m[col][c];
+                    inv[r][c] -= f * inv[col][c];
+                }
+            }
+        }
+    }
+    Ok(inv)
+}
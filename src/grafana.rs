@@ -0,0 +1,193 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, params_from_iter};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{config::{PortsConfig, PrivacyConfig}, db, privacy};
+
+/// Time series offered to Grafana, bucketed by the panel's interval.
+const SERIES: &[(&str, &str)] = &[
+    ("requests", "count(*)"),
+    ("users", "count(DISTINCT user_or_session)"),
+    ("bandwidth_mb", "round(sum(COALESCE(bytes, 0)) / 1024.0 / 1024.0, 2)"),
+    ("errors", "count(*) FILTER (WHERE status >= 400)"),
+    ("denials", "count(*) FILTER (WHERE status IN (401, 403))"),
+];
+
+/// Tables for the whole panel range, the same rankings the dashboard shows.
+/// `{cond}` is replaced with the range and the target's toggles, `{resource}`
+/// with what host rankings leave out, and `{host}` with
+/// `db::target_host(ports)`.
+const TABLES: &[(&str, &str)] = &[
+    (
+        "top_hosts",
+        "SELECT {host} AS host, count(*) AS requests, count(DISTINCT user_or_session) AS users FROM requests \
+         WHERE {host} IS NOT NULL AND {cond} AND {resource} GROUP BY 1 ORDER BY 2 DESC LIMIT 15",
+    ),
+    (
+        "status_codes",
+        "SELECT CAST(status AS VARCHAR) AS status, count(*) AS requests FROM requests \
+         WHERE {cond} GROUP BY 1 ORDER BY 2 DESC",
+    ),
+    (
+        "top_countries",
+        "SELECT country, count(*) AS requests, count(DISTINCT user_or_session) AS users FROM requests \
+         WHERE country IS NOT NULL AND country <> '' AND {cond} GROUP BY 1 ORDER BY 2 DESC LIMIT 20",
+    ),
+    (
+        "top_issns",
+        "SELECT issn, count(*) AS requests, count(DISTINCT user_or_session) AS users FROM requests \
+         WHERE issn IS NOT NULL AND {cond} GROUP BY 1 ORDER BY 2 DESC LIMIT 25",
+    ),
+];
+
+/// Smallest bucket handed back, whatever interval the panel asks for.
+const MIN_INTERVAL_MS: i64 = 60_000;
+
+/// Metric names for the query editor's picker, filtered by what has been
+/// typed so far.
+pub fn search(typed: &str) -> Vec<&'static str> {
+    SERIES
+        .iter()
+        .chain(TABLES)
+        .map(|(name, _)| *name)
+        .filter(|name| name.contains(typed))
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    range: Range,
+    #[serde(rename = "intervalMs")]
+    interval_ms: Option<i64>,
+    #[serde(rename = "maxDataPoints")]
+    max_data_points: Option<i64>,
+    #[serde(default)]
+    targets: Vec<Target>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Range {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+    target: Option<String>,
+    /// Grafana's per-query JSON, with the dashboard's toggles; see `Toggles`
+    #[serde(default)]
+    payload: serde_json::Value,
+    #[serde(default)]
+    hide: bool,
+}
+
+/// The dashboard's toggles, as a target's payload sets them, e.g.
+/// `{"exclude_noise": true}`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Toggles {
+    pub exclude_noise: bool,
+    pub include_assets: bool,
+    pub include_proxy: bool,
+}
+
+impl Target {
+    fn toggles(&self) -> Toggles {
+        let flag = |name: &str| self.payload.get(name).and_then(|v| v.as_bool()).unwrap_or(false);
+        Toggles {
+            exclude_noise: flag("exclude_noise"),
+            include_assets: flag("include_assets"),
+            include_proxy: flag("include_proxy"),
+        }
+    }
+}
+
+/// The WHERE clause and bind args for a range and toggles, and what host
+/// rankings leave out on top, built as the dashboard builds its own.
+pub type Conditions = (String, Vec<String>, &'static str);
+
+impl QueryRequest {
+    /// Parsed range, or an error naming what Grafana sent that we can't use.
+    pub fn check(&self) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let from = DateTime::parse_from_rfc3339(&self.range.from).context("range.from")?;
+        let to = DateTime::parse_from_rfc3339(&self.range.to).context("range.to")?;
+        for t in self.targets.iter().filter(|t| !t.hide) {
+            let name = t.target.as_deref().unwrap_or_default();
+            if !SERIES.iter().chain(TABLES).any(|(n, _)| *n == name) {
+                bail!("unknown metric {:?}", name);
+            }
+        }
+        Ok((from.with_timezone(&Utc), to.with_timezone(&Utc)))
+    }
+}
+
+/// Answer a `/query` call: a `{target, datapoints}` series or a `table` for
+/// each visible target, in order. `conditions` filters each target the way
+/// the dashboard filters its panels, so the two agree, and table rows are
+/// held to `[privacy]` as the dashboard's are.
+pub fn query(
+    conn: &Connection,
+    req: &QueryRequest,
+    ports: &PortsConfig,
+    privacy: &PrivacyConfig,
+    conditions: impl Fn(DateTime<Utc>, DateTime<Utc>, Toggles) -> Conditions,
+) -> Result<Vec<serde_json::Value>> {
+    let (from, to) = req.check()?;
+    let span_ms = (to - from).num_milliseconds().max(0);
+    let mut bucket_ms = req.interval_ms.unwrap_or(0).max(MIN_INTERVAL_MS);
+    if let Some(max_points) = req.max_data_points.filter(|n| *n > 0) {
+        bucket_ms = bucket_ms.max(span_ms / max_points);
+    }
+    // Bucket boundaries are whole seconds.
+    let bucket_s = (bucket_ms + 999) / 1000;
+
+    let mut out = Vec::new();
+    for t in req.targets.iter().filter(|t| !t.hide) {
+        let name = t.target.as_deref().unwrap_or_default();
+        let (cond, args, resource) = conditions(from, to, t.toggles());
+
+        if let Some((_, expr)) = SERIES.iter().find(|(n, _)| *n == name) {
+            let mut stmt = conn.prepare(&format!(
+                r#"
+                SELECT CAST(floor(epoch(CAST(ts AS TIMESTAMP)) / {bucket_s}) AS BIGINT) * {bucket_s} * 1000 AS t,
+                       CAST({expr} AS DOUBLE) AS v
+                FROM requests
+                WHERE {cond}
+                GROUP BY 1 ORDER BY 1
+                "#
+            ))?;
+            let mut rows = stmt.query(params_from_iter(&args))?;
+            let mut datapoints = Vec::new();
+            while let Some(r) = rows.next()? {
+                let t: i64 = r.get(0)?;
+                let v: f64 = r.get(1)?;
+                datapoints.push(json!([v, t]));
+            }
+            out.push(json!({ "target": name, "datapoints": datapoints }));
+        } else if let Some((_, sql)) = TABLES.iter().find(|(n, _)| *n == name) {
+            let sql = sql.replace("{cond}", &cond).replace("{resource}", resource).replace("{host}", db::target_host(ports));
+            let mut table = db::query_table(conn, &sql, params_from_iter(&args))?;
+            // `privacy::protect` reads `users` by name, so it runs over the
+            // rows as objects.
+            let kept = privacy::protect(serde_json::Value::Array(table.to_objects()), privacy);
+            table.rows = kept
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|row| table.columns.iter().map(|c| row[c].clone()).collect())
+                .collect();
+            let columns: Vec<_> = table
+                .columns
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    let numeric = table.rows.first().is_some_and(|r| r[i].is_number());
+                    json!({ "text": c, "type": if numeric { "number" } else { "string" } })
+                })
+                .collect();
+            out.push(json!({ "type": "table", "columns": columns, "rows": table.rows }));
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use duckdb::{Connection, OptionalExt, params, params_from_iter};
+use serde_json::json;
+
+use crate::{config::HoneytokenConfig, db, parser::LogRow, watch::wildcard_match};
+
+/// `alerts.source` for rows written here.
+const SOURCE: &str = "honeytoken";
+
+/// `alerts.severity` of a hit: nobody follows a bait link by accident.
+const SEVERITY: &str = "high";
+
+/// Distinct URLs and IPs kept in an alert's detail.
+const MAX_DETAIL: usize = 10;
+
+/// Reject honeytokens that would never match or can't be told apart.
+pub fn validate(tokens: &[HoneytokenConfig]) -> Result<()> {
+    for (i, t) in tokens.iter().enumerate() {
+        if tokens[..i].iter().any(|u| u.name == t.name) {
+            bail!("honeytoken {:?} is defined twice", t.name);
+        }
+        if t.url.trim().is_empty() || t.url.trim() == "*" {
+            bail!("honeytoken {:?}: url would match every request", t.name);
+        }
+    }
+    Ok(())
+}
+
+fn matches(token: &HoneytokenConfig, row: &LogRow) -> bool {
+    match &row.path {
+        Some(path) if token.url.starts_with('/') => {
+            let full = match &row.query {
+                Some(q) => format!("{}?{}", path, q),
+                None => path.clone(),
+            };
+            wildcard_match(&token.url, path) || wildcard_match(&token.url, &full)
+        }
+        _ => wildcard_match(&token.url, &row.url),
+    }
+}
+
+/// Record requests among `rows` for any of `tokens` as alerts, merging
+/// them into the day's alert for the same user (or IP), and warn on stderr
+/// as each is found. Returns the number of requests that hit one.
+pub fn record(conn: &Connection, tokens: &[HoneytokenConfig], rows: &[LogRow]) -> Result<u64> {
+    let mut hits = 0;
+    for row in rows {
+        for token in tokens.iter().filter(|t| matches(t, row)) {
+            hits += 1;
+            let subject = row.user_or_session.as_deref().filter(|u| !u.is_empty()).unwrap_or(&row.remote_addr);
+            let day = row.ts.date_naive().to_string();
+            eprintln!("honeytoken {:?} requested by {} from {}: {}", token.name, subject, row.remote_addr, row.url);
+
+            let existing: Option<String> = conn
+                .query_row(
+                    "SELECT detail FROM alerts WHERE source = ? AND rule = ? AND subject = ? AND day = CAST(? AS DATE)",
+                    params![SOURCE, token.name, subject, day],
+                    |r| r.get(0),
+                )
+                .optional()?
+                .flatten();
+            let mut detail: BTreeMap<String, serde_json::Value> =
+                existing.and_then(|d| serde_json::from_str(&d).ok()).unwrap_or_default();
+            let count = detail.get("requests").and_then(|n| n.as_u64()).unwrap_or(0) + 1;
+            detail.insert("requests".into(), json!(count));
+            for (key, value) in [("urls", &row.url), ("remote_addrs", &row.remote_addr)] {
+                let list = detail.entry(key.into()).or_insert_with(|| json!([]));
+                if let Some(list) = list.as_array_mut()
+                    && list.len() < MAX_DETAIL
+                    && !list.iter().any(|v| v == value)
+                {
+                    list.push(json!(value));
+                }
+            }
+            detail.insert("last_seen".into(), json!(row.ts.to_rfc3339()));
+
+            conn.execute(
+                r#"
+                INSERT INTO alerts (source, rule, subject, day, detail, severity)
+                VALUES (?, ?, ?, CAST(? AS DATE), ?, ?)
+                ON CONFLICT (source, rule, subject, day) DO UPDATE SET detail = excluded.detail, updated_at = now()
+                "#,
+                params![SOURCE, token.name, subject, day, serde_json::to_string(&detail)?, SEVERITY],
+            )?;
+        }
+    }
+    Ok(hits)
+}
+
+/// Recorded honeytoken hits between the days of `start` and `end` (dates or
+/// timestamps, inclusive), optionally for one honeytoken, newest first, with
+/// a count per honeytoken.
+pub fn hits(conn: &Connection, start: Option<&str>, end: Option<&str>, name: Option<&str>) -> Result<serde_json::Value> {
+    let mut conds = vec!["source = ?"];
+    let mut args = vec![SOURCE.to_string()];
+    for (cond, arg) in [("day >= CAST(left(?, 10) AS DATE)", start), ("day <= CAST(left(?, 10) AS DATE)", end), ("rule = ?", name)] {
+        if let Some(arg) = arg {
+            conds.push(cond);
+            args.push(arg.to_string());
+        }
+    }
+    let cond = conds.join(" AND ");
+
+    let table = db::query_table(
+        conn,
+        &format!(
+            r#"
+            SELECT rule AS honeytoken, subject, CAST(day AS VARCHAR) AS day, severity, detail,
+                   CAST(created_at AS VARCHAR) AS first_seen
+            FROM alerts
+            WHERE {cond}
+            ORDER BY day DESC, rule, subject
+            "#
+        ),
+        params_from_iter(&args),
+    )?;
+    let mut rows = table.to_objects();
+    for row in &mut rows {
+        if let Some(detail) = row["detail"].as_str().and_then(|d| serde_json::from_str::<serde_json::Value>(d).ok()) {
+            row["detail"] = detail;
+        }
+    }
+    let by_honeytoken = db::query_table(
+        conn,
+        &format!("SELECT rule AS honeytoken, count(*) AS hits FROM alerts WHERE {cond} GROUP BY rule ORDER BY rule"),
+        params_from_iter(&args),
+    )?;
+    Ok(json!({ "by_honeytoken": by_honeytoken.to_objects(), "hits": rows }))
+}
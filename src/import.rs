@@ -0,0 +1,516 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, FixedOffset};
+use duckdb::{Connection, OptionalExt, params};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{HoneytokenConfig, SiemConfig},
+    db,
+    enrich::Pipeline,
+    honeytokens,
+    parser::{self, LogRow, ParseError},
+    seen, siem,
+    sink::Sink,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Skip rows for which `LogRow::is_noise` is true
+    pub exclude_noise: bool,
+    /// Keep only this share of lines; all of them when `None`
+    pub sample: Option<Sample>,
+    pub timestamps: parser::Timestamps,
+    /// How each row's original line is kept
+    pub raw: db::RawStorage,
+    /// Bait URLs to raise an alert for as rows are written
+    pub honeytokens: Vec<HoneytokenConfig>,
+    /// Where alerts and selected requests are forwarded once the file is in
+    pub siem: Option<SiemConfig>,
+}
+
+/// Keep `keep` lines out of every `of`, evenly spaced, so the same file
+/// always yields the same sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    keep: u64,
+    of: u64,
+}
+
+impl Sample {
+    /// `1%` or `0.5%` for a percentage, `1/100` for every 100th line.
+    pub fn parse(s: &str) -> Result<Sample> {
+        let s = s.trim();
+        let (keep, of) = if let Some(pct) = s.strip_suffix('%') {
+            // Percentages to three decimal places, as parts per 100000.
+            let pct: f64 = pct.trim().parse().map_err(|_| anyhow!("sample {:?} is not a percentage", s))?;
+            ((pct * 1000.0).round() as u64, 100_000)
+        } else if let Some((keep, of)) = s.split_once('/') {
+            let parse = |n: &str| n.trim().parse::<u64>().map_err(|_| anyhow!("sample {:?} must look like 1/100", s));
+            (parse(keep)?, parse(of)?)
+        } else {
+            bail!("sample {:?} must be a percentage like 1% or a ratio like 1/100", s);
+        };
+        if keep == 0 || of == 0 || keep > of {
+            bail!("sample {:?} must keep more than none and at most all lines", s);
+        }
+        Ok(Sample { keep, of })
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.keep as f64 / self.of as f64
+    }
+
+    /// Whether the line at 0-based index `i` is in the sample.
+    fn keeps(&self, i: u64) -> bool {
+        let (i, keep, of) = (i as u128, self.keep as u128, self.of as u128);
+        (i + 1) * keep / of > i * keep / of
+    }
+}
+
+impl ImportOptions {
+    fn keeps(&self, i: u64) -> bool {
+        self.sample.is_none_or(|s| s.keeps(i))
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.sample.map_or(1.0, |s| s.rate())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    pub ok: u64,
+    pub bad: u64,
+    /// Rows dropped by `exclude_noise`
+    pub skipped: u64,
+    /// Lines that failed to parse, by `ParseError::kind`, plus `not_utf8`.
+    /// `bad_url` rows are imported anyway, without the URL's parts.
+    pub failures: BTreeMap<String, u64>,
+    /// Query parameter values the `scrub` stage blanked out in this run, by
+    /// parameter name
+    pub scrubbed: BTreeMap<String, u64>,
+    /// Requests in this run for one of the `honeytokens`
+    pub honeytoken_hits: u64,
+}
+
+impl ImportSummary {
+    /// Lines that produced no row; `bad_url` lines are imported anyway.
+    pub fn unparsed(&self) -> u64 {
+        self.failures.iter().filter(|(k, _)| *k != "bad_url").map(|(_, n)| n).sum()
+    }
+}
+
+/// `reason=count` pairs for printing, e.g. `bad_timestamp=3 no_match=1`.
+pub fn describe_counts(counts: &BTreeMap<String, u64>) -> String {
+    counts.iter().map(|(k, n)| format!("{}={}", k, n)).collect::<Vec<_>>().join(" ")
+}
+
+/// A line's row, or a description of why it has none, counting the reason
+/// in `failures`. Rows whose URL couldn't be split are counted but kept.
+fn parse_counted(
+    line: &[u8],
+    timestamps: &parser::Timestamps,
+    failures: &mut BTreeMap<String, u64>,
+) -> std::result::Result<LogRow, String> {
+    let Ok(line) = std::str::from_utf8(line) else {
+        *failures.entry("not_utf8".to_string()).or_default() += 1;
+        return Err("line is not valid UTF-8".to_string());
+    };
+    match parser::parse_line(line.trim_end_matches(['\n', '\r']), timestamps) {
+        Ok(row) => Ok(row),
+        Err(e) => {
+            *failures.entry(e.kind().to_string()).or_default() += 1;
+            match e {
+                ParseError::BadUrl { row, .. } => Ok(*row),
+                e => Err(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Lines read between checkpoints. Each chunk's rows are committed together
+/// with the byte offset it ends at, so an interrupted import loses at most
+/// one chunk of work and a rerun never writes a row twice.
+const CHECKPOINT_LINES: u64 = 50_000;
+
+/// Longest first line hashed to tell a file from a different one later
+/// written to the same path.
+const FINGERPRINT_BYTES: u64 = 4096;
+
+/// How far an import has got, as stored in the imports table.
+#[derive(Debug, Default)]
+struct Progress {
+    bytes: u64,
+    lines: u64,
+    ok: u64,
+    bad: u64,
+    skipped: u64,
+    failures: BTreeMap<String, u64>,
+}
+
+/// SHA-256 of the file's first line, which stays the same while EZproxy
+/// appends to it.
+fn fingerprint(f: &mut File) -> Result<String> {
+    let mut head = Vec::new();
+    BufReader::new(f.take(FINGERPRINT_BYTES)).read_until(b'\n', &mut head)?;
+    Ok(format!("{:x}", Sha256::digest(&head)))
+}
+
+/// The latest run over this file that stopped part-way, with its sample
+/// rate.
+fn unfinished(conn: &Connection, log_path: &str, fingerprint: &str) -> Result<Option<(i64, Progress, f64)>> {
+    let found = conn
+        .query_row(
+            r#"
+            SELECT id, bytes_done, lines_done, ok, bad, skipped, sample_rate, parse_failures
+            FROM imports
+            WHERE path = ? AND fingerprint = ? AND finished_at IS NULL AND bytes_done > 0
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+            params![log_path, fingerprint],
+            |r| {
+                let progress = Progress {
+                    bytes: r.get::<_, i64>(1)? as u64,
+                    lines: r.get::<_, i64>(2)? as u64,
+                    ok: r.get::<_, i64>(3)? as u64,
+                    bad: r.get::<_, i64>(4)? as u64,
+                    skipped: r.get::<_, i64>(5)? as u64,
+                    failures: BTreeMap::new(),
+                };
+                Ok((r.get(0)?, progress, r.get(6)?, r.get::<_, Option<String>>(7)?))
+            },
+        )
+        .optional()?;
+    let Some((id, mut progress, sample_rate, failures)) = found else {
+        return Ok(None);
+    };
+    if let Some(failures) = failures {
+        progress.failures = serde_json::from_str(&failures).context("imports.parse_failures")?;
+    }
+    Ok(Some((id, progress, sample_rate)))
+}
+
+/// Write a chunk of rows and record the progress they bring, atomically.
+/// Returns the number of honeytoken hits among the rows.
+fn checkpoint(
+    conn: &mut Connection,
+    id: i64,
+    progress: &mut Progress,
+    rows: Vec<LogRow>,
+    opts: &ImportOptions,
+) -> Result<u64> {
+    let tx = conn.transaction()?;
+    seen::record(&tx, &rows)?;
+    let hits = honeytokens::record(&tx, &opts.honeytokens, &rows)?;
+    let (ok, bad) = db::insert_rows(&tx, rows, opts.raw)?;
+    progress.ok += ok;
+    progress.bad += bad;
+    tx.execute(
+        "UPDATE imports SET ok = ?, bad = ?, skipped = ?, parse_failures = ?, bytes_done = ?, lines_done = ? WHERE id = ?",
+        params![
+            progress.ok as i64,
+            progress.bad as i64,
+            progress.skipped as i64,
+            serde_json::to_string(&progress.failures)?,
+            progress.bytes as i64,
+            progress.lines as i64,
+            id
+        ],
+    )?;
+    tx.commit()?;
+    Ok(hits)
+}
+
+/// Read `rdr` to the end, parsing, sampling, and enriching each line and
+/// counting it in `progress`, and hand the rows to `flush` every
+/// `CHECKPOINT_LINES` lines and at the end. `size`, unknown for a pipe, is
+/// only for progress messages.
+fn read_chunks(
+    rdr: &mut impl BufRead,
+    size: Option<u64>,
+    opts: &ImportOptions,
+    pipeline: &Pipeline,
+    progress: &mut Progress,
+    mut flush: impl FnMut(Vec<LogRow>, &mut Progress) -> Result<()>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = Vec::new();
+    loop {
+        buf.clear();
+        let n = rdr.read_until(b'\n', &mut buf)?;
+        if n > 0 {
+            let i = progress.lines;
+            progress.lines += 1;
+            progress.bytes += n as u64;
+            let parsed = opts
+                .keeps(i)
+                .then(|| parse_counted(&buf, &opts.timestamps, &mut progress.failures).ok())
+                .flatten();
+            if let Some(mut row) = parsed {
+                if opts.exclude_noise && row.is_noise() {
+                    progress.skipped += 1;
+                } else {
+                    pipeline.run(&mut row);
+                    chunk.push(row);
+                }
+            }
+        }
+        if n == 0 || progress.lines.is_multiple_of(CHECKPOINT_LINES) {
+            flush(std::mem::take(&mut chunk), progress)?;
+            match size {
+                _ if n == 0 => {}
+                Some(size) => eprintln!(
+                    "  {} lines read ({:.0}%), {} rows written",
+                    progress.lines,
+                    progress.bytes as f64 / size.max(1) as f64 * 100.0,
+                    progress.ok
+                ),
+                None => eprintln!("  {} lines read, {} rows written", progress.lines, progress.ok),
+            }
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// The path that reads standard input instead of a file.
+pub const STDIN: &str = "-";
+
+/// `path`, or standard input for `-`, with its size when known.
+fn open_input(path: &Path) -> Result<(Box<dyn BufRead>, Option<u64>)> {
+    if path == Path::new(STDIN) {
+        return Ok((Box::new(io::stdin().lock()), None));
+    }
+    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let size = f.metadata()?.len();
+    Ok((Box::new(BufReader::new(f)), Some(size)))
+}
+
+/// Parse a log file (or standard input, for `-`) like `import_file`, but
+/// hand the rows to `sink` instead of the database. Nothing is recorded,
+/// so there is no resuming, and neither honeytokens nor the SIEM are
+/// checked.
+pub fn import_to_sink(path: &Path, opts: &ImportOptions, pipeline: &Pipeline, sink: &mut dyn Sink) -> Result<ImportSummary> {
+    let (mut rdr, size) = open_input(path)?;
+
+    pipeline.take_scrubbed();
+    let mut progress = Progress::default();
+    read_chunks(&mut rdr, size, opts, pipeline, &mut progress, |rows, progress| {
+        let (ok, bad) = sink.write(rows)?;
+        progress.ok += ok;
+        progress.bad += bad;
+        Ok(())
+    })?;
+    sink.finish()?;
+    Ok(ImportSummary {
+        ok: progress.ok,
+        bad: progress.bad,
+        skipped: progress.skipped,
+        failures: progress.failures,
+        scrubbed: pipeline.take_scrubbed(),
+        honeytoken_hits: 0,
+    })
+}
+
+/// Parse a log file, run each row through `pipeline`, and append every
+/// matching line to the requests table.
+/// `ok` and `bad` are the row counts reported by `db::insert_rows`. Each
+/// run is recorded in the imports table, with its sample rate so counts
+/// from a sampled import can be scaled back up.
+///
+/// Progress is checkpointed as the file is read; if an earlier import of
+/// the same file was interrupted, this one picks up where it stopped and
+/// the summary covers both. Once the file is in, new alerts and selected
+/// requests go to `opts.siem`, if set.
+///
+/// Only files: `-` is a file of that name here, not standard input, which
+/// the CLI imports with `import_reader`.
+pub fn import_file(
+    conn: &mut Connection,
+    path: &Path,
+    opts: &ImportOptions,
+    pipeline: &Pipeline,
+) -> Result<ImportSummary> {
+    let mut f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    // As recorded in the imports table; names that aren't valid UTF-8 (an
+    // old Windows share, say) keep a lossy but stable spelling.
+    let log_path = &*path.to_string_lossy();
+    let size = f.metadata()?.len();
+    let fingerprint = fingerprint(&mut f)?;
+
+    let (id, progress) = match unfinished(conn, log_path, &fingerprint)? {
+        Some((id, progress, sample_rate)) => {
+            if sample_rate != opts.sample_rate() {
+                bail!(
+                    "an interrupted import of {} used a sample rate of {}; rerun it with the same --sample to resume",
+                    log_path,
+                    sample_rate
+                );
+            }
+            if progress.bytes > size {
+                bail!("{} is shorter than when its import stopped at byte {}", log_path, progress.bytes);
+            }
+            eprintln!("Resuming import of {} at byte {} ({} rows already written)", log_path, progress.bytes, progress.ok);
+            (id, progress)
+        }
+        None => (start_import(conn, log_path, opts, Some(&fingerprint))?, Progress::default()),
+    };
+    f.seek(SeekFrom::Start(progress.bytes))?;
+    read_into(conn, id, &mut BufReader::new(f), Some(size), progress, opts, pipeline)
+}
+
+/// Import from `rdr`, such as standard input, like `import_file`. It can't
+/// be read twice, so the run is recorded under `name` but never resumed.
+pub fn import_reader(
+    conn: &mut Connection,
+    name: &str,
+    rdr: &mut impl BufRead,
+    opts: &ImportOptions,
+    pipeline: &Pipeline,
+) -> Result<ImportSummary> {
+    let id = start_import(conn, name, opts, None)?;
+    read_into(conn, id, rdr, None, Progress::default(), opts, pipeline)
+}
+
+/// Record a new run in the imports table, returning its ID. Without a
+/// `fingerprint` it can never be resumed.
+fn start_import(conn: &Connection, log_path: &str, opts: &ImportOptions, fingerprint: Option<&str>) -> Result<i64> {
+    Ok(conn.query_row(
+        "INSERT INTO imports (path, sample_rate, fingerprint) VALUES (?, ?, ?) RETURNING id",
+        params![log_path, opts.sample_rate(), fingerprint],
+        |r| r.get(0),
+    )?)
+}
+
+/// The rest of `import_file`, from wherever `rdr` starts, for import `id`.
+fn read_into(
+    conn: &mut Connection,
+    id: i64,
+    rdr: &mut impl BufRead,
+    size: Option<u64>,
+    mut progress: Progress,
+    opts: &ImportOptions,
+    pipeline: &Pipeline,
+) -> Result<ImportSummary> {
+    // Counts left over from a file that failed part way belong to no one.
+    pipeline.take_scrubbed();
+    let mut honeytoken_hits = 0;
+    read_chunks(rdr, size, opts, pipeline, &mut progress, |rows, progress| {
+        honeytoken_hits += checkpoint(conn, id, progress, rows, opts)?;
+        Ok(())
+    })?;
+
+    conn.execute("UPDATE imports SET finished_at = now() WHERE id = ?", params![id])?;
+    siem::forward_pending(conn, opts.siem.as_ref());
+    Ok(ImportSummary {
+        ok: progress.ok,
+        bad: progress.bad,
+        skipped: progress.skipped,
+        failures: progress.failures,
+        scrubbed: pipeline.take_scrubbed(),
+        honeytoken_hits,
+    })
+}
+
+/// Failing lines kept as examples in a dry run.
+const DRY_RUN_EXAMPLES: usize = 5;
+
+#[derive(Debug, Serialize)]
+pub struct DryRunReport {
+    /// Lines read; only those in the sample, when sampling
+    pub lines: u64,
+    pub parsed: u64,
+    /// Lines that didn't parse or weren't valid UTF-8
+    pub failed: u64,
+    /// Failures by reason, as in `ImportSummary::failures`
+    pub failures: BTreeMap<String, u64>,
+    /// Parsed rows `exclude_noise` would drop
+    pub skipped: u64,
+    /// Rows a real import would write
+    pub projected_rows: u64,
+    pub first_ts: Option<DateTime<FixedOffset>>,
+    pub last_ts: Option<DateTime<FixedOffset>>,
+    pub distinct_hosts: u64,
+    /// Values the `scrub` stage would blank out, by parameter name
+    pub scrubbed: BTreeMap<String, u64>,
+    /// Line number and error of the first few failures
+    pub examples: Vec<(u64, String)>,
+}
+
+impl DryRunReport {
+    pub fn success_pct(&self) -> f64 {
+        if self.lines == 0 {
+            return 0.0;
+        }
+        (self.parsed as f64 / self.lines as f64 * 1000.0).round() / 10.0
+    }
+}
+
+/// Everything `import_file` would do short of touching the database, to
+/// check how a new log source parses before importing it.
+pub fn dry_run(path: &Path, opts: &ImportOptions, pipeline: &Pipeline) -> Result<DryRunReport> {
+    let (mut rdr, _) = open_input(path)?;
+    let mut report = DryRunReport {
+        lines: 0,
+        parsed: 0,
+        failed: 0,
+        failures: BTreeMap::new(),
+        skipped: 0,
+        projected_rows: 0,
+        first_ts: None,
+        last_ts: None,
+        distinct_hosts: 0,
+        scrubbed: BTreeMap::new(),
+        examples: Vec::new(),
+    };
+    pipeline.take_scrubbed();
+    let mut hosts = HashSet::new();
+
+    let mut buf = Vec::new();
+    for i in 0.. {
+        buf.clear();
+        if rdr.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        if !opts.keeps(i) {
+            continue;
+        }
+        report.lines += 1;
+        let mut row = match parse_counted(&buf, &opts.timestamps, &mut report.failures) {
+            Ok(row) => row,
+            Err(e) => {
+                report.failed += 1;
+                if report.examples.len() < DRY_RUN_EXAMPLES {
+                    report.examples.push((i + 1, e));
+                }
+                continue;
+            }
+        };
+        report.parsed += 1;
+        if opts.exclude_noise && row.is_noise() {
+            report.skipped += 1;
+            continue;
+        }
+        pipeline.run(&mut row);
+        report.projected_rows += 1;
+        // Logs are mostly but not strictly in order.
+        report.first_ts = Some(report.first_ts.map_or(row.ts, |t| t.min(row.ts)));
+        report.last_ts = Some(report.last_ts.map_or(row.ts, |t| t.max(row.ts)));
+        if let Some(host) = row.host {
+            hosts.insert(host);
+        }
+    }
+    report.distinct_hosts = hosts.len() as u64;
+    report.scrubbed = pipeline.take_scrubbed();
+    Ok(report)
+}
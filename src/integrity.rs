@@ -0,0 +1,189 @@
+use anyhow::{Context, Result, anyhow, bail};
+use duckdb::{Connection, OptionalExt, params};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of a row's original log line, as hex.
+pub fn raw_hash(raw: &str) -> String {
+    format!("{:x}", Sha256::digest(raw.as_bytes()))
+}
+
+/// Running hash over an export's rows. Each row's `chain_sha256` is the
+/// SHA-256 of the previous row's chain value and every other field of the
+/// row, so editing, removing, or reordering any row changes every chain
+/// value after it, including the one in the manifest.
+#[derive(Debug, Default)]
+pub struct Chain {
+    pub head: String,
+    pub rows: u64,
+}
+
+impl Chain {
+    /// Add a row, given as its fields before CSV quoting with NULL as an
+    /// empty string; returns its `chain_sha256`.
+    pub fn push(&mut self, fields: &[String]) -> String {
+        let mut h = Sha256::new();
+        h.update(self.head.as_bytes());
+        for f in fields {
+            // Unit separator: can't be confused with field content the way
+            // a comma could.
+            h.update(b"\x1f");
+            h.update(f.as_bytes());
+        }
+        self.head = format!("{:x}", h.finalize());
+        self.rows += 1;
+        self.head.clone()
+    }
+}
+
+/// What was exported and the chain value it ended on, signed with
+/// `export.signing_key`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: i64,
+    pub created_at: String,
+    /// Query parameters the export was made with
+    pub filters: serde_json::Value,
+    pub rows: u64,
+    pub chain_sha256: String,
+    /// HMAC-SHA256 over the fields above, as hex
+    pub signature: String,
+}
+
+impl Manifest {
+    fn signed_message(&self) -> String {
+        format!(
+            "ezvis-export-v1\n{}\n{}\n{}\n{}\n{}",
+            self.id, self.created_at, self.filters, self.rows, self.chain_sha256
+        )
+    }
+
+    fn mac(&self, key: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(self.signed_message().as_bytes());
+        mac
+    }
+
+    pub fn verify_signature(&self, key: &str) -> Result<()> {
+        let sig = (0..self.signature.len())
+            .step_by(2)
+            .map(|i| self.signature.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| anyhow!("signature is not hex"))?;
+        self.mac(key)
+            .verify_slice(&sig)
+            .map_err(|_| anyhow!("signature does not match: the manifest was altered or signed with another key"))
+    }
+}
+
+/// Record that an export started and return its id. The manifest stays
+/// incomplete until `finish`, e.g. when the client disconnects.
+pub fn begin(conn: &Connection, filters: &serde_json::Value) -> Result<i64> {
+    Ok(conn.query_row(
+        "INSERT INTO export_manifests (filters) VALUES (?) RETURNING id",
+        params![filters.to_string()],
+        |r| r.get(0),
+    )?)
+}
+
+/// Sign and store the manifest once every row has been sent.
+pub fn finish(conn: &Connection, id: i64, chain: &Chain, key: &str) -> Result<()> {
+    let (created_at, filters): (String, String) = conn.query_row(
+        "SELECT CAST(created_at AS VARCHAR), filters FROM export_manifests WHERE id = ?",
+        params![id],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    let mut manifest = Manifest {
+        id,
+        created_at,
+        filters: serde_json::from_str(&filters)?,
+        rows: chain.rows,
+        chain_sha256: chain.head.clone(),
+        signature: String::new(),
+    };
+    manifest.signature = format!("{:x}", manifest.mac(key).finalize().into_bytes());
+    conn.execute(
+        r#"
+        UPDATE export_manifests
+        SET row_count = ?, chain_sha256 = ?, signature = ?, completed_at = now()
+        WHERE id = ?
+        "#,
+        params![manifest.rows as i64, manifest.chain_sha256, manifest.signature, id],
+    )?;
+    Ok(())
+}
+
+/// The manifest for export `id`, if there is one: an export the client
+/// abandoned partway through never gets one.
+pub fn manifest(conn: &Connection, id: i64) -> Result<Option<Manifest>> {
+    let row = conn
+        .query_row(
+            r#"
+            SELECT CAST(created_at AS VARCHAR), filters, row_count, chain_sha256, signature
+            FROM export_manifests WHERE id = ? AND completed_at IS NOT NULL
+            "#,
+            params![id],
+            |r| {
+                let filters: String = r.get(1)?;
+                let rows: i64 = r.get(2)?;
+                Ok((filters, Manifest {
+                    id,
+                    created_at: r.get(0)?,
+                    filters: serde_json::Value::Null,
+                    rows: rows as u64,
+                    chain_sha256: r.get(3)?,
+                    signature: r.get(4)?,
+                }))
+            },
+        )
+        .optional()?;
+    let Some((filters, mut manifest)) = row else {
+        return Ok(None);
+    };
+    manifest.filters = serde_json::from_str(&filters)?;
+    Ok(Some(manifest))
+}
+
+/// Check an integrity export against its manifest: the signature, every
+/// row's `raw_sha256` and `chain_sha256`, and the row count.
+pub fn verify_file(path: &str, manifest: &Manifest, key: &str) -> Result<()> {
+    manifest.verify_signature(key)?;
+
+    let conn = Connection::open_in_memory()?;
+    let mut stmt = conn.prepare(
+        "SELECT * FROM read_csv(?, header = true, all_varchar = true, delim = ',', quote = '\"', escape = '\"')",
+    )?;
+    let mut rows = stmt.query(params![path]).with_context(|| format!("read {}", path))?;
+    let columns = rows.as_ref().map(|s| s.column_names()).unwrap_or_default();
+    let find = |name: &str| {
+        columns.iter().position(|c| c == name).ok_or_else(|| anyhow!("no {} column; not an integrity export", name))
+    };
+    let (raw_at, raw_hash_at, chain_at) = (find("raw")?, find("raw_sha256")?, find("chain_sha256")?);
+    if chain_at != columns.len() - 1 {
+        bail!("chain_sha256 must be the last column");
+    }
+
+    let mut chain = Chain::default();
+    while let Some(r) = rows.next()? {
+        let mut fields = Vec::with_capacity(columns.len());
+        for i in 0..columns.len() {
+            fields.push(r.get::<_, Option<String>>(i)?.unwrap_or_default());
+        }
+        let row = chain.rows + 1;
+        if fields[raw_hash_at] != raw_hash(&fields[raw_at]) {
+            bail!("row {}: raw_sha256 does not match the raw column", row);
+        }
+        let claimed = fields.pop().unwrap_or_default();
+        if chain.push(&fields) != claimed {
+            bail!("row {}: chain_sha256 does not match; this row or one before it was changed, removed, or reordered", row);
+        }
+    }
+    if chain.rows != manifest.rows {
+        bail!("file has {} rows, manifest says {}", chain.rows, manifest.rows);
+    }
+    if chain.head != manifest.chain_sha256 {
+        bail!("final chain_sha256 differs from the manifest");
+    }
+    Ok(())
+}
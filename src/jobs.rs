@@ -0,0 +1,322 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Local;
+use croner::Cron;
+use duckdb::{Connection, OptionalExt, params};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{backup, baseline, config::{self, Config}, db, duration, enrich, export, federation, import, parser, policy, siem};
+
+/// A unit of background work. Stored as JSON in `jobs.spec`, so variants
+/// must stay backwards compatible with rows already in the table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobSpec {
+    /// Import a log file readable by the server process
+    Import {
+        path: String,
+        #[serde(default)]
+        exclude_noise: bool,
+    },
+    /// Recompute detection baselines over a window such as `90d`
+    BaselineBuild { window: String },
+    /// Write monthly usage CSVs; defaults to the previous month
+    UsageExport { month: Option<String> },
+    /// Refresh consortium aggregates from every `[federation]` member
+    FederationPull,
+    /// Delete requests older than a duration such as `400d`
+    Prune { older_than: String },
+    /// Copy the database to a path or `s3://` URL, as `ezvis backup` does
+    Backup { out: String },
+    /// Check `[[policies]]` and record violations; the window defaults to `7d`
+    PolicyCheck { window: Option<String> },
+    /// Send new alerts and selected requests to the `[siem]`
+    SiemForward,
+}
+
+impl JobSpec {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JobSpec::Import { .. } => "import",
+            JobSpec::BaselineBuild { .. } => "baseline_build",
+            JobSpec::UsageExport { .. } => "usage_export",
+            JobSpec::FederationPull => "federation_pull",
+            JobSpec::Prune { .. } => "prune",
+            JobSpec::Backup { .. } => "backup",
+            JobSpec::PolicyCheck { .. } => "policy_check",
+            JobSpec::SiemForward => "siem_forward",
+        }
+    }
+
+    fn run(&self, conn: &mut Connection, config: &Config) -> Result<serde_json::Value> {
+        match self {
+            JobSpec::Import { path, exclude_noise } => {
+                let opts = import::ImportOptions {
+                    exclude_noise: *exclude_noise,
+                    timestamps: parser::Timestamps::from_config(&config.parser)?,
+                    honeytokens: config.honeytokens.clone(),
+                    siem: config.siem.clone(),
+                    ..Default::default()
+                };
+                let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+                let summary = import::import_file(conn, std::path::Path::new(path), &opts, &pipeline)?;
+                Ok(serde_json::to_value(summary)?)
+            }
+            JobSpec::BaselineBuild { window } => {
+                let summary = baseline::build(conn, duration::parse_duration(window)?, &config.ports)?;
+                Ok(serde_json::to_value(summary)?)
+            }
+            JobSpec::UsageExport { month } => {
+                let month = month.clone().unwrap_or_else(export::previous_month);
+                let cfg = config.export.clone().unwrap_or_default();
+                let summary = export::export_month(conn, &month, &cfg, &config.ports)?;
+                Ok(serde_json::to_value(summary)?)
+            }
+            JobSpec::FederationPull => {
+                let cfg = config
+                    .federation
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("no [federation] section in config"))?;
+                let summary = federation::pull(conn, cfg)?;
+                Ok(serde_json::to_value(summary)?)
+            }
+            JobSpec::Prune { older_than } => {
+                let cutoff = chrono::Utc::now() - duration::parse_duration(older_than)?;
+                let deleted = db::prune(conn, &cutoff.to_rfc3339())?;
+                Ok(json!({ "cutoff": cutoff.to_rfc3339(), "deleted": deleted }))
+            }
+            JobSpec::Backup { out } => {
+                let summary = backup::backup(conn, out)?;
+                Ok(serde_json::to_value(summary)?)
+            }
+            JobSpec::PolicyCheck { window } => {
+                let window = duration::parse_duration(window.as_deref().unwrap_or("7d"))?;
+                let summary = policy::check(conn, &config.policies, window)?;
+                siem::forward_pending(conn, config.siem.as_ref());
+                Ok(serde_json::to_value(summary)?)
+            }
+            JobSpec::SiemForward => {
+                let cfg = config.siem.as_ref().ok_or_else(|| anyhow::anyhow!("no [siem] section in config"))?;
+                let summary = siem::forward(conn, cfg)?;
+                Ok(serde_json::to_value(summary)?)
+            }
+        }
+    }
+}
+
+pub fn enqueue(conn: &Connection, spec: &JobSpec) -> Result<i64> {
+    let id = conn.query_row(
+        "INSERT INTO jobs (kind, spec, status) VALUES (?, ?, 'queued') RETURNING id",
+        params![spec.kind(), serde_json::to_string(spec)?],
+        |r| r.get(0),
+    )?;
+    Ok(id)
+}
+
+fn job_json(r: &duckdb::Row<'_>) -> duckdb::Result<serde_json::Value> {
+    let id: i64 = r.get(0)?;
+    let kind: String = r.get(1)?;
+    let spec: String = r.get(2)?;
+    let status: String = r.get(3)?;
+    let created_at: String = r.get(4)?;
+    let started_at: Option<String> = r.get(5)?;
+    let finished_at: Option<String> = r.get(6)?;
+    let result: Option<String> = r.get(7)?;
+    let error: Option<String> = r.get(8)?;
+    Ok(json!({
+        "id": id,
+        "kind": kind,
+        "spec": serde_json::from_str::<serde_json::Value>(&spec).unwrap_or_default(),
+        "status": status,
+        "created_at": created_at,
+        "started_at": started_at,
+        "finished_at": finished_at,
+        "result": result.and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok()),
+        "error": error,
+    }))
+}
+
+const JOB_COLUMNS: &str = r#"
+    id, kind, spec, status,
+    CAST(created_at AS VARCHAR),
+    CAST(started_at AS VARCHAR),
+    CAST(finished_at AS VARCHAR),
+    result, error
+"#;
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<serde_json::Value>> {
+    let sql = format!("SELECT {JOB_COLUMNS} FROM jobs WHERE id = ?");
+    Ok(conn.query_row(&sql, params![id], job_json).optional()?)
+}
+
+pub fn list(conn: &Connection, limit: i64) -> Result<Vec<serde_json::Value>> {
+    let sql = format!("SELECT {JOB_COLUMNS} FROM jobs ORDER BY id DESC LIMIT ?");
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params![limit])?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        out.push(job_json(r)?);
+    }
+    Ok(out)
+}
+
+/// Claim the oldest queued job, marking it running.
+fn claim_next(conn: &Connection) -> Result<Option<(i64, String)>> {
+    let next = conn
+        .query_row(
+            r#"
+            UPDATE jobs SET status = 'running', started_at = now()
+            WHERE id = (SELECT min(id) FROM jobs WHERE status = 'queued')
+            RETURNING id, spec
+            "#,
+            params![],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    Ok(next)
+}
+
+fn finish(conn: &Connection, id: i64, outcome: Result<serde_json::Value>) -> Result<()> {
+    match outcome {
+        Ok(result) => conn.execute(
+            "UPDATE jobs SET status = 'done', finished_at = now(), result = ? WHERE id = ?",
+            params![result.to_string(), id],
+        )?,
+        Err(e) => conn.execute(
+            "UPDATE jobs SET status = 'failed', finished_at = now(), error = ? WHERE id = ?",
+            params![format!("{:#}", e), id],
+        )?,
+    };
+    Ok(())
+}
+
+/// Run at most one queued job. Returns false when the queue was empty.
+fn run_next(db_path: &str, config: &Config) -> Result<bool> {
+    let mut conn = db::open_db(db_path)?;
+    let Some((id, spec)) = claim_next(&conn)? else {
+        return Ok(false);
+    };
+
+    println!("job {} started", id);
+    let outcome = serde_json::from_str::<JobSpec>(&spec)
+        .map_err(anyhow::Error::from)
+        .and_then(|spec| spec.run(&mut conn, config));
+    if let Err(e) = &outcome {
+        eprintln!("job {} failed: {:#}", id, e);
+    }
+    finish(&conn, id, outcome)?;
+    println!("job {} finished", id);
+    Ok(true)
+}
+
+/// Background worker: processes queued jobs one at a time, polling when idle.
+/// Jobs left `running` by a previous process are marked failed on startup.
+pub async fn run_worker(db_path: String, config: config::Shared) {
+    let path = db_path.clone();
+    let reset = tokio::task::spawn_blocking(move || {
+        let conn = db::open_db(&path)?;
+        Ok::<_, anyhow::Error>(conn.execute(
+            "UPDATE jobs SET status = 'failed', finished_at = now(), error = 'interrupted' WHERE status = 'running'",
+            params![],
+        )?)
+    })
+    .await;
+    match reset {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => eprintln!("job worker: could not reset interrupted jobs: {:#}", e),
+        Err(e) => eprintln!("job worker: resetting interrupted jobs panicked: {}", e),
+    }
+
+    loop {
+        let path = db_path.clone();
+        // Read afresh for each job, so a reloaded config applies to the next.
+        let config = config.read().expect("config lock poisoned").clone();
+        let ran = tokio::task::spawn_blocking(move || run_next(&path, &config)).await;
+        match ran {
+            Ok(Ok(true)) => continue,
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => eprintln!("job worker: {:#}", e),
+            Err(e) => eprintln!("job worker panicked: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+/// Start queueing every job `config` schedules: `[export] schedule`,
+/// `[federation] schedule`, and `[[schedules]]`.
+pub fn spawn_schedules(db_path: &str, config: &Config) {
+    if let Some(schedule) = config.export.as_ref().and_then(|e| e.schedule.clone()) {
+        tokio::spawn(run_schedule(db_path.to_string(), schedule, JobSpec::UsageExport { month: None }));
+    }
+    if let Some(schedule) = config.federation.as_ref().and_then(|f| f.schedule.clone()) {
+        tokio::spawn(run_schedule(db_path.to_string(), schedule, JobSpec::FederationPull));
+    }
+    for s in &config.schedules {
+        tokio::spawn(run_schedule(db_path.to_string(), s.schedule.clone(), s.job.clone()));
+    }
+}
+
+/// Run the job worker and the schedules without the dashboard, for hosts
+/// that have no cron of their own. Runs until the process is stopped.
+pub async fn run_scheduler(db_path: String, config: Config) -> Result<()> {
+    db::init_schema(&db::open_db(&db_path)?)?;
+    spawn_schedules(&db_path, &config);
+    let shared: config::Shared = Arc::new(RwLock::new(Arc::new(config)));
+    run_worker(db_path, shared).await;
+    Ok(())
+}
+
+/// Whether a job with exactly `spec` is queued or running.
+fn pending(conn: &Connection, spec: &JobSpec) -> Result<bool> {
+    let n: i64 = conn.query_row(
+        "SELECT count(*) FROM jobs WHERE spec = ? AND status IN ('queued', 'running')",
+        params![serde_json::to_string(spec)?],
+        |r| r.get(0),
+    )?;
+    Ok(n > 0)
+}
+
+/// Enqueue `spec` each time the cron expression `schedule` fires, unless the
+/// last one hasn't finished yet. Runs inside `serve` or `ezvis cron`; the
+/// work itself happens on the job worker.
+pub async fn run_schedule(db_path: String, schedule: String, spec: JobSpec) {
+    let kind = spec.kind();
+    let cron = match Cron::new(&schedule).parse() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} schedule {:?} is invalid: {}", kind, schedule, e);
+            return;
+        }
+    };
+
+    loop {
+        let now = Local::now();
+        let next = match cron.find_next_occurrence(&now, false) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("{} schedule: {}", kind, e);
+                return;
+            }
+        };
+        let wait = (next - now).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let (path, job) = (db_path.clone(), spec.clone());
+        let queued = tokio::task::spawn_blocking(move || {
+            let conn = db::open_db(&path)?;
+            if pending(&conn, &job)? { Ok(None) } else { enqueue(&conn, &job).map(Some) }
+        })
+        .await;
+        match queued {
+            Ok(Ok(Some(id))) => println!("scheduled {} queued as job {}", kind, id),
+            Ok(Ok(None)) => println!("scheduled {} skipped: the last one hasn't finished", kind),
+            Ok(Err(e)) => eprintln!("{} schedule: could not queue job: {:#}", kind, e),
+            Err(e) => eprintln!("{} schedule panicked: {}", kind, e),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Everything behind the command line, as a library so that tests,
+//! benchmarks, and fuzz targets can call the parser and friends directly.
+
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod baseline;
+pub mod calendar;
+pub mod capacity;
+pub mod charts;
+pub mod check;
+pub mod clients;
+pub mod config;
+pub mod costs;
+pub mod db;
+pub mod destinations;
+pub mod devices;
+pub mod downloads;
+pub mod duration;
+pub mod elastic;
+pub mod enrich;
+pub mod export;
+pub mod federation;
+pub mod forecast;
+pub mod grafana;
+pub mod honeytokens;
+pub mod import;
+pub mod integrity;
+pub mod jobs;
+pub mod licenses;
+pub mod logins;
+pub mod maintain;
+pub mod parser;
+pub mod perf;
+pub mod policy;
+pub mod privacy;
+pub mod pseudonyms;
+pub mod public;
+pub mod report;
+pub mod schema;
+pub mod seen;
+pub mod service;
+pub mod sessions;
+pub mod siem;
+pub mod sink;
+pub mod summary;
+pub mod tokens;
+pub mod top;
+pub mod transfer;
+pub mod trends;
+pub mod turnaways;
+pub mod ui;
+pub mod users;
+pub mod watch;
+pub mod web;
+#[cfg(windows)]
+pub mod winsvc;
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, db, sessions};
+
+/// Exceedances listed per platform; the totals still count all of them.
+const MAX_LISTED: usize = 50;
+
+fn fmt_us(us: i64) -> String {
+    DateTime::<Utc>::from_timestamp_micros(us).map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default()
+}
+
+fn day_of(us: i64) -> i64 {
+    us.div_euclid(86_400_000_000)
+}
+
+/// Concurrent users on each licensed platform among the requests matching
+/// `filter`, against the simultaneous-user limits in `seats` (host to
+/// limit). A user holds a seat from their first request on a platform to
+/// their last, with gaps of up to `sessions::SESSION_GAP_SECS` in between; vendors
+/// usually hold it a little longer, so the counts err low, which keeps them
+/// fair as evidence. Intervals with more users than seats are listed as
+/// exceedances, and `daily` gives each day's peak.
+pub fn pressure(
+    conn: &Connection,
+    seats: &BTreeMap<String, u32>,
+    filter: &str,
+    args: &[String],
+    ports: &PortsConfig,
+) -> Result<serde_json::Value> {
+    let seats: BTreeMap<String, u32> = seats.iter().map(|(h, n)| (h.to_lowercase(), *n)).collect();
+    if seats.is_empty() {
+        return Ok(json!({ "platforms": [] }));
+    }
+    let hosts = vec!["?"; seats.len()].join(", ");
+    let cte = sessions::platform_cte(&format!("{filter} AND lower({}) IN ({hosts})", db::target_host(ports)), ports);
+    let sql = format!("WITH {cte} SELECT host, first_us, last_us FROM platform_sessions");
+    let mut stmt = conn.prepare(&sql)?;
+    let bind: Vec<String> = args.iter().cloned().chain(seats.keys().cloned()).collect();
+    let mut rows = stmt.query(params_from_iter(&bind))?;
+    // Per host, +1 when a session starts and -1 when it ends.
+    let mut events: BTreeMap<String, Vec<(i64, i32)>> = BTreeMap::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let start: i64 = r.get(1)?;
+        let end: i64 = r.get(2)?;
+        let e = events.entry(host).or_default();
+        e.push((start, 1));
+        e.push((end, -1));
+    }
+
+    let mut platforms = Vec::new();
+    for (host, limit) in &seats {
+        let mut events = events.remove(host).unwrap_or_default();
+        // Starts before ends at the same instant, so a one-request session counts.
+        events.sort_by_key(|&(t, delta)| (t, -delta));
+        let sessions = events.len() / 2;
+        let (mut current, mut peak, mut peak_at) = (0i64, 0i64, None);
+        let mut daily: BTreeMap<i64, i64> = BTreeMap::new();
+        let mut exceedances = Vec::new();
+        let mut over: Option<(i64, i64)> = None;
+        let mut over_us = 0i64;
+        let mut prev_t: Option<i64> = None;
+        for (t, delta) in events {
+            // Days passed through since the last event ran at the same count.
+            if let Some(p) = prev_t {
+                for d in day_of(p) + 1..=day_of(t) {
+                    let slot = daily.entry(d).or_default();
+                    *slot = (*slot).max(current);
+                }
+            }
+            prev_t = Some(t);
+            current += delta as i64;
+            let slot = daily.entry(day_of(t)).or_default();
+            *slot = (*slot).max(current);
+            if current > peak {
+                peak = current;
+                peak_at = Some(t);
+            }
+            match over.as_mut() {
+                None if current > *limit as i64 => over = Some((t, current)),
+                Some((_, max)) if current > *limit as i64 => *max = (*max).max(current),
+                Some(&mut (start, max)) => {
+                    over_us += t - start;
+                    exceedances.push(json!({
+                        "start": fmt_us(start),
+                        "end": fmt_us(t),
+                        "peak": max,
+                        "seconds": (t - start) / 1_000_000,
+                    }));
+                    over = None;
+                }
+                None => {}
+            }
+        }
+        let count = exceedances.len();
+        exceedances.sort_by(|a, b| b["peak"].as_i64().cmp(&a["peak"].as_i64()).then(a["start"].as_str().cmp(&b["start"].as_str())));
+        exceedances.truncate(MAX_LISTED);
+        platforms.push(json!({
+            "host": host,
+            "seats": limit,
+            "sessions": sessions,
+            "peak": peak,
+            "peak_at": peak_at.map(fmt_us),
+            "exceedances": count,
+            "seconds_over": over_us / 1_000_000,
+            "worst": exceedances,
+            "daily": daily
+                .into_iter()
+                .map(|(d, n)| json!({"day": DateTime::<Utc>::from_timestamp(d * 86_400, 0).map(|t| t.date_naive().to_string()), "peak": n}))
+                .collect::<Vec<_>>(),
+        }));
+    }
+    Ok(json!({ "platforms": platforms }))
+}
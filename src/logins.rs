@@ -0,0 +1,116 @@
+use anyhow::Result;
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+/// POSTs to EZproxy's login form that were refused. A successful login
+/// answers with a redirect to the resource, so only 4xx and 5xx count.
+/// Rows the `request_kind` stage tagged as something other than `proxy` are
+/// a vendor's own `/login`, not the proxy's.
+pub const LOGIN_FAILURE_CONDITION: &str = "method = 'POST' AND status >= 400 \
+     AND COALESCE(request_kind = 'proxy', TRUE) \
+     AND regexp_matches(lower(COALESCE(path, '')), '^/login(/|$)')";
+
+/// Failures from one IP and user agent within an hour needed to list it as
+/// a burst. A patron mistyping a password rarely gets past a few.
+pub const BURST_MIN_FAILURES: i64 = 10;
+
+/// IP and user agent pairs listed; the totals still count all of them.
+const MAX_LISTED: usize = 50;
+
+/// Failed logins among the requests matching `filter`: totals, failures per
+/// IP and user agent, hourly bursts that look like password guessing, and
+/// an hourly trend. Works from the access log alone, so it also covers
+/// proxies without an audit log.
+pub fn analyze(conn: &Connection, filter: &str, args: &[String]) -> Result<serde_json::Value> {
+    let base = format!(
+        r#"
+        WITH f AS (
+          SELECT remote_addr AS ip, COALESCE(user_agent, '') AS user_agent,
+                 CAST(ts AS TIMESTAMP) AS ts, user_or_session
+          FROM requests
+          WHERE {LOGIN_FAILURE_CONDITION} AND {filter}
+        )
+        "#
+    );
+
+    let (failures, ips): (i64, i64) = conn.query_row(
+        &format!("{base} SELECT count(*), count(DISTINCT ip) FROM f"),
+        params_from_iter(args),
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT ip, user_agent, count(*) AS failures,
+               count(DISTINCT user_or_session) AS users,
+               CAST(min(ts) AS VARCHAR) AS first_seen,
+               CAST(max(ts) AS VARCHAR) AS last_seen
+        FROM f GROUP BY 1, 2
+        ORDER BY failures DESC, ip, user_agent
+        LIMIT {MAX_LISTED}
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut by_source = Vec::new();
+    while let Some(r) = rows.next()? {
+        let ip: Option<String> = r.get(0)?;
+        let user_agent: String = r.get(1)?;
+        let failures: i64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        let first_seen: String = r.get(4)?;
+        let last_seen: String = r.get(5)?;
+        by_source.push(json!({
+            "ip": ip,
+            "user_agent": user_agent,
+            "failures": failures,
+            "users": users,
+            "first_seen": first_seen,
+            "last_seen": last_seen,
+        }));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT ip, user_agent, CAST(date_trunc('hour', ts) AS VARCHAR) AS hour, count(*) AS failures
+        FROM f GROUP BY 1, 2, 3
+        HAVING count(*) >= {BURST_MIN_FAILURES}
+        ORDER BY failures DESC, hour DESC
+        LIMIT {MAX_LISTED}
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut bursts = Vec::new();
+    while let Some(r) = rows.next()? {
+        let ip: Option<String> = r.get(0)?;
+        let user_agent: String = r.get(1)?;
+        let hour: String = r.get(2)?;
+        let failures: i64 = r.get(3)?;
+        bursts.push(json!({"ip": ip, "user_agent": user_agent, "hour": hour, "failures": failures}));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT CAST(date_trunc('hour', ts) AS VARCHAR) AS hour, count(*) AS failures, count(DISTINCT ip) AS ips
+        FROM f GROUP BY 1 ORDER BY 1
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut trend = Vec::new();
+    while let Some(r) = rows.next()? {
+        let hour: String = r.get(0)?;
+        let failures: i64 = r.get(1)?;
+        let ips: i64 = r.get(2)?;
+        trend.push(json!({"hour": hour, "failures": failures, "ips": ips}));
+    }
+
+    Ok(json!({
+        "failures": failures,
+        "ips": ips,
+        "by_source": by_source,
+        "bursts": bursts,
+        "trend": trend,
+    }))
+}
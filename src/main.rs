@@ -1,33 +1,336 @@
 // src/main.rs
-mod db;
-mod parser;
-mod web;
-
-use std::{fs::File, io::{BufRead, BufReader}, net::SocketAddr};
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode};
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use serde::Serialize;
+use serde_json::json;
+use pulezviz::{
+    auth, backup, baseline, check, config, costs, db, duration, elastic, enrich, export, honeytokens, import, integrity, jobs, maintain, parser, policy, report, service, siem, sink, summary,
+    tokens, top, transfer, users, watch, web,
+};
 
 #[derive(Parser)]
 #[command(name = "ezvis")]
 #[command(about = "Ezproxy log -> DuckDB -> dashboard", long_about = None)]
 struct Cli {
+    /// Config file [default: ezvis.toml if present]
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    /// Run under the Windows service control manager, as `install-service
+    /// --platform windows` sets up
+    #[arg(long, global = true, hide = true)]
+    service: bool,
+
+    /// How to print the outcome: text, or one line of JSON
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+
+    /// Short for --output json
+    #[arg(long, global = true)]
+    json: bool,
+
     #[command(subcommand)]
     cmd: Command,
 }
 
+/// Text is plain, without color, so it reads the same piped into a file, a
+/// diff, or a cron mail; `top` draws its own screen.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Command {
-    /// Import a log file into DuckDB
+    /// Import a log file into DuckDB, or convert it with --sink
     Import {
-        /// Path to log file
-        log_path: String,
+        /// Path to log file, or - to read standard input
+        log_path: PathBuf,
+
+        /// Skip CORS preflights, HEADs, other non-GET/POST methods, and
+        /// 0-byte responses
+        #[arg(long)]
+        exclude_noise: bool,
+
+        /// Import only a share of lines, as a percentage (1%) or a ratio
+        /// (1/100), to explore a large file quickly
+        #[arg(long)]
+        sample: Option<String>,
+
+        /// Read timestamps without a UTC offset in this zone (e.g.
+        /// America/Chicago or +01:00) and repair two-digit years, instead of
+        /// rejecting those lines [default: parser.assume_tz from config]
+        #[arg(long)]
+        assume_tz: Option<String>,
+
+        /// Don't keep each row's original log line, roughly halving the
+        /// database; integrity exports then carry an empty raw column
+        #[arg(long, conflicts_with = "raw_compressed")]
+        no_raw: bool,
+
+        /// Keep original log lines zstd-compressed instead of as text
+        #[arg(long)]
+        raw_compressed: bool,
+
+        /// Parse the file and report on it without writing to the database
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Write parsed rows here instead of the database: jsonl or csv (to
+        /// stdout without --out), parquet, elastic, or kafka
+        #[arg(long, value_enum, conflicts_with = "dry_run")]
+        sink: Option<sink::SinkKind>,
+
+        /// File for the jsonl, csv, and parquet sinks
+        #[arg(long, requires = "sink")]
+        out: Option<PathBuf>,
+
+        /// Cluster URL for the elastic sink, or Kafka REST proxy URL for
+        /// the kafka sink; the elastic API key is read from
+        /// EZVIS_ELASTIC_API_KEY
+        #[arg(long, requires = "sink")]
+        url: Option<String>,
+
+        /// Index for the elastic sink, created with mappings if missing
+        #[arg(long, default_value = "ezproxy")]
+        index: String,
+
+        /// Topic for the kafka sink
+        #[arg(long, default_value = "ezproxy")]
+        topic: String,
+
+        /// Share of lines, as a percentage, that may fail to parse before
+        /// the import exits with status 2
+        #[arg(long, default_value_t = 1.0)]
+        max_bad_pct: f64,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Parse a log file into JSON lines or CSV, without a database
+    Convert {
+        /// Path to log file, or - to read standard input
+        log_path: PathBuf,
+
+        /// jsonl (one JSON object per row) or csv (the columns of the
+        /// requests table, with a header)
+        #[arg(long, value_enum, default_value = "jsonl")]
+        to: sink::Format,
+
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+
+        /// Include each line as logged, in `raw`
+        #[arg(long)]
+        raw: bool,
+
+        /// Skip CORS preflights, HEADs, other non-GET/POST methods, and
+        /// 0-byte responses
+        #[arg(long)]
+        exclude_noise: bool,
+
+        /// Read timestamps without a UTC offset in this zone [default:
+        /// parser.assume_tz from config]
+        #[arg(long)]
+        assume_tz: Option<String>,
+
+        /// Share of lines, as a percentage, that may fail to parse before
+        /// the conversion exits with status 2
+        #[arg(long, default_value_t = 1.0)]
+        max_bad_pct: f64,
+    },
+
+    /// Import log files from a directory as log rotation completes them
+    Watch {
+        /// Directory EZproxy writes its logs to
+        dir: PathBuf,
+
+        /// File names to import, with * and ? wildcards
+        #[arg(long, default_value = "*.log")]
+        pattern: String,
+
+        /// How long a file must be unchanged before it is imported, e.g. 2m
+        #[arg(long, default_value = "2m")]
+        settle: String,
+
+        /// Move imported files here instead of leaving them in place
+        #[arg(long)]
+        move_to: Option<String>,
+
+        /// Skip CORS preflights, HEADs, other non-GET/POST methods, and
+        /// 0-byte responses
+        #[arg(long)]
+        exclude_noise: bool,
+
+        /// Don't keep each row's original log line, roughly halving the
+        /// database; integrity exports then carry an empty raw column
+        #[arg(long, conflicts_with = "raw_compressed")]
+        no_raw: bool,
+
+        /// Keep original log lines zstd-compressed instead of as text
+        #[arg(long)]
+        raw_compressed: bool,
 
         /// DuckDB database file
         #[arg(long, default_value = "ezvis.duckdb")]
         db: String,
     },
 
+    /// Write a hardened systemd unit for `serve` or `watch`
+    InstallService {
+        #[arg(long, value_enum)]
+        mode: service::Mode,
+
+        /// What to set up [default: systemd, or windows on Windows]
+        #[arg(long, value_enum)]
+        platform: Option<service::Platform>,
+
+        /// Unprivileged user the service runs as [default: ezvis, or NT SERVICE\ezvis-<mode> on Windows]
+        #[arg(long)]
+        user: Option<String>,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+
+        /// serve: bind address
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+
+        /// watch: directory EZproxy writes its logs to
+        #[arg(long)]
+        dir: Option<String>,
+
+        /// watch: file names to import
+        #[arg(long, default_value = "*.log")]
+        pattern: String,
+
+        /// watch: move imported files here
+        #[arg(long)]
+        move_to: Option<String>,
+
+        /// Environment variable for the service, KEY=VALUE; repeatable
+        #[arg(long)]
+        env: Vec<String>,
+
+        /// ezvis binary the unit runs [default: this one]
+        #[arg(long)]
+        binary: Option<String>,
+
+        /// Unit file or script to write, or - for stdout [default: /etc/systemd/system/ezvis-<mode>.service,
+        /// or ezvis-<mode>-service.cmd]
+        #[arg(long)]
+        out: Option<String>,
+
+        /// Replace an existing unit file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Re-run enrichment stages over rows already in the database
+    Enrich {
+        /// Stage to run, in order; repeat for several (see `enrich.stages`)
+        #[arg(long = "stage", required = true)]
+        stages: Vec<String>,
+
+        /// SQL condition selecting the rows, e.g. "country IS NULL"
+        #[arg(long = "where", default_value = "true")]
+        filter: String,
+
+        /// Rows updated per transaction
+        #[arg(long, default_value_t = 10000)]
+        batch_size: usize,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Checkpoint, rebuild the baseline, and drop orphaned indexes after a
+    /// large prune or migration
+    Maintain {
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Copy the database to a standalone file, consistently even while it
+    /// is in use
+    Backup {
+        /// File to write, or s3://bucket/key; a name ending in .zst is
+        /// zstd-compressed
+        #[arg(long)]
+        out: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Replace the database with a backup
+    Restore {
+        /// Backup file or s3://bucket/key, as written by `backup`
+        from: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+
+        /// Replace the database if it exists
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Encrypt an existing database in place with the key from
+    /// EZVIS_DB_KEY or EZVIS_DB_KEY_FILE
+    Encrypt {
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Show recent activity in the terminal, for when no browser is at hand
+    Top {
+        /// How much recent activity to show, ending at the newest request
+        #[arg(long, default_value = "60m")]
+        window: String,
+
+        /// Keep refreshing instead of showing one snapshot
+        #[arg(long)]
+        follow: bool,
+
+        /// Time between refreshes with --follow, e.g. 5s
+        #[arg(long, default_value = "5s")]
+        interval: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Print a plaintext digest of recent activity, e.g. to pipe into mail
+    Summary {
+        /// How much activity to cover, ending at the newest request
+        #[arg(long, default_value = "24h")]
+        since: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Reports that compare calendar periods
+    Report {
+        #[command(subcommand)]
+        cmd: ReportCommand,
+    },
+
     /// Run a local dashboard server
     Serve {
         /// DuckDB database file
@@ -38,42 +341,888 @@ enum Command {
         #[arg(long, default_value = "127.0.0.1:8080")]
         bind: String,
     },
+
+    /// Run the job worker and the config's schedules without the dashboard
+    Cron {
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Manage detection baselines
+    Baseline {
+        #[command(subcommand)]
+        cmd: BaselineCommand,
+    },
+
+    /// Check usage against the `[[policies]]` in the config
+    Policy {
+        #[command(subcommand)]
+        cmd: PolicyCommand,
+    },
+
+    /// Send alerts, and requests picked out by the config, to the `[siem]`
+    Siem {
+        #[command(subcommand)]
+        cmd: SiemCommand,
+    },
+
+    /// Export aggregated data
+    Export {
+        #[command(subcommand)]
+        cmd: ExportCommand,
+    },
+
+    /// Load annual platform costs for `/api/cost_per_use`
+    Costs {
+        #[command(subcommand)]
+        cmd: CostsCommand,
+    },
+
+    /// Load user attributes for `/api/usage_by_department`
+    Users {
+        #[command(subcommand)]
+        cmd: UsersCommand,
+    },
+
+    /// Merge SSL or transfer logs into imported requests
+    Transfer {
+        #[command(subcommand)]
+        cmd: TransferCommand,
+    },
+
+    /// Manage dashboard logins
+    Auth {
+        #[command(subcommand)]
+        cmd: AuthCommand,
+    },
+
+    /// Manage API tokens for scripts and dashboards
+    Token {
+        #[command(subcommand)]
+        cmd: TokenCommand,
+    },
+
+    /// Inspect the config file
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Report every problem with the config, with line numbers, without
+    /// starting anything
+    Check,
+}
+
+#[derive(Subcommand)]
+enum CostsCommand {
+    /// Load a CSV with platform, year, and cost columns
+    Import {
+        /// CSV file; rows replace costs already loaded for the same platform and year
+        file: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum UsersCommand {
+    /// Load a CSV with user_id and department, status, or affiliation columns
+    Import {
+        /// CSV file; rows replace attributes already loaded for the same user
+        file: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TransferCommand {
+    /// Fill in byte counts and TLS details from a transfer log
+    Import {
+        /// Transfer log: `%h %u [%t] "%r" %b`, optionally followed by the
+        /// quoted TLS protocol and cipher
+        file: String,
+
+        /// How far apart a request and its transfer line may be logged
+        #[arg(long, default_value = "2s")]
+        window: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuthCommand {
+    /// Read a password from stdin and print its hash for `[[auth.users]]`
+    HashPassword,
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    /// Create a token and print its secret, which is shown only once
+    Create {
+        /// What the token may do: read:aggregates, read:requests, write:jobs
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<auth::Scope>,
+
+        /// Label shown in `token list`, e.g. grafana
+        #[arg(long)]
+        name: Option<String>,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// List tokens, including revoked ones
+    List {
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Revoke a token by id
+    Revoke {
+        id: i64,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReportCommand {
+    /// Platform-by-platform usage in one calendar period against another,
+    /// e.g. two terms, with flags for notable changes
+    Compare {
+        /// Earlier period, by its name in [[calendar.periods]]
+        #[arg(long)]
+        a: String,
+
+        /// Later period, by its name in [[calendar.periods]]
+        #[arg(long)]
+        b: String,
+
+        /// Platforms to list, busiest first
+        #[arg(long, default_value_t = 25)]
+        top: usize,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BaselineCommand {
+    /// Learn per-host and per-user normal ranges from recent data
+    Build {
+        /// How much history to learn from, e.g. 90d or 12w
+        #[arg(long, default_value = "90d")]
+        window: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyCommand {
+    /// Find violations in recent data and record them as alerts
+    Check {
+        /// How many days to check, ending on the last day with data
+        #[arg(long, default_value = "7d")]
+        window: String,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SiemCommand {
+    /// Send everything new since the last forward
+    Forward {
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExportCommand {
+    /// Write monthly top-platform, bandwidth, and user tables as CSV
+    Usage {
+        /// Month to export, e.g. 2026-02 [default: previous month]
+        #[arg(long)]
+        month: Option<String>,
+
+        /// Output directory [default: export.dir from config, or exports]
+        #[arg(long)]
+        dir: Option<String>,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Bulk-load parsed requests into an Elasticsearch or OpenSearch index,
+    /// authenticating with the API key in EZVIS_ELASTIC_API_KEY if set
+    Elastic {
+        /// Cluster base URL, e.g. https://elastic.example.edu:9200
+        #[arg(long)]
+        url: String,
+
+        /// Index to load into; created with mappings for each column if missing
+        #[arg(long, default_value = "ezproxy")]
+        index: String,
+
+        /// Only requests from this time on, e.g. 2026-02-01 or -30d
+        #[arg(long)]
+        start: Option<String>,
+
+        /// Only requests before this time, in the same forms
+        #[arg(long)]
+        end: Option<String>,
+
+        /// DuckDB database file
+        #[arg(long, default_value = "ezvis.duckdb")]
+        db: String,
+    },
+
+    /// Check an integrity export of raw requests against its manifest
+    Verify {
+        /// CSV from /api/requests/export?integrity=true
+        file: String,
+
+        /// JSON from /api/requests/export/{id}/manifest
+        #[arg(long)]
+        manifest: String,
+    },
+}
+
+/// Storage for original log lines from `--no-raw` and `--raw-compressed`.
+fn raw_storage(no_raw: bool, raw_compressed: bool) -> db::RawStorage {
+    if no_raw {
+        db::RawStorage::Omit
+    } else if raw_compressed {
+        db::RawStorage::Zstd
+    } else {
+        db::RawStorage::Text
+    }
+}
+
+/// Exit status of an import, dry run or not, with more than `--max-bad-pct`
+/// of its lines unparseable. Everything else that was read got imported.
+const EXIT_PARTIAL: u8 = 2;
+/// Exit status of `policy check` when it found violations.
+const EXIT_ALERTS: u8 = 3;
+
+/// `EXIT_PARTIAL` if more than `max_pct` of `lines` were `bad`.
+fn import_outcome(bad: u64, lines: u64, max_pct: f64) -> ExitCode {
+    if lines > 0 && bad as f64 * 100.0 / lines as f64 > max_pct {
+        eprintln!("{} of {} lines failed to parse, more than {}%", bad, lines, max_pct);
+        ExitCode::from(EXIT_PARTIAL)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// `import_outcome` for an import's summary, out of every line it read.
+fn summary_outcome(summary: &import::ImportSummary, max_pct: f64) -> ExitCode {
+    let unparsed = summary.unparsed();
+    import_outcome(unparsed, summary.ok + summary.bad + summary.skipped + unparsed, max_pct)
+}
+
+impl Command {
+    /// Commands that run until stopped, draw to the terminal, or print only a
+    /// secret, with no outcome to put in JSON.
+    fn text_only(&self) -> Option<&'static str> {
+        match self {
+            Command::Serve { .. } => Some("serve"),
+            Command::Watch { .. } => Some("watch"),
+            Command::Cron { .. } => Some("cron"),
+            Command::Top { .. } => Some("top"),
+            Command::Auth { cmd: AuthCommand::HashPassword } => Some("auth hash-password"),
+            _ => None,
+        }
+    }
+}
+
+/// Print `value` as JSON under `--output json`, for jq and scripts; otherwise show
+/// it with `text`. Progress goes to stderr either way.
+fn emit<T: Serialize>(json: bool, value: &T, text: impl FnOnce(&T)) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(value)?);
+    } else {
+        text(value);
+    }
+    Ok(())
+}
+
+/// Like `emit` for an import to a sink, but on stderr when the sink has
+/// stdout, so that only rows go there.
+fn emit_sink_summary(json: bool, to_stdout: bool, summary: &import::ImportSummary, sample: Option<import::Sample>) -> Result<()> {
+    let report = |line: String| if to_stdout { eprintln!("{}", line) } else { println!("{}", line) };
+    if json {
+        report(serde_json::to_string(&json!({ "summary": summary, "sample_rate": sample.map(|s| s.rate()) }))?);
+    } else {
+        report(format!("wrote: ok={} bad={} skipped={}", summary.ok, summary.bad, summary.skipped));
+        if !summary.failures.is_empty() {
+            report(format!("parse failures: {}", import::describe_counts(&summary.failures)));
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
+    if cli.service {
+        return run_service(cli).await;
+    }
+    run(cli).await
+}
+
+/// Run the command until it ends or Windows stops the service.
+#[cfg(windows)]
+async fn run_service(cli: Cli) -> Result<ExitCode> {
+    let (service, stopped) = pulezviz::winsvc::start()?;
+    let res = tokio::select! {
+        res = run(cli) => res,
+        _ = stopped => Ok(ExitCode::SUCCESS),
+    };
+    if let Err(e) = &res {
+        eprintln!("{:#}", e);
+    }
+    service.finish(res.is_ok());
+    res
+}
+
+#[cfg(not(windows))]
+async fn run_service(_cli: Cli) -> Result<ExitCode> {
+    anyhow::bail!("--service is for Windows; on Linux, install-service writes a systemd unit")
+}
+
+/// Run the command; the exit code says how it went when it didn't fail
+/// outright.
+async fn run(cli: Cli) -> Result<ExitCode> {
+    let json = cli.json || cli.output == Output::Json;
+    if json && let Some(name) = cli.cmd.text_only() {
+        anyhow::bail!("`{}` has no JSON output; drop --output json", name);
+    }
+    // Before loading, which would stop at the first problem.
+    if let Command::Config { cmd: ConfigCommand::Check } = cli.cmd {
+        let path = config::resolve_path(cli.config.as_deref()).context("no config file to check")?;
+        let issues = check::check_file(path)?;
+        emit(json, &json!({ "file": path, "issues": issues }), |_| {
+            for issue in &issues {
+                match issue.line {
+                    Some(line) => println!("{}:{}: {}", path, line, issue.message),
+                    None => println!("{}: {}", path, issue.message),
+                }
+            }
+            if issues.is_empty() {
+                println!("{} is fine", path);
+            }
+        })?;
+        if !issues.is_empty() {
+            anyhow::bail!("{} problem(s) in {}", issues.len(), path);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    let config = config::load(cli.config.as_deref())?;
+    let mut outcome = ExitCode::SUCCESS;
 
     match cli.cmd {
-        Command::Import { log_path, db } => {
+        Command::Import { log_path, exclude_noise, sample, assume_tz, max_bad_pct, dry_run: true, .. } => {
+            let sample = sample.as_deref().map(import::Sample::parse).transpose()?;
+            let parser_config = config::ParserConfig { assume_tz: assume_tz.or(config.parser.assume_tz.clone()), ..config.parser.clone() };
+            let timestamps = parser::Timestamps::from_config(&parser_config)?;
+            let opts = import::ImportOptions { exclude_noise, sample, timestamps, ..Default::default() };
+            let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+            let report = import::dry_run(&log_path, &opts, &pipeline)?;
+            emit(json, &report, |report| {
+                println!("lines:          {}", report.lines);
+                println!("parsed:         {} ({}%)", report.parsed, report.success_pct());
+                println!("failed:         {}", report.failed);
+                if !report.failures.is_empty() {
+                    println!("  by reason:    {}", import::describe_counts(&report.failures));
+                }
+                for (line, err) in &report.examples {
+                    println!("  line {}: {}", line, err);
+                }
+                if exclude_noise {
+                    println!("noise skipped:  {}", report.skipped);
+                }
+                println!("projected rows: {}", report.projected_rows);
+                match (report.first_ts, report.last_ts) {
+                    (Some(first), Some(last)) => println!("date range:     {} .. {}", first.to_rfc3339(), last.to_rfc3339()),
+                    _ => println!("date range:     (no rows)"),
+                }
+                println!("distinct hosts: {}", report.distinct_hosts);
+                if !report.scrubbed.is_empty() {
+                    println!("scrubbed:       {}", import::describe_counts(&report.scrubbed));
+                }
+                println!("dry run: nothing was written");
+            })?;
+            outcome = import_outcome(report.failed, report.lines, max_bad_pct);
+        }
+
+        Command::Import {
+            log_path,
+            exclude_noise,
+            sample,
+            assume_tz,
+            no_raw,
+            raw_compressed,
+            max_bad_pct,
+            sink: Some(kind),
+            out,
+            url,
+            index,
+            topic,
+            ..
+        } => {
+            let sample = sample.as_deref().map(import::Sample::parse).transpose()?;
+            let parser_config = config::ParserConfig { assume_tz: assume_tz.or(config.parser.assume_tz.clone()), ..config.parser.clone() };
+            let timestamps = parser::Timestamps::from_config(&parser_config)?;
+            let raw = raw_storage(no_raw, raw_compressed);
+            let opts = import::ImportOptions { exclude_noise, sample, timestamps, raw, ..Default::default() };
+            let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+            let sink_opts = sink::SinkOptions { out, url, index, topic, api_key: std::env::var(elastic::API_KEY_ENV).ok(), raw };
+            let to_stdout = sink_opts.to_stdout(kind);
+            let mut sink = sink::open(kind, &sink_opts)?;
+            let summary = import::import_to_sink(&log_path, &opts, &pipeline, sink.as_mut())?;
+            emit_sink_summary(json, to_stdout, &summary, sample)?;
+            outcome = summary_outcome(&summary, max_bad_pct);
+        }
+
+        Command::Convert { log_path, to, out, raw, exclude_noise, assume_tz, max_bad_pct } => {
+            let parser_config = config::ParserConfig { assume_tz: assume_tz.or(config.parser.assume_tz.clone()), ..config.parser.clone() };
+            let timestamps = parser::Timestamps::from_config(&parser_config)?;
+            let raw = if raw { db::RawStorage::Text } else { db::RawStorage::Omit };
+            let opts = import::ImportOptions { exclude_noise, timestamps, raw, ..Default::default() };
+            let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+            let sink_opts = sink::SinkOptions { out, raw, ..Default::default() };
+            let to_stdout = sink_opts.to_stdout(to.into());
+            let mut sink = sink::open(to.into(), &sink_opts)?;
+            let summary = import::import_to_sink(&log_path, &opts, &pipeline, sink.as_mut())?;
+            emit_sink_summary(json, to_stdout, &summary, None)?;
+            outcome = summary_outcome(&summary, max_bad_pct);
+        }
+
+        Command::Import { log_path, exclude_noise, sample, assume_tz, no_raw, raw_compressed, max_bad_pct, db, .. } => {
+            let sample = sample.as_deref().map(import::Sample::parse).transpose()?;
             // FIX 1: conn must be mutable to start a transaction later
             let mut conn = db::open_db(&db)?; 
             db::init_schema(&conn)?;
 
-            let f = File::open(&log_path).with_context(|| format!("open {}", log_path))?;
-            let rdr = BufReader::new(f);
+            // FIX 2: pass &mut conn
+            let parser_config = config::ParserConfig { assume_tz: assume_tz.or(config.parser.assume_tz.clone()), ..config.parser.clone() };
+            let timestamps = parser::Timestamps::from_config(&parser_config)?;
+            let raw = raw_storage(no_raw, raw_compressed);
+            honeytokens::validate(&config.honeytokens)?;
+            let opts = import::ImportOptions {
+                exclude_noise,
+                sample,
+                timestamps,
+                raw,
+                honeytokens: config.honeytokens.clone(),
+                siem: config.siem.clone(),
+            };
+            let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+            let summary = if log_path.as_os_str() == import::STDIN {
+                import::import_reader(&mut conn, import::STDIN, &mut std::io::stdin().lock(), &opts, &pipeline)?
+            } else {
+                import::import_file(&mut conn, &log_path, &opts, &pipeline)?
+            };
+            let rate = sample.map(|s| s.rate());
+            emit(json, &json!({ "summary": summary, "sample_rate": rate }), |_| {
+                println!(
+                    "import complete: ok={} bad={} skipped={}",
+                    summary.ok, summary.bad, summary.skipped
+                );
+                if !summary.failures.is_empty() {
+                    println!("parse failures: {}", import::describe_counts(&summary.failures));
+                }
+                if !summary.scrubbed.is_empty() {
+                    println!("scrubbed:       {}", import::describe_counts(&summary.scrubbed));
+                }
+                if summary.honeytoken_hits > 0 {
+                    println!("honeytoken hits: {} (see /api/honeytoken_hits)", summary.honeytoken_hits);
+                }
+                if let Some(rate) = rate {
+                    println!(
+                        "sampled {}% of lines; multiply counts by {} for estimates",
+                        rate * 100.0,
+                        (100.0 / rate).round() / 100.0
+                    );
+                }
+            })?;
+            outcome = summary_outcome(&summary, max_bad_pct);
+        }
+
+        Command::Watch { dir, pattern, settle, move_to, exclude_noise, no_raw, raw_compressed, db } => {
+            let mut conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let opts = watch::WatchOptions {
+                dir,
+                pattern,
+                settle: duration::parse_duration(&settle)?.to_std().context("settle")?,
+                move_to: move_to.map(Into::into),
+                import: import::ImportOptions {
+                    exclude_noise,
+                    timestamps: parser::Timestamps::from_config(&config.parser)?,
+                    raw: raw_storage(no_raw, raw_compressed),
+                    honeytokens: config.honeytokens.clone(),
+                    siem: config.siem.clone(),
+                    ..Default::default()
+                },
+            };
+            let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+            watch::run(&mut conn, &opts, &pipeline)?;
+        }
+
+        Command::InstallService { mode, platform, user, db, bind, dir, pattern, move_to, env, binary, out, force } => {
+            // The unit uses the same config this run would have.
+            let config_path = cli
+                .config
+                .clone()
+                .or_else(|| std::path::Path::new(config::DEFAULT_PATH).exists().then(|| config::DEFAULT_PATH.to_string()));
+            let binary = match binary {
+                Some(b) => b.into(),
+                None => std::env::current_exe().context("locate the ezvis binary")?,
+            };
+            let platform = platform.unwrap_or(service::Platform::HOST);
+            let opts = service::ServiceOptions {
+                mode,
+                user: user.unwrap_or_else(|| service::default_user(platform, mode)),
+                binary,
+                db: db.into(),
+                config: config_path.map(Into::into),
+                bind,
+                dir: dir.map(Into::into),
+                pattern,
+                move_to: move_to.map(Into::into),
+                env,
+            };
+            let (unit, default_output) = match platform {
+                service::Platform::Systemd => {
+                    (service::unit(&opts)?, format!("/etc/systemd/system/ezvis-{}.service", mode.as_str()))
+                }
+                service::Platform::Windows => (service::windows_script(&opts)?, format!("ezvis-{}-service.cmd", mode.as_str())),
+            };
 
-            let rows = rdr.lines().filter_map(|line| {
-                let line = match line {
-                    Ok(l) => l,
-                    Err(_) => return None,
+            let output = out.unwrap_or(default_output);
+            if output == "-" {
+                emit(json, &json!({ "unit": unit }), |_| print!("{}", unit))?;
+            } else {
+                if std::path::Path::new(&output).exists() && !force {
+                    anyhow::bail!("{} already exists; pass --force to replace it", output);
+                }
+                std::fs::write(&output, unit).with_context(|| format!("write {}", output))?;
+                let name = std::path::Path::new(&output).file_name().unwrap_or_default().to_string_lossy();
+                emit(json, &json!({ "wrote": output }), |_| {
+                    println!("wrote {}", output);
+                    match platform {
+                        service::Platform::Systemd => {
+                            println!("next: systemctl daemon-reload && systemctl enable --now {}", name)
+                        }
+                        service::Platform::Windows => println!("next: run {} from an elevated prompt", name),
+                    }
+                })?;
+            }
+        }
+
+        Command::Enrich { stages, filter, batch_size, db } => {
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let pipeline = enrich::Pipeline::only(&config.enrich, &stages)?;
+            let n = enrich::backfill(&conn, &pipeline, &filter, batch_size.max(1), |done, total| {
+                eprintln!("  Enriched {} / {} rows", done, total);
+            })?;
+            let scrubbed = pipeline.take_scrubbed();
+            emit(json, &json!({ "updated": n, "scrubbed": scrubbed }), |_| {
+                println!("enrich complete: {} rows updated", n);
+                if !scrubbed.is_empty() {
+                    println!("scrubbed: {}", import::describe_counts(&scrubbed));
+                }
+            })?;
+        }
+
+        Command::Maintain { db } => {
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let summary = maintain::run(&conn, &db, &config.ports)?;
+            emit(json, &summary, |summary| {
+                for index in &summary.dropped_indexes {
+                    println!("  dropped index {}", index);
+                }
+                match summary.baseline_hours {
+                    Some(hours) => println!("  baseline rebuilt over {}h", hours),
+                    None => println!("  no baseline to rebuild"),
+                }
+                let change = match summary.reclaimed_bytes() {
+                    n if n < 0 => format!("grew by {} bytes", -n),
+                    n => format!("{} reclaimed", n),
                 };
-                match parser::parse_line(&line) {
-                    Ok(r) => Some(r),
-                    Err(_) => None,
+                println!(
+                    "maintain complete: {} -> {} bytes ({}), {} free blocks of {} bytes",
+                    summary.bytes_before, summary.bytes_after, change, summary.free_blocks, summary.block_size
+                );
+                if summary.reclaimed_bytes() < 0 {
+                    println!("  later imports fill the {} free blocks before the file grows again", summary.free_blocks);
                 }
-            });
+            })?;
+        }
 
-            // FIX 2: pass &mut conn
-            let (ok, bad) = db::insert_rows(&mut conn, rows)?; 
-            println!("import complete: ok={} bad={}", ok, bad);
+        Command::Backup { out, db } => {
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let summary = backup::backup(&conn, &out)?;
+            emit(json, &summary, |summary| {
+                println!(
+                    "backup complete: {} ({} bytes{})",
+                    summary.out,
+                    summary.bytes,
+                    if summary.compressed { ", zstd" } else { "" }
+                )
+            })?;
+        }
+
+        Command::Restore { from, db, force } => {
+            let summary = backup::restore(&from, &db, force)?;
+            emit(json, &summary, |s| println!("restore complete: {} -> {} ({} requests)", s.from, s.db, s.requests))?;
+        }
+
+        Command::Encrypt { db: path } => {
+            let key = db::encryption_key()?
+                .with_context(|| format!("set {} or {} to the key to encrypt with", db::KEY_ENV, db::KEY_FILE_ENV))?;
+            db::encrypt(&path, &key)?;
+            // Proves the key opens it, and brings the schema up to date.
+            db::init_schema(&db::open_db(&path)?)?;
+            emit(json, &json!({ "encrypted": path }), |_| println!("encrypted {}", path))?;
+        }
+
+        Command::Top { window, follow, interval, db } => {
+            let opts = top::TopOptions {
+                db,
+                window: duration::parse_duration(&window)?,
+                follow,
+                interval: duration::parse_duration(&interval)?.to_std().context("interval")?,
+                ports: config.ports.clone(),
+            };
+            top::run(&opts)?;
+        }
+
+        Command::Summary { since, db } => {
+            let since = duration::parse_duration(&since)?;
+            let conn = db::open_read_only(&db)?;
+            if json {
+                println!("{}", serde_json::to_string(&summary::report(&conn, since, &config.ports)?)?);
+            } else {
+                print!("{}", summary::digest(&conn, since, &config.ports)?);
+            }
+        }
+
+        Command::Report { cmd: ReportCommand::Compare { a, b, top, db } } => {
+            let conn = db::open_read_only(&db)?;
+            let comparison = report::compare(&conn, &config.calendar, &a, &b, top, &config.ports)?;
+            if json {
+                println!("{}", serde_json::to_string(&comparison)?);
+            } else {
+                print!("{}", report::render(&comparison)?);
+            }
         }
 
         Command::Serve { db, bind } => {
             let bind: SocketAddr = bind.parse().context("parse bind addr")?;
-            web::serve(db, bind).await?;
+            let config_path = config::resolve_path(cli.config.as_deref()).map(Into::into);
+            web::serve(db, bind, config, config_path).await?;
+        }
+
+        Command::Cron { db } => {
+            jobs::run_scheduler(db, config).await?;
         }
+
+        Command::Baseline { cmd: BaselineCommand::Build { window, db } } => {
+            let window = duration::parse_duration(&window)?;
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let summary = baseline::build(&conn, window, &config.ports)?;
+            emit(json, &summary, |s| {
+                println!("baseline built: {} -> {} ({} hosts, {} users)", s.window_start, s.window_end, s.hosts, s.users)
+            })?;
+        }
+
+        Command::Policy { cmd: PolicyCommand::Check { window, db } } => {
+            let window = duration::parse_duration(&window)?;
+            if config.policies.is_empty() {
+                anyhow::bail!("no [[policies]] in config");
+            }
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let summary = policy::check(&conn, &config.policies, window)?;
+            siem::forward_pending(&conn, config.siem.as_ref());
+            emit(json, &summary, |summary| {
+                for p in &summary.policies {
+                    println!("  {}: {} violations", p.name, p.violations);
+                }
+                match (&summary.start, &summary.end) {
+                    (Some(start), Some(end)) => println!("policy check complete: {} -> {}", start, end),
+                    _ => println!("policy check complete: no requests"),
+                }
+            })?;
+            if summary.policies.iter().any(|p| p.violations > 0) {
+                outcome = ExitCode::from(EXIT_ALERTS);
+            }
+        }
+
+        Command::Siem { cmd: SiemCommand::Forward { db } } => {
+            let cfg = config.siem.as_ref().context("no [siem] section in config")?;
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+
+            let summary = siem::forward(&conn, cfg)?;
+            emit(json, &summary, |s| println!("forwarded {} alerts and {} requests to {}", s.alerts, s.requests, cfg.url))?;
+        }
+
+        Command::Export { cmd: ExportCommand::Usage { month, dir, db } } => {
+            let mut cfg = config.export.unwrap_or_default();
+            if let Some(dir) = dir {
+                cfg.dir = dir;
+            }
+            let month = month.unwrap_or_else(export::previous_month);
+            let conn = db::open_db(&db)?;
+
+            let summary = export::export_month(&conn, &month, &cfg, &config.ports)?;
+            emit(json, &summary, |summary| {
+                for f in &summary.files {
+                    println!("wrote {}", f);
+                }
+            })?;
+        }
+
+        Command::Export { cmd: ExportCommand::Elastic { url, index, start, end, db } } => {
+            let target = elastic::Target {
+                url,
+                api_key: std::env::var(elastic::API_KEY_ENV).ok().filter(|k| !k.is_empty()),
+            };
+            let now = chrono::Utc::now();
+            let time = |t: Option<String>, name: &str| -> Result<Option<String>> {
+                t.map(|t| Ok(duration::parse_time(&t, now).with_context(|| format!("--{name}"))?.to_rfc3339())).transpose()
+            };
+            let (start, end) = (time(start, "start")?, time(end, "end")?);
+            let conn = db::open_read_only(&db)?;
+
+            let summary = elastic::export(&conn, &target, &index, start.as_deref(), end.as_deref(), |rows| {
+                eprintln!("  {} rows sent", rows);
+            })?;
+            emit(json, &summary, |s| {
+                if s.created {
+                    println!("created index {}", s.index);
+                }
+                println!("export complete: {} rows to {}", s.rows, s.index);
+            })?;
+        }
+
+        Command::Export { cmd: ExportCommand::Verify { file, manifest } } => {
+            let key = config
+                .export
+                .and_then(|e| e.signing_key)
+                .context("export.signing_key is not configured")?;
+            let text = std::fs::read_to_string(&manifest).with_context(|| format!("read {}", manifest))?;
+            let manifest: integrity::Manifest = serde_json::from_str(&text).context("parse manifest")?;
+            integrity::verify_file(&file, &manifest, &key)?;
+            emit(json, &json!({ "ok": true, "rows": manifest.rows, "export": manifest.id }), |_| {
+                println!("ok: {} rows match export {} signed {}", manifest.rows, manifest.id, manifest.created_at)
+            })?;
+        }
+
+        Command::Auth { cmd: AuthCommand::HashPassword } => {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password).context("read password")?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                anyhow::bail!("empty password");
+            }
+            println!("{}", auth::hash_password(password)?);
+        }
+
+        Command::Costs { cmd: CostsCommand::Import { file, db } } => {
+            let text = std::fs::read_to_string(&file).with_context(|| format!("read {}", file))?;
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+            let loaded = costs::load_csv(&conn, &text)?;
+            emit(json, &json!({ "loaded": loaded, "file": file }), |_| println!("loaded {} costs from {}", loaded, file))?;
+        }
+
+        Command::Users { cmd: UsersCommand::Import { file, db } } => {
+            let text = std::fs::read_to_string(&file).with_context(|| format!("read {}", file))?;
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+            let loaded = users::load_csv(&conn, &text)?;
+            emit(json, &json!({ "loaded": loaded, "file": file }), |_| println!("loaded {} users from {}", loaded, file))?;
+        }
+
+        Command::Transfer { cmd: TransferCommand::Import { file, window, db } } => {
+            let window = duration::parse_duration(&window)?;
+            let timestamps = parser::Timestamps::from_config(&config.parser)?;
+            let conn = db::open_db(&db)?;
+            db::init_schema(&conn)?;
+            let pipeline = enrich::Pipeline::from_config(&config.enrich)?;
+            let summary = transfer::import(&conn, &file, &timestamps, &pipeline, window)?;
+            emit(json, &summary, |s| {
+                println!("{} lines ({} unparseable), {} requests updated from {}", s.lines, s.bad, s.matched, file)
+            })?;
+        }
+
+        Command::Token { cmd } => match cmd {
+            TokenCommand::Create { scopes, name, db } => {
+                let conn = db::open_db(&db)?;
+                db::init_schema(&conn)?;
+                let (id, secret) = tokens::create(&conn, name.as_deref(), &scopes)?;
+                eprintln!("created token {}; store it now, it can't be shown again", id);
+                emit(json, &json!({ "id": id, "secret": secret }), |_| println!("{}", secret))?;
+            }
+            TokenCommand::List { db } => {
+                let conn = db::open_db(&db)?;
+                db::init_schema(&conn)?;
+                let tokens = tokens::list(&conn)?;
+                emit(json, &tokens.to_objects(), |_| print!("{}", tokens.to_csv()))?;
+            }
+            TokenCommand::Revoke { id, db } => {
+                let conn = db::open_db(&db)?;
+                db::init_schema(&conn)?;
+                if !tokens::revoke(&conn, id)? {
+                    anyhow::bail!("no active token with id {}", id);
+                }
+                emit(json, &json!({ "revoked": id }), |_| println!("revoked token {}", id))?;
+            }
+        },
+
+        Command::Config { cmd: ConfigCommand::Check } => unreachable!("checked before the config is loaded"),
     }
 
-    Ok(())
+    Ok(outcome)
 }
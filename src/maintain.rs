@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::Duration;
+use duckdb::{Connection, OptionalExt, params};
+use serde::Serialize;
+
+use crate::{baseline, config::PortsConfig, db};
+
+#[derive(Debug, Serialize)]
+pub struct MaintainSummary {
+    /// Indexes found by `db::orphaned_indexes` and dropped
+    pub dropped_indexes: Vec<String>,
+    /// Window of the rebuilt baseline; `None` when none had been built or
+    /// no requests are left to build it from
+    pub baseline_hours: Option<i64>,
+    /// Database file plus WAL, before and after
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Unused blocks inside the file after the checkpoint. DuckDB reuses
+    /// them before growing the file but never gives them back to the OS.
+    pub free_blocks: i64,
+    pub block_size: i64,
+}
+
+impl MaintainSummary {
+    /// Bytes the files shrank by; negative when the checkpoint grew them.
+    pub fn reclaimed_bytes(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+/// Size of the database at `path` and its write-ahead log.
+fn on_disk(path: &str) -> u64 {
+    let len = |p: &Path| p.metadata().map_or(0, |m| m.len());
+    len(Path::new(path)) + len(Path::new(&format!("{}.wal", path)))
+}
+
+/// Tidy up the database at `path`, open as `conn`, after a large prune or
+/// migration: drop orphaned indexes, rebuild the baseline over the window it
+/// was last built with so it no longer reflects deleted rows, and checkpoint
+/// so the WAL is folded in and freed blocks become reusable.
+pub fn run(conn: &Connection, path: &str, ports: &PortsConfig) -> Result<MaintainSummary> {
+    let bytes_before = on_disk(path);
+
+    let dropped_indexes = db::orphaned_indexes(conn)?;
+    for index in &dropped_indexes {
+        conn.execute_batch(&format!("DROP INDEX \"{}\"", index.replace('"', "\"\"")))?;
+    }
+
+    let window_hours: Option<i64> = conn
+        .query_row("SELECT window_hours FROM baseline_meta LIMIT 1", params![], |r| r.get(0))
+        .optional()?
+        .flatten();
+    let has_data: bool = conn.query_row("SELECT count(*) > 0 FROM requests", params![], |r| r.get(0))?;
+    let baseline_hours = match window_hours {
+        Some(hours) if has_data => {
+            baseline::build(conn, Duration::hours(hours), ports)?;
+            Some(hours)
+        }
+        _ => None,
+    };
+
+    conn.execute_batch("CHECKPOINT")?;
+    let (free_blocks, block_size) = conn.query_row(
+        "SELECT free_blocks, block_size FROM pragma_database_size() WHERE database_name = current_database()",
+        params![],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+
+    Ok(MaintainSummary { dropped_indexes, baseline_hours, bytes_before, bytes_after: on_disk(path), free_blocks, block_size })
+}
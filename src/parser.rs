@@ -1,9 +1,20 @@
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, FixedOffset};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{
+    format::{Item, ParseErrorKind, StrftimeItems},
+    DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
 use regex::Regex;
 use serde::Serialize;
-use std::sync::OnceLock;
-use url::Url;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Once, OnceLock},
+};
+use url::{Host, Url};
+
+use crate::config::ParserConfig;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct LogRow {
@@ -14,7 +25,11 @@ pub struct LogRow {
     pub method: String,
     pub url: String,
     pub scheme: Option<String>,
+    /// Lowercase ASCII, internationalized names in punycode (`xn--`); see
+    /// `ascii_host`
     pub host: Option<String>,
+    /// `host` with its punycode labels decoded, for display
+    pub host_unicode: Option<String>,
     pub port: Option<i32>,
     pub path: Option<String>,
     pub query: Option<String>,
@@ -24,6 +39,33 @@ pub struct LogRow {
     pub country: Option<String>,
     pub user_agent: Option<String>,
     pub raw: String,
+    /// Filled by the `identifiers` enricher
+    pub issn: Option<String>,
+    pub isbn: Option<String>,
+    pub referrer: Option<String>,
+    /// Filled by the `user_agent` enricher
+    pub browser: Option<String>,
+    /// Filled by the `path_template` enricher
+    pub path_template: Option<String>,
+    /// Filled by the `request_kind` enricher: `page_view`, `content`,
+    /// `asset`, or `proxy`
+    pub request_kind: Option<String>,
+    /// Filled by the `target_host` enricher: the vendor host behind a
+    /// proxy-by-hostname name
+    pub target_host: Option<String>,
+    /// Filled by the `truncate` enricher when it shortened `url`: SHA-256
+    /// of the URL as logged
+    pub url_sha256: Option<String>,
+    /// Lowercased extension of the path's last segment; see `file_ext`
+    pub file_ext: Option<String>,
+}
+
+impl LogRow {
+    /// CORS preflights, HEADs and other non-GET/POST methods, and 0-byte
+    /// responses. Kept in step with `db::SIGNAL_CONDITION`.
+    pub fn is_noise(&self) -> bool {
+        !matches!(self.method.as_str(), "GET" | "POST") || self.bytes.unwrap_or(0) == 0
+    }
 }
 
 fn none_if_dash(s: &str) -> Option<String> {
@@ -31,14 +73,447 @@ fn none_if_dash(s: &str) -> Option<String> {
     if t == "-" { None } else { Some(t.to_string()) }
 }
 
-// Example timestamp: 15/Feb/2026:00:00:04 +0000
-fn parse_ts(ts: &str) -> Result<DateTime<FixedOffset>> {
-    // chrono format: "%d/%b/%Y:%H:%M:%S %z"
-    Ok(DateTime::parse_from_str(ts, "%d/%b/%Y:%H:%M:%S %z")?)
+/// A host name spelled the one way `host` stores it, however it was
+/// logged: lowercase, internationalized labels in punycode, and no trailing
+/// dot, so `bücher.ch`, `xn--bcher-kva.ch`, and `XN--BCHER-KVA.CH.` are one
+/// host. Names that aren't valid IDNA are just lowercased. A `:port` after
+/// the name, as host analytics add with `[ports] split_hosts`, is kept.
+pub fn ascii_host(host: &str) -> String {
+    let (name, port) = split_port(host);
+    let name = name.trim_end_matches('.');
+    idna::domain_to_ascii(name).unwrap_or_else(|_| name.to_lowercase()) + port
+}
+
+/// `host` with its punycode labels decoded, or as it is when they don't
+/// decode; a `:port` is kept.
+pub fn unicode_host(host: &str) -> String {
+    let (name, port) = split_port(host);
+    match idna::domain_to_unicode(name) {
+        (unicode, Ok(())) => unicode + port,
+        (_, Err(_)) => host.to_string(),
+    }
+}
+
+/// The extension of `path`'s last segment, lowercased: `pdf` for
+/// `/doi/pdf/10.1/Paper.PDF`. Only 1 to 10 letters and digits count, so
+/// `/v1.2/` or a dotted record ID isn't taken for one. Kept in step with
+/// `db::FILE_EXT_OF_PATH`.
+pub fn file_ext(path: &str) -> Option<String> {
+    let file = path.rsplit('/').next()?;
+    let (_, ext) = file.rsplit_once('.')?;
+    (1..=10).contains(&ext.len()).then_some(())?;
+    ext.bytes().all(|b| b.is_ascii_alphanumeric()).then(|| ext.to_ascii_lowercase())
+}
+
+/// `host` as its name and its `:port`, which is empty when there is none.
+pub(crate) fn split_port(host: &str) -> (&str, &str) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            host.split_at(name.len())
+        }
+        _ => (host, ""),
+    }
+}
+
+/// Month abbreviations as a localized `strftime("%b")` writes them, per
+/// locale, January first. `|` separates spellings of the same month.
+const MONTH_NAMES: &[(&str, [&str; 12])] = &[
+    ("de", ["jan", "feb", "mär|mrz", "apr", "mai", "jun", "jul", "aug", "sep", "okt", "nov", "dez"]),
+    ("es", ["ene", "feb", "mar", "abr", "may", "jun", "jul", "ago", "sep|sept", "oct", "nov", "dic"]),
+    ("fr", ["janv", "févr", "mars", "avr|avril", "mai", "juin", "juil", "août", "sept", "oct", "nov", "déc"]),
+    ("it", ["gen", "feb", "mar", "apr", "mag", "giu", "lug", "ago", "set", "ott", "nov", "dic"]),
+    ("nl", ["jan", "feb", "mrt", "apr", "mei", "jun", "jul", "aug", "sep", "okt", "nov", "dec"]),
+    ("pt", ["jan", "fev", "mar", "abr", "mai", "jun", "jul", "ago", "set", "out", "nov", "dez"]),
+];
+
+const ENGLISH_MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Accepted in place of a chrono format for RFC 3339 / ISO 8601 timestamps.
+const RFC3339: &str = "rfc3339";
+
+/// Zone for timestamps logged without a UTC offset.
+#[derive(Debug, Clone, Copy)]
+pub enum AssumedTz {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl AssumedTz {
+    /// `UTC`, an offset like `+05:30` or `-0500`, or an IANA name like
+    /// `America/Chicago`.
+    pub fn parse(s: &str) -> Result<AssumedTz> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("utc") || s == "Z" {
+            return Ok(AssumedTz::Fixed(FixedOffset::east_opt(0).expect("zero offset")));
+        }
+        if let Ok(tz) = s.parse::<Tz>() {
+            return Ok(AssumedTz::Named(tz));
+        }
+        static OFFSET: OnceLock<Regex> = OnceLock::new();
+        let re = OFFSET.get_or_init(|| Regex::new(r"^([+-])(\d{2}):?(\d{2})$").expect("regex compiles"));
+        let caps = re
+            .captures(s)
+            .ok_or_else(|| anyhow!("time zone {:?} is not UTC, an offset like +05:00, or a name like America/Chicago", s))?;
+        let secs = caps[2].parse::<i32>()? * 3600 + caps[3].parse::<i32>()? * 60;
+        let secs = if &caps[1] == "-" { -secs } else { secs };
+        FixedOffset::east_opt(secs)
+            .map(AssumedTz::Fixed)
+            .ok_or_else(|| anyhow!("time zone offset {:?} is out of range", s))
+    }
+
+    /// The instant `naive` names in this zone; the earlier one when a DST
+    /// change makes it ambiguous, none when it falls in the gap.
+    fn localize(&self, naive: NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        match self {
+            AssumedTz::Fixed(offset) => offset.from_local_datetime(&naive).single(),
+            AssumedTz::Named(tz) => tz.from_local_datetime(&naive).earliest().map(|dt| dt.fixed_offset()),
+        }
+    }
+
+    /// The date here at `t`.
+    pub fn date(&self, t: DateTime<Utc>) -> NaiveDate {
+        match self {
+            AssumedTz::Fixed(offset) => t.with_timezone(offset).date_naive(),
+            AssumedTz::Named(tz) => t.with_timezone(tz).date_naive(),
+        }
+    }
+
+    /// The first instant of `day` here: midnight, or the end of the DST
+    /// gap where a zone springs forward at midnight.
+    pub fn start_of(&self, day: NaiveDate) -> DateTime<Utc> {
+        let midnight = day.and_time(NaiveTime::MIN);
+        (0..=2)
+            .find_map(|h| self.localize(midnight + chrono::Duration::hours(h)))
+            .map_or(midnight.and_utc(), |t| t.with_timezone(&Utc))
+    }
+}
+
+impl fmt::Display for AssumedTz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssumedTz::Fixed(offset) => write!(f, "{}", offset),
+            AssumedTz::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+/// Repairs made with `assume_tz` set, each reported the first time it is
+/// needed.
+#[derive(Debug, Clone, Copy)]
+enum Fallback {
+    NoOffset,
+    TwoDigitYear,
+    YearSince1900,
+}
+
+/// How `parse_line` reads the timestamp between the brackets: each format is
+/// tried in turn, after translating a localized month name to English.
+#[derive(Debug, Clone)]
+pub struct Timestamps {
+    formats: Vec<String>,
+    /// Lowercase localized month name to its English abbreviation
+    months: HashMap<String, &'static str>,
+    /// When set, legacy timestamps are repaired instead of rejected: a
+    /// missing offset is taken to be this zone, and two-digit or
+    /// 1900-based years are moved into the right century.
+    assume_tz: Option<AssumedTz>,
+    warned: Arc<[Once; 3]>,
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Timestamps::from_config(&ParserConfig::default()).expect("default formats are valid")
+    }
+}
+
+impl Timestamps {
+    pub fn from_config(cfg: &ParserConfig) -> Result<Timestamps> {
+        if cfg.timestamp_formats.is_empty() {
+            bail!("parser.timestamp_formats needs at least one format");
+        }
+        for f in &cfg.timestamp_formats {
+            if f != RFC3339 && StrftimeItems::new(f).any(|item| item == Item::Error) {
+                bail!("parser.timestamp_formats: {:?} is not a valid chrono format", f);
+            }
+        }
+        let mut months = HashMap::new();
+        for locale in &cfg.month_locales {
+            let (_, names) = MONTH_NAMES.iter().find(|(l, _)| l == locale).ok_or_else(|| {
+                let known: Vec<_> = MONTH_NAMES.iter().map(|(l, _)| *l).collect();
+                anyhow!("parser.month_locales: unknown locale {:?} (known: {})", locale, known.join(", "))
+            })?;
+            for (spellings, english) in names.iter().zip(ENGLISH_MONTHS) {
+                for name in spellings.split('|') {
+                    months.entry(name.to_string()).or_insert(english);
+                }
+            }
+        }
+        let assume_tz = cfg.assume_tz.as_deref().map(AssumedTz::parse).transpose().context("parser.assume_tz")?;
+        Ok(Timestamps {
+            formats: cfg.timestamp_formats.clone(),
+            months,
+            assume_tz,
+            warned: Arc::new([Once::new(), Once::new(), Once::new()]),
+        })
+    }
+
+    /// `ts` with its first word replaced by the English month it names, if
+    /// it names one in a configured locale.
+    fn to_english<'a>(&self, ts: &'a str) -> Cow<'a, str> {
+        let Some(start) = ts.find(char::is_alphabetic) else {
+            return Cow::Borrowed(ts);
+        };
+        let len = ts[start..].find(|c: char| !c.is_alphabetic()).unwrap_or(ts.len() - start);
+        let word = &ts[start..start + len];
+        match self.months.get(&word.to_lowercase()) {
+            Some(english) => {
+                // "janv." and the like: the abbreviation's dot goes too.
+                let rest = &ts[start + len..];
+                let rest = rest.strip_prefix('.').unwrap_or(rest);
+                Cow::Owned(format!("{}{}{}", &ts[..start], english, rest))
+            }
+            None => Cow::Borrowed(ts),
+        }
+    }
+
+    fn warn_once(&self, fallback: Fallback, ts: &str) {
+        self.warned[fallback as usize].call_once(|| match fallback {
+            Fallback::NoOffset => eprintln!(
+                "warning: timestamps without a UTC offset (first: {:?}) are read as {}",
+                ts,
+                self.assume_tz.map(|tz| tz.to_string()).unwrap_or_default()
+            ),
+            Fallback::TwoDigitYear => {
+                eprintln!("warning: two-digit years (first: {:?}) are read as 1969-2068", ts)
+            }
+            Fallback::YearSince1900 => {
+                eprintln!("warning: years counted from 1900 (first: {:?}) are read as 1900 + year", ts)
+            }
+        });
+    }
+
+    fn strict(ts: &str, format: &str) -> Option<DateTime<FixedOffset>> {
+        if format == RFC3339 {
+            return DateTime::parse_from_rfc3339(ts).ok();
+        }
+        match DateTime::parse_from_str(ts, format) {
+            Ok(dt) => Some(dt),
+            // Epoch seconds carry no offset but are UTC by definition.
+            Err(e) if e.kind() == ParseErrorKind::NotEnough && format.contains("%s") => {
+                NaiveDateTime::parse_from_str(ts, format).ok().map(|dt| dt.and_utc().fixed_offset())
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// `format` with its offset left off, for timestamps logged without one.
+    fn without_offset(format: &str) -> Option<String> {
+        if format == RFC3339 {
+            return Some("%Y-%m-%dT%H:%M:%S%.f".to_string());
+        }
+        let stripped = ["%::z", "%:z", "%#z", "%z"].iter().fold(format.to_string(), |f, z| f.replace(z, ""));
+        (stripped != format).then(|| stripped.trim_end().to_string())
+    }
+
+    /// The year chrono read literally from a two-digit or 1900-based field,
+    /// moved into the right century.
+    fn fix_year<T: Datelike>(&self, dt: T, ts: &str) -> T {
+        let (year, fallback) = match dt.year() {
+            y @ 0..69 => (2000 + y, Fallback::TwoDigitYear),
+            y @ 69..100 => (1900 + y, Fallback::TwoDigitYear),
+            y @ 100..1000 => (1900 + y, Fallback::YearSince1900),
+            _ => return dt,
+        };
+        match dt.with_year(year) {
+            Some(fixed) => {
+                self.warn_once(fallback, ts);
+                fixed
+            }
+            None => dt,
+        }
+    }
+
+    pub fn parse(&self, original: &str) -> Result<DateTime<FixedOffset>, ParseError> {
+        let ts = if self.months.is_empty() { Cow::Borrowed(original) } else { self.to_english(original) };
+        let parsed = self.formats.iter().find_map(|format| Self::strict(&ts, format));
+        let Some(tz) = self.assume_tz else {
+            return parsed.ok_or_else(|| ParseError::BadTimestamp(original.to_string()));
+        };
+        if let Some(dt) = parsed {
+            return Ok(self.fix_year(dt, original));
+        }
+
+        // The year is fixed before localizing: zones had other offsets then.
+        let lenient = self.formats.iter().find_map(|format| {
+            let naive = NaiveDateTime::parse_from_str(&ts, &Self::without_offset(format)?).ok()?;
+            let dt = tz.localize(self.fix_year(naive, original))?;
+            self.warn_once(Fallback::NoOffset, original);
+            Some(dt)
+        });
+        match lenient {
+            Some(dt) => Ok(dt),
+            None => Err(ParseError::BadTimestamp(original.to_string())),
+        }
+    }
+}
+
+fn issn_check_digit(digits: &[u32]) -> char {
+    let sum: u32 = digits.iter().zip((2..=8).rev()).map(|(d, w)| d * w).sum();
+    match (11 - sum % 11) % 11 {
+        10 => 'X',
+        c => char::from_digit(c, 10).expect("single digit"),
+    }
+}
+
+/// First ISSN with a valid check digit, normalized to `NNNN-NNNC`. The
+/// hyphen is required except in `issn=` query parameters, since bare
+/// 8-digit runs are mostly record IDs.
+pub fn extract_issn(path: &str, query: Option<&str>) -> Option<String> {
+    static HYPHENATED: OnceLock<Regex> = OnceLock::new();
+    static PARAM: OnceLock<Regex> = OnceLock::new();
+    let hyphenated = HYPHENATED.get_or_init(|| {
+        Regex::new(r"(?i)(?:^|[^\dA-Z])(\d{4})-(\d{3}[\dX])(?:$|[^\dA-Z])").expect("regex compiles")
+    });
+    let param = PARAM.get_or_init(|| {
+        Regex::new(r"(?i)(?:^|[&;])e?issn=(\d{4})-?(\d{3}[\dX])(?:$|[&;])").expect("regex compiles")
+    });
+
+    let candidates = query
+        .into_iter()
+        .flat_map(|q| param.captures_iter(q))
+        .chain(hyphenated.captures_iter(path))
+        .chain(query.into_iter().flat_map(|q| hyphenated.captures_iter(q)));
+
+    for caps in candidates {
+        let issn = format!("{}{}", &caps[1], &caps[2]).to_ascii_uppercase();
+        let digits: Vec<u32> = issn[..7].chars().filter_map(|c| c.to_digit(10)).collect();
+        if issn.ends_with(issn_check_digit(&digits)) {
+            return Some(format!("{}-{}", &issn[..4], &issn[4..]));
+        }
+    }
+    None
+}
+
+fn isbn13_sum(digits: &[u32]) -> u32 {
+    digits
+        .iter()
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 0 { *d } else { d * 3 })
+        .sum()
 }
 
-pub fn parse_line(line: &str) -> Result<LogRow> {
-    // remote_addr SP identd SP user_or_session SP [ts] SP "METHOD URL HTTP/x" SP status SP bytes SP "country" SP "ua"
+fn digit_string(digits: &[u32]) -> String {
+    digits
+        .iter()
+        .map(|d| char::from_digit(*d, 10).expect("single digit"))
+        .collect()
+}
+
+fn isbn10_to_13(isbn10: &str) -> Option<String> {
+    let chars: Vec<char> = isbn10.chars().collect();
+    let sum: u32 = chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'X' | 'x' if i == 9 => Some(10),
+            c => c.to_digit(10),
+        })
+        .collect::<Option<Vec<u32>>>()?
+        .iter()
+        .zip((1..=10).rev())
+        .map(|(d, w)| d * w)
+        .sum();
+    if !sum.is_multiple_of(11) {
+        return None;
+    }
+
+    let mut digits: Vec<u32> = [9, 7, 8]
+        .into_iter()
+        .chain(chars[..9].iter().filter_map(|c| c.to_digit(10)))
+        .collect();
+    digits.push((10 - isbn13_sum(&digits) % 10) % 10);
+    Some(digit_string(&digits))
+}
+
+/// First valid ISBN, normalized to 13 digits without hyphens. ISBN-13s are
+/// recognized anywhere; ISBN-10s only in `isbn=` query parameters.
+pub fn extract_isbn(path: &str, query: Option<&str>) -> Option<String> {
+    static ISBN13: OnceLock<Regex> = OnceLock::new();
+    static PARAM10: OnceLock<Regex> = OnceLock::new();
+    let isbn13 = ISBN13.get_or_init(|| {
+        Regex::new(r"(?:^|[^\d-])(97[89](?:-?\d){10})(?:$|[^\d-])").expect("regex compiles")
+    });
+    let param10 = PARAM10.get_or_init(|| {
+        Regex::new(r"(?i)(?:^|[&;])isbn=(\d(?:-?\d){8}-?[\dX])(?:$|[&;])").expect("regex compiles")
+    });
+
+    let thirteen = std::iter::once(path)
+        .chain(query)
+        .flat_map(|s| isbn13.captures_iter(s))
+        .find_map(|caps| {
+            let digits: Vec<u32> = caps[1].chars().filter_map(|c| c.to_digit(10)).collect();
+            isbn13_sum(&digits).is_multiple_of(10).then(|| digit_string(&digits))
+        });
+
+    thirteen.or_else(|| {
+        query
+            .into_iter()
+            .flat_map(|q| param10.captures_iter(q))
+            .find_map(|caps| isbn10_to_13(&caps[1].replace('-', "")))
+    })
+}
+
+/// Why `parse_line` couldn't produce a row.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The line isn't laid out like an EZproxy log line
+    NoMatch,
+    /// The timestamp matched none of the configured formats
+    BadTimestamp(String),
+    BadStatus(String),
+    /// Neither `-` nor a byte count
+    BadBytes(String),
+    /// The URL couldn't be split into its parts. `row` has every other
+    /// field; scheme, host, port, path, and query are empty.
+    BadUrl { row: Box<LogRow>, error: url::ParseError },
+}
+
+impl ParseError {
+    /// Short name for counting failures by reason.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::NoMatch => "no_match",
+            ParseError::BadTimestamp(_) => "bad_timestamp",
+            ParseError::BadStatus(_) => "bad_status",
+            ParseError::BadBytes(_) => "bad_bytes",
+            ParseError::BadUrl { .. } => "bad_url",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NoMatch => write!(f, "line did not match expected format"),
+            ParseError::BadTimestamp(ts) => write!(f, "timestamp {:?} matched none of the configured formats", ts),
+            ParseError::BadStatus(s) => write!(f, "status {:?} is not a number", s),
+            ParseError::BadBytes(b) => write!(f, "bytes {:?} is neither - nor a number", b),
+            ParseError::BadUrl { row, error } => write!(f, "URL {:?} could not be parsed: {}", row.url, error),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::BadUrl { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_line(line: &str, timestamps: &Timestamps) -> Result<LogRow, ParseError> {
+    // remote_addr SP identd SP user_or_session SP [ts] SP "METHOD URL HTTP/x" SP status SP bytes SP "country" SP "ua" [SP "referrer"]
     // country may be e.g. "US", "TR", "VN", or "98"
     //
     // Capture groups:
@@ -53,32 +528,33 @@ pub fn parse_line(line: &str) -> Result<LogRow> {
     // 9 bytes or -
     // 10 country
     // 11 user-agent
+    // 12 referrer, when the LogFormat ends with "%{Referer}i"
     //
     // NOTE: This assumes the request is fully quoted and country/ua are quoted.
     static RE: OnceLock<Regex> = OnceLock::new();
     let re = RE.get_or_init(|| {
-        Regex::new(r#"^(\S+)\s+(\S+)\s+(\S+)\s+\[([^\]]+)\]\s+"(\S+)\s+(\S+)\s+([^"]+)"\s+(\d{3})\s+(\S+)\s+"([^"]*)"\s+"([^"]*)"\s*$"#)
+        Regex::new(r#"^(\S+)\s+(\S+)\s+(\S+)\s+\[([^\]]+)\]\s+"(\S+)\s+(\S+)\s+([^"]+)"\s+(\d{3})\s+(\S+)\s+"([^"]*)"\s+"([^"]*)"(?:\s+"([^"]*)")?\s*$"#)
             .expect("regex compiles")
     });
 
     let caps = re
         .captures(line)
-        .ok_or_else(|| anyhow!("line did not match expected format"))?;
+        .ok_or(ParseError::NoMatch)?;
 
     let remote_addr = caps[1].to_string();
     let identd = none_if_dash(&caps[2]);
     let user_or_session = none_if_dash(&caps[3]);
-    let ts = parse_ts(&caps[4])?;
+    let ts = timestamps.parse(&caps[4])?;
 
     let method = caps[5].to_string();
     let url_str = caps[6].to_string();
     let http_version = caps[7].to_string();
 
-    let status: i32 = caps[8].parse()?;
+    let status: i32 = caps[8].parse().map_err(|_| ParseError::BadStatus(caps[8].to_string()))?;
 
     let bytes = match &caps[9] {
         "-" => None,
-        x => Some(x.parse::<i64>()?),
+        x => Some(x.parse::<i64>().map_err(|_| ParseError::BadBytes(x.to_string()))?),
     };
 
     let country = {
@@ -91,11 +567,17 @@ pub fn parse_line(line: &str) -> Result<LogRow> {
         if ua.is_empty() { None } else { Some(ua.to_string()) }
     };
 
+    let referrer = caps.get(12).and_then(|m| none_if_dash(m.as_str())).filter(|r| !r.is_empty());
+
     // Parse URL into components (best-effort; URL can be huge)
-    let (scheme, host, port, path, query) = match Url::parse(&url_str) {
+    let parsed_url = Url::parse(&url_str);
+    let (scheme, host, port, path, query) = match &parsed_url {
         Ok(u) => (
             Some(u.scheme().to_string()),
-            u.host_str().map(|s| s.to_string()),
+            u.host().map(|h| match h {
+                Host::Domain(d) => ascii_host(d),
+                ip => ip.to_string(),
+            }),
             u.port().map(|p| p as i32),
             Some(u.path().to_string()),
             u.query().map(|q| q.to_string()),
@@ -103,7 +585,10 @@ pub fn parse_line(line: &str) -> Result<LogRow> {
         Err(_) => (None, None, None, None, None),
     };
 
-    Ok(LogRow {
+    let host_unicode = host.as_deref().map(unicode_host);
+    let file_ext = path.as_deref().and_then(file_ext);
+
+    let row = LogRow {
         remote_addr,
         identd,
         user_or_session,
@@ -112,6 +597,7 @@ pub fn parse_line(line: &str) -> Result<LogRow> {
         url: url_str,
         scheme,
         host,
+        host_unicode,
         port,
         path,
         query,
@@ -121,5 +607,18 @@ pub fn parse_line(line: &str) -> Result<LogRow> {
         country,
         user_agent,
         raw: line.to_string(),
-    })
+        issn: None,
+        isbn: None,
+        referrer,
+        browser: None,
+        path_template: None,
+        request_kind: None,
+        target_host: None,
+        url_sha256: None,
+        file_ext,
+    };
+    match parsed_url {
+        Ok(_) => Ok(row),
+        Err(error) => Err(ParseError::BadUrl { row: Box::new(row), error }),
+    }
 }
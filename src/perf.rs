@@ -0,0 +1,117 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde_json::json;
+
+/// Upper bounds of the histogram buckets, in milliseconds. Anything slower
+/// than the last lands in an overflow bucket.
+const BUCKETS_MS: &[f64] = &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Per bucket, not cumulative; one more than `BUCKETS_MS` for overflow
+    counts: Vec<u64>,
+    count: u64,
+    sum_ms: f64,
+    max_ms: f64,
+}
+
+impl Histogram {
+    fn record(&mut self, ms: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; BUCKETS_MS.len() + 1];
+        }
+        let i = BUCKETS_MS.iter().position(|b| ms <= *b).unwrap_or(BUCKETS_MS.len());
+        self.counts[i] += 1;
+        self.count += 1;
+        self.sum_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile; the maximum seen
+    /// when that is the overflow bucket.
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return BUCKETS_MS.get(i).copied().unwrap_or(self.max_ms).min(self.max_ms);
+            }
+        }
+        self.max_ms
+    }
+}
+
+/// How long each endpoint's database work took since the server started.
+/// Kept in memory only; a restart starts over.
+#[derive(Debug, Default)]
+pub struct Timings {
+    by_name: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Timings {
+    pub fn record(&self, name: &str, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let mut by_name = self.by_name.lock().unwrap_or_else(|e| e.into_inner());
+        by_name.entry(name.to_string()).or_default().record(ms);
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, Histogram> {
+        self.by_name.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Summary per endpoint for `/api/perf`, slowest p95 first.
+    pub fn to_json(&self) -> serde_json::Value {
+        let round = |ms: f64| (ms * 10.0).round() / 10.0;
+        let mut rows: Vec<_> = self
+            .snapshot()
+            .into_iter()
+            .map(|(name, h)| {
+                let buckets: Vec<_> = h
+                    .counts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, n)| json!({ "le_ms": BUCKETS_MS.get(i), "count": n }))
+                    .collect();
+                json!({
+                    "endpoint": name,
+                    "count": h.count,
+                    "mean_ms": round(h.sum_ms / h.count.max(1) as f64),
+                    "p50_ms": round(h.quantile(0.5)),
+                    "p95_ms": round(h.quantile(0.95)),
+                    "max_ms": round(h.max_ms),
+                    "buckets": buckets,
+                })
+            })
+            .collect();
+        rows.sort_by(|a, b| b["p95_ms"].as_f64().partial_cmp(&a["p95_ms"].as_f64()).unwrap_or(std::cmp::Ordering::Equal));
+        json!({ "bucket_bounds_ms": BUCKETS_MS, "endpoints": rows })
+    }
+
+    /// The same histograms in the Prometheus text format, in seconds.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP ezvis_query_duration_seconds Time spent on the database per endpoint.\n");
+        out.push_str("# TYPE ezvis_query_duration_seconds histogram\n");
+        for (name, h) in self.snapshot() {
+            let label = name.replace('\\', "\\\\").replace('"', "\\\"");
+            let mut cumulative = 0;
+            for (i, n) in h.counts.iter().enumerate() {
+                cumulative += n;
+                let le = match BUCKETS_MS.get(i) {
+                    Some(ms) => (ms / 1000.0).to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(out, "ezvis_query_duration_seconds_bucket{{endpoint=\"{}\",le=\"{}\"}} {}", label, le, cumulative);
+            }
+            let _ = writeln!(out, "ezvis_query_duration_seconds_sum{{endpoint=\"{}\"}} {}", label, h.sum_ms / 1000.0);
+            let _ = writeln!(out, "ezvis_query_duration_seconds_count{{endpoint=\"{}\"}} {}", label, h.count);
+        }
+        out
+    }
+}
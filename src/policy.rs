@@ -0,0 +1,245 @@
+use anyhow::{Result, bail};
+use chrono::{Days, Duration, NaiveDate};
+use duckdb::{Connection, params, params_from_iter};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::{PolicyConfig, PolicyKind},
+    db, logins,
+};
+
+/// Who a request counts against: the user, or the IP when there is none.
+const SUBJECT: &str = "COALESCE(NULLIF(user_or_session, ''), remote_addr)";
+
+/// `alerts.source` for rows written here.
+const SOURCE: &str = "policy";
+
+#[derive(Debug, Serialize)]
+pub struct PolicySummary {
+    /// First and last day checked
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub policies: Vec<PolicyCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PolicyCount {
+    pub name: String,
+    /// Users (or IPs) and days in breach, whether or not already recorded
+    pub violations: u64,
+}
+
+/// Reject policies missing what their kind needs, before any are checked.
+pub fn validate(policies: &[PolicyConfig]) -> Result<()> {
+    for (i, p) in policies.iter().enumerate() {
+        if policies[..i].iter().any(|q| q.name == p.name) {
+            bail!("policy {:?} is defined twice", p.name);
+        }
+        match p.kind {
+            PolicyKind::MaxCountriesPerDay if p.max.is_none() => {
+                bail!("policy {:?}: max_countries_per_day needs max", p.name)
+            }
+            PolicyKind::MaxLoginFailuresPerDay if p.max.is_none() => {
+                bail!("policy {:?}: max_login_failures_per_day needs max", p.name)
+            }
+            PolicyKind::BlockedCountries | PolicyKind::AllowedCountries if p.countries.is_empty() => {
+                bail!("policy {:?}: needs countries", p.name)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// `(?, ?, ...)` for `n` bind args.
+fn placeholders(n: usize) -> String {
+    format!("({})", vec!["?"; n].join(", "))
+}
+
+/// Subject, day, and alert detail for every breach of `policy` from `start` on.
+fn find(conn: &Connection, policy: &PolicyConfig, start: NaiveDate) -> Result<Vec<(String, String, serde_json::Value)>> {
+    let mut args: Vec<String> = vec![format!("{}T00:00:00Z", start)];
+    let exempt_cond = if policy.exempt_users.is_empty() {
+        "TRUE".to_string()
+    } else {
+        args.extend(policy.exempt_users.iter().cloned());
+        format!("COALESCE(user_or_session, '') NOT IN {}", placeholders(policy.exempt_users.len()))
+    };
+    let (country_cond, having) = match policy.kind {
+        PolicyKind::MaxCountriesPerDay => {
+            ("TRUE".to_string(), format!("HAVING count(DISTINCT country) > {}", policy.max.unwrap_or_default()))
+        }
+        PolicyKind::BlockedCountries => {
+            args.extend(policy.countries.iter().cloned());
+            (format!("country IN {}", placeholders(policy.countries.len())), String::new())
+        }
+        PolicyKind::AllowedCountries => {
+            args.extend(policy.countries.iter().cloned());
+            (format!("country NOT IN {}", placeholders(policy.countries.len())), String::new())
+        }
+        PolicyKind::MaxLoginFailuresPerDay => return find_login_failures(conn, policy, &exempt_cond, args),
+    };
+    let sql = format!(
+        r#"
+        SELECT {SUBJECT} AS subject,
+               CAST(CAST(CAST(ts AS TIMESTAMP) AS DATE) AS VARCHAR) AS day,
+               string_agg(DISTINCT country, ',' ORDER BY country) AS countries,
+               count(*) AS requests
+        FROM requests
+        WHERE ts >= CAST(? AS TIMESTAMPTZ)
+          AND {exempt_cond}
+          AND country IS NOT NULL AND country <> ''
+          AND {country_cond}
+        GROUP BY 1, 2
+        {having}
+        ORDER BY 2, 1
+        "#
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(args), |r| {
+        let countries: String = r.get(2)?;
+        let requests: i64 = r.get(3)?;
+        let detail = json!({
+            "countries": countries.split(',').collect::<Vec<_>>(),
+            "requests": requests,
+        });
+        Ok((r.get(0)?, r.get(1)?, detail))
+    })?;
+    Ok(rows.collect::<duckdb::Result<Vec<_>>>()?)
+}
+
+/// IPs refused at the login form more than `policy.max` times in a day, with
+/// the user agents they used. Guessing scripts rarely log in, so the IP is the
+/// subject even when a user was logged.
+fn find_login_failures(
+    conn: &Connection,
+    policy: &PolicyConfig,
+    exempt_cond: &str,
+    args: Vec<String>,
+) -> Result<Vec<(String, String, serde_json::Value)>> {
+    let sql = format!(
+        r#"
+        SELECT remote_addr AS subject,
+               CAST(CAST(CAST(ts AS TIMESTAMP) AS DATE) AS VARCHAR) AS day,
+               count(*) AS failures,
+               string_agg(DISTINCT COALESCE(user_agent, ''), chr(10) ORDER BY COALESCE(user_agent, '')) AS user_agents
+        FROM requests
+        WHERE ts >= CAST(? AS TIMESTAMPTZ)
+          AND {exempt_cond}
+          AND remote_addr IS NOT NULL
+          AND {failed}
+        GROUP BY 1, 2
+        HAVING count(*) > {max}
+        ORDER BY 2, 1
+        "#,
+        failed = logins::LOGIN_FAILURE_CONDITION,
+        max = policy.max.unwrap_or_default(),
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(args), |r| {
+        let failures: i64 = r.get(2)?;
+        let user_agents: String = r.get(3)?;
+        let detail = json!({"failures": failures, "user_agents": user_agents.split('\n').collect::<Vec<_>>()});
+        Ok((r.get(0)?, r.get(1)?, detail))
+    })?;
+    Ok(rows.collect::<duckdb::Result<Vec<_>>>()?)
+}
+
+/// Check every policy against the whole days of `window` ending on the last day with
+/// data, and record each breach in the alerts table. Logs are usually
+/// imported after the fact, so the window follows the data, as baselines
+/// do. Rechecking a day updates its alerts and removes those that no longer
+/// apply, such as for a user since exempted.
+pub fn check(conn: &Connection, policies: &[PolicyConfig], window: Duration) -> Result<PolicySummary> {
+    validate(policies)?;
+    let end: Option<NaiveDate> = conn
+        .query_row(
+            "SELECT CAST(CAST(max(CAST(ts AS TIMESTAMP)) AS DATE) AS VARCHAR) FROM requests",
+            params![],
+            |r| r.get::<_, Option<String>>(0),
+        )?
+        .and_then(|d| d.parse().ok());
+    let Some(end) = end else {
+        return Ok(PolicySummary { start: None, end: None, policies: Vec::new() });
+    };
+    let start = end - Days::new(window.num_days().max(1) as u64 - 1);
+
+    let mut counts = Vec::new();
+    for policy in policies {
+        let found = find(conn, policy, start)?;
+        conn.execute_batch("BEGIN TRANSACTION")?;
+        let res = (|| -> Result<()> {
+            for (subject, day, detail) in &found {
+                conn.execute(
+                    r#"
+                    INSERT INTO alerts (source, rule, subject, day, detail)
+                    VALUES (?, ?, ?, CAST(? AS DATE), ?)
+                    ON CONFLICT (source, rule, subject, day) DO UPDATE SET detail = excluded.detail, updated_at = now()
+                    "#,
+                    params![SOURCE, policy.name, subject, day, detail.to_string()],
+                )?;
+            }
+            // now() is the transaction's start, so rows touched above keep.
+            conn.execute(
+                "DELETE FROM alerts WHERE source = ? AND rule = ? AND day >= CAST(? AS DATE) AND updated_at < now()",
+                params![SOURCE, policy.name, start.to_string()],
+            )?;
+            Ok(())
+        })();
+        match res {
+            Ok(()) => conn.execute_batch("COMMIT")?,
+            Err(e) => {
+                let _ = conn.execute_batch("ROLLBACK");
+                return Err(e);
+            }
+        }
+        counts.push(PolicyCount { name: policy.name.clone(), violations: found.len() as u64 });
+    }
+    Ok(PolicySummary { start: Some(start.to_string()), end: Some(end.to_string()), policies: counts })
+}
+
+/// Recorded violations between the days of `start` and `end` (dates or
+/// timestamps, inclusive), optionally for one rule, newest first, with a count per rule.
+pub fn violations(
+    conn: &Connection,
+    start: Option<&str>,
+    end: Option<&str>,
+    rule: Option<&str>,
+) -> Result<serde_json::Value> {
+    let mut conds = vec!["source = ?"];
+    let mut args = vec![SOURCE.to_string()];
+    for (cond, arg) in [("day >= CAST(left(?, 10) AS DATE)", start), ("day <= CAST(left(?, 10) AS DATE)", end), ("rule = ?", rule)] {
+        if let Some(arg) = arg {
+            conds.push(cond);
+            args.push(arg.to_string());
+        }
+    }
+    let cond = conds.join(" AND ");
+
+    let table = db::query_table(
+        conn,
+        &format!(
+            r#"
+            SELECT rule, subject, CAST(day AS VARCHAR) AS day, detail,
+                   CAST(created_at AS VARCHAR) AS first_seen
+            FROM alerts
+            WHERE {cond}
+            ORDER BY day DESC, rule, subject
+            "#
+        ),
+        params_from_iter(&args),
+    )?;
+    let mut rows = table.to_objects();
+    for row in &mut rows {
+        if let Some(detail) = row["detail"].as_str().and_then(|d| serde_json::from_str::<serde_json::Value>(d).ok()) {
+            row["detail"] = detail;
+        }
+    }
+    let by_rule = db::query_table(
+        conn,
+        &format!("SELECT rule, count(*) AS violations FROM alerts WHERE {cond} GROUP BY rule ORDER BY rule"),
+        params_from_iter(&args),
+    )?;
+    Ok(json!({ "by_rule": by_rule.to_objects(), "violations": rows }))
+}
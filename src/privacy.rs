@@ -0,0 +1,140 @@
+use serde_json::Value;
+
+use crate::config::{PrivacyConfig, SmallGroups};
+
+/// Fields of a bucket that count something and so are rounded under
+/// `SmallGroups::Round`. Codes, years, and other labels are left alone.
+const COUNT_FIELDS: &[&str] = &[
+    "n", "users", "requests", "page_views", "downloads", "sessions", "denials", "failures", "ips", "platforms",
+];
+
+/// Apply `[privacy] min_group_users` to an API response: every object in an
+/// array that carries a `users` count below the minimum is dropped, or has
+/// its counts rounded to the nearest multiple of it. Objects without a
+/// `users` count are left as they are, so it only touches breakdowns that
+/// say how many people are behind each bucket.
+pub fn protect(value: Value, cfg: &PrivacyConfig) -> Value {
+    if cfg.min_group_users == 0 {
+        return value;
+    }
+    walk(value, u64::from(cfg.min_group_users), cfg.small_groups)
+}
+
+fn walk(value: Value, k: u64, mode: SmallGroups) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .filter_map(|item| {
+                    let small = item.get("users").and_then(Value::as_u64).is_some_and(|u| u < k);
+                    match (small, mode) {
+                        (true, SmallGroups::Suppress) => None,
+                        (true, SmallGroups::Round) => Some(round(walk(item, k, mode), k)),
+                        (false, _) => Some(walk(item, k, mode)),
+                    }
+                })
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(map.into_iter().map(|(key, v)| (key, walk(v, k, mode))).collect()),
+        other => other,
+    }
+}
+
+fn round(mut item: Value, k: u64) -> Value {
+    if let Value::Object(map) = &mut item {
+        for field in COUNT_FIELDS {
+            if let Some(n) = map.get(*field).and_then(Value::as_u64) {
+                map.insert(field.to_string(), ((n + k / 2) / k * k).into());
+            }
+        }
+    }
+    item
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cfg(min_group_users: u32, small_groups: SmallGroups) -> PrivacyConfig {
+        PrivacyConfig { min_group_users, small_groups, ..PrivacyConfig::default() }
+    }
+
+    fn countries() -> Value {
+        json!({ "countries": [
+            { "country": "US", "n": 120, "users": 40 },
+            { "country": "IS", "n": 7, "users": 1 },
+            { "country": "LU", "n": 3, "users": 4 },
+        ] })
+    }
+
+    #[test]
+    fn suppress_drops_small_buckets() {
+        let out = protect(countries(), &cfg(5, SmallGroups::Suppress));
+        assert_eq!(out, json!({ "countries": [{ "country": "US", "n": 120, "users": 40 }] }));
+    }
+
+    #[test]
+    fn round_keeps_small_buckets_with_counts_rounded() {
+        let out = protect(countries(), &cfg(5, SmallGroups::Round));
+        assert_eq!(
+            out,
+            json!({ "countries": [
+                { "country": "US", "n": 120, "users": 40 },
+                { "country": "IS", "n": 5, "users": 0 },
+                { "country": "LU", "n": 5, "users": 5 },
+            ] })
+        );
+    }
+
+    #[test]
+    fn round_leaves_labels_and_large_buckets_alone() {
+        let value = json!([
+            { "status": 404, "year": 2026, "n": 12, "users": 2 },
+            { "status": 200, "year": 2026, "n": 12, "users": 9 },
+        ]);
+        let out = protect(value, &cfg(5, SmallGroups::Round));
+        assert_eq!(
+            out,
+            json!([
+                { "status": 404, "year": 2026, "n": 10, "users": 0 },
+                { "status": 200, "year": 2026, "n": 12, "users": 9 },
+            ])
+        );
+    }
+
+    #[test]
+    fn nested_arrays_are_protected_too() {
+        let value = json!({ "groups": [
+            { "name": "History", "users": 30, "platforms": [
+                { "platform": "jstor", "users": 25 },
+                { "platform": "muse", "users": 2 },
+            ] },
+            { "name": "Classics", "users": 3, "platforms": [{ "platform": "jstor", "users": 3 }] },
+        ] });
+        let out = protect(value, &cfg(5, SmallGroups::Suppress));
+        assert_eq!(
+            out,
+            json!({ "groups": [
+                { "name": "History", "users": 30, "platforms": [{ "platform": "jstor", "users": 25 }] },
+            ] })
+        );
+
+        let rows = json!([[{ "users": 1 }, { "users": 9 }]]);
+        assert_eq!(protect(rows, &cfg(5, SmallGroups::Suppress)), json!([[{ "users": 9 }]]));
+    }
+
+    #[test]
+    fn buckets_without_users_are_kept() {
+        let value = json!({ "status": [{ "status": 500, "n": 1 }], "total": { "users": 1 } });
+        assert_eq!(protect(value.clone(), &cfg(5, SmallGroups::Suppress)), value);
+    }
+
+    #[test]
+    fn zero_minimum_changes_nothing() {
+        for mode in [SmallGroups::Suppress, SmallGroups::Round] {
+            assert_eq!(protect(countries(), &cfg(0, mode)), countries());
+        }
+    }
+}
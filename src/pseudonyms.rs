@@ -0,0 +1,79 @@
+use anyhow::{Result, bail};
+use duckdb::{Connection, params};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{db, enrich};
+
+/// Resolutions listed by `list`, newest first.
+const MAX_LISTED: usize = 200;
+
+/// A request to find out who is behind a pseudonym of the anonymize stage.
+#[derive(Debug, Deserialize)]
+pub struct Resolution {
+    /// As stored in `user_or_session`, e.g. `anon-3f2a...`
+    pub pseudonym: String,
+    /// `[enrich.anonymize] key` as it was when the requests were imported;
+    /// never stored
+    pub key: String,
+    /// Why, for the audit trail, e.g. an incident ticket
+    pub reason: String,
+    /// Usernames to try; the loaded `users` if left out
+    #[serde(default)]
+    pub candidates: Option<Vec<String>>,
+}
+
+/// Map a pseudonym back to a username. The pseudonyms are keyed hashes and
+/// can't be reversed, so each candidate is hashed with the key supplied
+/// and compared. Every attempt is recorded in `pseudonym_resolutions` with
+/// `caller`, the pseudonym, and the reason before anything is returned, but
+/// neither the key nor the username found.
+pub fn resolve(conn: &Connection, req: &Resolution, caller: &str) -> Result<serde_json::Value> {
+    let reason = req.reason.trim();
+    if reason.is_empty() {
+        bail!("a reason is required");
+    }
+    if req.key.is_empty() {
+        bail!("a key is required");
+    }
+    let candidates: Vec<String> = match &req.candidates {
+        Some(c) => c.clone(),
+        None => {
+            let mut stmt = conn.prepare("SELECT user_id FROM users ORDER BY user_id")?;
+            let ids = stmt.query_map(params![], |r| r.get(0))?;
+            ids.collect::<duckdb::Result<_>>()?
+        }
+    };
+    let matches: Vec<&String> = candidates.iter().filter(|c| enrich::pseudonym(&req.key, c) == req.pseudonym).collect();
+
+    conn.execute(
+        "INSERT INTO pseudonym_resolutions (caller, pseudonym, reason, candidates, matched) VALUES (?, ?, ?, ?, ?)",
+        params![caller, req.pseudonym, reason, candidates.len() as i64, !matches.is_empty()],
+    )?;
+    let (requests, first, last): (i64, Option<String>, Option<String>) = conn.query_row(
+        "SELECT count(*), CAST(min(ts) AS VARCHAR), CAST(max(ts) AS VARCHAR) FROM requests WHERE user_or_session = ?",
+        params![req.pseudonym],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )?;
+    Ok(json!({
+        "pseudonym": req.pseudonym,
+        "matches": matches,
+        "candidates": candidates.len(),
+        "requests": requests,
+        "first_seen": first,
+        "last_seen": last,
+    }))
+}
+
+/// Recorded resolutions, newest first.
+pub fn list(conn: &Connection) -> Result<Vec<serde_json::Value>> {
+    let table = db::query_table(
+        conn,
+        &format!(
+            "SELECT id, CAST(created_at AS VARCHAR) AS created_at, caller, pseudonym, reason, candidates, matched \
+             FROM pseudonym_resolutions ORDER BY id DESC LIMIT {MAX_LISTED}"
+        ),
+        params![],
+    )?;
+    Ok(table.to_objects())
+}
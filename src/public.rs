@@ -0,0 +1,85 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use duckdb::{Connection, OptionalExt, params};
+use serde_json::json;
+
+use crate::{
+    config::{PortsConfig, PublicConfig},
+    db, downloads,
+};
+
+/// Figures for the public stats page: per-month totals for the
+/// `cfg.months` months up to the newest request, and the busiest platforms
+/// over the same months. Only page views of vendor resources are counted,
+/// and nothing below `cfg.min_users` distinct users is shown, so no figure
+/// can be traced back to a handful of patrons. No user, IP, path, or day
+/// appears in the output.
+pub fn stats(conn: &Connection, cfg: &PublicConfig, ports: &PortsConfig) -> Result<serde_json::Value> {
+    let newest: Option<i64> =
+        conn.query_row("SELECT max(epoch_us(ts)) FROM requests", params![], |r| r.get(0)).optional()?.flatten();
+    let Some(newest) = newest.and_then(DateTime::<Utc>::from_timestamp_micros) else {
+        return Ok(json!({ "title": cfg.title, "months": [], "platforms": [] }));
+    };
+    let this_month = NaiveDate::from_ymd_opt(newest.year(), newest.month(), 1).unwrap_or_default();
+    let first = this_month - Months::new(cfg.months.max(1) - 1);
+    let start = format!("{}T00:00:00Z", first);
+    let filter = format!("ts >= CAST(? AS TIMESTAMPTZ) AND {} AND {}", db::PAGE_VIEW_CONDITION, db::RESOURCE_CONDITION);
+    let min_users = cfg.min_users;
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        WITH {downloads},
+        dl AS (SELECT strftime(day, '%Y-%m') AS month, count(*) AS n FROM d GROUP BY 1),
+        m AS (
+          SELECT strftime(CAST(ts AS TIMESTAMP), '%Y-%m') AS month,
+                 count(*) AS page_views,
+                 count(DISTINCT COALESCE(user_or_session, remote_addr)) AS users
+          FROM requests WHERE {filter}
+          GROUP BY 1
+        )
+        SELECT m.month, m.page_views, m.users, COALESCE(dl.n, 0)
+        FROM m LEFT JOIN dl USING (month)
+        WHERE m.users >= {min_users}
+        ORDER BY 1
+        "#,
+        downloads = downloads::cte(&filter, ports),
+    ))?;
+    let mut rows = stmt.query(params![start, start])?;
+    let mut months = Vec::new();
+    while let Some(r) = rows.next()? {
+        let month: String = r.get(0)?;
+        let page_views: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        let downloads: i64 = r.get(3)?;
+        months.push(json!({"month": month, "page_views": page_views, "users": users, "downloads": downloads}));
+    }
+
+    let host = db::target_host(ports);
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT {host} AS platform, count(*) AS page_views
+        FROM requests
+        WHERE {host} IS NOT NULL AND {filter}
+        GROUP BY 1
+        HAVING count(DISTINCT COALESCE(user_or_session, remote_addr)) >= {min_users}
+        ORDER BY page_views DESC, platform
+        LIMIT {limit}
+        "#,
+        limit = cfg.top_platforms,
+    ))?;
+    let mut rows = stmt.query(params![start])?;
+    let mut platforms = Vec::new();
+    while let Some(r) = rows.next()? {
+        let platform: String = r.get(0)?;
+        let page_views: i64 = r.get(1)?;
+        platforms.push(json!({"platform": platform, "page_views": page_views}));
+    }
+
+    Ok(json!({
+        "title": cfg.title,
+        "start": first.to_string(),
+        "end": newest.date_naive().to_string(),
+        "months": months,
+        "platforms": platforms,
+    }))
+}
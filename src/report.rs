@@ -0,0 +1,295 @@
+use std::fmt::Write as _;
+
+use anyhow::{Result, bail};
+use chrono::{Days, NaiveDate};
+use duckdb::{Connection, params};
+use serde::Serialize;
+
+use crate::{
+    config::{CalendarConfig, CalendarPeriod, PortsConfig},
+    db, summary, trends,
+};
+
+/// A platform whose requests rose or fell by more than this share between
+/// the periods is flagged.
+const FLAG_CHANGE_PCT: f64 = 50.0;
+/// ... provided it had at least this many requests in one of them, so
+/// small platforms swinging from 3 to 9 requests stay quiet.
+const FLAG_MIN_REQUESTS: i64 = 1000;
+/// Unique users overall changing by more than this share is flagged.
+const FLAG_USERS_PCT: f64 = 20.0;
+/// A period with requests on fewer than this share of its days is flagged
+/// as probably missing logs.
+const FLAG_MIN_COVERAGE: f64 = 0.9;
+/// Periods whose lengths differ by more than this share are flagged, since
+/// their totals aren't like for like.
+const FLAG_LENGTH_PCT: f64 = 10.0;
+
+#[derive(Debug, Serialize)]
+pub struct PeriodTotals {
+    pub name: String,
+    pub start: NaiveDate,
+    /// Inclusive
+    pub end: NaiveDate,
+    pub days: i64,
+    /// Days with at least one request
+    pub days_with_data: i64,
+    pub requests: i64,
+    pub requests_per_day: f64,
+    pub gb: f64,
+    pub users: i64,
+    pub platforms: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlatformDelta {
+    pub platform: String,
+    pub requests_a: i64,
+    pub requests_b: i64,
+    /// None when the platform had no requests in period a
+    pub requests_change_pct: Option<f64>,
+    pub users_a: i64,
+    pub users_b: i64,
+    pub users_change: i64,
+}
+
+/// Something in a comparison worth a sentence in the written report.
+#[derive(Debug, Serialize)]
+pub struct Flag {
+    /// `new_platform`, `dropped_platform`, `surge`, `decline`,
+    /// `users_shift`, `incomplete_data`, or `length_mismatch`
+    pub kind: &'static str,
+    pub platform: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub a: PeriodTotals,
+    pub b: PeriodTotals,
+    pub requests_change_pct: Option<f64>,
+    /// Per day, which is fairer when the periods differ in length
+    pub requests_per_day_change_pct: Option<f64>,
+    pub users_change: i64,
+    pub users_change_pct: Option<f64>,
+    /// Busiest first, by the larger of the two periods
+    pub platforms: Vec<PlatformDelta>,
+    pub flags: Vec<Flag>,
+}
+
+/// Period names compare loosely, so `--a 2025-fall` finds "2025 Fall".
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase().replace([' ', '_'], "-")
+}
+
+/// The `[[calendar.periods]]` entry called `name`.
+pub fn period<'a>(cfg: &'a CalendarConfig, name: &str) -> Result<&'a CalendarPeriod> {
+    let wanted = normalize(name);
+    match cfg.periods.iter().find(|p| normalize(&p.name) == wanted) {
+        Some(p) => Ok(p),
+        None if cfg.periods.is_empty() => bail!("no calendar period named {:?}: [calendar] has no periods", name),
+        None => bail!(
+            "no calendar period named {:?}; known periods: {}",
+            name,
+            cfg.periods.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+/// SQL condition for requests within `p`, whole days in UTC. The dates come
+/// from the config, parsed, so they are safe to interpolate.
+fn within(p: &CalendarPeriod) -> String {
+    format!(
+        "(ts >= CAST('{}' AS TIMESTAMP) AND ts < CAST('{}' AS TIMESTAMP))",
+        p.start,
+        p.end + Days::new(1)
+    )
+}
+
+fn period_totals(conn: &Connection, p: &CalendarPeriod, ports: &PortsConfig) -> Result<PeriodTotals> {
+    let cond = within(p);
+    let t = summary::totals(conn, &cond, &[], ports)?;
+    let days_with_data: i64 = conn.query_row(
+        &format!("SELECT count(DISTINCT CAST(CAST(ts AS TIMESTAMP) AS DATE)) FROM requests WHERE {cond}"),
+        params![],
+        |r| r.get(0),
+    )?;
+    let days = (p.end - p.start).num_days() + 1;
+    Ok(PeriodTotals {
+        name: p.name.clone(),
+        start: p.start,
+        end: p.end,
+        days,
+        days_with_data,
+        requests: t.requests,
+        requests_per_day: (t.requests as f64 / days as f64 * 10.0).round() / 10.0,
+        gb: t.gb,
+        users: t.users,
+        platforms: t.hosts,
+    })
+}
+
+/// Compare usage in calendar periods `a` and `b` (usually two terms, `b`
+/// the later): totals, the `top` busiest platforms side by side, and flags
+/// for the changes a term report would mention.
+pub fn compare(
+    conn: &Connection,
+    cfg: &CalendarConfig,
+    a: &str,
+    b: &str,
+    top: usize,
+    ports: &PortsConfig,
+) -> Result<Comparison> {
+    let (pa, pb) = (period(cfg, a)?, period(cfg, b)?);
+    let (ta, tb) = (period_totals(conn, pa, ports)?, period_totals(conn, pb, ports)?);
+
+    let (in_a, in_b) = (within(pa), within(pb));
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT {host} AS platform,
+               count(*) FILTER (WHERE {in_a}) AS requests_a,
+               count(*) FILTER (WHERE {in_b}) AS requests_b,
+               count(DISTINCT user_or_session) FILTER (WHERE {in_a}) AS users_a,
+               count(DISTINCT user_or_session) FILTER (WHERE {in_b}) AS users_b
+        FROM requests
+        WHERE {host} IS NOT NULL AND ({in_a} OR {in_b})
+        GROUP BY 1
+        ORDER BY greatest(requests_a, requests_b) DESC, 1
+        LIMIT {top}
+        "#,
+        host = db::target_host(ports)
+    ))?;
+    let platforms = stmt
+        .query_map(params![], |r| {
+            let (requests_a, requests_b, users_a, users_b): (i64, i64, i64, i64) =
+                (r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?);
+            Ok(PlatformDelta {
+                platform: r.get(0)?,
+                requests_a,
+                requests_b,
+                requests_change_pct: trends::pct_change(requests_b as f64, requests_a as f64),
+                users_a,
+                users_b,
+                users_change: users_b - users_a,
+            })
+        })?
+        .collect::<duckdb::Result<Vec<_>>>()?;
+
+    let flags = flags(&ta, &tb, &platforms);
+    Ok(Comparison {
+        requests_change_pct: trends::pct_change(tb.requests as f64, ta.requests as f64),
+        requests_per_day_change_pct: trends::pct_change(tb.requests_per_day, ta.requests_per_day),
+        users_change: tb.users - ta.users,
+        users_change_pct: trends::pct_change(tb.users as f64, ta.users as f64),
+        a: ta,
+        b: tb,
+        platforms,
+        flags,
+    })
+}
+
+fn flags(a: &PeriodTotals, b: &PeriodTotals, platforms: &[PlatformDelta]) -> Vec<Flag> {
+    let mut flags = Vec::new();
+    for t in [a, b] {
+        if (t.days_with_data as f64) < t.days as f64 * FLAG_MIN_COVERAGE {
+            flags.push(Flag {
+                kind: "incomplete_data",
+                platform: None,
+                message: format!(
+                    "{} has requests on only {} of its {} days; logs may be missing",
+                    t.name, t.days_with_data, t.days
+                ),
+            });
+        }
+    }
+    if trends::pct_change(b.days as f64, a.days as f64).is_some_and(|p| p.abs() > FLAG_LENGTH_PCT) {
+        flags.push(Flag {
+            kind: "length_mismatch",
+            platform: None,
+            message: format!(
+                "{} is {} days and {} is {}; compare per-day figures",
+                a.name, a.days, b.name, b.days
+            ),
+        });
+    }
+    if let Some(pct) = trends::pct_change(b.users as f64, a.users as f64).filter(|p| p.abs() > FLAG_USERS_PCT) {
+        flags.push(Flag {
+            kind: "users_shift",
+            platform: None,
+            message: format!("unique users {} {}% ({} -> {})", updown(pct), pct.abs(), a.users, b.users),
+        });
+    }
+    for p in platforms {
+        if p.requests_a.max(p.requests_b) < FLAG_MIN_REQUESTS {
+            continue;
+        }
+        let (kind, message) = match p.requests_change_pct {
+            _ if p.requests_a == 0 => ("new_platform", format!("no use in {}, {} requests in {}", a.name, p.requests_b, b.name)),
+            _ if p.requests_b == 0 => ("dropped_platform", format!("{} requests in {}, none in {}", p.requests_a, a.name, b.name)),
+            Some(pct) if pct.abs() > FLAG_CHANGE_PCT => (
+                if pct > 0.0 { "surge" } else { "decline" },
+                format!("requests {} {}% ({} -> {})", updown(pct), pct.abs(), p.requests_a, p.requests_b),
+            ),
+            _ => continue,
+        };
+        flags.push(Flag { kind, platform: Some(p.platform.clone()), message: format!("{}: {}", p.platform, message) });
+    }
+    flags
+}
+
+fn updown(pct: f64) -> &'static str {
+    if pct >= 0.0 { "up" } else { "down" }
+}
+
+fn pct(p: Option<f64>) -> String {
+    p.map_or("-".to_string(), |p| format!("{:+}%", p))
+}
+
+/// `c` as a plaintext report.
+pub fn render(c: &Comparison) -> Result<String> {
+    let mut out = String::new();
+    let (a, b) = (&c.a, &c.b);
+    writeln!(out, "ezvis compare: {} ({} .. {}) -> {} ({} .. {})", a.name, a.start, a.end, b.name, b.start, b.end)?;
+    writeln!(out)?;
+    writeln!(out, "{:<18} {:>12} {:>12} {:>9}", "", a.name, b.name, "change")?;
+    writeln!(out, "{:<18} {:>12} {:>12} {:>9}", "Requests", a.requests, b.requests, pct(c.requests_change_pct))?;
+    writeln!(
+        out,
+        "{:<18} {:>12} {:>12} {:>9}",
+        "Requests per day",
+        a.requests_per_day,
+        b.requests_per_day,
+        pct(c.requests_per_day_change_pct)
+    )?;
+    writeln!(out, "{:<18} {:>12} {:>12} {:>9}", "Unique users", a.users, b.users, pct(c.users_change_pct))?;
+    writeln!(out, "{:<18} {:>12} {:>12}", "Bandwidth (GB)", a.gb, b.gb)?;
+    writeln!(out, "{:<18} {:>12} {:>12}", "Platforms", a.platforms, b.platforms)?;
+
+    writeln!(out)?;
+    writeln!(out, "Platforms:")?;
+    if c.platforms.is_empty() {
+        writeln!(out, "  (none)")?;
+    }
+    for p in &c.platforms {
+        writeln!(
+            out,
+            "  {:>10} {:>10} {:>9}  users {:>6} -> {:<6} {}",
+            p.requests_a,
+            p.requests_b,
+            pct(p.requests_change_pct),
+            p.users_a,
+            p.users_b,
+            p.platform
+        )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Flags:")?;
+    if c.flags.is_empty() {
+        writeln!(out, "  (none)")?;
+    }
+    for f in &c.flags {
+        writeln!(out, "  {}", f.message)?;
+    }
+    Ok(out)
+}
@@ -0,0 +1,125 @@
+use anyhow::Result;
+use duckdb::{Connection, params};
+use serde_json::json;
+
+use crate::db;
+
+/// What each table holds, for tables created by `db::init_schema`.
+const TABLES: &[(&str, &str)] = &[
+    ("requests", "One row per imported log line; a view over monthly tables named requests_YYYY_MM"),
+    ("imports", "One row per import run, with its row counts"),
+    ("jobs", "Background jobs and their results"),
+    ("baseline_meta", "When the detection baseline was built and over which window"),
+    ("baseline_host_hourly", "Expected requests per host and hour of the week"),
+    ("baseline_user_hourly", "Expected requests per user and hour of the day"),
+    ("baseline_countries", "Share of each host's and user's traffic per country"),
+    ("federation_daily", "Daily per-host totals pulled from consortium members"),
+    ("api_tokens", "API tokens; only hashes of the secrets"),
+    ("export_manifests", "Signed manifests of integrity exports"),
+    ("alerts", "Policy violations and honeytoken hits, one per rule, user, and day"),
+    ("platform_costs", "Annual cost per platform, loaded by costs import"),
+    ("access_audit", "Who requested which endpoint with which filters, when auth is on"),
+    ("pseudonym_resolutions", "Audit trail of pseudonyms mapped back to usernames"),
+    ("users", "Department, status, and affiliation per user, loaded by users import"),
+    ("host_seen", "Earliest and latest request per host (target_host, else host), kept up by imports"),
+    ("user_seen", "Earliest and latest request per user, kept up by imports; users are forgotten with their requests by a prune"),
+    ("siem_cursor", "How far alerts and selected requests have been forwarded to the [siem]"),
+];
+
+/// Column descriptions for `requests`, the table ad-hoc SQL is written
+/// against. Other tables are listed without them.
+const REQUEST_COLUMNS: &[(&str, &str)] = &[
+    ("ts", "Request time"),
+    ("remote_addr", "Client IP address; truncated by the anonymize stage"),
+    ("identd", "Ident string, almost always empty"),
+    ("user_or_session", "Username or EZproxy session ID; pseudonymized by the anonymize stage"),
+    ("method", "HTTP method"),
+    ("url", "Requested URL; cut short with a trailing … by the truncate stage"),
+    ("scheme", "http or https, from the URL"),
+    ("host", "Host name, from the URL: lowercase, with internationalized names in punycode (xn--)"),
+    ("port", "Port, when the URL gives one other than the scheme's default; part of the host key with [ports] split_hosts"),
+    ("path", "URL path; cut short like url"),
+    ("query", "Query string without the leading ?; cut short like url"),
+    ("http_version", "e.g. HTTP/1.1"),
+    ("status", "HTTP status code"),
+    ("bytes", "Response size; empty when logged as -"),
+    ("country", "Country code logged by EZproxy or filled by the geoip stage"),
+    ("user_agent", "User-Agent header"),
+    ("raw", "The original log line; NULL when imported with --no-raw or --raw-compressed"),
+    ("issn", "First valid ISSN in the path or query, as NNNN-NNNC (identifiers stage)"),
+    ("isbn", "First valid ISBN in the path or query, as 13 digits (identifiers stage)"),
+    ("referrer", "Referer header, when the LogFormat includes it"),
+    ("browser", "Browser family (user_agent stage)"),
+    ("path_template", "Path with record IDs replaced by {id} (path_template stage)"),
+    ("raw_zstd", "The original log line, zstd-compressed, when imported with --raw-compressed"),
+    ("request_kind", "content for full-text downloads, asset for scripts, styles, images, and fonts, proxy for EZproxy's login and menu pages, otherwise page_view (request_kind stage)"),
+    ("target_host", "Vendor host recovered from a proxy-by-hostname name; host analytics use it over host (target_host stage)"),
+    ("tls_protocol", "TLS protocol of the request, merged from an SSL or transfer log by transfer import"),
+    ("tls_cipher", "TLS cipher of the request, merged from an SSL or transfer log by transfer import"),
+    ("url_sha256", "SHA-256 of the URL as logged, when the truncate stage cut url short; equal for equal URLs"),
+    ("host_unicode", "host with internationalized names decoded, for display"),
+    ("file_ext", "Lowercased extension of the path's last segment, e.g. pdf or html; NULL when it has none"),
+];
+
+fn quote_ident(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+/// Every table with its columns' types, descriptions, and how many rows
+/// have each one filled in. Fill rates are computed on every call, so a
+/// column an enrichment stage never ran for shows up as mostly NULL.
+/// Monthly partitions are left out; `requests` stands for them.
+pub fn describe(conn: &Connection) -> Result<serde_json::Value> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT table_name, column_name, data_type
+        FROM information_schema.columns
+        WHERE table_schema = 'main' AND NOT regexp_matches(table_name, ?)
+        ORDER BY table_name, ordinal_position
+        "#,
+    )?;
+    let mut rows = stmt.query(params![db::PARTITION_PATTERN])?;
+    let mut tables: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    while let Some(r) = rows.next()? {
+        let table: String = r.get(0)?;
+        let column: String = r.get(1)?;
+        let data_type: String = r.get(2)?;
+        match tables.last_mut() {
+            Some((t, cols)) if *t == table => cols.push((column, data_type)),
+            _ => tables.push((table, vec![(column, data_type)])),
+        }
+    }
+    // Ours first, in the order above; anything else someone created after.
+    let rank = |t: &str| TABLES.iter().position(|(n, _)| *n == t).unwrap_or(TABLES.len());
+    tables.sort_by_key(|(t, _)| rank(t));
+
+    let mut out = Vec::new();
+    for (table, cols) in tables {
+        // One scan per table: count(*) and count(col) for every column.
+        let counts: Vec<String> = cols.iter().map(|(c, _)| format!("count({})", quote_ident(c))).collect();
+        let sql = format!("SELECT count(*), {} FROM {}", counts.join(", "), quote_ident(&table));
+        let filled: Vec<i64> = conn.query_row(&sql, params![], |r| (0..=cols.len()).map(|i| r.get(i)).collect())?;
+        let total = filled[0];
+
+        let columns: Vec<_> = cols
+            .iter()
+            .zip(&filled[1..])
+            .map(|((name, data_type), n)| {
+                let description = (table == "requests")
+                    .then(|| REQUEST_COLUMNS.iter().find(|(c, _)| c == name).map(|(_, d)| *d))
+                    .flatten();
+                let null_pct = (total > 0).then(|| ((total - n) as f64 / total as f64 * 1000.0).round() / 10.0);
+                json!({
+                    "name": name,
+                    "type": data_type,
+                    "description": description,
+                    "non_null": n,
+                    "null_pct": null_pct,
+                })
+            })
+            .collect();
+        let description = TABLES.iter().find(|(t, _)| *t == table).map(|(_, d)| *d);
+        out.push(json!({ "name": table, "description": description, "rows": total, "columns": columns }));
+    }
+    Ok(json!({ "tables": out }))
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, FixedOffset};
+use duckdb::{Connection, params};
+use serde::Serialize;
+
+use crate::parser::LogRow;
+
+/// The host key `host_seen` is kept by: `target_host`, else `host`, without
+/// a port whatever `[ports] split_hosts` says.
+const HOST_NAME: &str = "COALESCE(target_host, host)";
+
+/// Earliest and latest request per host and per user among `rows`, merged
+/// into `host_seen` and `user_seen`. Called with each chunk an import
+/// writes, in the same transaction.
+pub fn record(conn: &Connection, rows: &[LogRow]) -> Result<()> {
+    type Span = (DateTime<FixedOffset>, DateTime<FixedOffset>);
+    fn widen(spans: &mut HashMap<String, Span>, key: &str, ts: DateTime<FixedOffset>) {
+        spans
+            .entry(key.to_string())
+            .and_modify(|(first, last)| {
+                *first = (*first).min(ts);
+                *last = (*last).max(ts);
+            })
+            .or_insert((ts, ts));
+    }
+
+    let mut hosts = HashMap::new();
+    let mut users = HashMap::new();
+    for row in rows {
+        if let Some(host) = row.target_host.as_deref().or(row.host.as_deref()) {
+            widen(&mut hosts, host, row.ts);
+        }
+        if let Some(user) = &row.user_or_session {
+            widen(&mut users, user, row.ts);
+        }
+    }
+    for (table, key, spans) in [("host_seen", "host", hosts), ("user_seen", "user_or_session", users)] {
+        let mut stmt = conn.prepare(&format!(
+            "INSERT INTO {table} ({key}, first_seen, last_seen) VALUES (?, ?, ?)
+             ON CONFLICT ({key}) DO UPDATE SET
+               first_seen = least({table}.first_seen, excluded.first_seen),
+               last_seen = greatest({table}.last_seen, excluded.last_seen)"
+        ))?;
+        for (subject, (first, last)) in spans {
+            stmt.execute(params![subject, first.to_rfc3339(), last.to_rfc3339()])?;
+        }
+    }
+    Ok(())
+}
+
+/// Refill `host_seen` and `user_seen` from the requests there are now: when
+/// the tables are new, and after a backfill rewrote users or hosts.
+pub fn rebuild(conn: &Connection) -> Result<()> {
+    conn.execute_batch(&format!(
+        r#"
+        BEGIN TRANSACTION;
+        DELETE FROM host_seen;
+        INSERT INTO host_seen
+          SELECT {HOST_NAME}, min(ts), max(ts) FROM requests WHERE {HOST_NAME} IS NOT NULL GROUP BY 1;
+        DELETE FROM user_seen;
+        INSERT INTO user_seen
+          SELECT user_or_session, min(ts), max(ts) FROM requests WHERE user_or_session IS NOT NULL GROUP BY 1;
+        COMMIT;
+        "#
+    ))?;
+    Ok(())
+}
+
+/// Forget users not seen since `cutoff` (RFC 3339), as a prune forgets
+/// their requests. Hosts are kept, so a platform that comes back isn't
+/// taken for a new one.
+pub fn forget_users(conn: &Connection, cutoff: &str) -> Result<usize> {
+    Ok(conn.execute("DELETE FROM user_seen WHERE last_seen < CAST(? AS TIMESTAMPTZ)", params![cutoff])?)
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewHost {
+    pub host: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    /// Requests and distinct users since `first_seen`
+    pub requests: i64,
+    pub users: i64,
+}
+
+/// Hosts first seen within `since` of the newest request, newest first.
+pub fn new_hosts(conn: &Connection, since: Duration) -> Result<Vec<NewHost>> {
+    let mut stmt = conn.prepare(
+        r#"
+        WITH s AS (
+          SELECT * FROM host_seen
+          WHERE epoch_us(first_seen) >= (SELECT max(epoch_us(last_seen)) FROM host_seen) - ?
+        )
+        SELECT s.host, CAST(CAST(s.first_seen AS TIMESTAMP) AS VARCHAR), CAST(CAST(s.last_seen AS TIMESTAMP) AS VARCHAR),
+               count(r.ts), count(DISTINCT r.user_or_session)
+        FROM s LEFT JOIN requests r ON COALESCE(r.target_host, r.host) = s.host AND r.ts >= s.first_seen
+        GROUP BY s.host, s.first_seen, s.last_seen
+        ORDER BY s.first_seen DESC, s.host
+        "#,
+    )?;
+    let micros = since.num_microseconds().unwrap_or(i64::MAX);
+    let rows = stmt.query_map(params![micros], |r| {
+        Ok(NewHost { host: r.get(0)?, first_seen: r.get(1)?, last_seen: r.get(2)?, requests: r.get(3)?, users: r.get(4)? })
+    })?;
+    Ok(rows.collect::<duckdb::Result<_>>()?)
+}
+
+/// Whether `init_schema` has still to create the tables, so that it fills
+/// them from the requests already imported.
+pub fn missing(conn: &Connection) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT count(*) = 0 FROM duckdb_tables() WHERE schema_name = 'main' AND table_name = 'host_seen'",
+        params![],
+        |r| r.get(0),
+    )?)
+}
@@ -0,0 +1,312 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// The dashboard and API
+    Serve,
+    /// Importing rotated logs from a directory
+    Watch,
+    /// Background jobs and `[[schedules]]`, without the dashboard
+    Cron,
+}
+
+impl Mode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Serve => "serve",
+            Mode::Watch => "watch",
+            Mode::Cron => "cron",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Mode::Serve => "dashboard",
+            Mode::Watch => "log import watcher",
+            Mode::Cron => "scheduled jobs",
+        }
+    }
+}
+
+/// What `install-service` sets a service up with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Platform {
+    /// A systemd unit
+    Systemd,
+    /// A script registering a Windows service with sc.exe
+    Windows,
+}
+
+impl Platform {
+    pub const HOST: Platform = if cfg!(windows) { Platform::Windows } else { Platform::Systemd };
+}
+
+/// What goes into a unit or Windows service. Paths may be relative; they
+/// are made absolute against the current directory, since neither systemd
+/// nor the service control manager has a notion of one.
+#[derive(Debug, Clone)]
+pub struct ServiceOptions {
+    pub mode: Mode,
+    pub user: String,
+    pub binary: PathBuf,
+    pub db: PathBuf,
+    pub config: Option<PathBuf>,
+    /// serve: address to listen on
+    pub bind: String,
+    /// watch: log directory, file pattern, and where imported files go
+    pub dir: Option<PathBuf>,
+    pub pattern: String,
+    pub move_to: Option<PathBuf>,
+    /// `KEY=VALUE` pairs for `Environment=`
+    pub env: Vec<String>,
+}
+
+fn absolute(p: &Path) -> Result<PathBuf> {
+    std::path::absolute(p).with_context(|| format!("resolve {}", p.display()))
+}
+
+/// One ExecStart argument, quoted when it needs to be. `%` starts a
+/// specifier in unit files, so it is doubled.
+fn quote_arg(s: &str) -> String {
+    let s = s.replace('%', "%%");
+    if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';' | '$')) {
+        return s;
+    }
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$"))
+}
+
+fn quote_path(p: &Path) -> String {
+    quote_arg(&p.to_string_lossy())
+}
+
+/// Under a directory `ProtectHome=yes` would hide entirely.
+fn in_home(p: &Path) -> bool {
+    ["/home", "/root", "/run/user"].iter().any(|h| p.starts_with(h))
+}
+
+/// A systemd unit running `ezvis <mode>` as `user`, with the filesystem
+/// read-only except for the database directory (and, for watch, the log
+/// directories), no privilege escalation, and no access to devices, other
+/// users' homes, or the kernel's tunables.
+pub fn unit(opts: &ServiceOptions) -> Result<String> {
+    if opts.user.is_empty() || opts.user == "root" {
+        bail!("run the service as a dedicated unprivileged user, not {:?}", opts.user);
+    }
+    for pair in &opts.env {
+        if pair.split_once('=').is_none_or(|(k, _)| k.is_empty()) {
+            bail!("--env takes KEY=VALUE, got {:?}", pair);
+        }
+    }
+    let binary = absolute(&opts.binary)?;
+    let db = absolute(&opts.db)?;
+    let data_dir = db.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("/"));
+    let config = opts.config.as_deref().map(absolute).transpose()?;
+
+    let mut args = vec![quote_path(&binary)];
+    if let Some(config) = &config {
+        args.extend(["--config".to_string(), quote_path(config)]);
+    }
+    args.push(opts.mode.as_str().to_string());
+    // The database directory is where DuckDB keeps its WAL and temp files,
+    // and where relative paths in the config (like export.dir) land.
+    let mut writable = vec![data_dir.clone()];
+    let mut readable: Vec<PathBuf> = config.iter().cloned().collect();
+    let mut low_port = false;
+    match opts.mode {
+        Mode::Serve => {
+            args.extend(["--bind".to_string(), quote_arg(&opts.bind)]);
+            low_port = opts.bind.rsplit(':').next().and_then(|p| p.parse::<u16>().ok()).is_some_and(|p| p < 1024);
+        }
+        Mode::Cron => {}
+        Mode::Watch => {
+            let Some(dir) = &opts.dir else {
+                bail!("--mode watch needs --dir");
+            };
+            let dir = absolute(dir)?;
+            args.push(quote_path(&dir));
+            args.extend(["--pattern".to_string(), quote_arg(&opts.pattern)]);
+            if let Some(to) = &opts.move_to {
+                let to = absolute(to)?;
+                args.extend(["--move-to".to_string(), quote_path(&to)]);
+                // Moving a file out of a directory writes to both.
+                writable.push(dir);
+                writable.push(to);
+            } else {
+                readable.push(dir);
+            }
+        }
+    }
+    args.extend(["--db".to_string(), quote_path(&db)]);
+
+    let mut all_paths = writable.iter().chain(&readable).chain([&binary]);
+    let protect_home = if all_paths.any(|p| in_home(p)) { "read-only" } else { "yes" };
+
+    let mut u = String::new();
+    u.push_str("[Unit]\n");
+    u.push_str(&format!("Description=ezvis {}\n", opts.mode.description()));
+    u.push_str("After=network-online.target\nWants=network-online.target\n\n");
+
+    u.push_str("[Service]\nType=simple\n");
+    u.push_str(&format!("User={}\nGroup={}\n", opts.user, opts.user));
+    // A single path, taken verbatim rather than as a quoted word.
+    u.push_str(&format!("WorkingDirectory={}\n", data_dir.to_string_lossy().replace('%', "%%")));
+    u.push_str(&format!("ExecStart={}\n", args.join(" ")));
+    if opts.mode == Mode::Serve {
+        // serve rereads its config on SIGHUP.
+        u.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+    }
+    for pair in &opts.env {
+        u.push_str(&format!("Environment={}\n", quote_arg(pair)));
+    }
+    u.push_str("Restart=on-failure\nRestartSec=5\n\n");
+
+    u.push_str("# Hardening\n");
+    u.push_str("NoNewPrivileges=yes\n");
+    u.push_str("ProtectSystem=strict\n");
+    u.push_str(&format!("ProtectHome={}\n", protect_home));
+    for p in &writable {
+        u.push_str(&format!("ReadWritePaths={}\n", quote_path(p)));
+    }
+    u.push_str("PrivateTmp=yes\nPrivateDevices=yes\n");
+    u.push_str("ProtectKernelTunables=yes\nProtectKernelModules=yes\nProtectKernelLogs=yes\n");
+    u.push_str("ProtectControlGroups=yes\nProtectClock=yes\nProtectHostname=yes\n");
+    u.push_str("RestrictNamespaces=yes\nRestrictRealtime=yes\nRestrictSUIDSGID=yes\n");
+    u.push_str("LockPersonality=yes\nMemoryDenyWriteExecute=yes\n");
+    u.push_str("SystemCallArchitectures=native\nSystemCallFilter=@system-service\n");
+    // serve talks HTTP, and serve and cron to federation members and
+    // webhooks; watch only needs local sockets.
+    u.push_str(match opts.mode {
+        Mode::Serve | Mode::Cron => "RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6\n",
+        Mode::Watch => "RestrictAddressFamilies=AF_UNIX\nIPAddressDeny=any\n",
+    });
+    if low_port {
+        u.push_str("AmbientCapabilities=CAP_NET_BIND_SERVICE\nCapabilityBoundingSet=CAP_NET_BIND_SERVICE\n");
+    } else {
+        u.push_str("CapabilityBoundingSet=\n");
+    }
+    u.push_str("UMask=0027\n\n");
+
+    u.push_str("[Install]\nWantedBy=multi-user.target\n");
+    Ok(u)
+}
+
+/// One argument of a Windows command line, quoted when it needs to be.
+/// Windows paths can't contain `"`.
+fn win_quote(s: &str) -> String {
+    if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || matches!(c, '&' | '|' | '<' | '>' | '^')) {
+        return s.to_string();
+    }
+    format!("\"{}\"", s)
+}
+
+/// The account a service runs as unless `--user` says otherwise: `ezvis`
+/// under systemd, and on Windows a virtual account of the service's own,
+/// which needs no password and can be granted access like any other.
+pub fn default_user(platform: Platform, mode: Mode) -> String {
+    match platform {
+        Platform::Systemd => "ezvis".to_string(),
+        Platform::Windows => format!("NT SERVICE\\ezvis-{}", mode.as_str()),
+    }
+}
+
+/// `opts.user`, which has to be a virtual or built-in account other than
+/// SYSTEM; accounts that need a password aren't supported.
+fn windows_account(opts: &ServiceOptions) -> Result<String> {
+    let user = opts.user.trim();
+    let upper = user.to_ascii_uppercase();
+    if !(upper.starts_with("NT SERVICE\\") || upper.starts_with("NT AUTHORITY\\")) {
+        bail!("Windows services run as an NT SERVICE\\ or NT AUTHORITY\\ account here, not {:?}", user);
+    }
+    if upper == "NT AUTHORITY\\SYSTEM" {
+        bail!("run the service as a dedicated unprivileged account, not {:?}", user);
+    }
+    Ok(user.to_string())
+}
+
+/// A `.cmd` script, run from an elevated prompt, that registers
+/// `ezvis --service <mode>` as a Windows service starting at boot and
+/// restarting on failure, under `windows_account`, with write access
+/// granted on the database directory (and, for watch, the log directories)
+/// and nothing else.
+pub fn windows_script(opts: &ServiceOptions) -> Result<String> {
+    for pair in &opts.env {
+        if pair.split_once('=').is_none_or(|(k, _)| k.is_empty()) {
+            bail!("--env takes KEY=VALUE, got {:?}", pair);
+        }
+    }
+    let account = windows_account(opts)?;
+    let name = format!("ezvis-{}", opts.mode.as_str());
+    let binary = absolute(&opts.binary)?;
+    let db = absolute(&opts.db)?;
+    let data_dir = db.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("\\"));
+    let config = opts.config.as_deref().map(absolute).transpose()?;
+
+    let mut args = vec![win_quote(&binary.to_string_lossy()), "--service".to_string()];
+    if let Some(config) = &config {
+        args.extend(["--config".to_string(), win_quote(&config.to_string_lossy())]);
+    }
+    args.push(opts.mode.as_str().to_string());
+    let mut writable = vec![data_dir];
+    let mut readable: Vec<PathBuf> = config.iter().cloned().collect();
+    match opts.mode {
+        Mode::Serve => args.extend(["--bind".to_string(), win_quote(&opts.bind)]),
+        Mode::Cron => {}
+        Mode::Watch => {
+            let Some(dir) = &opts.dir else {
+                bail!("--mode watch needs --dir");
+            };
+            let dir = absolute(dir)?;
+            args.push(win_quote(&dir.to_string_lossy()));
+            args.extend(["--pattern".to_string(), win_quote(&opts.pattern)]);
+            if let Some(to) = &opts.move_to {
+                let to = absolute(to)?;
+                args.extend(["--move-to".to_string(), win_quote(&to.to_string_lossy())]);
+                writable.push(dir);
+                writable.push(to);
+            } else {
+                readable.push(dir);
+            }
+        }
+    }
+    args.extend(["--db".to_string(), win_quote(&db.to_string_lossy())]);
+    writable.sort();
+    writable.dedup();
+    // sc.exe takes the whole command line as one argument, with the quotes
+    // inside escaped; `%` is doubled since this is a batch file.
+    let bin_path = args.join(" ").replace('"', "\\\"").replace('%', "%%");
+    let batch = |s: &str| s.replace('%', "%%");
+
+    let mut s = String::new();
+    s.push_str("@echo off\r\n");
+    s.push_str(&format!("rem ezvis {} as the Windows service {}; run from an elevated prompt.\r\n", opts.mode.description(), name));
+    s.push_str(&format!(
+        "sc.exe create {} binPath= \"{}\" start= delayed-auto obj= \"{}\" DisplayName= \"ezvis {}\" || exit /b 1\r\n",
+        name,
+        bin_path,
+        account,
+        opts.mode.description()
+    ));
+    s.push_str(&format!("sc.exe description {} \"ezvis {}\"\r\n", name, opts.mode.description()));
+    s.push_str(&format!("sc.exe failure {} reset= 86400 actions= restart/5000/restart/5000/restart/60000\r\n", name));
+    // Only the privileges a service account always needs.
+    s.push_str(&format!("sc.exe privs {} SeChangeNotifyPrivilege/SeCreateGlobalPrivilege\r\n", name));
+    if !opts.env.is_empty() {
+        s.push_str(&format!(
+            "reg add \"HKLM\\SYSTEM\\CurrentControlSet\\Services\\{}\" /v Environment /t REG_MULTI_SZ /d \"{}\" /f\r\n",
+            name,
+            batch(&opts.env.join("\\0"))
+        ));
+    }
+    for p in &writable {
+        s.push_str(&format!("icacls {} /grant \"{}:(OI)(CI)M\"\r\n", win_quote(&batch(&p.to_string_lossy())), account));
+    }
+    for p in &readable {
+        let inherit = if p.is_dir() { "(OI)(CI)" } else { "" };
+        s.push_str(&format!("icacls {} /grant \"{}:{}RX\"\r\n", win_quote(&batch(&p.to_string_lossy())), account, inherit));
+    }
+    s.push_str(&format!("sc.exe start {}\r\n", name));
+    Ok(s)
+}
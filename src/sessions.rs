@@ -0,0 +1,219 @@
+use anyhow::Result;
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, db};
+
+/// Inactivity after which a user's next request starts a new session.
+pub const SESSION_GAP_SECS: i64 = 30 * 60;
+
+/// Requests for these are page furniture, not something a patron chose to
+/// open, so they don't count as page views.
+pub const ASSET_EXTENSIONS: &[&str] = &[
+    "js", "css", "map", "png", "jpg", "jpeg", "gif", "svg", "ico", "webp", "woff", "woff2", "ttf",
+    "eot",
+];
+
+/// CTEs reconstructing sessions from page-level requests matching `filter`.
+/// Ends in `session_pages(who, session_no, ts, host, path, dwell_s)`, where
+/// `who` is the user (or IP when no user was logged), `ts` is a UTC
+/// TIMESTAMP, and `dwell_s` is the time until the next page view in the
+/// same session (NULL for the last one).
+pub fn cte(filter: &str, ports: &PortsConfig) -> String {
+    let assets = ASSET_EXTENSIONS.join("|");
+    let host = db::target_host(ports);
+    format!(
+        r#"
+        pages AS (
+          SELECT COALESCE(user_or_session, remote_addr) AS who,
+                 CAST(ts AS TIMESTAMP) AS ts, {host} AS host, path
+          FROM requests
+          WHERE {filter}
+            AND NOT regexp_matches(lower(COALESCE(path, '')), '\.({assets})$')
+        ),
+        marked AS (
+          SELECT *,
+                 CASE WHEN epoch(ts) - epoch(lag(ts) OVER (PARTITION BY who ORDER BY ts)) <= {SESSION_GAP_SECS}
+                      THEN 0 ELSE 1 END AS new_session
+          FROM pages
+        ),
+        numbered AS (
+          SELECT *,
+                 sum(new_session) OVER (PARTITION BY who ORDER BY ts ROWS UNBOUNDED PRECEDING) AS session_no
+          FROM marked
+        ),
+        session_pages AS (
+          SELECT who, session_no, ts, host, path,
+                 epoch(lead(ts) OVER (PARTITION BY who, session_no ORDER BY ts)) - epoch(ts) AS dwell_s
+          FROM numbered
+        )
+        "#
+    )
+}
+
+/// CTEs splitting each user's requests matching `filter` into visits per
+/// platform, apart from their other platforms. Ends in
+/// `platform_sessions(host, who, first_us, last_us)`, where `host` is the
+/// lowercased platform and the times are epoch microseconds.
+pub fn platform_cte(filter: &str, ports: &PortsConfig) -> String {
+    let host = db::target_host(ports);
+    let gap_us = SESSION_GAP_SECS * 1_000_000;
+    format!(
+        r#"
+        platform_requests AS (
+          SELECT lower({host}) AS host, COALESCE(user_or_session, remote_addr) AS who, epoch_us(ts) AS t
+          FROM requests
+          WHERE {host} IS NOT NULL AND {filter}
+        ),
+        platform_marked AS (
+          SELECT *,
+                 CASE WHEN t - lag(t) OVER (PARTITION BY host, who ORDER BY t) <= {gap_us}
+                      THEN 0 ELSE 1 END AS new_session
+          FROM platform_requests
+        ),
+        platform_numbered AS (
+          SELECT *,
+                 sum(new_session) OVER (PARTITION BY host, who ORDER BY t ROWS UNBOUNDED PRECEDING) AS session_no
+          FROM platform_marked
+        ),
+        platform_sessions AS (
+          SELECT host, who, min(t) AS first_us, max(t) AS last_us
+          FROM platform_numbered GROUP BY host, who, session_no
+        )
+        "#
+    )
+}
+
+fn quantiles(conn: &Connection, sql: &str, args: &[String]) -> Result<serde_json::Value> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let r = rows.next()?.expect("aggregate returns one row");
+    let count: i64 = r.get(0)?;
+    let mean: Option<f64> = r.get(1)?;
+    let p25: Option<f64> = r.get(2)?;
+    let median: Option<f64> = r.get(3)?;
+    let p75: Option<f64> = r.get(4)?;
+    let p90: Option<f64> = r.get(5)?;
+    Ok(json!({
+        "count": count,
+        "mean_s": mean,
+        "p25_s": p25,
+        "median_s": median,
+        "p75_s": p75,
+        "p90_s": p90,
+    }))
+}
+
+const STATS: &str = r#"
+    count(x), avg(x),
+    quantile_cont(x, 0.25), quantile_cont(x, 0.5),
+    quantile_cont(x, 0.75), quantile_cont(x, 0.9)
+"#;
+
+/// Distribution of session lengths (first to last page view, sessions with
+/// at least two pages) and of dwell time between consecutive page views.
+pub fn durations(conn: &Connection, filter: &str, args: &[String], ports: &PortsConfig) -> Result<serde_json::Value> {
+    let cte = cte(filter, ports);
+
+    let per_session = format!(
+        r#"
+        WITH {cte},
+        s AS (
+          SELECT who, session_no, count(*) AS pages, epoch(max(ts)) - epoch(min(ts)) AS secs
+          FROM session_pages GROUP BY 1, 2
+        )
+        "#
+    );
+
+    let sessions = quantiles(
+        conn,
+        &format!("{per_session} SELECT {STATS} FROM (SELECT secs AS x FROM s WHERE pages > 1)"),
+        args,
+    )?;
+    let dwell = quantiles(
+        conn,
+        &format!("WITH {cte} SELECT {STATS} FROM (SELECT dwell_s AS x FROM session_pages WHERE dwell_s IS NOT NULL)"),
+        args,
+    )?;
+
+    let sql = format!(
+        r#"
+        {per_session}
+        SELECT count(*) AS sessions,
+               count(*) FILTER (WHERE pages = 1) AS single_page,
+               count(*) FILTER (WHERE pages > 1 AND secs < 60) AS under_1m,
+               count(*) FILTER (WHERE pages > 1 AND secs >= 60 AND secs < 300) AS m1_5,
+               count(*) FILTER (WHERE pages > 1 AND secs >= 300 AND secs < 900) AS m5_15,
+               count(*) FILTER (WHERE pages > 1 AND secs >= 900 AND secs < 1800) AS m15_30,
+               count(*) FILTER (WHERE pages > 1 AND secs >= 1800 AND secs < 3600) AS m30_60,
+               count(*) FILTER (WHERE pages > 1 AND secs >= 3600) AS h1_plus
+        FROM s
+        "#
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let r = rows.next()?.expect("aggregate returns one row");
+    let total: i64 = r.get(0)?;
+    let single_page: i64 = r.get(1)?;
+    let labels = ["<1m", "1-5m", "5-15m", "15-30m", "30-60m", "1h+"];
+    let mut histogram = Vec::new();
+    for (i, label) in labels.iter().enumerate() {
+        let n: i64 = r.get(i + 2)?;
+        histogram.push(json!({"bucket": label, "n": n}));
+    }
+
+    Ok(json!({
+        "total_sessions": total,
+        "single_page_sessions": single_page,
+        "session_length": sessions,
+        "dwell": dwell,
+        "histogram": histogram,
+    }))
+}
+
+/// First page view of each session, grouped by host and by host + path.
+/// Shows where patrons arrive from: the discovery layer, guides, or direct
+/// database links.
+pub fn entry_pages(
+    conn: &Connection,
+    filter: &str,
+    args: &[String],
+    limit: i64,
+    ports: &PortsConfig,
+) -> Result<serde_json::Value> {
+    let cte = cte(filter, ports);
+    let entries = format!(
+        r#"
+        WITH {cte},
+        entries AS (
+          SELECT host, path FROM session_pages
+          QUALIFY row_number() OVER (PARTITION BY who, session_no ORDER BY ts) = 1
+        )
+        "#
+    );
+
+    let mut stmt = conn.prepare(&format!(
+        "{entries} SELECT host, count(*) AS n FROM entries GROUP BY 1 ORDER BY n DESC LIMIT {limit}"
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut hosts = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: Option<String> = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        hosts.push(json!({"host": host, "sessions": n}));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "{entries} SELECT host, path, count(*) AS n FROM entries GROUP BY 1, 2 ORDER BY n DESC LIMIT {limit}"
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut pages = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: Option<String> = r.get(0)?;
+        let path: Option<String> = r.get(1)?;
+        let n: i64 = r.get(2)?;
+        pages.push(json!({"host": host, "path": path, "sessions": n}));
+    }
+
+    Ok(json!({ "hosts": hosts, "pages": pages }))
+}
@@ -0,0 +1,357 @@
+use std::{
+    io::Write,
+    net::{TcpStream, UdpSocket},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, OptionalExt, params};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::{SiemConfig, SiemFormat, SiemKind},
+    db, elastic,
+};
+
+/// Events per syslog burst or HTTP request.
+const BATCH: usize = 500;
+
+/// CEF severity (0-10) of forwarded requests, which are only selected, not
+/// judged.
+const REQUEST_SEVERITY: u8 = 3;
+
+#[derive(Debug, Default, Serialize)]
+pub struct ForwardSummary {
+    /// Alerts raised or updated since the last forward
+    pub alerts: u64,
+    /// Requests matching `[siem] requests` since the last forward
+    pub requests: u64,
+}
+
+/// Reject settings that could never deliver anything.
+pub fn validate(cfg: &SiemConfig) -> Result<()> {
+    match cfg.kind {
+        SiemKind::Syslog => {
+            syslog_target(&cfg.url)?;
+        }
+        SiemKind::Splunk | SiemKind::Elastic => {
+            if !cfg.url.starts_with("http://") && !cfg.url.starts_with("https://") {
+                bail!("siem.url for {:?} must be an http(s) URL, got {:?}", cfg.kind, cfg.url);
+            }
+            if cfg.kind == SiemKind::Splunk && cfg.token.is_none() {
+                bail!("siem.token is needed for Splunk HEC");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `url` is `tcp://` (else `udp://`), and its `host:port`.
+fn syslog_target(url: &str) -> Result<(bool, &str)> {
+    match url.split_once("://") {
+        Some(("udp", addr)) if !addr.is_empty() => Ok((false, addr)),
+        Some(("tcp", addr)) if !addr.is_empty() => Ok((true, addr)),
+        _ => bail!("siem.url for syslog looks like udp://host:514 or tcp://host:514, got {:?}", url),
+    }
+}
+
+/// One alert or request on its way out.
+struct Event {
+    ts: DateTime<Utc>,
+    /// `alert` or `request`
+    kind: &'static str,
+    /// CEF signature ID, e.g. `honeytoken:bait_ebook`
+    signature: String,
+    name: String,
+    severity: u8,
+    body: serde_json::Value,
+}
+
+/// Send alerts raised or updated, and requests matching `[siem] requests`,
+/// since the last forward. Each stream's position is saved as its batches
+/// are delivered, so a SIEM that can't be reached gets them next time.
+pub fn forward(conn: &Connection, cfg: &SiemConfig) -> Result<ForwardSummary> {
+    validate(cfg)?;
+    let mut summary = ForwardSummary::default();
+
+    let since = position(conn, "alerts")?;
+    let mut alerts = Vec::new();
+    db::for_each_row(
+        conn,
+        r#"
+        SELECT epoch_us(updated_at), source, rule, subject, CAST(day AS VARCHAR), severity, detail
+        FROM alerts
+        WHERE epoch_us(updated_at) > ?
+        ORDER BY updated_at, id
+        "#,
+        params![since],
+        |row| {
+            alerts.push(alert_event(row)?);
+            Ok(true)
+        },
+    )?;
+    summary.alerts = send_all(conn, cfg, "alerts", alerts)?;
+
+    if let Some(cond) = &cfg.requests {
+        let since = position(conn, "requests")?;
+        let mut requests = Vec::new();
+        db::for_each_row(
+            conn,
+            &format!(
+                r#"
+                SELECT epoch_us(ts), remote_addr, user_or_session, method, url, host, status, bytes, country, user_agent
+                FROM requests
+                WHERE epoch_us(ts) > ? AND ({cond})
+                ORDER BY ts
+                "#
+            ),
+            params![since],
+            |row| {
+                requests.push(request_event(row)?);
+                Ok(true)
+            },
+        )
+        .context("siem.requests")?;
+        summary.requests = send_all(conn, cfg, "requests", requests)?;
+    }
+    Ok(summary)
+}
+
+/// `forward`, if a SIEM is configured, with failures reported on stderr
+/// rather than failing the import or check that raised the alerts.
+pub fn forward_pending(conn: &Connection, cfg: Option<&SiemConfig>) {
+    let Some(cfg) = cfg else { return };
+    match forward(conn, cfg) {
+        Ok(s) if s.alerts + s.requests > 0 => {
+            eprintln!("siem: forwarded {} alerts and {} requests", s.alerts, s.requests)
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("siem: {:#}; will retry on the next forward", e),
+    }
+}
+
+/// Timestamp, in microseconds, of the last event forwarded from `stream`.
+fn position(conn: &Connection, stream: &str) -> Result<i64> {
+    let at = conn
+        .query_row("SELECT forwarded_to FROM siem_cursor WHERE stream = ?", params![stream], |r| r.get(0))
+        .optional()?;
+    Ok(at.unwrap_or(i64::MIN))
+}
+
+fn parse_ts(v: &serde_json::Value) -> Result<DateTime<Utc>> {
+    v.as_i64().and_then(DateTime::from_timestamp_micros).with_context(|| format!("timestamp {}", v))
+}
+
+fn alert_event(row: &[serde_json::Value]) -> Result<Event> {
+    let (source, rule, subject) = (db::plain_field(&row[1]), db::plain_field(&row[2]), db::plain_field(&row[3]));
+    let severity = db::plain_field(&row[5]);
+    let detail = row[6]
+        .as_str()
+        .and_then(|d| serde_json::from_str(d).ok())
+        .unwrap_or(serde_json::Value::Null);
+    let ts = parse_ts(&row[0])?;
+    let body = json!({
+        "type": "alert",
+        "updated_at": ts.to_rfc3339(),
+        "source": source,
+        "rule": rule,
+        "subject": subject,
+        "day": row[4],
+        "severity": severity,
+        "detail": detail,
+    });
+    Ok(Event {
+        ts,
+        kind: "alert",
+        signature: format!("{}:{}", source, rule),
+        name: match source.as_str() {
+            "honeytoken" => format!("Honeytoken hit: {}", rule),
+            "policy" => format!("Policy violation: {}", rule),
+            _ => format!("{}: {}", source, rule),
+        },
+        severity: match severity.as_str() {
+            "high" => 8,
+            "low" => 3,
+            _ => 5,
+        },
+        body,
+    })
+}
+
+fn request_event(row: &[serde_json::Value]) -> Result<Event> {
+    let ts = parse_ts(&row[0])?;
+    let body = json!({
+        "type": "request",
+        "ts": ts.to_rfc3339(),
+        "remote_addr": row[1],
+        "user_or_session": row[2],
+        "method": row[3],
+        "url": row[4],
+        "host": row[5],
+        "status": row[6],
+        "bytes": row[7],
+        "country": row[8],
+        "user_agent": row[9],
+    });
+    Ok(Event {
+        ts,
+        kind: "request",
+        signature: "request".to_string(),
+        name: "EZproxy request".to_string(),
+        severity: REQUEST_SEVERITY,
+        body,
+    })
+}
+
+/// Deliver `events` in batches, moving `stream`'s position past each one
+/// sent. A batch never ends between events with the same timestamp, since
+/// the position is a timestamp.
+fn send_all(conn: &Connection, cfg: &SiemConfig, stream: &str, events: Vec<Event>) -> Result<u64> {
+    let mut sent = 0;
+    let mut start = 0;
+    while start < events.len() {
+        let mut end = (start + BATCH).min(events.len());
+        while end < events.len() && events[end].ts == events[end - 1].ts {
+            end += 1;
+        }
+        let batch = &events[start..end];
+        send(cfg, batch).with_context(|| format!("send {} to {}", stream, cfg.url))?;
+        conn.execute(
+            r#"
+            INSERT INTO siem_cursor (stream, forwarded_to) VALUES (?, ?)
+            ON CONFLICT (stream) DO UPDATE SET forwarded_to = excluded.forwarded_to
+            "#,
+            params![stream, batch[batch.len() - 1].ts.timestamp_micros()],
+        )?;
+        sent += batch.len() as u64;
+        start = end;
+    }
+    Ok(sent)
+}
+
+fn send(cfg: &SiemConfig, batch: &[Event]) -> Result<()> {
+    match cfg.kind {
+        SiemKind::Syslog => {
+            let (tcp, addr) = syslog_target(&cfg.url)?;
+            let lines: Vec<String> = batch.iter().map(|e| syslog_line(cfg.format, e)).collect();
+            if tcp {
+                let mut stream = TcpStream::connect(addr)?;
+                stream.set_write_timeout(Some(Duration::from_secs(30)))?;
+                for line in &lines {
+                    stream.write_all(line.as_bytes())?;
+                    stream.write_all(b"\n")?;
+                }
+                stream.flush()?;
+            } else {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                for line in &lines {
+                    socket.send(line.as_bytes())?;
+                }
+            }
+        }
+        SiemKind::Splunk => {
+            let body: String = batch
+                .iter()
+                .map(|e| {
+                    json!({
+                        "time": e.ts.timestamp_millis() as f64 / 1000.0,
+                        "source": "ezvis",
+                        "sourcetype": format!("ezvis:{}", e.kind),
+                        "event": e.body,
+                    })
+                    .to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            ureq::post(&cfg.url)
+                .header("Authorization", format!("Splunk {}", cfg.token.as_deref().unwrap_or_default()))
+                .content_type("application/json")
+                .send(body)?;
+        }
+        SiemKind::Elastic => {
+            let mut body = String::new();
+            for e in batch {
+                let index = format!("{}-{}s", cfg.index, e.kind);
+                body.push_str(&json!({ "index": { "_index": index } }).to_string());
+                body.push('\n');
+                let mut doc = e.body.clone();
+                doc["@timestamp"] = json!(e.ts.to_rfc3339());
+                body.push_str(&doc.to_string());
+                body.push('\n');
+            }
+            let target = elastic::Target { url: cfg.url.clone(), api_key: cfg.token.clone() };
+            target.bulk(body)?;
+        }
+    }
+    Ok(())
+}
+
+/// An RFC 5424 syslog message, facility security (13), carrying a CEF or
+/// JSON event.
+fn syslog_line(format: SiemFormat, e: &Event) -> String {
+    // Syslog severities run the other way: 2 (critical) for CEF 8 and up.
+    let level = match e.severity {
+        8.. => 2,
+        5..=7 => 4,
+        _ => 6,
+    };
+    let msg = match format {
+        SiemFormat::Cef => cef(e),
+        SiemFormat::Json => e.body.to_string(),
+    };
+    format!("<{}>1 {} - ezvis - {} - {}", 13 * 8 + level, e.ts.to_rfc3339(), e.kind, msg)
+}
+
+fn cef_header(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_value(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('=', "\\=").replace('\r', "\\r").replace('\n', "\\n")
+}
+
+/// `CEF:0|vendor|product|version|signature|name|severity|extension`, with
+/// the event's fields as standard CEF keys where there are ones.
+fn cef(e: &Event) -> String {
+    let b = &e.body;
+    let mut ext = vec![("rt", e.ts.timestamp_millis().to_string())];
+    let field = |k: &str| b[k].as_str().map(str::to_string).or_else(|| b[k].as_i64().map(|n| n.to_string()));
+    if e.kind == "alert" {
+        let subject = field("subject").unwrap_or_default();
+        // An IP for requests without a user.
+        let key = if subject.parse::<std::net::IpAddr>().is_ok() { "src" } else { "suser" };
+        ext.push((key, subject));
+        ext.push(("cat", field("source").unwrap_or_default()));
+        ext.push(("cs1Label", "detail".to_string()));
+        ext.push(("cs1", b["detail"].to_string()));
+    } else {
+        for (key, name) in [
+            ("src", "remote_addr"),
+            ("suser", "user_or_session"),
+            ("requestMethod", "method"),
+            ("request", "url"),
+            ("dhost", "host"),
+            ("in", "bytes"),
+            ("requestClientApplication", "user_agent"),
+        ] {
+            if let Some(v) = field(name) {
+                ext.push((key, v));
+            }
+        }
+        if let Some(status) = field("status") {
+            ext.push(("cn1Label", "status".to_string()));
+            ext.push(("cn1", status));
+        }
+    }
+    format!(
+        "CEF:0|pulezviz|ezvis|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        cef_header(&e.signature),
+        cef_header(&e.name),
+        e.severity,
+        ext.iter().map(|(k, v)| format!("{}={}", k, cef_value(v))).collect::<Vec<_>>().join(" ")
+    )
+}
@@ -0,0 +1,288 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use duckdb::Connection;
+use serde_json::json;
+
+use crate::{
+    db::{self, RawStorage},
+    elastic,
+    parser::LogRow,
+};
+
+/// Where `import --sink` writes parsed rows instead of the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SinkKind {
+    /// One JSON object per line
+    Jsonl,
+    /// Comma-separated, with a header line and the columns of `requests`
+    Csv,
+    /// A Parquet file with the columns of `requests`
+    Parquet,
+    /// An Elasticsearch or OpenSearch index
+    Elastic,
+    /// A Kafka topic, through a Kafka REST proxy
+    Kafka,
+}
+
+/// What `convert --to` writes: the sinks that make a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl From<Format> for SinkKind {
+    fn from(f: Format) -> Self {
+        match f {
+            Format::Jsonl => SinkKind::Jsonl,
+            Format::Csv => SinkKind::Csv,
+        }
+    }
+}
+
+/// Where a sink writes, for the kinds that need to be told.
+#[derive(Debug, Clone, Default)]
+pub struct SinkOptions {
+    /// File written by jsonl and csv (stdout without it) and parquet
+    pub out: Option<PathBuf>,
+    /// Elastic cluster or Kafka REST proxy base URL
+    pub url: Option<String>,
+    pub index: String,
+    pub topic: String,
+    /// Elastic API key
+    pub api_key: Option<String>,
+    /// How each row's original line is kept; only parquet can hold it
+    /// compressed, so the others send it as text or not at all
+    pub raw: RawStorage,
+}
+
+impl SinkOptions {
+    /// Whether the sink writes to stdout, which then can't carry anything
+    /// else.
+    pub fn to_stdout(&self, kind: SinkKind) -> bool {
+        matches!(kind, SinkKind::Jsonl | SinkKind::Csv) && self.out.is_none()
+    }
+
+    fn url(&self, kind: SinkKind) -> Result<&str> {
+        self.url.as_deref().with_context(|| format!("--sink {:?} needs --url", kind).to_lowercase())
+    }
+}
+
+/// Somewhere parsed rows go other than the database, turning `import` into
+/// a converter. There is no DuckDB sink: writing a database is what
+/// `import` does without `--sink`, through `import::import_file`, which
+/// also checkpoints, resumes, and raises alerts in that same database. A
+/// sink would write one without any of that.
+pub trait Sink {
+    /// Take the next chunk of rows, in file order. Returns how many were
+    /// written and how many were refused.
+    fn write(&mut self, rows: Vec<LogRow>) -> Result<(u64, u64)>;
+    /// Flush anything held back, once the input is done.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// The sink of `kind`, ready to take rows.
+pub fn open(kind: SinkKind, opts: &SinkOptions) -> Result<Box<dyn Sink>> {
+    Ok(match kind {
+        SinkKind::Jsonl => Box::new(Jsonl { out: writer(opts)?, raw: opts.raw }),
+        SinkKind::Csv => {
+            let columns: Vec<&str> = db::REQUEST_COLUMNS
+                .iter()
+                .map(|(c, _)| *c)
+                .filter(|c| *c != "raw_zstd" && (*c != "raw" || opts.raw == RawStorage::Text))
+                .collect();
+            let mut out = writer(opts)?;
+            writeln!(out, "{}", columns.join(","))?;
+            Box::new(Csv { out, columns, raw: opts.raw })
+        }
+        SinkKind::Parquet => {
+            let path = opts.out.clone().context("--sink parquet needs --out")?;
+            let conn = Connection::open_in_memory()?;
+            // So `requests` exists, and an empty input still gives a file
+            // with its columns.
+            db::init_schema(&conn)?;
+            Box::new(Parquet { conn, path, raw: opts.raw })
+        }
+        SinkKind::Elastic => {
+            let target = elastic::Target { url: opts.url(kind)?.to_string(), api_key: opts.api_key.clone() };
+            target.ensure_index(&opts.index, &elastic::mappings())?;
+            Box::new(Elastic { target, index: opts.index.clone(), raw: opts.raw })
+        }
+        SinkKind::Kafka => Box::new(Kafka {
+            url: format!("{}/topics/{}", opts.url(kind)?.trim_end_matches('/'), opts.topic),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(120)))
+                .http_status_as_error(false)
+                .build()
+                .into(),
+            raw: opts.raw,
+        }),
+    })
+}
+
+/// `--out`, or stdout without it.
+fn writer(opts: &SinkOptions) -> Result<BufWriter<Box<dyn Write>>> {
+    let out: Box<dyn Write> = match &opts.out {
+        Some(path) => Box::new(File::create(path).with_context(|| format!("create {}", path.display()))?),
+        None => Box::new(io::stdout().lock()),
+    };
+    Ok(BufWriter::new(out))
+}
+
+/// A row's fields keyed by column name, with the original line only when
+/// it is kept as text.
+fn fields(row: &LogRow, raw: RawStorage) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(row)? else {
+        unreachable!("LogRow serializes as an object");
+    };
+    if raw != RawStorage::Text {
+        fields.remove("raw");
+    }
+    fields.retain(|_, v| !v.is_null());
+    Ok(fields)
+}
+
+struct Jsonl {
+    out: BufWriter<Box<dyn Write>>,
+    raw: RawStorage,
+}
+
+impl Sink for Jsonl {
+    fn write(&mut self, rows: Vec<LogRow>) -> Result<(u64, u64)> {
+        for row in &rows {
+            serde_json::to_writer(&mut self.out, &fields(row, self.raw)?)?;
+            self.out.write_all(b"\n")?;
+        }
+        Ok((rows.len() as u64, 0))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// Columns the parser doesn't fill, such as `tls_protocol`, are kept, empty,
+/// so the file lines up with an export of the table.
+struct Csv {
+    out: BufWriter<Box<dyn Write>>,
+    columns: Vec<&'static str>,
+    raw: RawStorage,
+}
+
+impl Sink for Csv {
+    fn write(&mut self, rows: Vec<LogRow>) -> Result<(u64, u64)> {
+        for row in &rows {
+            let fields = fields(row, self.raw)?;
+            let line: Vec<String> = self
+                .columns
+                .iter()
+                .map(|c| fields.get(*c).map(|v| db::csv_quote(&db::plain_field(v))).unwrap_or_default())
+                .collect();
+            writeln!(self.out, "{}", line.join(","))?;
+        }
+        Ok((rows.len() as u64, 0))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// Rows are gathered in an in-memory database, laid out as the real one,
+/// and copied out in one go at the end.
+struct Parquet {
+    conn: Connection,
+    path: PathBuf,
+    raw: RawStorage,
+}
+
+impl Sink for Parquet {
+    fn write(&mut self, rows: Vec<LogRow>) -> Result<(u64, u64)> {
+        db::insert_rows(&self.conn, rows, self.raw)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let path = self.path.to_string_lossy();
+        self.conn
+            .execute_batch(&format!(
+                "COPY (SELECT * FROM requests ORDER BY ts) TO {} (FORMAT parquet, COMPRESSION zstd)",
+                db::sql_string(&path)
+            ))
+            .with_context(|| format!("write {}", path))?;
+        Ok(())
+    }
+}
+
+struct Elastic {
+    target: elastic::Target,
+    index: String,
+    raw: RawStorage,
+}
+
+impl Sink for Elastic {
+    fn write(&mut self, rows: Vec<LogRow>) -> Result<(u64, u64)> {
+        for batch in rows.chunks(elastic::BATCH) {
+            let mut body = String::new();
+            for row in batch {
+                elastic::push_document(&mut body, &self.index, fields(row, self.raw)?);
+            }
+            self.target.bulk(body)?;
+        }
+        Ok((rows.len() as u64, 0))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Records per request to the REST proxy.
+const KAFKA_BATCH: usize = 500;
+
+/// Keyed by user (or IP), so one user's requests stay in order on one
+/// partition.
+struct Kafka {
+    /// The topic's URL on the REST proxy
+    url: String,
+    agent: ureq::Agent,
+    raw: RawStorage,
+}
+
+impl Sink for Kafka {
+    fn write(&mut self, rows: Vec<LogRow>) -> Result<(u64, u64)> {
+        for batch in rows.chunks(KAFKA_BATCH) {
+            let mut records = Vec::with_capacity(batch.len());
+            for row in batch {
+                let key = row.user_or_session.as_deref().filter(|u| !u.is_empty()).unwrap_or(&row.remote_addr);
+                records.push(json!({ "key": key, "value": fields(row, self.raw)? }));
+            }
+            let body = json!({ "records": records }).to_string();
+            let mut res = self
+                .agent
+                .post(&self.url)
+                .content_type("application/vnd.kafka.json.v2+json")
+                .send(body)
+                .with_context(|| format!("post {}", self.url))?;
+            let status = res.status();
+            let reply: serde_json::Value = res.body_mut().read_json().unwrap_or_default();
+            if !status.is_success() {
+                bail!("{} answered {}: {}", self.url, status, reply["message"]);
+            }
+            if let Some(failed) = reply["offsets"].as_array().and_then(|o| o.iter().find(|o| !o["error_code"].is_null())) {
+                bail!("{} refused a record: {}", self.url, failed["error"]);
+            }
+        }
+        Ok((rows.len() as u64, 0))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
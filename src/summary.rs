@@ -0,0 +1,195 @@
+use std::fmt::Write as _;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use duckdb::{Connection, OptionalExt, params, params_from_iter};
+use serde::Serialize;
+
+use crate::{baseline, config::PortsConfig, db};
+
+/// Rows in each top list of the digest.
+const DIGEST_TOP_N: usize = 5;
+/// Anomalies listed in the digest; the rest are counted.
+const DIGEST_MAX_ANOMALIES: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct TopCountry {
+    pub country: String,
+    pub requests: i64,
+}
+
+/// Headline totals, as `/api/summary` serves them.
+#[derive(Debug, Serialize)]
+pub struct Totals {
+    pub requests: i64,
+    pub gb: f64,
+    pub users: i64,
+    pub hosts: i64,
+    /// Share of 4xx and 5xx responses, None without requests
+    pub error_rate_pct: Option<f64>,
+    pub top_country: Option<TopCountry>,
+}
+
+/// Totals over the requests matching `cond`.
+pub fn totals(conn: &Connection, cond: &str, args: &[String], ports: &PortsConfig) -> Result<Totals> {
+    let host = db::target_host(ports);
+    let (requests, gb, users, hosts, error_rate_pct) = conn.query_row(
+        &format!(
+            r#"
+            SELECT count(*),
+                   round(COALESCE(sum(COALESCE(bytes, 0)), 0) / 1024.0 / 1024.0 / 1024.0, 2),
+                   count(DISTINCT user_or_session),
+                   count(DISTINCT {host}),
+                   round(count(*) FILTER (WHERE status >= 400) * 100.0 / NULLIF(count(*), 0), 2)
+            FROM requests
+            WHERE {cond}
+            "#
+        ),
+        params_from_iter(args),
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+    )?;
+
+    let top_country = conn
+        .query_row(
+            &format!(
+                r#"
+                SELECT country, count(*) AS n FROM requests
+                WHERE country IS NOT NULL AND country <> '' AND {cond}
+                GROUP BY 1 ORDER BY n DESC LIMIT 1
+                "#
+            ),
+            params_from_iter(args),
+            |r| Ok(TopCountry { country: r.get(0)?, requests: r.get(1)? }),
+        )
+        .optional()?;
+
+    Ok(Totals { requests, gb, users, hosts, error_rate_pct, top_country })
+}
+
+/// The `limit` most frequent values of `column` among the requests matching
+/// `cond`, busiest first, with their request and distinct user counts.
+/// `column` is interpolated, so it must not come from user input.
+pub fn top(conn: &Connection, column: &str, cond: &str, args: &[String], limit: usize) -> Result<Vec<(String, i64, i64)>> {
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT {column}, count(*) AS n, count(DISTINCT user_or_session) AS users FROM requests
+        WHERE {column} IS NOT NULL AND {cond}
+        GROUP BY 1 ORDER BY n DESC LIMIT {limit}
+        "#
+    ))?;
+    let rows = stmt.query_map(params_from_iter(args), |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+    Ok(rows.collect::<duckdb::Result<_>>()?)
+}
+
+/// The `since` before the newest request, if there are any.
+fn window(conn: &Connection, since: Duration) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>> {
+    let newest: Option<i64> =
+        conn.query_row("SELECT max(epoch_us(ts)) FROM requests", params![], |r| r.get(0)).optional()?.flatten();
+    Ok(newest.and_then(DateTime::<Utc>::from_timestamp_micros).map(|end| (end - since, end)))
+}
+
+/// What `digest` covers, for `--json`: the window, totals, top hosts and
+/// users, and anomalies, or why they are unavailable.
+pub fn report(conn: &Connection, since: Duration, ports: &PortsConfig) -> Result<serde_json::Value> {
+    let Some((start, end)) = window(conn, since)? else {
+        return Ok(serde_json::json!({ "start": null, "end": null }));
+    };
+    let (start_s, end_s) = (start.to_rfc3339(), end.to_rfc3339());
+    let cond = "ts >= CAST(? AS TIMESTAMPTZ) AND ts <= CAST(? AS TIMESTAMPTZ)";
+    let args = [start_s.clone(), end_s.clone()];
+    let top_json = |column: &str| -> Result<Vec<serde_json::Value>> {
+        Ok(top(conn, column, cond, &args, DIGEST_TOP_N)?
+            .into_iter()
+            .map(|(name, n, users)| serde_json::json!({ "name": name, "requests": n, "users": users }))
+            .collect())
+    };
+    let anomalies = match baseline::anomalies(conn, Some(&start_s), Some(&end_s), ports) {
+        Ok(found) => found,
+        Err(e) => serde_json::json!({ "unavailable": format!("{:#}", e) }),
+    };
+    Ok(serde_json::json!({
+        "start": start_s,
+        "end": end_s,
+        "totals": totals(conn, cond, &args, ports)?,
+        "top_hosts": top_json(db::target_host(ports))?,
+        "top_users": top_json("user_or_session")?,
+        "anomalies": anomalies,
+    }))
+}
+
+/// Plaintext digest of the `since` before the newest request, for mail or a
+/// ticket. Like the baselines, it is anchored to the data rather than the
+/// clock, since logs are usually imported after the fact.
+pub fn digest(conn: &Connection, since: Duration, ports: &PortsConfig) -> Result<String> {
+    let Some((start, end)) = window(conn, since)? else {
+        return Ok("ezvis summary: no requests imported\n".to_string());
+    };
+    let (start_s, end_s) = (start.to_rfc3339(), end.to_rfc3339());
+    let cond = "ts >= CAST(? AS TIMESTAMPTZ) AND ts <= CAST(? AS TIMESTAMPTZ)";
+    let args = [start_s.clone(), end_s.clone()];
+
+    let t = totals(conn, cond, &args, ports)?;
+    let fmt = "%Y-%m-%d %H:%M UTC";
+    let mut out = String::new();
+    writeln!(out, "ezvis summary: {} -> {}", start.format(fmt), end.format(fmt))?;
+    writeln!(out)?;
+    writeln!(out, "Requests:    {}", t.requests)?;
+    writeln!(out, "Users:       {}", t.users)?;
+    writeln!(out, "Hosts:       {}", t.hosts)?;
+    writeln!(out, "Bandwidth:   {} GB", t.gb)?;
+    writeln!(out, "Error rate:  {}", t.error_rate_pct.map_or("-".to_string(), |p| format!("{}%", p)))?;
+    if let Some(c) = &t.top_country {
+        writeln!(out, "Top country: {} ({} requests)", c.country, c.requests)?;
+    }
+
+    for (title, column) in [("Top hosts", db::target_host(ports)), ("Top users", "user_or_session")] {
+        writeln!(out)?;
+        writeln!(out, "{}:", title)?;
+        let rows = top(conn, column, cond, &args, DIGEST_TOP_N)?;
+        if rows.is_empty() {
+            writeln!(out, "  (none)")?;
+        }
+        for (name, n, _) in rows {
+            writeln!(out, "  {:>10}  {}", n, name)?;
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Anomalies:")?;
+    match baseline::anomalies(conn, Some(&start_s), Some(&end_s), ports) {
+        Ok(found) => {
+            let mut lines = Vec::new();
+            for s in found["spikes"].as_array().into_iter().flatten() {
+                lines.push(format!(
+                    "  {} {} spiked to {} requests at {} (usual {:.0}, z {:.1})",
+                    s["kind"].as_str().unwrap_or_default(),
+                    s["subject"].as_str().unwrap_or_default(),
+                    s["n"],
+                    s["t"].as_str().unwrap_or_default(),
+                    s["mean"].as_f64().unwrap_or_default(),
+                    s["z"].as_f64().unwrap_or_default(),
+                ));
+            }
+            for c in found["unusual_countries"].as_array().into_iter().flatten() {
+                lines.push(format!(
+                    "  user {} from unusual country {} ({} requests)",
+                    c["user"].as_str().unwrap_or_default(),
+                    c["country"].as_str().unwrap_or_default(),
+                    c["n"],
+                ));
+            }
+            if lines.is_empty() {
+                writeln!(out, "  (none)")?;
+            }
+            for line in lines.iter().take(DIGEST_MAX_ANOMALIES) {
+                writeln!(out, "{}", line)?;
+            }
+            if lines.len() > DIGEST_MAX_ANOMALIES {
+                writeln!(out, "  ... and {} more", lines.len() - DIGEST_MAX_ANOMALIES)?;
+            }
+        }
+        // Most often no baseline yet; the rest of the digest is still useful.
+        Err(e) => writeln!(out, "  unavailable: {:#}", e)?,
+    }
+    Ok(out)
+}
@@ -0,0 +1,95 @@
+use anyhow::{Result, bail};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use duckdb::{Connection, OptionalExt, params};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::Scope,
+    db::{self, Table},
+};
+
+/// Prefix on every secret, so a leaked one is recognisable in logs and
+/// secret scanners.
+const TOKEN_PREFIX: &str = "ezv_";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Tokens are long random strings, so a plain SHA-256 is enough to keep the
+/// table useless to whoever copies it, and it is cheap to check per request.
+fn hash_token(secret: &str) -> String {
+    to_hex(&Sha256::digest(secret.as_bytes()))
+}
+
+/// Store a new token and return its id and secret. Only the hash is kept, so
+/// the secret can't be shown again.
+pub fn create(conn: &Connection, name: Option<&str>, scopes: &[Scope]) -> Result<(i64, String)> {
+    if scopes.is_empty() {
+        bail!("a token needs at least one scope");
+    }
+    let mut raw = [0u8; 32];
+    OsRng.fill_bytes(&mut raw);
+    let secret = format!("{}{}", TOKEN_PREFIX, to_hex(&raw));
+
+    let mut scope_names: Vec<&str> = scopes.iter().map(|s| s.as_str()).collect();
+    scope_names.sort();
+    scope_names.dedup();
+
+    let id: i64 = conn.query_row(
+        "INSERT INTO api_tokens (name, token_hash, scopes) VALUES (?, ?, ?) RETURNING id",
+        params![name, hash_token(&secret), scope_names.join(",")],
+        |r| r.get(0),
+    )?;
+    Ok((id, secret))
+}
+
+pub fn list(conn: &Connection) -> Result<Table> {
+    db::query_table(
+        conn,
+        r#"
+        SELECT id, name, scopes,
+               CAST(created_at AS VARCHAR) AS created_at,
+               CAST(last_used_at AS VARCHAR) AS last_used_at,
+               CAST(revoked_at AS VARCHAR) AS revoked_at
+        FROM api_tokens
+        ORDER BY id
+        "#,
+        params![],
+    )
+}
+
+/// Returns false when there is no active token with that id.
+pub fn revoke(conn: &Connection, id: i64) -> Result<bool> {
+    let n = conn.execute(
+        "UPDATE api_tokens SET revoked_at = now() WHERE id = ? AND revoked_at IS NULL",
+        params![id],
+    )?;
+    Ok(n > 0)
+}
+
+/// Label and scopes of the active token with this secret.
+pub fn lookup(conn: &Connection, secret: &str) -> Result<Option<(String, Vec<Scope>)>> {
+    let hit: Option<(i64, Option<String>, String)> = conn
+        .query_row(
+            "SELECT id, name, scopes FROM api_tokens WHERE token_hash = ? AND revoked_at IS NULL",
+            params![hash_token(secret)],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .optional()?;
+    let Some((id, name, scopes)) = hit else {
+        return Ok(None);
+    };
+
+    // Best effort: two requests with the same token can race on this row,
+    // and a stale timestamp is not worth failing the request over.
+    let _ = conn.execute("UPDATE api_tokens SET last_used_at = now() WHERE id = ?", params![id]);
+
+    // Scopes this build doesn't know (say, after a downgrade) grant nothing.
+    let scopes = scopes.split(',').filter_map(|s| s.parse().ok()).collect();
+    let label = match name {
+        Some(name) => format!("token {} ({})", id, name),
+        None => format!("token {}", id),
+    };
+    Ok(Some((label, scopes)))
+}
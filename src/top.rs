@@ -0,0 +1,214 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use duckdb::{Connection, OptionalExt, params};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Sparkline, Table},
+};
+
+use crate::{config::PortsConfig, db};
+
+/// Rows shown in the top hosts and top users tables.
+const TOP_N: usize = 10;
+
+/// What `ezvis top` shows, for the `window` minutes up to the newest request.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub newest: Option<DateTime<Utc>>,
+    pub requests: i64,
+    pub users: i64,
+    pub error_rate_pct: Option<f64>,
+    /// Requests in each minute of the window, oldest first
+    pub per_minute: Vec<u64>,
+    pub top_hosts: Vec<(String, i64)>,
+    pub top_users: Vec<(String, i64)>,
+}
+
+/// Aggregate the `window` before the newest request. Anchored to the data
+/// rather than the clock, so a database fed in batches still shows its
+/// latest activity.
+pub fn snapshot(conn: &Connection, window: chrono::Duration, ports: &PortsConfig) -> Result<Snapshot> {
+    let newest: Option<i64> =
+        conn.query_row("SELECT max(epoch_us(ts)) FROM requests", params![], |r| r.get(0)).optional()?.flatten();
+    let Some(newest) = newest.and_then(DateTime::<Utc>::from_timestamp_micros) else {
+        return Ok(Snapshot::default());
+    };
+    let minutes = window.num_minutes().max(1);
+    // Whole minutes, ending with the newest request's.
+    let last_minute = newest.timestamp() / 60;
+    let first_minute = last_minute - minutes + 1;
+    let start = DateTime::<Utc>::from_timestamp(first_minute * 60, 0).unwrap_or(newest).to_rfc3339();
+
+    let (requests, users, error_rate_pct) = conn.query_row(
+        r#"
+        SELECT count(*), count(DISTINCT user_or_session),
+               round(count(*) FILTER (WHERE status >= 400) * 100.0 / NULLIF(count(*), 0), 1)
+        FROM requests
+        WHERE ts >= CAST(? AS TIMESTAMPTZ)
+        "#,
+        params![start],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )?;
+
+    let mut per_minute = vec![0u64; minutes as usize];
+    let mut stmt = conn.prepare(
+        "SELECT epoch_us(ts) // 60000000 AS m, count(*) FROM requests WHERE ts >= CAST(? AS TIMESTAMPTZ) GROUP BY 1",
+    )?;
+    let mut rows = stmt.query(params![start])?;
+    while let Some(r) = rows.next()? {
+        let (m, n): (i64, i64) = (r.get(0)?, r.get(1)?);
+        if let Some(slot) = per_minute.get_mut((m - first_minute) as usize) {
+            *slot = n as u64;
+        }
+    }
+
+    let top = |column: &str| -> Result<Vec<(String, i64)>> {
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {column}, count(*) AS n
+            FROM requests
+            WHERE ts >= CAST(? AS TIMESTAMPTZ) AND {column} IS NOT NULL
+            GROUP BY 1 ORDER BY n DESC, 1
+            LIMIT {TOP_N}
+            "#
+        ))?;
+        let rows = stmt.query_map(params![start], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        Ok(rows.collect::<duckdb::Result<_>>()?)
+    };
+
+    Ok(Snapshot {
+        newest: Some(newest),
+        requests,
+        users,
+        error_rate_pct,
+        per_minute,
+        top_hosts: top(db::target_host(ports))?,
+        top_users: top("user_or_session")?,
+    })
+}
+
+pub struct TopOptions {
+    pub db: String,
+    /// How much recent activity to summarize
+    pub window: chrono::Duration,
+    /// Refresh every `interval` instead of showing one snapshot
+    pub follow: bool,
+    pub interval: Duration,
+    pub ports: PortsConfig,
+}
+
+/// Show the dashboard until `q`, Esc, or Ctrl-C. The database is opened
+/// read-only for each refresh and closed again, so `serve` and imports can
+/// write in between.
+pub fn run(opts: &TopOptions) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let res = event_loop(&mut terminal, opts);
+    ratatui::restore();
+    res
+}
+
+fn load(opts: &TopOptions) -> Result<Snapshot> {
+    let conn = db::open_read_only(&opts.db)?;
+    snapshot(&conn, opts.window, &opts.ports)
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, opts: &TopOptions) -> Result<()> {
+    let mut state = load(opts);
+    let mut loaded_at = Instant::now();
+    loop {
+        terminal.draw(|f| draw(f, opts, &state))?;
+        let wait = if opts.follow { opts.interval.saturating_sub(loaded_at.elapsed()) } else { Duration::from_secs(3600) };
+        if event::poll(wait)? {
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                    KeyCode::Char('r') => {
+                        state = load(opts);
+                        loaded_at = Instant::now();
+                    }
+                    _ => {}
+                }
+            }
+        } else if opts.follow {
+            state = load(opts);
+            loaded_at = Instant::now();
+        }
+    }
+}
+
+fn draw(f: &mut Frame, opts: &TopOptions, state: &Result<Snapshot>) {
+    let [header, kpis, spark, tables, footer] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(3),
+        Constraint::Length(7),
+        Constraint::Min(5),
+        Constraint::Length(1),
+    ])
+    .areas(f.area());
+
+    let mode = if opts.follow { format!("following every {}s", opts.interval.as_secs()) } else { "snapshot".to_string() };
+    let snap = match state {
+        Ok(s) => s,
+        Err(e) => {
+            f.render_widget(Paragraph::new(format!("ezvis top — {} — {}", opts.db, mode)).bold(), header);
+            f.render_widget(Paragraph::new(format!("{:#}", e)).red().block(Block::bordered().title("Error")), kpis);
+            f.render_widget(footer_line(), footer);
+            return;
+        }
+    };
+    let newest = snap.newest.map_or("no requests".to_string(), |t| format!("newest request {}", t.format("%Y-%m-%d %H:%M:%S UTC")));
+    f.render_widget(
+        Paragraph::new(format!("ezvis top — {} — last {}m to {} — {}", opts.db, opts.window.num_minutes().max(1), newest, mode)).bold(),
+        header,
+    );
+
+    let last_minute = snap.per_minute.last().copied().unwrap_or(0);
+    let kpi_values = [
+        ("Requests", snap.requests.to_string()),
+        ("Requests/min (last)", last_minute.to_string()),
+        ("Users", snap.users.to_string()),
+        ("Error rate", snap.error_rate_pct.map_or("–".to_string(), |p| format!("{}%", p))),
+    ];
+    let cells = Layout::horizontal([Constraint::Ratio(1, 4); 4]).split(kpis);
+    for ((label, value), area) in kpi_values.into_iter().zip(cells.iter()) {
+        f.render_widget(Paragraph::new(value).bold().block(Block::bordered().title(label)), *area);
+    }
+
+    // Newest minutes on the right; a narrow terminal shows only the latest.
+    let width = spark.width.saturating_sub(2) as usize;
+    let shown = &snap.per_minute[snap.per_minute.len().saturating_sub(width)..];
+    f.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(format!("Requests per minute (max {})", shown.iter().max().unwrap_or(&0))))
+            .data(shown)
+            .style(Style::default().cyan()),
+        spark,
+    );
+
+    let [hosts, users] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(tables);
+    f.render_widget(top_table("Top hosts", &snap.top_hosts), hosts);
+    f.render_widget(top_table("Top users", &snap.top_users), users);
+    f.render_widget(footer_line(), footer);
+}
+
+fn top_table<'a>(title: &'a str, rows: &'a [(String, i64)]) -> Table<'a> {
+    Table::new(
+        rows.iter().map(|(name, n)| Row::new(vec![name.clone(), n.to_string()])),
+        [Constraint::Fill(1), Constraint::Length(10)],
+    )
+    .header(Row::new(vec!["", "Requests"]).bold())
+    .block(Block::bordered().title(title))
+}
+
+fn footer_line() -> Paragraph<'static> {
+    Paragraph::new(Line::from("q quit · r refresh").dim())
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
+use duckdb::{Connection, params};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{db, enrich::Pipeline, parser::Timestamps};
+
+#[derive(Debug, Default, Serialize)]
+pub struct TransferSummary {
+    pub lines: u64,
+    pub bad: u64,
+    /// Requests that took bytes or TLS details from a transfer line
+    pub matched: u64,
+}
+
+/// One line of an SSL or transfer log:
+/// `%h %u [%t] "%r" %b "<tls protocol>" "<tls cipher>"`, with the TLS
+/// fields optional and `-` for anything not logged.
+struct TransferLine {
+    remote_addr: String,
+    user_or_session: Option<String>,
+    ts: String,
+    url: String,
+    bytes: Option<i64>,
+    tls_protocol: Option<String>,
+    tls_cipher: Option<String>,
+}
+
+fn none_if_dash(s: Option<&str>) -> Option<String> {
+    s.map(|s| s.trim_matches('"')).filter(|s| !s.is_empty() && *s != "-").map(String::from)
+}
+
+fn parse_line(line: &str, timestamps: &Timestamps) -> Option<TransferLine> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r#"^(\S+)\s+(\S+)\s+\[([^\]]+)\]\s+"\S+\s+(\S+)[^"]*"\s+(\S+)(?:\s+("[^"]*"|\S+))?(?:\s+("[^"]*"|\S+))?\s*$"#)
+            .expect("regex compiles")
+    });
+    let caps = re.captures(line)?;
+    let ts = timestamps.parse(&caps[3]).ok()?;
+    let bytes = match &caps[5] {
+        "-" => None,
+        b => Some(b.parse().ok()?),
+    };
+    Some(TransferLine {
+        remote_addr: caps[1].to_string(),
+        user_or_session: none_if_dash(Some(&caps[2])),
+        // Appended as text into a TIMESTAMPTZ, which drops any offset.
+        ts: ts.with_timezone(&Utc).to_rfc3339(),
+        url: caps[4].to_string(),
+        bytes,
+        tls_protocol: none_if_dash(caps.get(6).map(|m| m.as_str())),
+        tls_cipher: none_if_dash(caps.get(7).map(|m| m.as_str())),
+    })
+}
+
+/// Merge an EZproxy SSL or transfer log into requests already imported
+/// from the access log. Each request takes the nearest transfer line within
+/// `window` for the same URL and user (or IP, when the transfer line has no
+/// user): its byte count where the access log logged `-`, and its TLS
+/// protocol and cipher. Rerunning with the same file changes nothing more.
+/// URLs are compared as `pipeline` stores them, so a request whose session
+/// token was scrubbed still finds its transfer line.
+pub fn import(
+    conn: &Connection,
+    path: &str,
+    timestamps: &Timestamps,
+    pipeline: &Pipeline,
+    window: Duration,
+) -> Result<TransferSummary> {
+    let file = File::open(path).with_context(|| format!("open {}", path))?;
+    let mut summary = TransferSummary::default();
+
+    conn.execute_batch(
+        "CREATE OR REPLACE TEMP TABLE transfer_lines (ts TIMESTAMPTZ, remote_addr TEXT, user_or_session TEXT, \
+         url TEXT, bytes BIGINT, tls_protocol TEXT, tls_cipher TEXT)",
+    )?;
+    {
+        let mut appender = conn.appender_to_catalog_and_db("transfer_lines", "temp", "main")?;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            summary.lines += 1;
+            match parse_line(&line, timestamps) {
+                Some(t) => appender.append_row(params![
+                    t.ts,
+                    t.remote_addr,
+                    t.user_or_session,
+                    pipeline.stored_url(&t.url),
+                    t.bytes,
+                    t.tls_protocol,
+                    t.tls_cipher
+                ])?,
+                None => summary.bad += 1,
+            }
+        }
+        appender.flush()?;
+    }
+
+    let window_us = window.num_microseconds().unwrap_or(i64::MAX);
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = (|| -> Result<u64> {
+        let mut matched = 0;
+        // Partition by partition, since rowids are only unique within a table.
+        // A URL the truncate stage cut short is matched by its hash, in a
+        // pass of its own so that each can join on equality.
+        let joins = ["t.url = r.url", "r.url_sha256 IS NOT NULL AND sha256(t.url) = r.url_sha256"];
+        for (part, same_url) in db::partitions(conn)?.iter().flat_map(|p| joins.map(|j| (p, j))) {
+            matched += conn.execute(
+                &format!(
+                    r#"
+                    UPDATE {part} SET
+                      bytes = COALESCE({part}.bytes, m.bytes),
+                      tls_protocol = COALESCE(m.tls_protocol, {part}.tls_protocol),
+                      tls_cipher = COALESCE(m.tls_cipher, {part}.tls_cipher)
+                    FROM (
+                      SELECT r.rowid AS rid, t.bytes, t.tls_protocol, t.tls_cipher
+                      FROM {part} r
+                      JOIN transfer_lines t
+                        ON {same_url}
+                       AND (t.user_or_session = r.user_or_session
+                            OR (t.user_or_session IS NULL AND t.remote_addr = r.remote_addr))
+                       AND abs(epoch_us(t.ts) - epoch_us(r.ts)) <= {window_us}
+                      QUALIFY row_number() OVER (PARTITION BY r.rowid ORDER BY abs(epoch_us(t.ts) - epoch_us(r.ts))) = 1
+                    ) m
+                    WHERE {part}.rowid = m.rid
+                      AND ({part}.bytes IS NULL AND m.bytes IS NOT NULL
+                           OR {part}.tls_protocol IS DISTINCT FROM COALESCE(m.tls_protocol, {part}.tls_protocol)
+                           OR {part}.tls_cipher IS DISTINCT FROM COALESCE(m.tls_cipher, {part}.tls_cipher))
+                    "#
+                ),
+                params![],
+            )? as u64;
+        }
+        Ok(matched)
+    })();
+    match res {
+        Ok(matched) => {
+            conn.execute_batch("COMMIT")?;
+            summary.matched = matched;
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    conn.execute_batch("DROP TABLE transfer_lines")?;
+    Ok(summary)
+}
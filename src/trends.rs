@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::{Days, NaiveDate};
+use duckdb::{Connection, params, params_from_iter};
+use serde_json::json;
+
+/// Days in each compared window.
+const WINDOW_DAYS: u64 = 7;
+
+/// A year back is taken as 52 weeks, so the windows cover the same weekdays
+/// and a Monday is never compared with a Sunday.
+const YEAR_DAYS: u64 = 364;
+
+/// Percentage change from `previous` to `current`, None when there is
+/// nothing to compare against.
+pub fn pct_change(current: f64, previous: f64) -> Option<f64> {
+    (previous > 0.0).then(|| ((current - previous) / previous * 1000.0).round() / 10.0)
+}
+
+/// Requests, bandwidth, and unique users for the 7 days ending on `anchor`
+/// (default: the last day with data), compared with the 7 days before and
+/// the same 7 days 52 weeks earlier. `filter`, with its bind args, is ANDed
+/// into the scan.
+pub fn compute(
+    conn: &Connection,
+    anchor: Option<NaiveDate>,
+    filter: &str,
+    filter_args: &[String],
+) -> Result<serde_json::Value> {
+    let anchor = match anchor {
+        Some(a) => Some(a),
+        None => conn
+            .query_row(
+                "SELECT CAST(CAST(max(CAST(ts AS TIMESTAMP)) AS DATE) AS VARCHAR) FROM requests",
+                params![],
+                |r| r.get::<_, Option<String>>(0),
+            )?
+            .and_then(|d| d.parse().ok()),
+    };
+    let Some(anchor) = anchor else {
+        return Ok(json!({ "anchor": null, "periods": {}, "metrics": {} }));
+    };
+
+    let window = |end: NaiveDate| (end - Days::new(WINDOW_DAYS - 1), end);
+    let periods = [
+        ("current", window(anchor)),
+        ("previous_week", window(anchor - Days::new(WINDOW_DAYS))),
+        ("previous_year", window(anchor - Days::new(YEAR_DAYS))),
+    ];
+
+    let mut args = filter_args.to_vec();
+    let mut values = Vec::new();
+    for (name, (start, end)) in &periods {
+        values.push(format!("('{}', CAST(? AS DATE), CAST(? AS DATE))", name));
+        args.push(start.to_string());
+        args.push(end.to_string());
+    }
+    let earliest = periods.iter().map(|(_, (s, _))| *s).min().unwrap_or(anchor);
+    args.push(earliest.to_string());
+    args.push(anchor.to_string());
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        WITH r AS (
+          SELECT CAST(CAST(ts AS TIMESTAMP) AS DATE) AS d, bytes, user_or_session
+          FROM requests
+          WHERE {filter}
+        ),
+        w(name, s, e) AS (VALUES {values})
+        SELECT w.name,
+               count(r.d) AS requests,
+               round(COALESCE(sum(COALESCE(r.bytes, 0)), 0) / 1024.0 / 1024.0, 1) AS mb,
+               count(DISTINCT r.user_or_session) AS users
+        FROM w
+        LEFT JOIN (SELECT * FROM r WHERE d BETWEEN CAST(? AS DATE) AND CAST(? AS DATE)) r
+          ON r.d BETWEEN w.s AND w.e
+        GROUP BY 1
+        "#,
+        values = values.join(", ")
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut totals = std::collections::HashMap::new();
+    while let Some(r) = rows.next()? {
+        let name: String = r.get(0)?;
+        let requests: i64 = r.get(1)?;
+        let mb: f64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        totals.insert(name, [requests as f64, mb, users as f64]);
+    }
+
+    let get = |period: &str, i: usize| totals.get(period).map(|t| t[i]).unwrap_or(0.0);
+    let mut metrics = serde_json::Map::new();
+    for (i, metric) in ["requests", "bandwidth_mb", "users"].iter().enumerate() {
+        let (cur, week, year) = (get("current", i), get("previous_week", i), get("previous_year", i));
+        // Counts stay integers in the JSON; only bandwidth is fractional.
+        let num = |v: f64| if i == 1 { json!(v) } else { json!(v as i64) };
+        metrics.insert(
+            metric.to_string(),
+            json!({
+                "current": num(cur),
+                "previous_week": num(week),
+                "previous_year": num(year),
+                "wow_pct": pct_change(cur, week),
+                "yoy_pct": pct_change(cur, year),
+            }),
+        );
+    }
+
+    let periods: serde_json::Map<_, _> = periods
+        .iter()
+        .map(|(name, (s, e))| (name.to_string(), json!({ "start": s.to_string(), "end": e.to_string() })))
+        .collect();
+
+    Ok(json!({ "anchor": anchor.to_string(), "periods": periods, "metrics": metrics }))
+}
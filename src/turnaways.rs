@@ -0,0 +1,144 @@
+use anyhow::Result;
+use duckdb::{Connection, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, db};
+
+/// Denials (401/403) from one user or IP on one host within an hour needed
+/// to count as a burst. A single denial is usually a stale link.
+pub const BURST_MIN_DENIALS: i64 = 5;
+
+/// Distinct users denied on one host in the same hour at which the cause is
+/// more likely the proxy or vendor configuration than any one patron.
+pub const MISCONFIG_MIN_USERS: i64 = 3;
+
+/// Share of a host's requests in an hour that must be denied, alongside
+/// `MISCONFIG_MIN_USERS`, to flag a likely misconfiguration.
+pub const MISCONFIG_MIN_SHARE: f64 = 0.5;
+
+/// 401/403 analysis for requests matching `filter`: bursts per user and
+/// vendor, hosts ranked by denials with hours that look like an access
+/// misconfiguration, and a daily trend.
+pub fn analyze(conn: &Connection, filter: &str, args: &[String], ports: &PortsConfig) -> Result<serde_json::Value> {
+    let host = db::target_host(ports);
+    let base = format!(
+        r#"
+        WITH r AS (
+          SELECT {host} AS host, COALESCE(user_or_session, remote_addr) AS who,
+                 CAST(ts AS TIMESTAMP) AS ts, status IN (401, 403) AS denied
+          FROM requests
+          WHERE host IS NOT NULL AND {filter}
+        )
+        "#
+    );
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT host, who,
+               CAST(date_trunc('hour', ts) AS VARCHAR) AS hour,
+               count(*) AS denials,
+               CAST(min(ts) AS VARCHAR) AS first_seen,
+               CAST(max(ts) AS VARCHAR) AS last_seen
+        FROM r WHERE denied
+        GROUP BY 1, 2, 3
+        HAVING count(*) >= {BURST_MIN_DENIALS}
+        ORDER BY denials DESC, hour DESC
+        LIMIT 50
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut bursts = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let who: Option<String> = r.get(1)?;
+        let hour: String = r.get(2)?;
+        let denials: i64 = r.get(3)?;
+        let first_seen: String = r.get(4)?;
+        let last_seen: String = r.get(5)?;
+        bursts.push(json!({
+            "host": host,
+            "user_or_ip": who,
+            "hour": hour,
+            "denials": denials,
+            "first_seen": first_seen,
+            "last_seen": last_seen,
+        }));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base},
+        hourly AS (
+          SELECT host, date_trunc('hour', ts) AS hour,
+                 count(*) AS requests,
+                 count(*) FILTER (WHERE denied) AS denials,
+                 count(DISTINCT who) FILTER (WHERE denied) AS users
+          FROM r GROUP BY 1, 2
+        ),
+        misconfig AS (
+          SELECT host, list(CAST(hour AS VARCHAR) ORDER BY hour) AS hours
+          FROM hourly
+          WHERE users >= {MISCONFIG_MIN_USERS} AND denials >= {MISCONFIG_MIN_SHARE} * requests
+          GROUP BY 1
+        ),
+        totals AS (
+          SELECT host, count(*) AS requests,
+                 count(*) FILTER (WHERE denied) AS denials,
+                 count(DISTINCT who) FILTER (WHERE denied) AS users
+          FROM r GROUP BY 1
+        )
+        SELECT t.host, t.denials, t.requests,
+               round(t.denials * 100.0 / t.requests, 1) AS denial_pct,
+               t.users,
+               array_to_string(COALESCE(m.hours, []), ',') AS misconfig_hours
+        FROM totals t LEFT JOIN misconfig m USING (host)
+        WHERE t.denials > 0
+        ORDER BY t.denials DESC
+        LIMIT 20
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut hosts = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let denials: i64 = r.get(1)?;
+        let requests: i64 = r.get(2)?;
+        let denial_pct: f64 = r.get(3)?;
+        let users: i64 = r.get(4)?;
+        let misconfig_hours: String = r.get(5)?;
+        let misconfig_hours: Vec<&str> = misconfig_hours.split(',').filter(|h| !h.is_empty()).collect();
+        hosts.push(json!({
+            "host": host,
+            "denials": denials,
+            "requests": requests,
+            "denial_pct": denial_pct,
+            "users": users,
+            "likely_misconfiguration": !misconfig_hours.is_empty(),
+            "misconfiguration_hours": misconfig_hours,
+        }));
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        {base}
+        SELECT CAST(CAST(ts AS DATE) AS VARCHAR) AS day,
+               count(*) FILTER (WHERE denied) AS denials,
+               count(DISTINCT who) FILTER (WHERE denied) AS users,
+               round(count(*) FILTER (WHERE denied) * 100.0 / count(*), 2) AS denial_pct
+        FROM r
+        GROUP BY 1 ORDER BY 1
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut trend = Vec::new();
+    while let Some(r) = rows.next()? {
+        let day: String = r.get(0)?;
+        let denials: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        let denial_pct: f64 = r.get(3)?;
+        trend.push(json!({"day": day, "denials": denials, "users": users, "denial_pct": denial_pct}));
+    }
+
+    Ok(json!({ "bursts": bursts, "hosts": hosts, "trend": trend }))
+}
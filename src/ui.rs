@@ -0,0 +1,151 @@
+use serde::Serialize;
+
+/// Colors for one dashboard theme. Surface colors become CSS variables;
+/// `palette` and `status` are used for Chart.js datasets.
+#[derive(Debug, Clone, Serialize)]
+pub struct Theme {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub background: &'static str,
+    pub surface: &'static str,
+    pub surface_alt: &'static str,
+    pub text: &'static str,
+    pub heading: &'static str,
+    pub muted: &'static str,
+    pub accent: &'static str,
+    pub accent_soft: &'static str,
+    pub grid: &'static str,
+    pub error: &'static str,
+    pub error_soft: &'static str,
+    /// Categorical series colors, in order of use
+    pub palette: &'static [&'static str],
+    /// 2xx, 3xx, 4xx, 5xx, other
+    pub status: [&'static str; 5],
+}
+
+// Okabe & Ito (2008), distinguishable under the common forms of color vision
+// deficiency.
+const OKABE_ITO: &[&str] = &[
+    "#0072B2", "#E69F00", "#009E73", "#D55E00", "#56B4E9", "#CC79A7", "#F0E442", "#000000",
+];
+
+// Paul Tol's "bright" scheme, color-blind safe and readable on dark surfaces.
+const TOL_BRIGHT: &[&str] = &[
+    "#4477AA", "#EE6677", "#228833", "#CCBB44", "#66CCEE", "#AA3377", "#BBBBBB",
+];
+
+pub const THEMES: &[Theme] = &[
+    Theme {
+        name: "light",
+        label: "Light",
+        background: "linear-gradient(135deg, #667eea 0%, #764ba2 100%)",
+        surface: "#ffffff",
+        surface_alt: "#f8f9fa",
+        text: "#333333",
+        heading: "#ffffff",
+        muted: "#999999",
+        accent: "#667eea",
+        accent_soft: "#e7e9fc",
+        grid: "rgba(0, 0, 0, 0.1)",
+        error: "#dc2626",
+        error_soft: "#fee",
+        palette: &[
+            "#3b82f6", "#10b981", "#f59e0b", "#ef4444", "#8b5cf6", "#ec4899", "#6b7280",
+        ],
+        status: ["#10b981", "#3b82f6", "#f59e0b", "#ef4444", "#6b7280"],
+    },
+    Theme {
+        name: "dark",
+        label: "Dark",
+        background: "#111827",
+        surface: "#1f2937",
+        surface_alt: "#273244",
+        text: "#e5e7eb",
+        heading: "#f9fafb",
+        muted: "#9ca3af",
+        accent: "#66CCEE",
+        accent_soft: "#1e3a4a",
+        grid: "rgba(255, 255, 255, 0.1)",
+        error: "#EE6677",
+        error_soft: "#3f1d24",
+        palette: TOL_BRIGHT,
+        status: ["#228833", "#4477AA", "#CCBB44", "#EE6677", "#BBBBBB"],
+    },
+    Theme {
+        name: "colorblind",
+        label: "Color-blind safe",
+        background: "#f3f4f6",
+        surface: "#ffffff",
+        surface_alt: "#f8f9fa",
+        text: "#1f2937",
+        heading: "#111827",
+        muted: "#6b7280",
+        accent: "#0072B2",
+        accent_soft: "#dbeaf5",
+        grid: "rgba(0, 0, 0, 0.1)",
+        error: "#D55E00",
+        error_soft: "#fbe7da",
+        palette: OKABE_ITO,
+        status: ["#009E73", "#0072B2", "#E69F00", "#D55E00", "#999999"],
+    },
+];
+
+pub fn theme(name: &str) -> Option<&'static Theme> {
+    THEMES.iter().find(|t| t.name == name)
+}
+
+/// Translation catalogs, one JSON object per language. English is the
+/// reference: keys missing from another catalog fall back to it.
+pub const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("en", "English", include_str!("../locales/en.json")),
+    ("es", "Español", include_str!("../locales/es.json")),
+    ("fr", "Français", include_str!("../locales/fr.json")),
+];
+
+fn supported(lang: &str) -> Option<&'static str> {
+    let primary = lang.split(['-', '_']).next()?.trim().to_ascii_lowercase();
+    LANGUAGES.iter().map(|(code, _, _)| *code).find(|code| *code == primary)
+}
+
+/// Pick the UI language: an explicit `?lang=` wins, then the best supported
+/// entry in `Accept-Language`, then the configured default.
+pub fn negotiate(requested: Option<&str>, accept_language: Option<&str>, default: &str) -> &'static str {
+    if let Some(lang) = requested.and_then(supported) {
+        return lang;
+    }
+
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|part| {
+            let mut it = part.split(';');
+            let tag = it.next()?.trim();
+            let q = it
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty()).then_some((tag, q))
+        })
+        .collect();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges
+        .into_iter()
+        .filter(|(_, q)| *q > 0.0)
+        .find_map(|(tag, _)| supported(tag))
+        .or_else(|| supported(default))
+        .unwrap_or("en")
+}
+
+fn parse_catalog(text: &str) -> serde_json::Map<String, serde_json::Value> {
+    serde_json::from_str(text).expect("locale catalogs are valid JSON objects")
+}
+
+/// Strings for `lang`, with English filling any gaps.
+pub fn catalog(lang: &str) -> serde_json::Map<String, serde_json::Value> {
+    let mut strings = parse_catalog(LANGUAGES[0].2);
+    if let Some((_, _, text)) = LANGUAGES.iter().find(|(code, _, _)| *code == lang) {
+        strings.extend(parse_catalog(text));
+    }
+    strings
+}
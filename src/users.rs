@@ -0,0 +1,171 @@
+use anyhow::{Context, Result, bail};
+use duckdb::{Connection, params, params_from_iter};
+use serde_json::json;
+
+use crate::{config::PortsConfig, costs::split_line, db, downloads};
+
+/// Platforms listed per group, busiest first.
+const TOP_PLATFORMS: usize = 5;
+
+/// Load user attributes, such as a Shibboleth or LDAP export, from CSV text
+/// with a header naming a `user_id` column and any of `department`,
+/// `status`, and `affiliation`; other columns are ignored. IDs are matched
+/// against `user_or_session` without regard to case. Rows replace what was
+/// loaded for the same user, so a fresh export can be loaded over an old
+/// one. Nothing is stored if any row lacks an ID. Returns the number of rows
+/// loaded.
+pub fn load_csv(conn: &Connection, text: &str) -> Result<usize> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    let (_, header) = lines.next().context("empty users file")?;
+    let header: Vec<String> = split_line(header.trim_start_matches('\u{feff}')).iter().map(|h| h.to_lowercase()).collect();
+    let find = |name: &str| header.iter().position(|h| h == name);
+    let id_at = find("user_id").context("users file has no user_id column")?;
+    let (department_at, status_at, affiliation_at) = (find("department"), find("status"), find("affiliation"));
+
+    let mut rows = Vec::new();
+    for (i, line) in lines {
+        let fields = split_line(line);
+        let field = |at: Option<usize>| at.and_then(|at| fields.get(at)).filter(|f| !f.is_empty()).cloned();
+        let Some(id) = field(Some(id_at)) else {
+            bail!("line {}: no user_id", i + 1);
+        };
+        rows.push((id.to_lowercase(), field(department_at), field(status_at), field(affiliation_at)));
+    }
+
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let res = (|| -> Result<()> {
+        for (id, department, status, affiliation) in &rows {
+            conn.execute(
+                r#"
+                INSERT INTO users (user_id, department, status, affiliation) VALUES (?, ?, ?, ?)
+                ON CONFLICT (user_id) DO UPDATE SET
+                  department = excluded.department, status = excluded.status,
+                  affiliation = excluded.affiliation, updated_at = now()
+                "#,
+                params![id, department, status, affiliation],
+            )?;
+        }
+        Ok(())
+    })();
+    match res {
+        Ok(()) => conn.execute_batch("COMMIT")?,
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    Ok(rows.len())
+}
+
+/// Columns of `users` that usage can be broken down by.
+pub const ATTRIBUTES: &[&str] = &["department", "affiliation"];
+
+/// Requests, downloads, and users per value of `attribute` (one of
+/// `ATTRIBUTES`) among the requests matching `filter`, with each group's
+/// busiest platforms. Users with no loaded attributes, and session IDs, fall
+/// under a `null` group. A user has one value of each attribute, so groups
+/// don't overlap: those with fewer than `min_users` distinct users are
+/// pooled into `other`, which is itself left out when still too small, and
+/// platforms seen by fewer are dropped. No group can then be traced back to
+/// a handful of patrons' reading.
+pub fn usage_by(
+    conn: &Connection,
+    attribute: &str,
+    filter: &str,
+    args: &[String],
+    min_users: u32,
+    ports: &PortsConfig,
+) -> Result<serde_json::Value> {
+    if !ATTRIBUTES.contains(&attribute) {
+        bail!("unknown user attribute {:?}", attribute);
+    }
+    let min_users = i64::from(min_users);
+    // Filtered first: `users` has a `status` column of its own.
+    let joined = format!(
+        "(SELECT r.*, u.{attribute} AS grp FROM (SELECT * FROM requests WHERE {filter}) r \
+         LEFT JOIN users u ON u.user_id = lower(r.user_or_session))"
+    );
+    let sql = format!(
+        r#"
+        WITH {downloads},
+        dl AS (
+          SELECT u.{attribute} AS grp, count(*) AS n FROM d LEFT JOIN users u ON u.user_id = lower(d.who) GROUP BY 1
+        ),
+        v AS (
+          SELECT grp, count(*) AS requests,
+                 count(DISTINCT COALESCE(user_or_session, remote_addr)) AS users
+          FROM {joined}
+          GROUP BY 1
+        )
+        SELECT v.grp, v.requests, v.users, COALESCE(dl.n, 0)
+        FROM v LEFT JOIN dl ON dl.grp IS NOT DISTINCT FROM v.grp
+        ORDER BY v.requests DESC, v.grp NULLS LAST
+        "#,
+        downloads = downloads::cte(filter, ports),
+    );
+    let bind: Vec<&String> = args.iter().chain(args.iter()).collect();
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(bind))?;
+    let mut groups = Vec::new();
+    let (mut other_groups, mut other_requests, mut other_users, mut other_downloads) = (0i64, 0i64, 0i64, 0i64);
+    while let Some(r) = rows.next()? {
+        let group: Option<String> = r.get(0)?;
+        let requests: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        let downloads: i64 = r.get(3)?;
+        if users < min_users {
+            other_groups += 1;
+            other_requests += requests;
+            other_users += users;
+            other_downloads += downloads;
+        } else {
+            groups.push((group, requests, users, downloads, Vec::new()));
+        }
+    }
+
+    let host = db::target_host(ports);
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT grp, {host} AS platform, count(*) AS requests,
+               count(DISTINCT COALESCE(user_or_session, remote_addr)) AS users
+        FROM {joined}
+        WHERE {host} IS NOT NULL
+        GROUP BY 1, 2
+        HAVING count(DISTINCT COALESCE(user_or_session, remote_addr)) >= {min_users}
+        QUALIFY row_number() OVER (PARTITION BY grp ORDER BY requests DESC, platform) <= {TOP_PLATFORMS}
+        ORDER BY requests DESC, platform
+        "#
+    ))?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+    while let Some(r) = rows.next()? {
+        let group: Option<String> = r.get(0)?;
+        let platform: String = r.get(1)?;
+        let requests: i64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        if let Some(g) = groups.iter_mut().find(|g| g.0 == group) {
+            g.4.push(json!({"platform": platform, "requests": requests, "users": users}));
+        }
+    }
+
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(group, requests, users, downloads, platforms)| {
+            json!({
+                attribute: group,
+                "requests": requests,
+                "users": users,
+                "downloads": downloads,
+                "platforms": platforms,
+            })
+        })
+        .collect();
+    let other = (other_groups > 0 && other_users >= min_users).then(|| {
+        json!({
+            "groups": other_groups,
+            "requests": other_requests,
+            "users": other_users,
+            "downloads": other_downloads,
+        })
+    });
+    Ok(json!({ "groups": groups, "other": other, "pooled": other_groups, "min_users": min_users }))
+}
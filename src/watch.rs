@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use duckdb::{Connection, OptionalExt, params};
+use notify::{RecursiveMode, Watcher};
+
+use crate::{enrich::Pipeline, import};
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    pub dir: PathBuf,
+    /// File names to pick up, with `*` and `?` wildcards
+    pub pattern: String,
+    /// How long a file must go unchanged before it counts as complete
+    pub settle: Duration,
+    /// Where imported files are moved; without it they stay put and the
+    /// imports table is what marks them done
+    pub move_to: Option<PathBuf>,
+    pub import: import::ImportOptions,
+}
+
+/// `*` matches any run of characters, `?` any one; everything else is
+/// literal.
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Size and modification time, which change while a file is being written.
+type Stamp = (u64, SystemTime);
+
+fn already_imported(conn: &Connection, path: &Path) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM imports WHERE path = ? AND finished_at IS NOT NULL LIMIT 1",
+            params![path.to_string_lossy()],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Matching files in the directory with their current stamps, oldest
+/// first.
+fn scan(opts: &WatchOptions) -> Result<Vec<(PathBuf, Stamp)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&opts.dir).with_context(|| format!("read {}", opts.dir.display()))? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let name = entry.file_name();
+        if !meta.is_file() || !wildcard_match(&opts.pattern, &name.to_string_lossy()) {
+            continue;
+        }
+        files.push((entry.path(), (meta.len(), meta.modified()?)));
+    }
+    files.sort_by_key(|(path, (_, modified))| (*modified, path.clone()));
+    Ok(files)
+}
+
+/// Import files that EZproxy has finished writing as they appear, until
+/// interrupted. A file counts as finished once a newer matching file exists
+/// (log rotation has moved on) and it hasn't changed for `settle`; so the
+/// file currently being written is never imported half-way.
+pub fn run(conn: &mut Connection, opts: &WatchOptions, pipeline: &Pipeline) -> Result<()> {
+    // Absolute, so the paths recorded in the imports table are too.
+    let dir = opts.dir.canonicalize().with_context(|| format!("watch {}", opts.dir.display()))?;
+    let opts = WatchOptions { dir, ..opts.clone() };
+    if let Some(to) = &opts.move_to {
+        fs::create_dir_all(to).with_context(|| format!("create {}", to.display()))?;
+    }
+
+    // Events only wake the loop early; what to import is always decided
+    // from a fresh scan, so missed or coalesced events don't matter.
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&opts.dir, RecursiveMode::NonRecursive)?;
+    println!("Watching {} for {}", opts.dir.display(), opts.pattern);
+
+    // When each file was last seen to change, and how it looked then.
+    let mut seen: HashMap<PathBuf, (Stamp, Instant)> = HashMap::new();
+    // Files whose import failed, with the stamp they had; retried once
+    // they change.
+    let mut failed: HashMap<PathBuf, Stamp> = HashMap::new();
+    let tick = opts.settle.min(Duration::from_secs(30)).max(Duration::from_secs(1));
+
+    loop {
+        let files = scan(&opts)?;
+        seen.retain(|p, _| files.iter().any(|(f, _)| f == p));
+        let newest = files.last().map(|(p, _)| p.clone());
+
+        for (path, stamp) in &files {
+            let since = match seen.get(path) {
+                Some((s, at)) if s == stamp => *at,
+                _ => {
+                    seen.insert(path.clone(), (*stamp, Instant::now()));
+                    continue;
+                }
+            };
+            if Some(path) == newest.as_ref() || since.elapsed() < opts.settle {
+                continue;
+            }
+            if failed.get(path) == Some(stamp) || already_imported(conn, path)? {
+                continue;
+            }
+
+            println!("Importing {}", path.display());
+            match import::import_file(conn, path, &opts.import, pipeline) {
+                Ok(summary) => {
+                    failed.remove(path);
+                    println!(
+                        "import complete: {} ok={} bad={} skipped={}",
+                        path.display(),
+                        summary.ok,
+                        summary.bad,
+                        summary.skipped
+                    );
+                    if !summary.failures.is_empty() {
+                        println!("parse failures: {}", import::describe_counts(&summary.failures));
+                    }
+                    if !summary.scrubbed.is_empty() {
+                        println!("scrubbed: {}", import::describe_counts(&summary.scrubbed));
+                    }
+                    if let Some(to) = &opts.move_to {
+                        let dest = to.join(path.file_name().unwrap_or_default());
+                        fs::rename(path, &dest)
+                            .with_context(|| format!("move {} to {}", path.display(), dest.display()))?;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("import of {} failed: {:#}", path.display(), e);
+                    failed.insert(path.clone(), *stamp);
+                }
+            }
+        }
+
+        // Wait for a change in the directory, or the next settle check.
+        match rx.recv_timeout(tick) {
+            Ok(Err(e)) => eprintln!("watch error: {}", e),
+            Ok(Ok(_)) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("file watcher stopped"),
+        }
+        // Drain the burst a single write or rename tends to produce.
+        while rx.try_recv().is_ok() {}
+    }
+}
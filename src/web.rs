@@ -1,41 +1,437 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use axum::{
     Json, Router,
-    extract::{Query, State},
-    http::StatusCode,
-    response::Html,
-    routing::get,
+    body::Body,
+    extract::{Extension, Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
 };
-use duckdb::{Connection, params};
-use serde::Deserialize;
+use base64ct::{Base64, Encoding};
+use duckdb::{Connection, params_from_iter};
+use futures_util::stream;
+use notify::Watcher;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{Any, CorsLayer},
+};
+
+use crate::{audit, auth, baseline, calendar, capacity, charts, clients, config::{self, Config, PortsConfig, TitlesConfig}, costs, db, destinations, devices, downloads, duration, enrich, federation, forecast, grafana, honeytokens, integrity, jobs, licenses, logins, parser, perf, policy, privacy, public, schema, sessions, summary, pseudonyms, seen, tokens, trends, turnaways, ui, users};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_path: Arc<String>,
+    live: Arc<RwLock<Arc<Live>>>,
+    /// Database time per endpoint, for `/api/perf` and `/metrics`
+    pub timings: Arc<perf::Timings>,
+}
+
+/// What `serve` builds from the config file; replaced whole when the file
+/// is reloaded.
+pub struct Live {
+    pub config: Arc<Config>,
+    pub networks: clients::NetworkList,
+    /// None when no `[auth]` section is configured
+    pub auth: Option<auth::Authenticator>,
+    /// Part of every ETag, so a restart or reload with different config
+    /// invalidates what browsers cached
+    pub loaded_at: String,
+    /// `server.query_timeout`; None for no limit
+    pub query_timeout: Option<Duration>,
+    /// `server.max_queries`
+    slots: Arc<tokio::sync::Semaphore>,
+}
+
+impl Live {
+    /// Check `config` for mistakes that would otherwise only show up in a
+    /// request, and build what the handlers need from it.
+    fn build(config: Config) -> anyhow::Result<Live> {
+        if let Some(fed) = &config.federation {
+            duration::parse_duration(&fed.window).map_err(|e| e.context("federation.window"))?;
+        }
+        let query_timeout = parse_query_timeout(&config.server.query_timeout)?;
+        if config.server.max_queries == 0 {
+            anyhow::bail!("server.max_queries must be at least 1");
+        }
+        let slots = Arc::new(tokio::sync::Semaphore::new(config.server.max_queries));
+        if ui::theme(&config.ui.default_theme).is_none() {
+            anyhow::bail!("unknown ui.default_theme {:?}", config.ui.default_theme);
+        }
+        for entry in &config.ports.expected {
+            if parse_scheme_port(entry).is_none() {
+                anyhow::bail!("ports.expected entries look like \"https:8443\", got {:?}", entry);
+            }
+        }
+        let conn = Connection::open_in_memory()?;
+        for system in &config.referrers {
+            if let Err(e) = db::check_regex(&conn, &system.pattern) {
+                anyhow::bail!("invalid pattern for referrer system {:?}: {}", system.name, e);
+            }
+        }
+
+        calendar::validate(&config.calendar)?;
+        destinations::Rules::new(&config.destinations, &config.licenses)?;
+        // Import jobs build their own; this only surfaces config mistakes now.
+        enrich::Pipeline::from_config(&config.enrich)?;
+        parser::Timestamps::from_config(&config.parser)?;
+
+        let networks = clients::load(&config.client_types)?;
+        let auth = match &config.auth {
+            Some(a) => Some(auth::Authenticator::new(a.clone())?),
+            None => None,
+        };
+        Ok(Live { config: Arc::new(config), networks, auth, loaded_at: chrono::Utc::now().to_rfc3339(), query_timeout, slots })
+    }
+}
+
+/// `server.query_timeout` as a limit, or None for `0s`.
+pub(crate) fn parse_query_timeout(s: &str) -> anyhow::Result<Option<Duration>> {
+    let d = duration::parse_duration(s).map_err(|e| e.context("server.query_timeout"))?;
+    let d = d.to_std().map_err(|_| anyhow::anyhow!("server.query_timeout {:?} is negative", s))?;
+    Ok((!d.is_zero()).then_some(d))
+}
+
+impl AppState {
+    /// The config and what was built from it, as of now.
+    fn live(&self) -> Arc<Live> {
+        self.live.read().expect("config lock poisoned").clone()
+    }
+
+    fn config(&self) -> Arc<Config> {
+        self.live().config.clone()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// A failed request as every endpoint reports it: the status, and
+/// `{"error": {"code", "message", "details", "request_id"}}`. `code` is
+/// stable for clients to branch on; `message` is for whoever reads it.
+#[derive(Debug, Clone)]
+struct ApiError {
+    status: StatusCode,
+    code: String,
+    message: String,
+    details: Option<serde_json::Value>,
 }
 
-type ApiResult<T> = Result<Json<T>, (StatusCode, String)>;
+impl ApiError {
+    fn new(status: StatusCode, code: &str, message: impl Into<String>) -> ApiError {
+        ApiError { status, code: code.to_string(), message: message.into(), details: None }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    fn not_found(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    /// Something a client can act on, e.g. the values a parameter accepts.
+    fn with_details(self, details: serde_json::Value) -> ApiError {
+        ApiError { details: Some(details), ..self }
+    }
+
+    fn body(&self, request_id: Option<&str>) -> serde_json::Value {
+        json!({ "error": {
+            "code": self.code,
+            "message": self.message,
+            "details": self.details,
+            "request_id": request_id,
+        }})
+    }
+}
+
+impl IntoResponse for ApiError {
+    /// The body without `request_id`, which `error_body` adds from the
+    /// request it answers.
+    fn into_response(self) -> Response {
+        let mut resp = (self.status, Json(self.body(None))).into_response();
+        resp.extensions_mut().insert(self);
+        resp
+    }
+}
+
+/// Most of a plain-text error body `error_body` reads to wrap.
+const PLAIN_ERROR_LIMIT: usize = 64 * 1024;
+
+/// The ID `request_id` gave a request, for the log lines it leads to.
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Longest `X-Request-Id` taken from a client rather than replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// A fresh request ID: 16 hex digits, unique within this server's lifetime
+/// and unlikely to repeat across restarts.
+fn new_request_id() -> String {
+    static STARTED: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let started = STARTED.get_or_init(|| format!("{}:{}", std::process::id(), chrono::Utc::now().to_rfc3339()));
+    let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    integrity::raw_hash(&format!("{}:{}", started, n))[..16].to_string()
+}
 
-fn internal_error<E: std::fmt::Display>(e: E) -> (StatusCode, String) {
-    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+/// Give every request an ID: the `X-Request-Id` it came with (a reverse
+/// proxy's, say) or a new one. It goes back as `X-Request-Id`, into error
+/// bodies, and into the server's log lines about the request.
+async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN && v.bytes().all(|b| b.is_ascii_graphic()))
+        .map_or_else(new_request_id, str::to_string);
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let mut resp = next.run(req).await;
+    if let Ok(v) = header::HeaderValue::from_str(&id) {
+        resp.headers_mut().insert("x-request-id", v);
+    }
+    resp
 }
 
-fn with_conn<T>(
-    db_path: &str,
+/// Every error in `ApiError`'s shape, with the request's ID: the handlers'
+/// get the ID filled in, and the plain-text ones axum makes itself, for a
+/// bad query string or an unknown route, are wrapped, their code named
+/// after the status. Each is logged with the ID.
+async fn error_body(req: Request, next: Next) -> Response {
+    let request_id = req.extensions().get::<RequestId>().map(|r| r.0.clone());
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    let resp = next.run(req).await;
+    if !resp.status().is_client_error() && !resp.status().is_server_error() {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let error = match parts.extensions.remove::<ApiError>() {
+        Some(e) => e,
+        None => {
+            let text = axum::body::to_bytes(body, PLAIN_ERROR_LIMIT).await.unwrap_or_default();
+            let reason = parts.status.canonical_reason().unwrap_or("error");
+            let text = String::from_utf8_lossy(&text).trim().to_string();
+            let message = if text.is_empty() { reason.to_string() } else { text };
+            ApiError::new(parts.status, &reason.to_ascii_lowercase().replace([' ', '-'], "_"), message)
+        }
+    };
+    eprintln!(
+        "[{}] {} {} {} {}: {}",
+        request_id.as_deref().unwrap_or("-"),
+        method,
+        path,
+        parts.status.as_u16(),
+        error.code,
+        error.message.lines().next().unwrap_or_default()
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    Response::from_parts(parts, Body::from(error.body(request_id.as_deref()).to_string()))
+}
+
+/// Seconds `Retry-After` asks a client to wait when the database is busy.
+const BUSY_RETRY_AFTER_SECS: u32 = 5;
+
+/// SQL cut off by `server.query_timeout`.
+#[derive(Debug)]
+struct QueryTimeout(Duration);
+
+impl std::fmt::Display for QueryTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the query was cancelled after {}s (server.query_timeout); try a shorter date range or more filters",
+            self.0.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for QueryTimeout {}
+
+/// Longest a request waits for a query slot before it is turned away.
+const QUERY_QUEUE_WAIT: Duration = Duration::from_secs(30);
+
+/// No query slot came free within `QUERY_QUEUE_WAIT`.
+#[derive(Debug)]
+struct QueriesQueued;
+
+impl std::fmt::Display for QueriesQueued {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many queries are running (server.max_queries); try again in a few seconds")
+    }
+}
+
+impl std::error::Error for QueriesQueued {}
+
+/// A query's place among `server.max_queries`, given back when dropped.
+/// Holding queries to that many at once lets a burst of dashboard tabs
+/// queue up instead of each query claiming its own share of memory.
+type QuerySlot = tokio::sync::OwnedSemaphorePermit;
+
+/// Wait up to `QUERY_QUEUE_WAIT` for a query slot, without holding a thread.
+async fn query_slot(st: &AppState) -> Result<QuerySlot, QueriesQueued> {
+    let slots = st.live().slots.clone();
+    match tokio::time::timeout(QUERY_QUEUE_WAIT, slots.acquire_owned()).await {
+        Ok(Ok(slot)) => Ok(slot),
+        _ => Err(QueriesQueued),
+    }
+}
+
+/// A 500; a 503 when the database is busy (see `db::is_busy`), which
+/// `retry_after` tells the client to retry; or a 504 when the query ran
+/// out of time.
+fn internal_error(e: anyhow::Error) -> ApiError {
+    if let Some(timeout) = e.downcast_ref::<QueryTimeout>() {
+        return ApiError::new(StatusCode::GATEWAY_TIMEOUT, "query_timeout", e.to_string())
+            .with_details(json!({ "timeout_secs": timeout.0.as_secs_f64() }));
+    }
+    if e.is::<QueriesQueued>() {
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "queries_queued", e.to_string());
+    }
+    if db::is_busy(&format!("{:#}", e)) {
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "database_busy",
+            "the database is busy, most likely with an import; try again in a few seconds",
+        );
+    }
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string())
+}
+
+/// `Retry-After` on every 503, however far down it was made.
+async fn retry_after(mut resp: Response) -> Response {
+    if resp.status() == StatusCode::SERVICE_UNAVAILABLE {
+        resp.headers_mut().entry(header::RETRY_AFTER).or_insert(BUSY_RETRY_AFTER_SECS.into());
+    }
+    resp
+}
+
+/// Run `f` on a fresh connection, recording how long it took under `name`
+/// in `AppState::timings`, and interrupting it after `server.query_timeout`.
+async fn with_conn<T: Send + 'static>(
+    st: &AppState,
+    name: &'static str,
+    f: impl FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    with_conn_timeout(st, name, st.live().query_timeout, f).await
+}
+
+/// `with_conn` with a limit of `timeout` rather than the configured one.
+/// Either way it first waits its turn among `server.max_queries`.
+async fn with_conn_timeout<T: Send + 'static>(
+    st: &AppState,
+    name: &'static str,
+    timeout: Option<Duration>,
+    f: impl FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let slot = query_slot(st).await?;
+    let st = st.clone();
+    blocking(move || on_conn(&st, name, timeout, slot, f)).await
+}
+
+/// The part of `with_conn_timeout` that waits on the database, for callers
+/// already on a blocking thread. `_slot` is held until `f` is done.
+fn on_conn<T>(
+    st: &AppState,
+    name: &str,
+    timeout: Option<Duration>,
+    _slot: QuerySlot,
     f: impl FnOnce(&Connection) -> anyhow::Result<T>,
 ) -> anyhow::Result<T> {
-    let conn = Connection::open(db_path)?;
-    Ok(f(&conn)?)
+    let started = std::time::Instant::now();
+    let conn = db::open_db(st.db_path.as_str())?;
+    let res = with_deadline(&conn, timeout, || f(&conn));
+    st.timings.record(name, started.elapsed());
+    res
+}
+
+/// Run `f`, which waits on the database, on tokio's blocking pool, so the
+/// runtime's own threads stay free for other requests. A panic in `f`
+/// carries on here, as if `f` had been called directly.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(out) => out,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// `f`, with whatever it runs on `conn` interrupted once `timeout` passes.
+/// The runtime's timer keeps the time, so no thread waits it out; call this
+/// from a blocking thread the runtime started, as `blocking` does.
+fn with_deadline<T>(
+    conn: &Connection,
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let Some(timeout) = timeout else {
+        return f();
+    };
+    let handle = conn.interrupt_handle();
+    let fired = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let timer = {
+        let fired = fired.clone();
+        tokio::runtime::Handle::current().spawn(async move {
+            tokio::time::sleep(timeout).await;
+            fired.store(true, std::sync::atomic::Ordering::SeqCst);
+            handle.interrupt();
+        })
+    };
+    let res = f();
+    timer.abort();
+    match res {
+        Err(_) if fired.load(std::sync::atomic::Ordering::SeqCst) => Err(QueryTimeout(timeout).into()),
+        res => res,
+    }
 }
 
-pub async fn serve(db_path: String, bind: SocketAddr) -> anyhow::Result<()> {
+/// Serve the dashboard. With `config_path`, the config is reloaded from it
+/// on SIGHUP and whenever the file changes; see `watch_config`.
+pub async fn serve(db_path: String, bind: SocketAddr, config: Config, config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    db::init_schema(&db::open_db(&db_path)?)?;
+    let live = Live::build(config)?;
+    let config = live.config.clone();
+    let shared: config::Shared = Arc::new(RwLock::new(config.clone()));
+    let live = Arc::new(RwLock::new(Arc::new(live)));
+    tokio::spawn(jobs::run_worker(db_path.clone(), shared.clone()));
+    jobs::spawn_schedules(&db_path, &config);
+    if let Some(path) = config_path {
+        tokio::spawn(watch_config(path, live.clone(), shared));
+    }
+
     let state = AppState {
         db_path: Arc::new(db_path),
+        live,
+        timings: Arc::new(perf::Timings::default()),
     };
+    let app = app(state);
+
+    println!("Listening on http://{}", bind);
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
 
+/// The dashboard as `serve` runs it over the database at `db_path`, without
+/// the job worker, schedules, or config reloads, for tests to send requests
+/// to. The schema must already be there.
+pub fn router(db_path: String, config: Config) -> anyhow::Result<Router> {
+    let live = Live::build(config)?;
+    Ok(app(AppState {
+        db_path: Arc::new(db_path),
+        live: Arc::new(RwLock::new(Arc::new(live))),
+        timings: Arc::new(perf::Timings::default()),
+    }))
+}
+
+/// Every route and the middleware in front of them.
+fn app(state: AppState) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -43,6 +439,10 @@ pub async fn serve(db_path: String, bind: SocketAddr) -> anyhow::Result<()> {
 
     let app = Router::new()
         .route("/", get(index))
+        .route("/public", get(public_page))
+        .route("/api/public", get(public_stats))
+        .route("/api/dashboard", get(dashboard))
+        .route("/api/summary", get(summary))
         .route("/api/requests_over_time", get(requests_over_time))
         .route("/api/top_hosts", get(top_hosts))
         .route("/api/status_codes", get(status_codes))
@@ -51,450 +451,2322 @@ pub async fn serve(db_path: String, bind: SocketAddr) -> anyhow::Result<()> {
         .route("/api/hourly_heatmap", get(hourly_heatmap))
         .route("/api/error_analysis", get(error_analysis))
         .route("/api/top_paths", get(top_paths))
+        .route("/api/top_query_params", get(top_query_params))
+        .route("/api/top_extensions", get(top_extensions))
         .route("/api/user_agents", get(user_agents))
-        .layer(cors)
-        .with_state(state);
+        .route("/api/ui_config", get(ui_config))
+        .route("/api/i18n", get(i18n))
+        .route("/api/top_issns", get(top_issns))
+        .route("/api/anomalies", get(anomalies))
+        .route("/api/session_durations", get(session_durations))
+        .route("/api/entry_pages", get(entry_pages))
+        .route("/api/referrer_systems", get(referrer_systems))
+        .route("/api/turnaways", get(turnaways))
+        .route("/api/downloads", get(downloads))
+        .route("/api/login_failures", get(login_failures))
+        .route("/api/license_pressure", get(license_pressure))
+        .route("/api/cost_per_use", get(cost_per_use))
+        .route("/api/costs", post(upload_costs))
+        .route("/api/access_audit", get(access_audit))
+        .route("/api/pseudonyms/resolve", post(resolve_pseudonym))
+        .route("/api/pseudonyms/resolutions", get(pseudonym_resolutions))
+        .route("/api/usage_by_department", get(usage_by_department))
+        .route("/api/usage_by_affiliation", get(usage_by_affiliation))
+        .route("/api/client_types", get(client_types))
+        .route("/api/ports", get(ports))
+        .route("/api/calendar_overlay", get(calendar_overlay))
+        .route("/api/trends", get(trends))
+        .route("/api/federation/summary", get(federation_summary))
+        .route("/api/federation", get(federation_overview))
+        .route("/api/facets", get(facets))
+        .route("/api/chart/{file}", get(chart))
+        .route("/api/policy_violations", get(policy_violations))
+        .route("/api/honeytoken_hits", get(honeytoken_hits))
+        .route("/api/new_hosts", get(new_hosts))
+        .route("/api/proxy_abuse", get(proxy_abuse))
+        .route("/api/peak_windows", get(peak_windows))
+        .route("/api/concurrency_over_time", get(concurrency_over_time))
+        .route("/api/forecast", get(forecast))
+        .route("/api/jobs", get(list_jobs).post(create_job))
+        .route("/api/jobs/{id}", get(get_job))
+        .route("/api/requests", get(raw_requests))
+        .route("/api/requests/export", get(export_requests))
+        .route("/api/requests/export/{id}/manifest", get(export_manifest))
+        .route("/api/query", post(run_query))
+        .route("/api/schema", get(schema))
+        .route("/api/perf", get(perf_timings))
+        .route("/metrics", get(metrics))
+        .route("/grafana", get(grafana_health))
+        .route("/grafana/", get(grafana_health))
+        .route("/grafana/search", post(grafana_search))
+        .route("/grafana/query", post(grafana_query))
+        .layer(middleware::from_fn_with_state(state.clone(), etag))
+        .layer(middleware::from_fn_with_state(state.clone(), resolve_range))
+        .layer(middleware::from_fn_with_state(state.clone(), record_access))
+        .layer(middleware::from_fn_with_state(state.clone(), require_role))
+        .layer(middleware::map_response(retry_after))
+        .layer(middleware::from_fn(error_body))
+        .layer(middleware::from_fn(request_id))
+        .layer(cors);
+    // Long-range series and exports shrink several times over, which
+    // matters over campus VPNs.
+    let app = if state.config().server.compression {
+        app.layer(CompressionLayer::new())
+    } else {
+        app
+    };
+    app.with_state(state)
+}
 
-    println!("Listening on http://{}", bind);
-    let listener = tokio::net::TcpListener::bind(bind).await?;
-    axum::serve(listener, app).await?;
-    Ok(())
+/// Reload the config from `path` on SIGHUP and whenever the file changes,
+/// so filters, panels, policies, and logins can be tuned without a restart.
+/// A file that doesn't load or check out is reported and the running config
+/// kept. The bind address, database, schedules, and compression stay as
+/// they were at startup.
+async fn watch_config(path: PathBuf, live: Arc<RwLock<Arc<Live>>>, shared: config::Shared) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // Editors often replace the file rather than write to it, so watch the
+    // directory and pick out its events.
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(std::path::Path::new(".")).to_path_buf();
+    let name = path.file_name().map(|n| n.to_owned());
+    let file_tx = tx.clone();
+    let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        use notify::{EventKind, event::ModifyKind};
+        // Not access events: reloading reads the file and would set them off.
+        if let Ok(event) = res
+            && matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any)
+            )
+            && event.paths.iter().any(|p| p.file_name() == name.as_deref())
+        {
+            let _ = file_tx.send(());
+        }
+    });
+    let _watcher = match watcher.and_then(|mut w| w.watch(&dir, notify::RecursiveMode::NonRecursive).map(|()| w)) {
+        Ok(w) => Some(w),
+        Err(e) => {
+            eprintln!("not watching {} for changes: {}", path.display(), e);
+            None
+        }
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+        let Ok(mut hup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while hup.recv().await.is_some() && tx.send(()).is_ok() {}
+    });
+
+    while rx.recv().await.is_some() {
+        // One save is often several events; take them as one.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        while rx.try_recv().is_ok() {}
+
+        match config::load(Some(&path.to_string_lossy())).and_then(Live::build) {
+            Ok(next) => {
+                *shared.write().expect("config lock poisoned") = next.config.clone();
+                *live.write().expect("config lock poisoned") = Arc::new(next);
+                eprintln!("reloaded config from {}", path.display());
+            }
+            Err(e) => eprintln!("config {} not reloaded: {:#}", path.display(), e),
+        }
+    }
+}
+
+/// Reject requests whose credentials don't carry the role the route needs
+/// (see `auth::required_role`), or for API tokens the matching scope.
+/// When auth is off everything passes but `auth::needs_auth` requests.
+async fn require_role(State(st): State<AppState>, mut req: Request, next: Next) -> Response {
+    let live = st.live();
+    let Some(authenticator) = &live.auth else {
+        if auth::needs_auth(req.method(), req.uri().path()) {
+            return ApiError::new(
+                StatusCode::FORBIDDEN,
+                "auth_required",
+                format!("{} {} needs [auth]", req.method(), req.uri().path()),
+            )
+            .into_response();
+        }
+        return next.run(req).await;
+    };
+    // The handlers refuse these themselves when `[public]` is off.
+    if auth::PUBLIC_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    let needed = auth::required_role(req.method(), req.uri().path());
+    let unauthorized = || {
+        (
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"ezvis\"")],
+            ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "authentication required"),
+        )
+            .into_response()
+    };
+
+    if let Some(secret) = auth::bearer_token(req.headers()) {
+        let scope = auth::Scope::for_role(needed);
+        let secret = secret.to_string();
+        return match with_conn(&st, "auth", move |conn| tokens::lookup(conn, &secret)).await {
+            Err(e) => internal_error(e).into_response(),
+            Ok(None) => unauthorized(),
+            Ok(Some((_, scopes))) if !scopes.contains(&scope) => {
+                ApiError::new(StatusCode::FORBIDDEN, "missing_scope", format!("requires the {} scope", scope.as_str()))
+                    .with_details(json!({ "scope": scope.as_str() }))
+                    .into_response()
+            }
+            Ok(Some((label, scopes))) => {
+                req.extensions_mut().insert(auth::Caller(label));
+                req.extensions_mut().insert(auth::Grant::Scopes(scopes));
+                next.run(req).await
+            }
+        };
+    }
+
+    match authenticator.authenticate(req.headers()) {
+        None => unauthorized(),
+        Some((_, role)) if role < needed => {
+            ApiError::new(StatusCode::FORBIDDEN, "missing_role", format!("requires the {} role", needed.as_str()))
+                .with_details(json!({ "role": needed.as_str() }))
+                .into_response()
+        }
+        Some((user, role)) => {
+            req.extensions_mut().insert(auth::Caller(user));
+            req.extensions_mut().insert(auth::Grant::Role(role));
+            next.run(req).await
+        }
+    }
+}
+
+/// Largest ad-hoc SQL body `record_access` accepts.
+const AUDIT_MAX_BODY: usize = 1 << 20;
+
+/// With `[auth]`, record each authenticated request in `access_audit`
+/// before serving it, so viewing patron data leaves a trail. A request that
+/// can't be recorded isn't served.
+async fn record_access(State(st): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(auth::Caller(caller)) = req.extensions().get::<auth::Caller>().cloned() else {
+        return next.run(req).await;
+    };
+    let (parts, body) = req.into_parts();
+    // The SQL is what was asked for; other bodies, such as keys and
+    // uploaded CSVs, are left out.
+    let (body, sql) = if parts.uri.path() == "/api/query" {
+        match axum::body::to_bytes(body, AUDIT_MAX_BODY).await {
+            Ok(bytes) => {
+                let sql = String::from_utf8_lossy(&bytes).into_owned();
+                (Body::from(bytes), Some(sql))
+            }
+            Err(_) => {
+                return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "request body too large")
+                    .into_response();
+            }
+        }
+    } else {
+        (body, None)
+    };
+    let (method, uri) = (parts.method.clone(), parts.uri.clone());
+    let recorded = with_conn(&st, "audit", move |conn| {
+        audit::record(conn, &caller, method.as_str(), uri.path(), uri.query(), sql.as_deref())
+    })
+    .await;
+    if let Err(e) = recorded {
+        return internal_error(e).into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessAuditParams {
+    caller: Option<String>,
+    start: Option<Time>,
+    end: Option<Time>,
+    limit: Option<usize>,
+}
+
+/// Entries of the access audit, newest first; see `record_access`.
+async fn access_audit(
+    State(st): State<AppState>,
+    Query(q): Query<AccessAuditParams>,
+) -> ApiResult<serde_json::Value> {
+    let limit = q.limit.unwrap_or(100);
+    let out = with_conn(&st, "access_audit", move |conn| {
+        let (start, end) = (q.start.map(Time::sql), q.end.map(Time::sql));
+        audit::list(conn, q.caller.as_deref(), start.as_deref(), end.as_deref(), limit)
+    })
+    .await
+    .map_err(internal_error)?;
+    Ok(Json(json!({ "entries": out })))
+}
+
+/// Rewrite `?range=` into the `start` and `end` it stands for now (see
+/// `calendar::resolve_range`), so every endpoint takes it, and an ETag
+/// covers the times it resolved to rather than the shortcut.
+async fn resolve_range(State(st): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(query) = req.uri().query() else {
+        return next.run(req).await;
+    };
+    let (ranges, mut pairs): (Vec<&str>, Vec<&str>) = query.split('&').partition(|kv| kv.starts_with("range="));
+    let Some(range) = ranges.last().and_then(|kv| kv.strip_prefix("range=")).filter(|r| !r.is_empty()) else {
+        return next.run(req).await;
+    };
+    if pairs.iter().any(|kv| kv.starts_with("start=") || kv.starts_with("end=")) {
+        return ApiError::bad_request("range can't be combined with start or end").into_response();
+    }
+    let (start, end) = match calendar::resolve_range(&st.config().calendar, range, chrono::Utc::now()) {
+        Ok(r) => r,
+        Err(e) => return ApiError::bad_request(format!("range: {e:#}")).into_response(),
+    };
+    // In UTC with a `Z`, so the times need no escaping.
+    let time = |t: chrono::DateTime<chrono::Utc>| t.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true);
+    let (start, end) = (format!("start={}", time(start)), end.map(|e| format!("end={}", time(e))));
+    pairs.push(&start);
+    pairs.extend(end.as_deref());
+    let uri = format!("{}?{}", req.uri().path(), pairs.join("&"));
+    match uri.parse() {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(e) => return ApiError::bad_request(format!("range: {e}")).into_response(),
+    }
+    next.run(req).await
+}
+
+/// Endpoints whose response depends only on the query string, the data
+/// (`db::data_version`), and the config: every panel plus the dashboard
+/// batch and the static lookups.
+fn cacheable(path: &str) -> bool {
+    let Some(name) = path.strip_prefix("/api/") else {
+        return false;
+    };
+    matches!(name, "dashboard" | "calendar_overlay" | "ui_config" | "i18n" | "public") || PANELS.iter().any(|(n, _)| *n == name)
+}
+
+/// Whether a query string has a time relative to now, such as `start=-7d`,
+/// whose answer moves with the clock as well as the data.
+fn relative_time(query: Option<&str>) -> bool {
+    query.is_some_and(|q| {
+        q.split('&').any(|kv| {
+            let value = kv.strip_prefix("start=").or_else(|| kv.strip_prefix("end=")).unwrap_or_default();
+            value.starts_with('-') || value.to_ascii_lowercase().starts_with("%2d")
+        })
+    })
+}
+
+/// ETags for aggregate endpoints, so the dashboard's auto-refresh gets a
+/// 304 instead of the same JSON again until something is imported. The tag
+/// is a hash of the data version, the path and query string, what the
+/// caller may see (`/api/dashboard` leaves out panels above their role),
+/// and the server start time; it's weak because compression changes the
+/// bytes.
+async fn etag(State(st): State<AppState>, req: Request, next: Next) -> Response {
+    if req.method() != axum::http::Method::GET || !cacheable(req.uri().path()) || relative_time(req.uri().query()) {
+        return next.run(req).await;
+    }
+    let version = match with_conn(&st, "etag", db::data_version).await {
+        Ok(v) => v,
+        Err(e) => return internal_error(e).into_response(),
+    };
+    let grant = req.extensions().get::<auth::Grant>().map(|g| format!("{:?}", g)).unwrap_or_default();
+    let key = format!("{}\n{}\n{}\n{}", st.live().loaded_at, version, req.uri(), grant);
+    let tag = format!("W/\"{}\"", &integrity::raw_hash(&key)[..32]);
+
+    let matched = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == tag || t.trim() == "*"));
+    let mut resp = if matched {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        next.run(req).await
+    };
+    if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED {
+        let headers = resp.headers_mut();
+        if let Ok(v) = tag.parse() {
+            headers.insert(header::ETAG, v);
+        }
+        // Always revalidate. Behind auth, shared proxies mustn't keep a copy
+        // to hand to whoever asks next.
+        let policy = if st.live().auth.is_some() { "private, no-cache" } else { "no-cache" };
+        headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static(policy));
+    }
+    resp
 }
 
 async fn index() -> Html<&'static str> {
     Html(INDEX_HTML)
 }
 
+/// Coarse usage figures anyone may see; see `public::stats`.
+async fn public_page(State(st): State<AppState>) -> Result<Html<&'static str>, ApiError> {
+    if st.config().public.is_none() {
+        return Err(ApiError::not_found("no [public] section in config"));
+    }
+    Ok(Html(PUBLIC_HTML))
+}
+
+async fn public_stats(State(st): State<AppState>) -> ApiResult<serde_json::Value> {
+    let config = st.config();
+    let Some(cfg) = config.public.clone() else {
+        return Err(ApiError::not_found("no [public] section in config"));
+    };
+    let out = with_conn(&st, "public", move |conn| public::stats(conn, &cfg, &config.ports))
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(out))
+}
+
+async fn ui_config(State(st): State<AppState>) -> Json<serde_json::Value> {
+    Json(json!({
+        "default_theme": st.config().ui.default_theme,
+        "themes": ui::THEMES,
+    }))
+}
+
 #[derive(Debug, Deserialize)]
-struct TimeParams {
-    start: Option<String>,
-    end: Option<String>,
+struct LangParams {
+    lang: Option<String>,
 }
 
-async fn requests_over_time(
+async fn i18n(
     State(st): State<AppState>,
-    Query(q): Query<TimeParams>,
-) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let query = match (&q.start, &q.end) {
-            (Some(_), Some(_)) => {
-                r#"
-                SELECT CAST(date_trunc('hour', ts) AS VARCHAR) AS t, count(*) AS n
-                FROM requests
-                WHERE ts >= CAST(? AS TIMESTAMPTZ) AND ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY 1
-            "#
+    Query(q): Query<LangParams>,
+    headers: HeaderMap,
+) -> Json<serde_json::Value> {
+    let accept = headers.get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok());
+    let lang = ui::negotiate(q.lang.as_deref(), accept, &st.config().ui.default_language);
+    let languages: Vec<_> = ui::LANGUAGES
+        .iter()
+        .map(|(code, label, _)| json!({"code": code, "label": label}))
+        .collect();
+
+    Json(json!({
+        "lang": lang,
+        "languages": languages,
+        "strings": ui::catalog(lang),
+    }))
+}
+
+/// A `start` or `end` parameter, parsed by `duration::parse_time` as the
+/// query string is read, so a malformed one is a 400 listing the accepted
+/// forms rather than a failed CAST. A relative one, such as `-7d`, is fixed
+/// against the clock then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+struct Time(chrono::DateTime<chrono::Utc>);
+
+impl<'de> Deserialize<'de> for Time {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        duration::parse_time(&s, chrono::Utc::now()).map(Time).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Time {
+    /// As a bind arg for `CAST(? AS TIMESTAMPTZ)`, or for the modules that
+    /// take their range as text.
+    fn sql(self) -> String {
+        self.0.to_rfc3339()
+    }
+}
+
+/// The time range, noise and asset filters, and cross-filters every panel
+/// shares, so a
+/// click on one chart narrows all of them. Endpoints with parameters of
+/// their own take this as a second `Query` over the same query string.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct FilterParams {
+    start: Option<Time>,
+    end: Option<Time>,
+    /// Leave out preflights, HEADs, and empty responses (`db::SIGNAL_CONDITION`)
+    #[serde(default)]
+    exclude_noise: bool,
+    /// Count scripts, styles, and images too, not just page views
+    /// (`db::PAGE_VIEW_CONDITION`)
+    #[serde(default)]
+    include_assets: bool,
+    /// Rank EZproxy's login and menu pages among hosts and paths too
+    /// (`db::RESOURCE_CONDITION`)
+    #[serde(default)]
+    include_proxy: bool,
+    host: Option<String>,
+    country: Option<String>,
+    /// A code such as `404`, or a class such as `4xx`
+    status: Option<String>,
+    method: Option<String>,
+    /// Users with this department in the `users` table
+    department: Option<String>,
+    /// Users with this affiliation in the `users` table
+    affiliation: Option<String>,
+    /// Requests from this time on are withheld; set by `embargoed`, never
+    /// from the query string
+    #[serde(skip)]
+    before: Option<String>,
+    /// `department` and `affiliation` values with fewer users loaded match
+    /// nothing; set by `run_panel`, never from the query string
+    #[serde(skip)]
+    attribute_floor: u32,
+}
+
+impl FilterParams {
+    /// WHERE clause for the time range and every filter, with its bind args.
+    /// `ports` says what a host filter matches.
+    fn condition(&self, ports: &PortsConfig) -> (String, Vec<String>) {
+        self.conditions(true, ports)
+    }
+
+    /// The same without the time range, for panels that pick their own dates.
+    fn filter_condition(&self, ports: &PortsConfig) -> (String, Vec<String>) {
+        self.conditions(false, ports)
+    }
+
+    fn conditions(&self, with_time: bool, ports: &PortsConfig) -> (String, Vec<String>) {
+        let mut conds: Vec<String> = Vec::new();
+        let mut args = Vec::new();
+        if with_time {
+            if let Some(s) = self.start {
+                conds.push("ts >= CAST(? AS TIMESTAMPTZ)".into());
+                args.push(s.sql());
             }
-            (Some(_), None) => {
-                r#"
-                SELECT CAST(date_trunc('hour', ts) AS VARCHAR) AS t, count(*) AS n
-                FROM requests
-                WHERE ts >= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY 1
-            "#
+            if let Some(e) = self.end {
+                conds.push("ts <= CAST(? AS TIMESTAMPTZ)".into());
+                args.push(e.sql());
             }
-            (None, Some(_)) => {
-                r#"
-                SELECT CAST(date_trunc('hour', ts) AS VARCHAR) AS t, count(*) AS n
-                FROM requests
-                WHERE ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY 1
-            "#
+        }
+        // Applies whatever the panel does with the time range.
+        if let Some(b) = &self.before {
+            conds.push("ts < CAST(? AS TIMESTAMPTZ)".into());
+            args.push(b.clone());
+        }
+        if self.exclude_noise {
+            conds.push(db::SIGNAL_CONDITION.into());
+        }
+        if !self.include_assets {
+            conds.push(db::PAGE_VIEW_CONDITION.into());
+        }
+        if let Some(host) = &self.host {
+            // Stored in punycode, but typed however the user likes.
+            conds.push(format!("{} = ?", db::target_host(ports)));
+            args.push(parser::ascii_host(host));
+        }
+        for (column, value) in [("country", &self.country), ("method", &self.method)] {
+            if let Some(v) = value {
+                conds.push(format!("{column} = ?"));
+                args.push(v.clone());
             }
-            (None, None) => {
-                r#"
-                SELECT CAST(date_trunc('hour', ts) AS VARCHAR) AS t, count(*) AS n
-                FROM requests
-                GROUP BY 1 ORDER BY 1
-                LIMIT 200
-            "#
+        }
+        for (attribute, value) in [("department", &self.department), ("affiliation", &self.affiliation)] {
+            if let Some(v) = value {
+                conds.push(format!(
+                    "lower(user_or_session) IN (SELECT user_id FROM (SELECT user_id, {attribute}, \
+                     count(*) OVER (PARTITION BY {attribute}) AS n FROM users) WHERE {attribute} = ? AND n >= {})",
+                    self.attribute_floor
+                ));
+                args.push(v.clone());
             }
-        };
+        }
+        if let Some(status) = &self.status {
+            match status.strip_suffix("xx").filter(|c| c.len() == 1 && c.as_bytes()[0].is_ascii_digit()) {
+                Some(class) => {
+                    conds.push("status BETWEEN ? AND ?".into());
+                    args.push(format!("{}00", class));
+                    args.push(format!("{}99", class));
+                }
+                // Compared as text so a malformed code matches nothing
+                // instead of failing the cast.
+                None => {
+                    conds.push("CAST(status AS VARCHAR) = ?".into());
+                    args.push(status.clone());
+                }
+            }
+        }
+        if conds.is_empty() {
+            ("TRUE".to_string(), args)
+        } else {
+            (conds.join(" AND "), args)
+        }
+    }
+
+    /// For the host and path rankings, where login chatter would otherwise
+    /// crowd out the vendors.
+    fn resource_condition(&self) -> &'static str {
+        if self.include_proxy { "TRUE" } else { db::RESOURCE_CONDITION }
+    }
+
+    /// Hourly series are capped when no range was asked for.
+    fn default_limit(&self) -> &'static str {
+        if self.start.is_none() && self.end.is_none() { "LIMIT 200" } else { "" }
+    }
+}
 
-        let mut stmt = conn.prepare(query)?;
-        let mut rows = match (&q.start, &q.end) {
-            (Some(s), Some(e)) => stmt.query(params![s, e])?,
-            (Some(s), None) => stmt.query(params![s])?,
-            (None, Some(e)) => stmt.query(params![e])?,
-            (None, None) => stmt.query(params![])?,
-        };
+/// `q` with the `[privacy]` embargo applied, for endpoints that name users
+/// or IPs.
+fn embargoed(st: &AppState, q: &FilterParams) -> FilterParams {
+    FilterParams { before: st.config().privacy.cutoff().map(|c| c.to_rfc3339()), ..q.clone() }
+}
+
+/// The earlier of `end` and the end of the `[privacy]` embargo, for
+/// endpoints that take their range as bounds rather than a condition.
+fn embargoed_end(st: &AppState, end: Option<Time>) -> Option<String> {
+    let Some(cutoff) = st.config().privacy.cutoff() else {
+        return end.map(Time::sql);
+    };
+    let last = Time(cutoff - chrono::Duration::microseconds(1));
+    Some(end.map_or(last, |end| end.min(last)).sql())
+}
+
+/// Quote a value for use as a SQL string literal, e.g. a file path passed to
+/// a table function.
+fn sql_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn sql_ident(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+/// Builds one endpoint's payload on a connection the caller opened, so the
+/// same aggregation can be served alone or batched by `/api/dashboard`.
+type PanelFn = fn(&AppState, &Connection, &FilterParams) -> anyhow::Result<serde_json::Value>;
+
+/// Aggregations `/api/dashboard` can batch, named after their endpoints.
+const PANELS: &[(&str, PanelFn)] = &[
+    ("summary", summary_panel),
+    ("trends", trends_panel),
+    ("requests_over_time", requests_over_time_panel),
+    ("top_hosts", top_hosts_panel),
+    ("status_codes", status_codes_panel),
+    ("top_countries", top_countries_panel),
+    ("bandwidth_over_time", bandwidth_over_time_panel),
+    ("forecast", forecast_panel),
+    ("hourly_heatmap", hourly_heatmap_panel),
+    ("error_analysis", error_analysis_panel),
+    ("top_paths", top_paths_panel),
+    ("top_query_params", top_query_params_panel),
+    ("top_extensions", top_extensions_panel),
+    ("user_agents", user_agents_panel),
+    ("top_issns", top_issns_panel),
+    ("anomalies", anomalies_panel),
+    ("session_durations", session_durations_panel),
+    ("entry_pages", entry_pages_panel),
+    ("referrer_systems", referrer_systems_panel),
+    ("turnaways", turnaways_panel),
+    ("downloads", downloads_panel),
+    ("login_failures", login_failures_panel),
+    ("license_pressure", license_pressure_panel),
+    ("cost_per_use", cost_per_use_panel),
+    ("usage_by_department", usage_by_department_panel),
+    ("usage_by_affiliation", usage_by_affiliation_panel),
+    ("client_types", client_types_panel),
+    ("ports", ports_panel),
+    ("proxy_abuse", proxy_abuse_panel),
+    ("federation", federation_panel),
+];
+
+async fn panel(st: &AppState, q: FilterParams, name: &'static str, f: PanelFn) -> ApiResult<serde_json::Value> {
+    let state = st.clone();
+    let payload = with_conn(st, name, move |conn| run_panel(&state, conn, &q, f)).await.map_err(internal_error)?;
+    Ok(Json(payload))
+}
 
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let t: String = r.get(0)?;
-            let n: i64 = r.get(1)?;
-            out.push(json!({"t": t, "n": n}));
+/// `f`'s payload with small groups held back per `[privacy]`, and too small
+/// a department or affiliation filtered on matching nothing; every way of
+/// serving a panel goes through here.
+fn run_panel(st: &AppState, conn: &Connection, q: &FilterParams, f: PanelFn) -> anyhow::Result<serde_json::Value> {
+    let q = FilterParams { attribute_floor: st.config().privacy.attribute_floor(), ..q.clone() };
+    Ok(privacy::protect(f(st, conn, &q)?, &st.config().privacy))
+}
+
+#[derive(Debug, Deserialize)]
+struct DashboardParams {
+    /// Comma-separated panel names [default: all of `PANELS`]
+    panels: Option<String>,
+}
+
+/// Several panels in one call, on one connection and one snapshot of the
+/// data. A panel that fails, or whose own endpoint the caller's role or
+/// scopes don't reach, is reported under `errors`; the rest still load.
+async fn dashboard(
+    State(st): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    grant: Option<Extension<auth::Grant>>,
+    Query(q): Query<DashboardParams>,
+    Query(filter): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    let wanted: Vec<(&str, PanelFn)> = match &q.panels {
+        None => PANELS.to_vec(),
+        Some(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                PANELS.iter().find(|(n, _)| *n == name).copied().ok_or_else(|| {
+                    let known: Vec<_> = PANELS.iter().map(|(n, _)| *n).collect();
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "unknown_panel",
+                        format!("unknown panel {:?}, expected one of {}", name, known.join(", ")),
+                    )
+                    .with_details(json!({ "expected": known }))
+                })
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    // Each panel gets the whole query_timeout, so one slow panel is
+    // cut off without taking the rest with it.
+    let timeout = st.live().query_timeout;
+    let state = st.clone();
+    let payload = with_conn_timeout(&st, "dashboard", None, move |conn| {
+        let mut panels = serde_json::Map::new();
+        let mut errors = serde_json::Map::new();
+        conn.execute_batch("BEGIN TRANSACTION")?;
+        for (name, f) in wanted {
+            let needed = auth::required_role(&axum::http::Method::GET, &format!("/api/{}", name));
+            if grant.as_ref().is_some_and(|Extension(g)| !g.allows(needed)) {
+                errors.insert(name.to_string(), format!("requires the {} role", needed.as_str()).into());
+                continue;
+            }
+            // Also timed on its own, so a slow panel shows up under its own
+            // name whichever way the dashboard loaded it.
+            let started = std::time::Instant::now();
+            let res = with_deadline(conn, timeout, || run_panel(&state, conn, &filter, f));
+            state.timings.record(name, started.elapsed());
+            match res {
+                Ok(v) => {
+                    panels.insert(name.to_string(), v);
+                }
+                Err(e) => {
+                    // A 200 for the dashboard as a whole, so `error_body`
+                    // never sees it.
+                    eprintln!("[{}] panel {} failed: {}", request_id, name, format!("{:#}", e).lines().next().unwrap_or_default());
+                    errors.insert(name.to_string(), format!("{:#}", e).into());
+                    // A failed statement aborts the transaction; start
+                    // another so the remaining panels can run.
+                    conn.execute_batch("ROLLBACK; BEGIN TRANSACTION")?;
+                }
+            }
         }
-        Ok(json!({ "series": out }))
+        conn.execute_batch("COMMIT")?;
+        Ok(json!({ "panels": panels, "errors": errors }))
     })
+    .await
     .map_err(internal_error)?;
 
     Ok(Json(payload))
 }
 
+async fn requests_over_time(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "requests_over_time", requests_over_time_panel).await
+}
+
+fn requests_over_time_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let limit = q.default_limit();
+    let query = format!(
+        r#"
+        SELECT CAST(date_trunc('hour', ts) AS VARCHAR) AS t, count(*) AS n
+        FROM requests
+        WHERE {cond}
+        GROUP BY 1 ORDER BY 1
+        {limit}
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let t: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        out.push(json!({"t": t, "n": n}));
+    }
+    Ok(json!({ "series": out }))
+}
+
+/// Headline totals for the selected window, for the dashboard's stats strip.
+/// The error rate is the share of 4xx and 5xx responses.
+async fn summary(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "summary", summary_panel).await
+}
+
+fn summary_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    Ok(serde_json::to_value(summary::totals(conn, &cond, &args, &st.config().ports)?)?)
+}
+
 async fn top_hosts(
     State(st): State<AppState>,
-    Query(q): Query<TimeParams>,
+    Query(q): Query<FilterParams>,
 ) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let query = match (&q.start, &q.end) {
-            (Some(_), Some(_)) => r#"
-                SELECT host, count(*) AS n FROM requests
-                WHERE host IS NOT NULL AND ts >= CAST(? AS TIMESTAMPTZ) AND ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC LIMIT 15
-            "#,
-            (Some(_), None) => r#"
-                SELECT host, count(*) AS n FROM requests
-                WHERE host IS NOT NULL AND ts >= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC LIMIT 15
-            "#,
-            (None, Some(_)) => r#"
-                SELECT host, count(*) AS n FROM requests
-                WHERE host IS NOT NULL AND ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC LIMIT 15
-            "#,
-            (None, None) => r#"
-                SELECT host, count(*) AS n FROM requests
-                WHERE host IS NOT NULL
-                GROUP BY 1 ORDER BY n DESC LIMIT 15
-            "#,
-        };
+    panel(&st, q, "top_hosts", top_hosts_panel).await
+}
 
-        let mut stmt = conn.prepare(query)?;
-        let mut rows = match (&q.start, &q.end) {
-            (Some(s), Some(e)) => stmt.query(params![s, e])?,
-            (Some(s), None) => stmt.query(params![s])?,
-            (None, Some(e)) => stmt.query(params![e])?,
-            (None, None) => stmt.query(params![])?,
-        };
+fn top_hosts_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let cond = format!("{} AND {}", cond, q.resource_condition());
+    let out: Vec<_> = summary::top(conn, db::target_host(&st.config().ports), &cond, &args, 15)?
+        .into_iter()
+        .map(|(host, n, users)| json!({"host_unicode": parser::unicode_host(&host), "host": host, "n": n, "users": users}))
+        .collect();
+    Ok(json!({ "hosts": out }))
+}
+
+async fn status_codes(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "status_codes", status_codes_panel).await
+}
+
+fn status_codes_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let query = format!(
+        r#"
+        SELECT status, count(*) AS n FROM requests
+        WHERE {cond}
+        GROUP BY 1 ORDER BY n DESC
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let status: i32 = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        out.push(json!({"status": status, "n": n}));
+    }
+    Ok(json!({ "status": out }))
+}
+
+async fn top_countries(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "top_countries", top_countries_panel).await
+}
+
+fn top_countries_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let query = format!(
+        r#"
+        SELECT country, count(*) AS n, count(DISTINCT user_or_session) AS users FROM requests
+        WHERE country IS NOT NULL AND country <> ''
+          AND {cond}
+        GROUP BY 1 ORDER BY n DESC LIMIT 20
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let country: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        out.push(json!({"country": country, "n": n, "users": users}));
+    }
+    Ok(json!({ "countries": out }))
+}
 
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let host: String = r.get(0)?;
-            let n: i64 = r.get(1)?;
-            out.push(json!({"host": host, "n": n}));
+async fn bandwidth_over_time(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "bandwidth_over_time", bandwidth_over_time_panel).await
+}
+
+fn bandwidth_over_time_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let limit = q.default_limit();
+    let query = format!(
+        r#"
+        SELECT 
+            CAST(date_trunc('hour', ts) AS VARCHAR) AS t,
+            CAST(SUM(COALESCE(bytes, 0)) / 1024.0 / 1024.0 AS BIGINT) AS mb
+        FROM requests
+        WHERE {cond}
+        GROUP BY 1 ORDER BY 1
+        {limit}
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let t: String = r.get(0)?;
+        let mb: i64 = r.get(1)?;
+        out.push(json!({"t": t, "mb": mb}));
+    }
+    Ok(json!({ "series": out }))
+}
+
+async fn hourly_heatmap(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "hourly_heatmap", hourly_heatmap_panel).await
+}
+
+fn hourly_heatmap_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT 
+            CAST(EXTRACT(hour FROM ts) AS INTEGER) AS hour,
+            CAST(EXTRACT(dow FROM ts) AS INTEGER) AS day_of_week,
+            COUNT(*) AS n
+        FROM requests
+        WHERE {cond}
+        GROUP BY 1, 2
+        ORDER BY 1, 2
+        "#
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let hour: i32 = r.get(0)?;
+        let dow: i32 = r.get(1)?;
+        let n: i64 = r.get(2)?;
+        out.push(json!({"hour": hour, "day": dow, "n": n}));
+    }
+    Ok(json!({ "data": out }))
+}
+
+async fn error_analysis(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "error_analysis", error_analysis_panel).await
+}
+
+fn error_analysis_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let host = db::target_host(&st.config().ports);
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT 
+            {host} AS host,
+            COUNT(*) AS errors,
+            SUM(CASE WHEN status >= 500 THEN 1 ELSE 0 END) AS server_errors,
+            SUM(CASE WHEN status >= 400 AND status < 500 THEN 1 ELSE 0 END) AS client_errors
+        FROM requests
+        WHERE status >= 400 AND {cond}
+        GROUP BY 1
+        ORDER BY 2 DESC
+        LIMIT 10
+        "#
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let errors: i64 = r.get(1)?;
+        let server_errors: i64 = r.get(2)?;
+        let client_errors: i64 = r.get(3)?;
+        out.push(json!({
+            "host": host, 
+            "errors": errors,
+            "server_errors": server_errors,
+            "client_errors": client_errors
+        }));
+    }
+    Ok(json!({ "hosts": out }))
+}
+
+async fn top_paths(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "top_paths", top_paths_panel).await
+}
+
+fn top_paths_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let resource = q.resource_condition();
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT 
+            path,
+            COUNT(*) AS n,
+            AVG(COALESCE(bytes, 0)) AS avg_bytes,
+            COUNT(DISTINCT user_or_session) AS users
+        FROM requests
+        WHERE path IS NOT NULL AND path <> '/' AND {resource} AND {cond}
+        GROUP BY 1
+        ORDER BY 2 DESC
+        LIMIT 15
+        "#
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let path: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let avg_bytes: f64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        out.push(json!({
+            "path": path, 
+            "n": n,
+            "avg_kb": (avg_bytes / 1024.0) as i64,
+            "users": users
+        }));
+    }
+    Ok(json!({ "paths": out }))
+}
+
+/// Query parameter names that usually carry a credential, a session, or
+/// something about the patron: anywhere in the lowercased name, or (being
+/// short enough to turn up inside harmless names like `author` or
+/// `keyword`) as a whole word of it.
+const SENSITIVE_PARAMS: &[&str] = &["token", "session", "secret", "passw", "email", "patron", "barcode", "jwt"];
+const SENSITIVE_PARAM_WORDS: &[&str] =
+    &["sid", "key", "apikey", "auth", "user", "uid", "login", "mail", "ticket", "sig", "signature"];
+
+fn sensitive_param(param: &str) -> bool {
+    SENSITIVE_PARAMS.iter().any(|s| param.contains(s))
+        || param.split(|c: char| !c.is_ascii_alphanumeric()).any(|w| SENSITIVE_PARAM_WORDS.contains(&w))
+}
+
+async fn top_query_params(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "top_query_params", top_query_params_panel).await
+}
+
+/// Query string parameter names per platform, busiest first, with how many
+/// distinct values each took. A parameter with nearly as many values as
+/// requests and a name like `token` or `email` is likely leaking something
+/// into the logs. Values themselves are never returned.
+fn top_query_params_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let host = db::target_host(&st.config().ports);
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT host, param, count(*) AS n, count(DISTINCT value) AS "values",
+               count(DISTINCT user_or_session) AS users
+        FROM (
+          SELECT {host} AS host, user_or_session,
+                 lower(split_part(kv, '=', 1)) AS param, split_part(kv, '=', 2) AS value
+          FROM (SELECT *, unnest(string_split(query, '&')) AS kv FROM requests
+                WHERE query IS NOT NULL AND query <> '' AND {cond})
+        )
+        WHERE param <> '' AND host IS NOT NULL
+        GROUP BY 1, 2
+        ORDER BY n DESC, host, param
+        LIMIT 50
+        "#
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let host: String = r.get(0)?;
+        let param: String = r.get(1)?;
+        let n: i64 = r.get(2)?;
+        let values: i64 = r.get(3)?;
+        let users: i64 = r.get(4)?;
+        let sensitive = sensitive_param(&param);
+        out.push(json!({
+            "host": host,
+            "param": param,
+            "n": n,
+            "values": values,
+            "users": users,
+            "sensitive": sensitive,
+        }));
+    }
+    Ok(json!({ "params": out }))
+}
+
+async fn top_extensions(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "top_extensions", top_extensions_panel).await
+}
+
+/// Requests by file extension: how many were PDFs, how many HTML pages.
+/// Paths without one, the usual page, come back as a `null` extension.
+fn top_extensions_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let resource = q.resource_condition();
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT file_ext, count(*) AS n, sum(COALESCE(bytes, 0)) AS bytes,
+               count(DISTINCT user_or_session) AS users
+        FROM requests
+        WHERE path IS NOT NULL AND {resource} AND {cond}
+        GROUP BY 1
+        ORDER BY 2 DESC, 1
+        LIMIT 20
+        "#
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let ext: Option<String> = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let bytes: i64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        out.push(json!({ "ext": ext, "n": n, "bytes": bytes, "users": users }));
+    }
+    Ok(json!({ "extensions": out }))
+}
+
+async fn user_agents(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "user_agents", user_agents_panel).await
+}
+
+fn user_agents_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT 
+            -- Rows imported before the user_agent enricher existed have no
+            -- browser; classify those the same way here.
+            COALESCE(browser, CASE 
+                WHEN user_agent LIKE '%Chrome%' AND user_agent NOT LIKE '%Edg%' THEN 'Chrome'
+                WHEN user_agent LIKE '%Firefox%' THEN 'Firefox'
+                WHEN user_agent LIKE '%Safari%' AND user_agent NOT LIKE '%Chrome%' THEN 'Safari'
+                WHEN user_agent LIKE '%Edg%' THEN 'Edge'
+                WHEN user_agent LIKE '%Opera%' THEN 'Opera'
+                WHEN user_agent LIKE '%bot%' OR user_agent LIKE '%Bot%' THEN 'Bot'
+                ELSE 'Other'
+            END) AS browser,
+            COUNT(*) AS n,
+            COUNT(DISTINCT user_or_session) AS users
+        FROM requests
+        WHERE user_agent IS NOT NULL AND {cond}
+        GROUP BY 1
+        ORDER BY 2 DESC
+        "#
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(args))?;
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let browser: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        out.push(json!({"browser": browser, "n": n, "users": users}));
+    }
+    Ok(json!({ "browsers": out }))
+}
+
+async fn top_issns(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "top_issns", top_issns_panel).await
+}
+
+/// Query giving `(issn, title)` for every ISSN in the `[titles]` list,
+/// whichever identifier column it appeared in; none without a list.
+pub(crate) fn titles_cte(titles: Option<&TitlesConfig>) -> String {
+    match titles {
+        Some(t) => {
+            let source = format!("read_csv({}, header = true, all_varchar = true)", sql_literal(&t.path));
+            let selects: Vec<String> = t
+                .issn_columns
+                .iter()
+                .map(|c| {
+                    format!(
+                        "SELECT upper(trim({})) AS issn, {} AS title FROM {}",
+                        sql_ident(c),
+                        sql_ident(&t.title_column),
+                        source
+                    )
+                })
+                .collect();
+            format!(
+                "SELECT issn, any_value(title) AS title FROM ({}) WHERE issn <> '' GROUP BY 1",
+                selects.join(" UNION ALL ")
+            )
         }
-        Ok(json!({ "hosts": out }))
-    }).map_err(internal_error)?;
+        None => "SELECT NULL::TEXT AS issn, NULL::TEXT AS title WHERE FALSE".to_string(),
+    }
+}
 
-    Ok(Json(payload))
+fn top_issns_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let titles_cte = titles_cte(st.config().titles.as_ref());
+    let (ts_cond, args) = q.condition(&st.config().ports);
+
+    let query = format!(
+        r#"
+        WITH titles AS ({titles_cte})
+        SELECT r.issn, any_value(t.title) AS title, count(*) AS n,
+               count(DISTINCT r.user_or_session) AS users
+        FROM requests r LEFT JOIN titles t ON t.issn = r.issn
+        WHERE r.issn IS NOT NULL AND {ts_cond}
+        GROUP BY 1 ORDER BY n DESC LIMIT 25
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let issn: String = r.get(0)?;
+        let title: Option<String> = r.get(1)?;
+        let n: i64 = r.get(2)?;
+        let users: i64 = r.get(3)?;
+        out.push(json!({"issn": issn, "title": title, "n": n, "users": users}));
+    }
+    Ok(json!({ "issns": out }))
 }
 
-async fn status_codes(
+async fn anomalies(
     State(st): State<AppState>,
-    Query(q): Query<TimeParams>,
-) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let query = match (&q.start, &q.end) {
-            (Some(_), Some(_)) => {
-                r#"
-                SELECT status, count(*) AS n FROM requests
-                WHERE ts >= CAST(? AS TIMESTAMPTZ) AND ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC
-            "#
-            }
-            (Some(_), None) => {
-                r#"
-                SELECT status, count(*) AS n FROM requests
-                WHERE ts >= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC
-            "#
-            }
-            (None, Some(_)) => {
-                r#"
-                SELECT status, count(*) AS n FROM requests
-                WHERE ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC
-            "#
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "anomalies", anomalies_panel).await
+}
+
+fn anomalies_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let end = embargoed_end(st, q.end);
+    baseline::anomalies(conn, q.start.map(Time::sql).as_deref(), end.as_deref(), &st.config().ports)
+}
+
+async fn session_durations(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "session_durations", session_durations_panel).await
+}
+
+fn session_durations_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (ts_cond, args) = q.condition(&st.config().ports);
+    sessions::durations(conn, &ts_cond, &args, &st.config().ports)
+}
+
+async fn entry_pages(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "entry_pages", entry_pages_panel).await
+}
+
+fn entry_pages_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (ts_cond, args) = q.condition(&st.config().ports);
+    sessions::entry_pages(conn, &ts_cond, &args, 20, &st.config().ports)
+}
+
+async fn referrer_systems(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "referrer_systems", referrer_systems_panel).await
+}
+
+fn referrer_systems_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let systems = st.config().referrer_systems();
+    let (ts_cond, args) = q.condition(&st.config().ports);
+
+    // First matching system wins; referrers matching none are "Other".
+    let whens: String = systems
+        .iter()
+        .map(|s| {
+            format!(
+                "WHEN regexp_matches(referrer, {}, 'i') THEN {} ",
+                sql_literal(&s.pattern),
+                sql_literal(&s.name)
+            )
+        })
+        .collect();
+    let query = format!(
+        r#"
+        SELECT CASE {whens}ELSE 'Other' END AS system,
+               count(*) AS n,
+               count(DISTINCT user_or_session) AS users
+        FROM requests
+        WHERE referrer IS NOT NULL AND {ts_cond}
+        GROUP BY 1 ORDER BY n DESC
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let system: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        out.push(json!({"system": system, "n": n, "users": users}));
+    }
+    Ok(json!({ "systems": out }))
+}
+
+async fn turnaways(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "turnaways", turnaways_panel).await
+}
+
+fn turnaways_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (ts_cond, args) = embargoed(st, q).condition(&st.config().ports);
+    turnaways::analyze(conn, &ts_cond, &args, &st.config().ports)
+}
+
+/// Likely full-text downloads, the figure licenses are judged by; see
+/// `downloads::analyze`.
+async fn downloads(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "downloads", downloads_panel).await
+}
+
+fn downloads_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = embargoed(st, q).condition(&st.config().ports);
+    downloads::analyze(conn, &cond, &args, &st.config().ports)
+}
+
+/// Refused logins per IP and user agent; see `logins::analyze`.
+async fn login_failures(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "login_failures", login_failures_panel).await
+}
+
+fn login_failures_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = embargoed(st, q).condition(&st.config().ports);
+    logins::analyze(conn, &cond, &args)
+}
+
+/// Concurrent users on each platform with a `[licenses]` limit; see
+/// `licenses::pressure`.
+async fn license_pressure(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "license_pressure", license_pressure_panel).await
+}
+
+fn license_pressure_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let config = st.config();
+    let (cond, args) = q.condition(&config.ports);
+    licenses::pressure(conn, &config.licenses, &cond, &args, &config.ports)
+}
+
+/// Annual platform costs against downloads and sessions; see
+/// `costs::cost_per_use`.
+async fn cost_per_use(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "cost_per_use", cost_per_use_panel).await
+}
+
+fn cost_per_use_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    costs::cost_per_use(conn, &cond, &args, &st.config().ports)
+}
+
+/// Load a CSV of annual platform costs sent as the request body; see
+/// `costs::load_csv`.
+async fn upload_costs(
+    State(st): State<AppState>,
+    body: String,
+) -> ApiResult<serde_json::Value> {
+    let loaded = with_conn(&st, "costs", move |conn| costs::load_csv(conn, &body))
+        .await
+        .map_err(|e| ApiError::bad_request(format!("{:#}", e)))?;
+    Ok(Json(json!({ "loaded": loaded })))
+}
+
+/// Map a pseudonym back to a username with a key sent in the body; see
+/// `pseudonyms::resolve`. Only with `[auth]`, so the audit trail can say who
+/// asked.
+async fn resolve_pseudonym(
+    State(st): State<AppState>,
+    caller: Option<Extension<auth::Caller>>,
+    Json(req): Json<pseudonyms::Resolution>,
+) -> ApiResult<serde_json::Value> {
+    let Some(Extension(auth::Caller(caller))) = caller else {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "auth_required", "pseudonym resolution needs [auth]"));
+    };
+    let out = with_conn(&st, "pseudonyms", move |conn| pseudonyms::resolve(conn, &req, &caller))
+        .await
+        .map_err(|e| ApiError::bad_request(format!("{:#}", e)))?;
+    Ok(Json(out))
+}
+
+/// The audit trail of `resolve_pseudonym`, newest first.
+async fn pseudonym_resolutions(
+    State(st): State<AppState>,
+) -> ApiResult<serde_json::Value> {
+    let out = with_conn(&st, "pseudonyms", pseudonyms::list).await.map_err(internal_error)?;
+    Ok(Json(json!({ "resolutions": out })))
+}
+
+/// Usage per department from the `users` table, held to
+/// `[privacy] min_attribute_users`; see `users::usage_by`.
+async fn usage_by_department(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "usage_by_department", usage_by_department_panel).await
+}
+
+fn usage_by_department_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let config = st.config();
+    let (cond, args) = q.condition(&config.ports);
+    users::usage_by(conn, "department", &cond, &args, config.privacy.attribute_floor(), &config.ports)
+}
+
+/// The same per affiliation, such as faculty, student, or staff.
+async fn usage_by_affiliation(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "usage_by_affiliation", usage_by_affiliation_panel).await
+}
+
+fn usage_by_affiliation_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let config = st.config();
+    let (cond, args) = q.condition(&config.ports);
+    users::usage_by(conn, "affiliation", &cond, &args, config.privacy.attribute_floor(), &config.ports)
+}
+
+async fn client_types(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "client_types", client_types_panel).await
+}
+
+fn client_types_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let live = st.live();
+    let networks = &live.networks;
+    let (cond, args) = embargoed(st, q).condition(&st.config().ports);
+    let query = format!(
+        r#"
+        SELECT remote_addr, count(*) AS n,
+               count(DISTINCT user_or_session) AS users
+        FROM requests
+        WHERE {cond}
+        GROUP BY 1
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    // Classified in Rust: the network list lives in memory, not in DuckDB.
+    let mut totals: Vec<(&str, i64, i64)> = Vec::new();
+    let mut flagged = Vec::new();
+    while let Some(r) = rows.next()? {
+        let ip: String = r.get(0)?;
+        let n: i64 = r.get(1)?;
+        let users: i64 = r.get(2)?;
+        let kind = networks.classify(&ip).unwrap_or("unknown");
+        match totals.iter_mut().find(|t| t.0 == kind) {
+            Some(t) => {
+                t.1 += n;
+                t.2 += 1;
             }
-            (None, None) => {
-                r#"
-                SELECT status, count(*) AS n FROM requests
-                GROUP BY 1 ORDER BY n DESC
-            "#
+            None => totals.push((kind, n, 1)),
+        }
+        if matches!(kind, "hosting" | "vpn") {
+            flagged.push((ip, kind, n, users));
+        }
+    }
+    totals.sort_by_key(|t| std::cmp::Reverse(t.1));
+    flagged.sort_by_key(|f| std::cmp::Reverse(f.2));
+    flagged.truncate(25);
+
+    let types: Vec<_> = totals
+        .iter()
+        .map(|(kind, n, ips)| json!({"type": kind, "n": n, "ips": ips}))
+        .collect();
+    let flagged: Vec<_> = flagged
+        .iter()
+        .map(|(ip, kind, n, users)| json!({"ip": ip, "type": kind, "n": n, "users": users}))
+        .collect();
+    Ok(json!({
+        "configured": !networks.is_empty(),
+        "types": types,
+        "hosting_and_vpn": flagged,
+    }))
+}
+
+/// Breaks, exams, and service hours to shade behind time series. Only the
+/// date part of `start` and `end` is used.
+async fn calendar_overlay(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    let (start, end) = (date_param(q.start), date_param(q.end));
+    let payload = calendar::overlay(&st.config().calendar, start, end).map_err(internal_error)?;
+    Ok(Json(payload))
+}
+
+/// The date, in UTC, of a `start`/`end` parameter.
+fn date_param(t: Option<Time>) -> Option<chrono::NaiveDate> {
+    t.map(|t| t.0.date_naive())
+}
+
+/// Week-over-week and year-over-year changes for the 7 days ending on the
+/// date of `end`, or on the last day with data.
+async fn trends(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "trends", trends_panel).await
+}
+
+fn trends_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let anchor = date_param(q.end);
+    let (filter, args) = q.filter_condition(&st.config().ports);
+    trends::compute(conn, anchor, &filter, &args)
+}
+
+/// Split a `scheme:port` entry from `ports.expected`.
+pub(crate) fn parse_scheme_port(s: &str) -> Option<(&str, i32)> {
+    let (scheme, port) = s.split_once(':')?;
+    Some((scheme, port.parse().ok()?))
+}
+
+async fn ports(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "ports", ports_panel).await
+}
+
+/// Traffic to hosts that aren't licensed resources, and who sends it; see
+/// `destinations::offenders`.
+async fn proxy_abuse(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "proxy_abuse", proxy_abuse_panel).await
+}
+
+fn proxy_abuse_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let config = st.config();
+    let rules = destinations::Rules::new(&config.destinations, &config.licenses)?;
+    let (cond, args) = embargoed(st, q).condition(&config.ports);
+    destinations::offenders(conn, &rules, &cond, &args, &config.ports)
+}
+
+#[derive(Debug, Deserialize)]
+struct PeakWindowParams {
+    /// Window length, e.g. `5m` or `1h` [default: 5m]
+    window: Option<String>,
+    /// Windows listed [default: 10, at most 100]
+    top: Option<usize>,
+}
+
+/// The busiest short windows in the range, with the hosts and users behind
+/// them, for sizing the proxy; see `capacity::peak_windows`.
+async fn peak_windows(
+    State(st): State<AppState>,
+    Query(p): Query<PeakWindowParams>,
+    Query(filter): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    let window = p.window.as_deref().unwrap_or("5m");
+    let length = duration::parse_duration(window)
+        .and_then(|d| capacity::check_window(d).map(|_| d))
+        .map_err(|e| ApiError::bad_request(format!("window: {e:#}")))?;
+    let top = p.top.unwrap_or(10).clamp(1, 100);
+    let config = st.config();
+    let (cond, args) = embargoed(&st, &filter).condition(&config.ports);
+    let mut payload = with_conn(&st, "peak_windows", move |conn| {
+        Ok(privacy::protect(capacity::peak_windows(conn, length, top, &cond, &args, &config.ports)?, &config.privacy))
+    })
+    .await
+    .map_err(internal_error)?;
+    payload["window"] = window.into();
+    Ok(Json(payload))
+}
+
+/// Days covered by `/api/concurrency_over_time` without a `start`.
+const CONCURRENCY_DEFAULT_DAYS: i64 = 7;
+
+/// Estimated concurrent sessions per minute, for sizing the proxy; see
+/// `capacity::concurrency`.
+async fn concurrency_over_time(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "concurrency_over_time", concurrency_over_time_panel).await
+}
+
+fn concurrency_over_time_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let mut q = q.clone();
+    // Without a start, the last week of data rather than every minute ever
+    // imported.
+    if q.start.is_none() {
+        let (cond, args) = q.condition(&st.config().ports);
+        let newest: Option<i64> =
+            conn.query_row(&format!("SELECT epoch_us(max(ts)) FROM requests WHERE {cond}"), params_from_iter(&args), |r| r.get(0))?;
+        q.start = newest
+            .and_then(chrono::DateTime::from_timestamp_micros)
+            .map(|t| Time(t - chrono::Duration::days(CONCURRENCY_DEFAULT_DAYS)));
+    }
+    let (cond, args) = q.condition(&st.config().ports);
+    let mut out = capacity::concurrency(conn, &cond, &args)?;
+    out["start"] = json!(q.start);
+    Ok(out)
+}
+
+#[derive(Debug, Deserialize)]
+struct ForecastParams {
+    /// How far to project, e.g. `30d` [default: 90d]
+    horizon: Option<String>,
+}
+
+/// Horizon of `/api/forecast` without one, and of the dashboard's panel.
+const FORECAST_DEFAULT_HORIZON: &str = "90d";
+
+/// Daily requests and bandwidth projected past the range, with confidence
+/// bands, for capacity planning; see `forecast::compute`.
+async fn forecast(
+    State(st): State<AppState>,
+    Query(p): Query<ForecastParams>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    let horizon = p.horizon.as_deref().unwrap_or(FORECAST_DEFAULT_HORIZON);
+    let length = duration::parse_duration(horizon)
+        .and_then(|d| forecast::check_horizon(d).map(|_| d))
+        .map_err(|e| ApiError::bad_request(format!("horizon: {e:#}")))?;
+    let config = st.config();
+    let q = FilterParams { attribute_floor: config.privacy.attribute_floor(), ..q };
+    let (cond, args) = q.condition(&config.ports);
+    let payload = with_conn(&st, "forecast", move |conn| {
+        Ok(privacy::protect(forecast::compute(conn, length, &cond, &args)?, &config.privacy))
+    })
+    .await
+    .map_err(internal_error)?;
+    Ok(Json(payload))
+}
+
+fn forecast_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    forecast::compute(conn, duration::parse_duration(FORECAST_DEFAULT_HORIZON)?, &cond, &args)
+}
+
+fn ports_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let expected: Vec<String> = [("http", 80), ("https", 443)]
+        .into_iter()
+        .chain(st.config().ports.expected.iter().filter_map(|e| parse_scheme_port(e)))
+        .map(|(scheme, port)| format!("({}, {})", sql_literal(&scheme.to_ascii_lowercase()), port))
+        .collect();
+    let (cond, args) = q.condition(&st.config().ports);
+    let expected = expected.join(", ");
+    let host = db::target_host(&st.config().ports);
+
+    // The URL parser leaves `port` NULL when it is the scheme's default.
+    let query = format!(
+        r#"
+        WITH p AS (
+          SELECT COALESCE(scheme, '(unparsed)') AS scheme,
+                 COALESCE(port, CASE scheme WHEN 'http' THEN 80 WHEN 'https' THEN 443
+                                            WHEN 'ftp' THEN 21 END) AS port,
+                 {host} AS host, user_or_session
+          FROM requests
+          WHERE {cond}
+        )
+        SELECT p.scheme, p.port, count(*) AS n,
+               count(DISTINCT host) AS hosts,
+               count(DISTINCT user_or_session) AS users,
+               array_to_string(list(DISTINCT host ORDER BY host)[1:5], ',') AS sample_hosts,
+               e.scheme IS NULL AS unexpected
+        FROM p LEFT JOIN (VALUES {expected}) e(scheme, port)
+          ON e.scheme = p.scheme AND e.port = p.port
+        GROUP BY p.scheme, p.port, e.scheme
+        ORDER BY n DESC
+        "#
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params_from_iter(args))?;
+
+    let mut out = Vec::new();
+    while let Some(r) = rows.next()? {
+        let scheme: String = r.get(0)?;
+        let port: Option<i32> = r.get(1)?;
+        let n: i64 = r.get(2)?;
+        let hosts: i64 = r.get(3)?;
+        let users: i64 = r.get(4)?;
+        let sample_hosts: Option<String> = r.get(5)?;
+        let sample_hosts: Vec<String> = sample_hosts
+            .unwrap_or_default()
+            .split(',')
+            .filter(|h| !h.is_empty())
+            .map(String::from)
+            .collect();
+        let unexpected: bool = r.get(6)?;
+        out.push(json!({
+            "scheme": scheme,
+            "port": port,
+            "n": n,
+            "hosts": hosts,
+            "users": users,
+            "sample_hosts": sample_hosts,
+            "unexpected": unexpected,
+        }));
+    }
+    Ok(json!({ "combinations": out }))
+}
+
+/// Served by every instance so a consortium hub can pull it.
+async fn federation_summary(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "federation_summary", federation_summary_panel).await
+}
+
+fn federation_summary_panel(st: &AppState, conn: &Connection, q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    let (cond, args) = q.condition(&st.config().ports);
+    let table = federation::member_summary(conn, &cond, params_from_iter(args), &st.config().ports)?;
+    Ok(json!({ "rows": table.to_objects() }))
+}
+
+async fn federation_overview(
+    State(st): State<AppState>,
+    Query(q): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    panel(&st, q, "federation", federation_panel).await
+}
+
+/// Everything pulled from consortium members; not limited by the time range.
+fn federation_panel(_st: &AppState, conn: &Connection, _q: &FilterParams) -> anyhow::Result<serde_json::Value> {
+    federation::overview(conn)
+}
+
+#[derive(Debug, Deserialize)]
+struct ChartParams {
+    width: Option<u32>,
+    height: Option<u32>,
+    /// A `ui::THEMES` name [default: `ui.default_theme`]
+    theme: Option<String>,
+}
+
+/// A panel drawn server-side as `{name}.png`, for reports, emails, and chat
+/// alerts that embed charts without a browser. Takes the same filters as
+/// the panel's own endpoint.
+async fn chart(
+    State(st): State<AppState>,
+    Path(file): Path<String>,
+    Query(c): Query<ChartParams>,
+    Query(filter): Query<FilterParams>,
+) -> Result<Response, ApiError> {
+    let not_found = || {
+        let known: Vec<_> = charts::CHARTS.iter().map(|(n, _)| format!("{}.png", n)).collect();
+        ApiError::not_found(format!("no chart {:?}, expected one of {}", file, known.join(", ")))
+            .with_details(json!({ "expected": known }))
+    };
+    let name = file.strip_suffix(".png").ok_or_else(not_found)?;
+    if !charts::CHARTS.iter().any(|(n, _)| *n == name) {
+        return Err(not_found());
+    }
+    let (name, f) = PANELS.iter().find(|(n, _)| *n == name).copied().ok_or_else(not_found)?;
+    let theme = match &c.theme {
+        Some(t) => ui::theme(t).ok_or_else(|| ApiError::bad_request(format!("unknown theme {:?}", t)))?,
+        None => ui::theme(&st.config().ui.default_theme).unwrap_or(&ui::THEMES[0]),
+    };
+    let width = c.width.unwrap_or(800).clamp(200, 2400);
+    let height = c.height.unwrap_or(400).clamp(150, 2400);
+
+    let state = st.clone();
+    let png = with_conn(&st, "chart", move |conn| charts::render_png(name, &run_panel(&state, conn, &filter, f)?, theme, width, height))
+        .await
+        .map_err(internal_error)?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+/// Columns `/api/facets` lists values of.
+const FACET_FIELDS: &[&str] = &["host", "country", "status", "method"];
+
+/// Values returned by `/api/facets` unless the request asks for fewer.
+const DEFAULT_FACET_LIMIT: i64 = 50;
+const MAX_FACET_LIMIT: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+struct FacetParams {
+    field: String,
+    /// Only values containing this, ignoring case
+    q: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Distinct values of one column under the current filters, most requested
+/// first, for filter dropdowns and typeahead. A filter on the field itself
+/// is ignored, so the dropdown still offers the other values.
+async fn facets(
+    State(st): State<AppState>,
+    Query(q): Query<FacetParams>,
+    Query(mut filter): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    let Some(field) = FACET_FIELDS.iter().find(|f| **f == q.field) else {
+        return Err(ApiError::bad_request(format!(
+            "unknown field {:?}, expected one of {}",
+            q.field,
+            FACET_FIELDS.join(", ")
+        ))
+        .with_details(json!({ "expected": FACET_FIELDS })));
+    };
+    let limit = q.limit.unwrap_or(DEFAULT_FACET_LIMIT).clamp(1, MAX_FACET_LIMIT);
+    match *field {
+        "host" => filter.host = None,
+        "country" => filter.country = None,
+        "status" => filter.status = None,
+        _ => filter.method = None,
+    }
+    let (mut cond, mut args) = filter.condition(&st.config().ports);
+    let column = if *field == "host" { db::target_host(&st.config().ports) } else { field };
+    if let Some(search) = q.q.as_deref().filter(|s| !s.is_empty()) {
+        cond.push_str(&format!(" AND CAST({column} AS VARCHAR) ILIKE ? ESCAPE '\\'"));
+        let escaped = search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        args.push(format!("%{}%", escaped));
+    }
+    let out = with_conn(&st, "facets", move |conn| {
+        // One extra row tells whether there were more than `limit`.
+        let query = format!(
+            r#"
+            SELECT CAST({column} AS VARCHAR) AS value, count(*) AS n
+            FROM requests
+            WHERE {column} IS NOT NULL AND {cond}
+            GROUP BY 1
+            ORDER BY 2 DESC, 1
+            LIMIT {}
+            "#,
+            limit + 1
+        );
+        let mut values = db::query_table(conn, &query, params_from_iter(&args))?.to_objects();
+        let truncated = values.len() as i64 > limit;
+        values.truncate(limit as usize);
+        Ok(json!({ "field": field, "values": values, "truncated": truncated }))
+    })
+    .await
+    .map_err(internal_error)?;
+    Ok(Json(out))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewHostsParams {
+    /// How far back from the newest request, e.g. `7d`
+    since: Option<String>,
+}
+
+/// Hosts first seen recently: a vendor platform just turned on, or
+/// somewhere the proxy shouldn't be sending anyone.
+async fn new_hosts(
+    State(st): State<AppState>,
+    Query(q): Query<NewHostsParams>,
+) -> ApiResult<serde_json::Value> {
+    let since = q.since.as_deref().unwrap_or("7d");
+    let window = duration::parse_duration(since).map_err(|e| ApiError::bad_request(format!("since: {e:#}")))?;
+    let hosts = with_conn(&st, "new_hosts", move |conn| seen::new_hosts(conn, window)).await.map_err(internal_error)?;
+    Ok(Json(json!({ "since": since, "hosts": hosts })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ViolationParams {
+    start: Option<Time>,
+    end: Option<Time>,
+    /// Policy name from `[[policies]]`, or honeytoken name for
+    /// `/api/honeytoken_hits`
+    rule: Option<String>,
+}
+
+/// Alerts written by `ezvis policy check` or a `policy_check` job.
+async fn policy_violations(
+    State(st): State<AppState>,
+    Query(q): Query<ViolationParams>,
+) -> ApiResult<serde_json::Value> {
+    let end = embargoed_end(&st, q.end);
+    let out = with_conn(&st, "policy_violations", move |conn| {
+        policy::violations(conn, q.start.map(Time::sql).as_deref(), end.as_deref(), q.rule.as_deref())
+    })
+    .await
+    .map_err(internal_error)?;
+    Ok(Json(out))
+}
+
+/// Alerts raised as requests for `[[honeytokens]]` were imported.
+async fn honeytoken_hits(
+    State(st): State<AppState>,
+    Query(q): Query<ViolationParams>,
+) -> ApiResult<serde_json::Value> {
+    let end = embargoed_end(&st, q.end);
+    let out = with_conn(&st, "honeytoken_hits", move |conn| {
+        honeytokens::hits(conn, q.start.map(Time::sql).as_deref(), end.as_deref(), q.rule.as_deref())
+    })
+    .await
+    .map_err(internal_error)?;
+    Ok(Json(out))
+}
+
+/// Drill-down parameters beyond the shared `FilterParams`.
+#[derive(Debug, Deserialize)]
+struct RequestsParams {
+    user: Option<String>,
+    ip: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// `csv` (default) or `tsv`; only used by `/api/requests/export`
+    format: Option<String>,
+    /// Add row hashes and a signed manifest to an export
+    #[serde(default)]
+    integrity: bool,
+}
+
+impl RequestsParams {
+    /// WHERE clause for `filter` plus the user and IP filters.
+    fn condition(&self, filter: &FilterParams, ports: &PortsConfig) -> (String, Vec<String>) {
+        let (mut cond, mut args) = filter.condition(ports);
+        for (column, value) in [("user_or_session", &self.user), ("remote_addr", &self.ip)] {
+            if let Some(v) = value {
+                cond.push_str(&format!(" AND {} = ?", column));
+                args.push(v.clone());
             }
-        };
+        }
+        (cond, args)
+    }
+}
 
-        let mut stmt = conn.prepare(query)?;
-        let mut rows = match (&q.start, &q.end) {
-            (Some(s), Some(e)) => stmt.query(params![s, e])?,
-            (Some(s), None) => stmt.query(params![s])?,
-            (None, Some(e)) => stmt.query(params![e])?,
-            (None, None) => stmt.query(params![])?,
-        };
+/// Columns returned for raw rows, by the drill-down and the export alike.
+const RAW_COLUMNS: &[&str] = &[
+    "ts", "remote_addr", "user_or_session", "method", "url",
+    "status", "bytes", "country", "user_agent", "referrer",
+];
+
+fn raw_select() -> String {
+    RAW_COLUMNS
+        .iter()
+        .map(|c| if *c == "ts" { "CAST(ts AS VARCHAR) AS ts".to_string() } else { c.to_string() })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let status: i32 = r.get(0)?;
-            let n: i64 = r.get(1)?;
-            out.push(json!({"status": status, "n": n}));
+/// Raw rows for drill-down from a chart, newest first. Filtered to one
+/// user, it also estimates how many devices they used (`devices::estimate`).
+async fn raw_requests(
+    State(st): State<AppState>,
+    Query(q): Query<RequestsParams>,
+    Query(filter): Query<FilterParams>,
+) -> ApiResult<serde_json::Value> {
+    let limit = q.limit.unwrap_or(100).clamp(1, 1000);
+    let offset = q.offset.unwrap_or(0).max(0);
+    let (cond, args) = q.condition(&embargoed(&st, &filter), &st.config().ports);
+    let payload = with_conn(&st, "requests", move |conn| {
+        let query = format!(
+            r#"
+            SELECT {}
+            FROM requests
+            WHERE {cond}
+            ORDER BY ts DESC
+            LIMIT {limit} OFFSET {offset}
+            "#,
+            raw_select()
+        );
+        let table = db::query_table(conn, &query, params_from_iter(&args))?;
+        let mut payload = json!({ "rows": table.to_objects(), "limit": limit, "offset": offset });
+        if q.user.is_some() {
+            payload["devices"] = devices::estimate(conn, &cond, &args)?;
         }
-        Ok(json!({ "status": out }))
+        Ok(payload)
     })
+    .await
     .map_err(internal_error)?;
 
     Ok(Json(payload))
 }
 
-async fn top_countries(
+/// The original line from an export row ending in `raw` and base64
+/// `raw_zstd`; empty for rows imported with `--no-raw`.
+fn raw_field(row: &[serde_json::Value]) -> anyhow::Result<String> {
+    let [.., raw, raw_zstd] = row else {
+        anyhow::bail!("export row has no raw columns");
+    };
+    let raw_zstd = raw_zstd
+        .as_str()
+        .map(|b| Base64::decode_vec(b).map_err(|e| anyhow::anyhow!("raw_zstd: {}", e)))
+        .transpose()?;
+    Ok(db::decode_raw(raw.as_str().map(String::from), raw_zstd.as_deref())?.unwrap_or_default())
+}
+
+/// Size at which a chunk of an export is handed to the client.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Every raw row matching the filters, oldest first, streamed as CSV or TSV.
+/// `limit` and `offset` are ignored: the point is a complete record.
+///
+/// With `integrity=true` the rows also carry `raw`, `raw_sha256`, and
+/// `chain_sha256` (see `integrity::Chain`), and a signed manifest is kept
+/// under the id in the `x-ezvis-export-id` header.
+async fn export_requests(
     State(st): State<AppState>,
-    Query(q): Query<TimeParams>,
-) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let query = match (&q.start, &q.end) {
-            (Some(_), Some(_)) => {
-                r#"
-                SELECT country, count(*) AS n FROM requests
-                WHERE country IS NOT NULL AND country <> ''
-                  AND ts >= CAST(? AS TIMESTAMPTZ) AND ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC LIMIT 20
-            "#
-            }
-            (Some(_), None) => {
-                r#"
-                SELECT country, count(*) AS n FROM requests
-                WHERE country IS NOT NULL AND country <> ''
-                  AND ts >= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC LIMIT 20
-            "#
-            }
-            (None, Some(_)) => {
-                r#"
-                SELECT country, count(*) AS n FROM requests
-                WHERE country IS NOT NULL AND country <> ''
-                  AND ts <= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY n DESC LIMIT 20
-            "#
+    Query(q): Query<RequestsParams>,
+    Query(filter): Query<FilterParams>,
+) -> Result<Response, ApiError> {
+    let (sep, quote, content_type, ext): (&str, fn(&str) -> String, _, _) =
+        match q.format.as_deref() {
+            None | Some("csv") => (",", db::csv_quote, "text/csv; charset=utf-8", "csv"),
+            Some("tsv") => ("\t", db::tsv_quote, "text/tab-separated-values; charset=utf-8", "tsv"),
+            Some(other) => {
+                return Err(ApiError::bad_request(format!("unknown format {:?}, expected csv or tsv", other))
+                    .with_details(json!({ "expected": ["csv", "tsv"] })));
             }
-            (None, None) => {
-                r#"
-                SELECT country, count(*) AS n FROM requests
-                WHERE country IS NOT NULL AND country <> ''
-                GROUP BY 1 ORDER BY n DESC LIMIT 20
-            "#
+        };
+    let filter = embargoed(&st, &filter);
+    let (cond, args) = q.condition(&filter, &st.config().ports);
+
+    let mut columns: Vec<&str> = RAW_COLUMNS.to_vec();
+    let mut select = raw_select();
+    let integrity = if q.integrity {
+        if ext != "csv" {
+            // TSV can't carry tabs or line breaks in `raw`, so its hashes
+            // couldn't be checked against the file.
+            return Err(ApiError::bad_request("integrity exports are CSV only"));
+        }
+        let Some(key) = st.config().export.as_ref().and_then(|e| e.signing_key.clone()) else {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "not_configured", "export.signing_key is not configured"));
+        };
+        let filters = json!({
+            "start": filter.start, "end": filter.end, "exclude_noise": filter.exclude_noise,
+            "include_assets": filter.include_assets, "host": filter.host, "country": filter.country, "status": filter.status, "method": filter.method,
+            "department": filter.department, "affiliation": filter.affiliation, "user": q.user, "ip": q.ip, "before": filter.before,
+        });
+        let id = with_conn(&st, "requests_export", move |conn| integrity::begin(conn, &filters))
+            .await
+            .map_err(internal_error)?;
+        columns.extend(["raw", "raw_sha256", "chain_sha256"]);
+        // Compressed lines come back as base64 text and are decoded below.
+        select.push_str(", raw, to_base64(raw_zstd)");
+        Some((id, key))
+    } else {
+        None
+    };
+    let query = format!("SELECT {select} FROM requests WHERE {cond} ORDER BY ts");
+
+    // The channel holds only a few chunks, so a slow client stalls the
+    // reader thread instead of the rows piling up in memory.
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(4);
+    let signing = integrity.clone();
+    let slot = query_slot(&st).await.map_err(|e| internal_error(e.into()))?;
+    tokio::task::spawn_blocking(move || {
+        let mut buf = columns.join(sep);
+        buf.push('\n');
+        let mut chain = signing.as_ref().map(|_| integrity::Chain::default());
+        let mut gone = false;
+        // However long it takes: a slow client holds the query up too.
+        let res = on_conn(&st, "requests_export", None, slot, |conn| {
+            db::for_each_row(conn, &query, params_from_iter(args), |row| {
+                let mut fields: Vec<String> = row.iter().map(db::plain_field).collect();
+                if let Some(chain) = &mut chain {
+                    let raw = raw_field(row)?;
+                    let hash = integrity::raw_hash(&raw);
+                    fields.truncate(fields.len() - 2);
+                    fields.extend([raw, hash]);
+                    let head = chain.push(&fields);
+                    fields.push(head);
+                }
+                let fields: Vec<String> = fields.iter().map(|f| quote(f)).collect();
+                buf.push_str(&fields.join(sep));
+                buf.push('\n');
+                if buf.len() < EXPORT_CHUNK_BYTES {
+                    return Ok(true);
+                }
+                // A send error means the client went away.
+                gone = tx.blocking_send(Ok(std::mem::take(&mut buf))).is_err();
+                Ok(!gone)
+            })?;
+            // Signed before the last chunk goes out, so the manifest is ready
+            // as soon as the download finishes.
+            if let (Some((id, key)), Some(chain), false) = (&signing, &chain, gone) {
+                integrity::finish(conn, *id, chain, key)?;
             }
+            Ok(())
+        });
+        let last = res.map(|_| buf).map_err(|e| std::io::Error::other(format!("{:#}", e)));
+        let _ = tx.blocking_send(last);
+    });
+
+    // A query that fails outright fails before the first chunk, while we can
+    // still answer with an error status. Later errors cut the download short.
+    let first = rx.recv().await.unwrap_or_else(|| Ok(String::new())).map_err(|e| internal_error(e.into()))?;
+    let chunks = stream::unfold((Some(first), rx), |(first, mut rx)| async move {
+        let chunk = match first {
+            Some(chunk) => Ok(chunk),
+            None => rx.recv().await?,
         };
+        Some((chunk, (None, rx)))
+    });
 
-        let mut stmt = conn.prepare(query)?;
-        let mut rows = match (&q.start, &q.end) {
-            (Some(s), Some(e)) => stmt.query(params![s, e])?,
-            (Some(s), None) => stmt.query(params![s])?,
-            (None, Some(e)) => stmt.query(params![e])?,
-            (None, None) => stmt.query(params![])?,
-        };
+    let filename = match &integrity {
+        Some((id, _)) => format!("requests-{}.{}", id, ext),
+        None => format!("requests.{}", ext),
+    };
+    let mut resp = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response();
+    if let Some((id, _)) = integrity {
+        resp.headers_mut().insert("x-ezvis-export-id", id.into());
+    }
+    Ok(resp)
+}
 
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let country: String = r.get(0)?;
-            let n: i64 = r.get(1)?;
-            out.push(json!({"country": country, "n": n}));
-        }
-        Ok(json!({ "countries": out }))
-    })
-    .map_err(internal_error)?;
+/// Signed manifest of a completed integrity export.
+async fn export_manifest(
+    State(st): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<integrity::Manifest> {
+    match with_conn(&st, "export_manifest", move |conn| integrity::manifest(conn, id)).await.map_err(internal_error)? {
+        Some(m) => Ok(Json(m)),
+        None => Err(ApiError::not_found(format!("no manifest for export {}; it may not have completed", id))),
+    }
+}
 
-    Ok(Json(payload))
+/// Rows returned by `/api/query` unless the request asks for fewer.
+const QUERY_MAX_ROWS: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    sql: String,
+    max_rows: Option<usize>,
 }
 
-async fn bandwidth_over_time(
+/// Ad-hoc SQL over a read-only connection.
+async fn run_query(
     State(st): State<AppState>,
-    Query(q): Query<TimeParams>,
-) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let query = match (&q.start, &q.end) {
-            (None, None) => r#"
-                SELECT 
-                    CAST(date_trunc('hour', ts) AS VARCHAR) AS t,
-                    CAST(SUM(COALESCE(bytes, 0)) / 1024.0 / 1024.0 AS BIGINT) AS mb
-                FROM requests
-                GROUP BY 1 ORDER BY 1 LIMIT 200
-            "#,
-            (Some(_), _) => r#"
-                SELECT 
-                    CAST(date_trunc('hour', ts) AS VARCHAR) AS t,
-                    CAST(SUM(COALESCE(bytes, 0)) / 1024.0 / 1024.0 AS BIGINT) AS mb
-                FROM requests
-                WHERE ts >= CAST(? AS TIMESTAMPTZ)
-                GROUP BY 1 ORDER BY 1
-            "#,
-            _ => r#"
-                SELECT 
-                    CAST(date_trunc('hour', ts) AS VARCHAR) AS t,
-                    CAST(SUM(COALESCE(bytes, 0)) / 1024.0 / 1024.0 AS BIGINT) AS mb
-                FROM requests
-                GROUP BY 1 ORDER BY 1 LIMIT 200
-            "#,
-        };
+    Json(q): Json<QueryRequest>,
+) -> ApiResult<serde_json::Value> {
+    // SQL can reach any row, so it can't be held to the embargo.
+    if st.config().privacy.cutoff().is_some() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "embargo", "ad-hoc SQL is off while [privacy] embargo_days is set"));
+    }
+    let max_rows = q.max_rows.unwrap_or(QUERY_MAX_ROWS).min(QUERY_MAX_ROWS);
+    // Not `with_conn`: this one must be read-only.
+    let slot = query_slot(&st).await.map_err(|e| internal_error(e.into()))?;
+    let state = st.clone();
+    let (table, truncated) = blocking(move || {
+        let _slot = slot;
+        let started = std::time::Instant::now();
+        let conn = db::open_read_only(&state.db_path).map_err(internal_error)?;
+        let res = with_deadline(&conn, state.live().query_timeout, || {
+            db::query_table_capped(&conn, &q.sql, params_from_iter(Vec::<String>::new()), max_rows)
+        });
+        state.timings.record("query", started.elapsed());
+        res.map_err(|e| {
+            if e.is::<QueryTimeout>() { internal_error(e) } else { ApiError::new(StatusCode::BAD_REQUEST, "sql_error", e.to_string()) }
+        })
+    })
+    .await?;
+    Ok(Json(json!({
+        "columns": table.columns,
+        "rows": table.rows,
+        "truncated": truncated,
+    })))
+}
 
-        let mut stmt = conn.prepare(query)?;
-        let mut rows = match (&q.start, &q.end) {
-            (None, None) => stmt.query(params![])?,
-            (Some(s), _) => stmt.query(params![s])?,
-            _ => stmt.query(params![])?,
-        };
+/// Tables and columns with descriptions and fill rates, for whoever is
+/// writing `/api/query` SQL.
+async fn schema(State(st): State<AppState>) -> ApiResult<serde_json::Value> {
+    let out = with_conn(&st, "schema", schema::describe).await.map_err(internal_error)?;
+    Ok(Json(out))
+}
 
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let t: String = r.get(0)?;
-            let mb: i64 = r.get(1)?;
-            out.push(json!({"t": t, "mb": mb}));
-        }
-        Ok(json!({ "series": out }))
-    }).map_err(internal_error)?;
+/// Database time per endpoint since startup, slowest first.
+async fn perf_timings(State(st): State<AppState>) -> Json<serde_json::Value> {
+    Json(st.timings.to_json())
+}
 
-    Ok(Json(payload))
+/// The same timings for Prometheus to scrape.
+async fn metrics(State(st): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        st.timings.to_prometheus(),
+    )
 }
 
-async fn hourly_heatmap(
-    State(st): State<AppState>,
-) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let mut stmt = conn.prepare(
-            r#"
-            SELECT 
-                CAST(EXTRACT(hour FROM ts) AS INTEGER) AS hour,
-                CAST(EXTRACT(dow FROM ts) AS INTEGER) AS day_of_week,
-                COUNT(*) AS n
-            FROM requests
-            GROUP BY 1, 2
-            ORDER BY 1, 2
-            "#,
-        )?;
+/// Grafana's "Save & test" on a simple-JSON datasource only needs a 200.
+async fn grafana_health() -> &'static str {
+    "OK"
+}
 
-        let mut rows = stmt.query(params![])?;
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let hour: i32 = r.get(0)?;
-            let dow: i32 = r.get(1)?;
-            let n: i64 = r.get(2)?;
-            out.push(json!({"hour": hour, "day": dow, "n": n}));
-        }
-        Ok(json!({ "data": out }))
-    }).map_err(internal_error)?;
+#[derive(Debug, Default, Deserialize)]
+struct GrafanaSearch {
+    #[serde(default)]
+    target: String,
+}
 
-    Ok(Json(payload))
+async fn grafana_search(body: Option<Json<GrafanaSearch>>) -> Json<Vec<&'static str>> {
+    let Json(q) = body.unwrap_or_default();
+    Json(grafana::search(&q.target))
 }
 
-async fn error_analysis(
+async fn grafana_query(
     State(st): State<AppState>,
-) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let mut stmt = conn.prepare(
-            r#"
-            SELECT 
-                host,
-                COUNT(*) AS errors,
-                SUM(CASE WHEN status >= 500 THEN 1 ELSE 0 END) AS server_errors,
-                SUM(CASE WHEN status >= 400 AND status < 500 THEN 1 ELSE 0 END) AS client_errors
-            FROM requests
-            WHERE status >= 400
-            GROUP BY 1
-            ORDER BY 2 DESC
-            LIMIT 10
-            "#,
-        )?;
-
-        let mut rows = stmt.query(params![])?;
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let host: String = r.get(0)?;
-            let errors: i64 = r.get(1)?;
-            let server_errors: i64 = r.get(2)?;
-            let client_errors: i64 = r.get(3)?;
-            out.push(json!({
-                "host": host, 
-                "errors": errors,
-                "server_errors": server_errors,
-                "client_errors": client_errors
-            }));
-        }
-        Ok(json!({ "hosts": out }))
-    }).map_err(internal_error)?;
-
-    Ok(Json(payload))
+    Json(q): Json<grafana::QueryRequest>,
+) -> ApiResult<Vec<serde_json::Value>> {
+    q.check().map_err(|e| ApiError::bad_request(format!("{:#}", e)))?;
+    let config = st.config();
+    let out = with_conn(&st, "grafana_query", move |conn| {
+        grafana::query(conn, &q, &config.ports, &config.privacy, |from, to, toggles| {
+            let filter = FilterParams {
+                start: Some(Time(from)),
+                end: Some(Time(to)),
+                exclude_noise: toggles.exclude_noise,
+                include_assets: toggles.include_assets,
+                include_proxy: toggles.include_proxy,
+                ..Default::default()
+            };
+            let (cond, args) = filter.condition(&config.ports);
+            (cond, args, filter.resource_condition())
+        })
+    })
+    .await
+    .map_err(internal_error)?;
+    Ok(Json(out))
 }
 
-async fn top_paths(
+async fn create_job(
     State(st): State<AppState>,
+    Json(spec): Json<jobs::JobSpec>,
 ) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let mut stmt = conn.prepare(
-            r#"
-            SELECT 
-                path,
-                COUNT(*) AS n,
-                AVG(COALESCE(bytes, 0)) AS avg_bytes
-            FROM requests
-            WHERE path IS NOT NULL AND path <> '/'
-            GROUP BY 1
-            ORDER BY 2 DESC
-            LIMIT 15
-            "#,
-        )?;
-
-        let mut rows = stmt.query(params![])?;
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let path: String = r.get(0)?;
-            let n: i64 = r.get(1)?;
-            let avg_bytes: f64 = r.get(2)?;
-            out.push(json!({
-                "path": path, 
-                "n": n,
-                "avg_kb": (avg_bytes / 1024.0) as i64
-            }));
-        }
-        Ok(json!({ "paths": out }))
-    }).map_err(internal_error)?;
+    let id = with_conn(&st, "jobs", move |conn| jobs::enqueue(conn, &spec)).await.map_err(internal_error)?;
+    Ok(Json(json!({ "id": id, "status": "queued" })))
+}
 
-    Ok(Json(payload))
+async fn list_jobs(
+    State(st): State<AppState>,
+) -> ApiResult<serde_json::Value> {
+    let out = with_conn(&st, "jobs", |conn| jobs::list(conn, 50)).await.map_err(internal_error)?;
+    Ok(Json(json!({ "jobs": out })))
 }
 
-async fn user_agents(
+async fn get_job(
     State(st): State<AppState>,
+    Path(id): Path<i64>,
 ) -> ApiResult<serde_json::Value> {
-    let db_path = st.db_path.clone();
-    let payload = with_conn(&db_path, |conn| {
-        let mut stmt = conn.prepare(
-            r#"
-            SELECT 
-                CASE 
-                    WHEN user_agent LIKE '%Chrome%' AND user_agent NOT LIKE '%Edg%' THEN 'Chrome'
-                    WHEN user_agent LIKE '%Firefox%' THEN 'Firefox'
-                    WHEN user_agent LIKE '%Safari%' AND user_agent NOT LIKE '%Chrome%' THEN 'Safari'
-                    WHEN user_agent LIKE '%Edg%' THEN 'Edge'
-                    WHEN user_agent LIKE '%Opera%' THEN 'Opera'
-                    WHEN user_agent LIKE '%bot%' OR user_agent LIKE '%Bot%' THEN 'Bot'
-                    ELSE 'Other'
-                END AS browser,
-                COUNT(*) AS n
-            FROM requests
-            WHERE user_agent IS NOT NULL
-            GROUP BY 1
-            ORDER BY 2 DESC
-            "#,
-        )?;
+    let job = with_conn(&st, "jobs", move |conn| jobs::get(conn, id)).await.map_err(internal_error)?;
+    match job {
+        Some(job) => Ok(Json(job)),
+        None => Err(ApiError::not_found(format!("job {} not found", id))),
+    }
+}
 
-        let mut rows = stmt.query(params![])?;
-        let mut out = Vec::new();
-        while let Some(r) = rows.next()? {
-            let browser: String = r.get(0)?;
-            let n: i64 = r.get(1)?;
-            out.push(json!({"browser": browser, "n": n}));
+const PUBLIC_HTML: &str = r#"
+<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>E-resource usage</title>
+    <style>
+        * { margin: 0; padding: 0; box-sizing: border-box; }
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif;
+            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+            min-height: 100vh;
+            padding: 20px;
+            color: #333333;
+        }
+        .container { max-width: 960px; margin: 0 auto; }
+        h1 { color: #ffffff; margin-bottom: 4px; }
+        .period { color: #ffffff; opacity: 0.85; margin-bottom: 20px; }
+        .card { background: #ffffff; border-radius: 10px; padding: 20px; margin-bottom: 20px; box-shadow: 0 4px 6px rgba(0,0,0,0.1); }
+        .card h2 { font-size: 1.1em; margin-bottom: 12px; }
+        table { width: 100%; border-collapse: collapse; }
+        th, td { text-align: left; padding: 6px 8px; border-bottom: 1px solid #f0f0f0; }
+        td.n, th.n { text-align: right; font-variant-numeric: tabular-nums; }
+        .bar { display: inline-block; height: 10px; background: #667eea; border-radius: 2px; vertical-align: middle; }
+        .note { color: #ffffff; opacity: 0.85; font-size: 0.85em; }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1 id="title"></h1>
+        <p class="period" id="period"></p>
+        <div class="card">
+            <h2>Monthly totals</h2>
+            <table>
+                <thead><tr><th>Month</th><th class="n">Page views</th><th class="n">Users</th><th class="n">Downloads</th></tr></thead>
+                <tbody id="months"></tbody>
+            </table>
+        </div>
+        <div class="card">
+            <h2>Most used platforms</h2>
+            <table>
+                <thead><tr><th>Platform</th><th class="n">Page views</th><th></th></tr></thead>
+                <tbody id="platforms"></tbody>
+            </table>
+        </div>
+        <p class="note">Figures for small numbers of users are left out to protect patron privacy.</p>
+    </div>
+    <script>
+        function cell(text, cls) {
+            const td = document.createElement('td');
+            td.textContent = text;
+            if (cls) td.className = cls;
+            return td;
         }
-        Ok(json!({ "browsers": out }))
-    }).map_err(internal_error)?;
 
-    Ok(Json(payload))
-}
+        fetch('/api/public').then(r => r.json()).then(data => {
+            document.title = data.title;
+            document.getElementById('title').textContent = data.title;
+            if (data.start) document.getElementById('period').textContent = data.start.slice(0, 7) + ' – ' + data.end.slice(0, 7);
+            const fmt = n => n.toLocaleString();
+
+            const months = document.getElementById('months');
+            data.months.slice().reverse().forEach(m => {
+                const tr = document.createElement('tr');
+                tr.append(cell(m.month), cell(fmt(m.page_views), 'n'), cell(fmt(m.users), 'n'), cell(fmt(m.downloads), 'n'));
+                months.append(tr);
+            });
+
+            const platforms = document.getElementById('platforms');
+            const max = Math.max(1, ...data.platforms.map(p => p.page_views));
+            data.platforms.forEach(p => {
+                const tr = document.createElement('tr');
+                const bar = document.createElement('td');
+                const span = document.createElement('span');
+                span.className = 'bar';
+                span.style.width = Math.round(p.page_views / max * 200) + 'px';
+                bar.append(span);
+                tr.append(cell(p.platform), cell(fmt(p.page_views), 'n'), bar);
+                platforms.append(tr);
+            });
+        });
+    </script>
+</body>
+</html>
+"#;
 
 const INDEX_HTML: &str = r#"
 <!DOCTYPE html>
@@ -505,22 +2777,54 @@ const INDEX_HTML: &str = r#"
     <title>EZproxy Analytics Dashboard</title>
     <script src="https://cdn.jsdelivr.net/npm/chart.js@4.4.0/dist/chart.umd.min.js"></script>
     <style>
+        :root {
+            --bg: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+            --surface: #ffffff;
+            --surface-alt: #f8f9fa;
+            --text: #333333;
+            --heading: #ffffff;
+            --muted: #999999;
+            --accent: #667eea;
+            --accent-soft: #e7e9fc;
+            --error: #dc2626;
+            --error-soft: #fee;
+        }
         * { margin: 0; padding: 0; box-sizing: border-box; }
         body {
             font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif;
-            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+            background: var(--bg);
             min-height: 100vh;
             padding: 20px;
         }
         .container { max-width: 1600px; margin: 0 auto; }
+        .header {
+            display: flex;
+            justify-content: space-between;
+            align-items: flex-start;
+            gap: 20px;
+        }
+        .toolbar {
+            display: flex;
+            align-items: center;
+            gap: 8px;
+            color: var(--heading);
+        }
+        .toolbar select {
+            padding: 6px 10px;
+            border-radius: 6px;
+            border: 1px solid var(--accent);
+            background: var(--surface);
+            color: var(--text);
+        }
         h1 {
-            color: white;
+            color: var(--heading);
             font-size: 2.5rem;
             margin-bottom: 10px;
             text-shadow: 2px 2px 4px rgba(0,0,0,0.2);
         }
         .subtitle {
-            color: rgba(255,255,255,0.9);
+            color: var(--heading);
+            opacity: 0.9;
             font-size: 1.1rem;
             margin-bottom: 30px;
         }
@@ -531,7 +2835,7 @@ const INDEX_HTML: &str = r#"
             margin-bottom: 20px;
         }
         .card {
-            background: white;
+            background: var(--surface);
             padding: 25px;
             border-radius: 12px;
             box-shadow: 0 10px 30px rgba(0,0,0,0.2);
@@ -541,8 +2845,8 @@ const INDEX_HTML: &str = r#"
         .card h2 {
             margin: 0 0 20px 0;
             font-size: 1.3rem;
-            color: #333;
-            border-bottom: 3px solid #667eea;
+            color: var(--text);
+            border-bottom: 3px solid var(--accent);
             padding-bottom: 10px;
         }
         .chart-container {
@@ -562,13 +2866,13 @@ const INDEX_HTML: &str = r#"
             align-items: center;
             padding: 12px;
             margin-bottom: 8px;
-            background: #f8f9fa;
+            background: var(--surface-alt);
             border-radius: 6px;
-            border-left: 4px solid #667eea;
+            border-left: 4px solid var(--accent);
         }
         .stat-label { 
             font-weight: 500; 
-            color: #333;
+            color: var(--text);
             flex: 1;
             overflow: hidden;
             text-overflow: ellipsis;
@@ -577,100 +2881,370 @@ const INDEX_HTML: &str = r#"
         }
         .stat-value {
             font-weight: bold;
-            color: #667eea;
-            background: #e7e9fc;
+            color: var(--accent);
+            background: var(--accent-soft);
             padding: 4px 12px;
             border-radius: 4px;
             white-space: nowrap;
         }
         .error-value {
-            background: #fee;
-            color: #dc2626;
+            background: var(--error-soft);
+            color: var(--error);
+        }
+        .kpi-strip {
+            display: grid;
+            grid-template-columns: repeat(auto-fit, minmax(220px, 1fr));
+            gap: 20px;
+            margin-bottom: 20px;
+        }
+        .kpi {
+            background: var(--surface);
+            padding: 18px 25px;
+            border-radius: 12px;
+            box-shadow: 0 10px 30px rgba(0,0,0,0.2);
+            color: var(--text);
+        }
+        .kpi-label { font-size: 0.9rem; color: var(--muted); }
+        .kpi-value { font-size: 2rem; font-weight: bold; margin: 4px 0; }
+        .kpi-delta { font-size: 0.85rem; margin-right: 12px; }
+        .delta-up { color: var(--accent); }
+        .delta-down { color: var(--error); }
+        .filter-bar {
+            display: flex;
+            flex-wrap: wrap;
+            align-items: center;
+            gap: 8px;
+            margin-bottom: 20px;
+            color: var(--heading);
+        }
+        .filter-chip {
+            padding: 4px 12px;
+            border-radius: 14px;
+            border: 1px solid var(--accent);
+            background: var(--surface);
+            color: var(--text);
+            cursor: pointer;
+        }
+        .filter-chip.active {
+            background: var(--accent);
+            color: var(--surface);
+        }
+        .range-picks {
+            display: flex;
+            flex-wrap: wrap;
+            gap: 8px;
+            margin-bottom: 20px;
         }
+        .clickable { cursor: pointer; }
         .loading {
             text-align: center;
             padding: 40px;
-            color: #999;
+            color: var(--muted);
             font-style: italic;
         }
     </style>
 </head>
 <body>
     <div class="container">
-        <h1>EZproxy Analytics Dashboard</h1>
-        <p class="subtitle">Real-time proxy usage insights and performance metrics</p>
+        <div class="header">
+            <div>
+                <h1 data-i18n="title">EZproxy Analytics Dashboard</h1>
+                <p class="subtitle" data-i18n="subtitle">Real-time proxy usage insights and performance metrics</p>
+                <div class="range-picks" id="range-picks">
+                    <button class="filter-chip" data-range="24h" data-i18n="range.24h">Last 24 hours</button>
+                    <button class="filter-chip" data-range="7d" data-i18n="range.7d">Last 7 days</button>
+                    <button class="filter-chip" data-range="30d" data-i18n="range.30d">Last 30 days</button>
+                    <button class="filter-chip" data-range="semester" data-i18n="range.semester">This semester</button>
+                    <button class="filter-chip" data-range="" data-i18n="range.all">All time</button>
+                </div>
+            </div>
+            <div class="toolbar">
+                <label for="lang-select" data-i18n="language">Language</label>
+                <select id="lang-select"></select>
+                <label for="theme-select" data-i18n="theme">Theme</label>
+                <select id="theme-select"></select>
+                <label><input type="checkbox" id="noise-toggle"> <span data-i18n="exclude_noise">Hide preflight noise</span></label>
+                <label><input type="checkbox" id="assets-toggle"> <span data-i18n="include_assets">Include page assets</span></label>
+                <label><input type="checkbox" id="proxy-toggle"> <span data-i18n="include_proxy">Include login pages</span></label>
+            </div>
+        </div>
+
+        <div class="filter-bar" id="filter-bar" hidden></div>
+        <div class="kpi-strip" id="summary-strip"></div>
+        <div class="kpi-strip" id="kpi-strip"></div>
 
         <div class="grid">
             <div class="card">
-                <h2>Requests Over Time</h2>
+                <h2 data-i18n="card.requests_over_time">Requests Over Time</h2>
                 <div class="chart-container">
                     <canvas id="timeChart"></canvas>
                 </div>
             </div>
 
             <div class="card">
-                <h2>💾 Bandwidth Usage (MB/hour)</h2>
+                <h2 data-i18n="card.bandwidth">💾 Bandwidth Usage (MB/hour)</h2>
                 <div class="chart-container">
                     <canvas id="bandwidthChart"></canvas>
                 </div>
             </div>
 
             <div class="card">
-                <h2>Top Hosts</h2>
-                <ul id="top-hosts" class="stat-list loading">Loading...</ul>
+                <h2 data-i18n="card.top_hosts">Top Hosts</h2>
+                <ul id="top-hosts" class="stat-list loading" data-i18n="loading">Loading...</ul>
             </div>
 
             <div class="card">
-                <h2>Status Codes Distribution</h2>
+                <h2 data-i18n="card.status_codes">Status Codes Distribution</h2>
                 <div class="chart-container">
                     <canvas id="statusChart"></canvas>
                 </div>
             </div>
 
             <div class="card">
-                <h2>Top Countries</h2>
+                <h2 data-i18n="card.top_countries">Top Countries</h2>
                 <div class="chart-container">
                     <canvas id="countryChart"></canvas>
                 </div>
             </div>
 
             <div class="card">
-                <h2>Usage Heatmap (Hour × Day)</h2>
+                <h2 data-i18n="card.heatmap">Usage Heatmap (Hour × Day)</h2>
                 <div class="chart-container">
                     <canvas id="heatmapChart"></canvas>
                 </div>
             </div>
 
             <div class="card">
-                <h2>Top Errors by Host</h2>
-                <ul id="error-list" class="stat-list loading">Loading...</ul>
+                <h2 data-i18n="card.errors">Top Errors by Host</h2>
+                <ul id="error-list" class="stat-list loading" data-i18n="loading">Loading...</ul>
             </div>
 
             <div class="card">
-                <h2>Browser Distribution</h2>
+                <h2 data-i18n="card.browsers">Browser Distribution</h2>
                 <div class="chart-container">
                     <canvas id="browserChart"></canvas>
                 </div>
             </div>
 
             <div class="card">
-                <h2>Most Accessed Paths</h2>
-                <ul id="path-list" class="stat-list loading">Loading...</ul>
+                <h2 data-i18n="card.referrers">Referring Discovery Systems</h2>
+                <div class="chart-container">
+                    <canvas id="referrerChart"></canvas>
+                </div>
+            </div>
+
+            <div class="card" id="federation-card" hidden>
+                <h2 data-i18n="card.federation">Consortium Requests by Member</h2>
+                <div class="chart-container">
+                    <canvas id="federationChart"></canvas>
+                </div>
+            </div>
+
+            <div class="card">
+                <h2 data-i18n="card.paths">Most Accessed Paths</h2>
+                <ul id="path-list" class="stat-list loading" data-i18n="loading">Loading...</ul>
             </div>
         </div>
     </div>
 
     <script>
-        async function fetchData(endpoint, elementId, renderFn) {
-            try {
-                const res = await fetch(endpoint);
-                const data = await res.json();
-                renderFn(data);
-            } catch (e) {
-                const el = document.getElementById(elementId);
-                if (el) el.innerHTML = '<div class="loading">Error loading data</div>';
-                console.error('Error:', e);
+        let uiConfig = { themes: [] };
+        let theme = null;
+        let lang = 'en';
+        let strings = {};
+        const charts = {};
+        let calendar = { periods: [], service_hours: {} };
+        let forecast = null;
+
+        function t(key) {
+            return strings[key] || key;
+        }
+
+        function formatHour(ts) {
+            const date = new Date(ts);
+            return date.toLocaleDateString(lang) + ' ' + date.getHours() + ':00';
+        }
+
+        async function loadStrings(requested) {
+            const query = requested ? `?lang=${encodeURIComponent(requested)}` : '';
+            const res = await (await fetch('/api/i18n' + query)).json();
+            lang = res.lang;
+            strings = res.strings;
+            document.documentElement.lang = lang;
+            document.title = t('title');
+            document.querySelectorAll('[data-i18n]').forEach(el => {
+                el.textContent = t(el.dataset.i18n);
+            });
+            const select = document.getElementById('lang-select');
+            select.innerHTML = res.languages
+                .map(l => `<option value="${l.code}">${l.label}</option>`)
+                .join('');
+            select.value = lang;
+        }
+
+        // '#rrggbb' -> 'rgba(r, g, b, a)'
+        function alpha(hex, a) {
+            const n = parseInt(hex.slice(1), 16);
+            return `rgba(${(n >> 16) & 255}, ${(n >> 8) & 255}, ${n & 255}, ${a})`;
+        }
+
+        function applyTheme(name) {
+            theme = uiConfig.themes.find(t => t.name === name) || uiConfig.themes[0];
+            const root = document.documentElement.style;
+            root.setProperty('--bg', theme.background);
+            root.setProperty('--surface', theme.surface);
+            root.setProperty('--surface-alt', theme.surface_alt);
+            root.setProperty('--text', theme.text);
+            root.setProperty('--heading', theme.heading);
+            root.setProperty('--muted', theme.muted);
+            root.setProperty('--accent', theme.accent);
+            root.setProperty('--accent-soft', theme.accent_soft);
+            root.setProperty('--error', theme.error);
+            root.setProperty('--error-soft', theme.error_soft);
+            Chart.defaults.color = theme.text;
+            Chart.defaults.borderColor = theme.grid;
+            document.getElementById('theme-select').value = theme.name;
+            localStorage.setItem('ezvis-theme', theme.name);
+        }
+
+        // Background for one hourly bucket: breaks, exams, and holidays from
+        // the calendar, then hours outside service hours. Terms are the norm
+        // and stay unshaded.
+        function calendarShade(date) {
+            const pad = n => String(n).padStart(2, '0');
+            const day = `${date.getFullYear()}-${pad(date.getMonth() + 1)}-${pad(date.getDate())}`;
+            const period = calendar.periods.find(p => p.kind !== 'term' && p.start <= day && day <= p.end);
+            if (period) {
+                const color = { break: theme.palette[2], exam: theme.palette[3], holiday: theme.palette[4] }[period.kind];
+                return alpha(color || theme.muted, 0.18);
             }
+            const hours = calendar.service_hours[['sun', 'mon', 'tue', 'wed', 'thu', 'fri', 'sat'][date.getDay()]];
+            if (hours === undefined) return null;
+            const time = `${pad(date.getHours())}:${pad(date.getMinutes())}`;
+            if (hours === null || time < hours.open || time >= hours.close) return alpha(theme.muted, 0.08);
+            return null;
+        }
+
+        // Chart.js plugin shading each bucket of a category-axis series;
+        // `options.plugins.calendarShade.times` holds one timestamp per label.
+        const calendarShadePlugin = {
+            id: 'calendarShade',
+            beforeDatasetsDraw(chart, args, opts) {
+                const times = opts.times || [];
+                if (times.length === 0) return;
+                const { ctx, chartArea, scales: { x } } = chart;
+                const half = times.length > 1 ? (x.getPixelForValue(1) - x.getPixelForValue(0)) / 2 : 0;
+                ctx.save();
+                times.forEach((ts, i) => {
+                    const fill = calendarShade(new Date(ts));
+                    if (!fill) return;
+                    ctx.fillStyle = fill;
+                    const px = x.getPixelForValue(i);
+                    ctx.fillRect(px - half, chartArea.top, half * 2, chartArea.bottom - chartArea.top);
+                });
+                ctx.restore();
+            }
+        };
+
+        function drawChart(canvasId, config) {
+            if (charts[canvasId]) charts[canvasId].destroy();
+            const ctx = document.getElementById(canvasId).getContext('2d');
+            charts[canvasId] = new Chart(ctx, config);
+        }
+
+        // Cross-filters live in the page's query string, so a filtered view
+        // can be bookmarked or shared and Back undoes a click.
+        const FILTER_KEYS = ['host', 'country', 'status', 'method', 'department', 'affiliation'];
+
+        function activeFilters() {
+            const params = new URLSearchParams(location.search);
+            return FILTER_KEYS.filter(k => params.get(k)).map(k => [k, params.get(k)]);
+        }
+
+        function setFilters(changes) {
+            const url = new URL(location.href);
+            Object.entries(changes).forEach(([k, v]) => {
+                if (v === null) url.searchParams.delete(k);
+                else url.searchParams.set(k, v);
+            });
+            history.pushState(null, '', url);
+            loadAll();
+        }
+
+        // Chart.js options making a click on a bar or slice filter every
+        // panel by `valueAt(index)`; null means the slice isn't filterable.
+        function clickToFilter(key, valueAt) {
+            return {
+                onClick: (e, elements) => {
+                    const value = elements.length ? valueAt(elements[0].index) : null;
+                    if (value !== null) setFilters({ [key]: value });
+                },
+                onHover: (e, elements) => {
+                    e.native.target.style.cursor = elements.length ? 'pointer' : 'default';
+                }
+            };
+        }
+
+        // List items filter by `values[i]` when clicked.
+        function clickableItems(container, key, values) {
+            container.querySelectorAll('.stat-item').forEach((li, i) => {
+                li.classList.add('clickable');
+                li.addEventListener('click', () => setFilters({ [key]: values[i] }));
+            });
+        }
+
+        // The quick-pick range sits in the query string beside the filters.
+        // The server resolves it, against the calendar for `semester`.
+        function activeRange() {
+            return new URLSearchParams(location.search).get('range') || '';
+        }
+
+        function renderRangePicks() {
+            document.querySelectorAll('#range-picks button').forEach(b => {
+                b.classList.toggle('active', b.dataset.range === activeRange());
+            });
+        }
+
+        // Built with textContent: the values come from the URL.
+        function renderFilterBar() {
+            const bar = document.getElementById('filter-bar');
+            const filters = activeFilters();
+            bar.hidden = filters.length === 0;
+            bar.replaceChildren();
+            if (bar.hidden) return;
+            const label = document.createElement('span');
+            label.textContent = t('filter.active');
+            bar.append(label);
+            filters.forEach(([key, value]) => {
+                const chip = document.createElement('button');
+                chip.className = 'filter-chip';
+                chip.textContent = `${t('filter.' + key)}: ${value} ✕`;
+                chip.addEventListener('click', () => setFilters({ [key]: null }));
+                bar.append(chip);
+            });
+            const clear = document.createElement('button');
+            clear.className = 'filter-chip';
+            clear.textContent = t('filter.clear');
+            clear.addEventListener('click', () => setFilters(Object.fromEntries(FILTER_KEYS.map(k => [k, null]))));
+            bar.append(clear);
+        }
+
+        // With the server's reason when there is one, e.g. that the
+        // database is busy with an import. Set as text: it may quote input.
+        function showError(elementId, message) {
+            const el = document.getElementById(elementId);
+            if (!el) return;
+            const div = document.createElement('div');
+            div.className = 'loading';
+            div.textContent = message ? `${t('error_loading')}: ${message}` : t('error_loading');
+            el.replaceChildren(div);
+        }
+
+        // Every API error comes as {"error": {"code", "message", ...}}.
+        // The request ID lets an admin find the failure in the server log.
+        async function errorMessage(res) {
+            const body = await res.json().catch(() => null);
+            return `${body?.error?.message || res.statusText} (${t('request')} ${res.headers.get('X-Request-Id')})`;
         }
 
         function renderTopHosts(data) {
@@ -678,43 +3252,115 @@ const INDEX_HTML: &str = r#"
             const hosts = data.hosts || [];
 
             if (hosts.length === 0) {
-                container.innerHTML = '<div class="loading">No data available</div>';
+                container.innerHTML = `<div class="loading">${t('no_data')}</div>`;
                 return;
             }
 
             container.innerHTML = hosts.map(item => `
                 <li class="stat-item">
-                    <span class="stat-label" title="${item.host}">${item.host}</span>
+                    <span class="stat-label" title="${item.host}">${item.host_unicode || item.host}</span>
                     <span class="stat-value">${item.n.toLocaleString()}</span>
                 </li>
             `).join('');
+            clickableItems(container, 'host', hosts.map(item => item.host));
+        }
+
+        function renderSummary(data) {
+            const container = document.getElementById('summary-strip');
+            const tiles = [
+                ['summary.requests', (data.requests || 0).toLocaleString(lang)],
+                ['summary.gb', (data.gb || 0).toLocaleString(lang)],
+                ['summary.users', (data.users || 0).toLocaleString(lang)],
+                ['summary.hosts', (data.hosts || 0).toLocaleString(lang)],
+                ['summary.error_rate', data.error_rate_pct === null ? '–' : `${data.error_rate_pct.toLocaleString(lang)}%`],
+                ['summary.top_country', data.top_country ? data.top_country.country : '–'],
+            ];
+            container.innerHTML = tiles.map(([label, value]) => `
+                <div class="kpi">
+                    <div class="kpi-label">${t(label)}</div>
+                    <div class="kpi-value">${value}</div>
+                </div>
+            `).join('');
+        }
+
+        function renderTrends(data) {
+            const container = document.getElementById('kpi-strip');
+            const metrics = data.metrics || {};
+            const delta = (pct, label) => {
+                if (pct === null || pct === undefined) return '';
+                const cls = pct >= 0 ? 'delta-up' : 'delta-down';
+                const arrow = pct >= 0 ? '▲' : '▼';
+                return `<span class="kpi-delta ${cls}">${arrow} ${Math.abs(pct).toLocaleString(lang)}% ${t(label)}</span>`;
+            };
+            container.innerHTML = ['requests', 'bandwidth_mb', 'users']
+                .filter(m => metrics[m])
+                .map(m => `
+                    <div class="kpi">
+                        <div class="kpi-label">${t('kpi.' + m)}</div>
+                        <div class="kpi-value">${metrics[m].current.toLocaleString(lang)}</div>
+                        ${delta(metrics[m].wow_pct, 'kpi.wow')}${delta(metrics[m].yoy_pct, 'kpi.yoy')}
+                    </div>
+                `).join('');
+        }
+
+        // Kept for the time-series charts, which render after it.
+        function renderForecast(data) {
+            forecast = data;
+        }
+
+        // Dashed projection and band continuing an hourly series, from the
+        // forecast of `metric`, as each forecast day's hourly mean. Left off
+        // unless the series reaches the last day the forecast was fitted on,
+        // as a capped series without a range may not.
+        function forecastDatasets(series, metric, key, color) {
+            const points = forecast?.[metric]?.forecast || [];
+            const last = series[series.length - 1];
+            if (points.length === 0 || !last || last.t.slice(0, 10) < forecast.history.end) {
+                return { labels: [], datasets: [] };
+            }
+            const lead = Array(series.length - 1).fill(null);
+            const perHour = v => Math.round(v / 24 * 10) / 10;
+            const line = (values, extra) => ({
+                type: 'line',
+                data: lead.concat([last[key]], values.map(perHour)),
+                pointRadius: 0,
+                tension: 0,
+                ...extra
+            });
+            return {
+                labels: points.map(p => new Date(p.day + 'T00:00:00').toLocaleDateString(lang)),
+                datasets: [
+                    line(points.map(p => p.value), { label: t('label.forecast'), borderColor: color, borderDash: [6, 4], fill: false }),
+                    line(points.map(p => p.low), { label: t('label.forecast_low'), borderColor: 'transparent', fill: false }),
+                    line(points.map(p => p.high), { label: t('label.forecast_high'), borderColor: 'transparent', backgroundColor: alpha(color, 0.15), fill: '-1' })
+                ]
+            };
         }
 
         function renderTimeSeries(data) {
             const series = data.series || [];
-            const ctx = document.getElementById('timeChart').getContext('2d');
+            const ahead = forecastDatasets(series, 'requests', 'n', theme.accent);
 
-            new Chart(ctx, {
+            drawChart('timeChart', {
                 type: 'line',
                 data: {
-                    labels: series.map(d => {
-                        const date = new Date(d.t);
-                        return date.toLocaleDateString() + ' ' + date.getHours() + ':00';
-                    }),
+                    labels: series.map(d => formatHour(d.t)).concat(ahead.labels),
                     datasets: [{
-                        label: 'Requests',
+                        label: t('label.requests'),
                         data: series.map(d => d.n),
-                        borderColor: '#667eea',
-                        backgroundColor: 'rgba(102, 126, 234, 0.1)',
+                        borderColor: theme.accent,
+                        backgroundColor: alpha(theme.accent, 0.1),
                         tension: 0.4,
                         fill: true
-                    }]
+                    }, ...ahead.datasets]
                 },
+                plugins: [calendarShadePlugin],
                 options: {
                     responsive: true,
                     maintainAspectRatio: false,
                     plugins: {
-                        legend: { display: false }
+                        legend: { display: false },
+                        calendarShade: { times: series.map(d => d.t) }
                     },
                     scales: {
                         y: { beginAtZero: true }
@@ -725,31 +3371,27 @@ const INDEX_HTML: &str = r#"
 
         function renderStatusCodes(data) {
             const statuses = data.status || [];
-            const ctx = document.getElementById('statusChart').getContext('2d');
 
-            const groups = { '2xx': 0, '3xx': 0, '4xx': 0, '5xx': 0, 'Other': 0 };
+            const other = t('label.other');
+            const groups = { '2xx': 0, '3xx': 0, '4xx': 0, '5xx': 0, [other]: 0 };
             statuses.forEach(item => {
                 const code = Math.floor(item.status / 100);
-                const key = code >= 2 && code <= 5 ? `${code}xx` : 'Other';
+                const key = code >= 2 && code <= 5 ? `${code}xx` : other;
                 groups[key] += item.n;
             });
 
-            new Chart(ctx, {
+            const labels = Object.keys(groups);
+            drawChart('statusChart', {
                 type: 'doughnut',
                 data: {
-                    labels: Object.keys(groups),
+                    labels,
                     datasets: [{
                         data: Object.values(groups),
-                        backgroundColor: [
-                            '#10b981',
-                            '#3b82f6',
-                            '#f59e0b',
-                            '#ef4444',
-                            '#6b7280'
-                        ]
+                        backgroundColor: theme.status
                     }]
                 },
                 options: {
+                    ...clickToFilter('status', i => labels[i] === other ? null : labels[i]),
                     responsive: true,
                     maintainAspectRatio: false,
                     plugins: {
@@ -763,21 +3405,21 @@ const INDEX_HTML: &str = r#"
 
         function renderCountries(data) {
             const countries = data.countries || [];
-            const ctx = document.getElementById('countryChart').getContext('2d');
 
-            new Chart(ctx, {
+            drawChart('countryChart', {
                 type: 'bar',
                 data: {
                     labels: countries.slice(0, 10).map(c => c.country),
                     datasets: [{
-                        label: 'Requests',
+                        label: t('label.requests'),
                         data: countries.slice(0, 10).map(c => c.n),
-                        backgroundColor: 'rgba(102, 126, 234, 0.8)',
-                        borderColor: '#667eea',
+                        backgroundColor: alpha(theme.accent, 0.8),
+                        borderColor: theme.accent,
                         borderWidth: 1
                     }]
                 },
                 options: {
+                    ...clickToFilter('country', i => countries[i].country),
                     indexAxis: 'y',
                     responsive: true,
                     maintainAspectRatio: false,
@@ -793,27 +3435,28 @@ const INDEX_HTML: &str = r#"
 
         function renderBandwidth(data) {
             const series = data.series || [];
-            const ctx = document.getElementById('bandwidthChart').getContext('2d');
+            const ahead = forecastDatasets(series, 'bandwidth_mb', 'mb', theme.palette[1]);
 
-            new Chart(ctx, {
+            drawChart('bandwidthChart', {
                 type: 'bar',
                 data: {
-                    labels: series.map(d => {
-                        const date = new Date(d.t);
-                        return date.toLocaleDateString() + ' ' + date.getHours() + ':00';
-                    }),
+                    labels: series.map(d => formatHour(d.t)).concat(ahead.labels),
                     datasets: [{
-                        label: 'Bandwidth (MB)',
+                        label: t('label.bandwidth_mb'),
                         data: series.map(d => d.mb),
-                        backgroundColor: 'rgba(16, 185, 129, 0.6)',
-                        borderColor: '#10b981',
+                        backgroundColor: alpha(theme.palette[1], 0.6),
+                        borderColor: theme.palette[1],
                         borderWidth: 1
-                    }]
+                    }, ...ahead.datasets]
                 },
+                plugins: [calendarShadePlugin],
                 options: {
                     responsive: true,
                     maintainAspectRatio: false,
-                    plugins: { legend: { display: false } },
+                    plugins: {
+                        legend: { display: false },
+                        calendarShade: { times: series.map(d => d.t) }
+                    },
                     scales: { y: { beginAtZero: true } }
                 }
             });
@@ -821,9 +3464,8 @@ const INDEX_HTML: &str = r#"
 
         function renderHeatmap(data) {
             const heatmapData = data.data || [];
-            const ctx = document.getElementById('heatmapChart').getContext('2d');
 
-            const days = ['Sun', 'Mon', 'Tue', 'Wed', 'Thu', 'Fri', 'Sat'];
+            const days = t('days').split(',');
             
             const dayData = days.map((day, dayIdx) => {
                 const dayTotal = heatmapData
@@ -832,23 +3474,15 @@ const INDEX_HTML: &str = r#"
                 return dayTotal;
             });
 
-            new Chart(ctx, {
+            drawChart('heatmapChart', {
                 type: 'bar',
                 data: {
                     labels: days,
                     datasets: [{
-                        label: 'Requests by Day',
+                        label: t('label.requests_by_day'),
                         data: dayData,
-                        backgroundColor: [
-                            'rgba(102, 126, 234, 0.4)',
-                            'rgba(102, 126, 234, 0.5)',
-                            'rgba(102, 126, 234, 0.6)',
-                            'rgba(102, 126, 234, 0.7)',
-                            'rgba(102, 126, 234, 0.8)',
-                            'rgba(102, 126, 234, 0.9)',
-                            'rgba(102, 126, 234, 0.4)'
-                        ],
-                        borderColor: '#667eea',
+                        backgroundColor: [0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 0.4].map(a => alpha(theme.accent, a)),
+                        borderColor: theme.accent,
                         borderWidth: 1
                     }]
                 },
@@ -866,7 +3500,7 @@ const INDEX_HTML: &str = r#"
             const hosts = data.hosts || [];
 
             if (hosts.length === 0) {
-                container.innerHTML = '<div class="loading">No errors found</div>';
+                container.innerHTML = `<div class="loading">${t('no_errors')}</div>`;
                 return;
             }
 
@@ -878,32 +3512,87 @@ const INDEX_HTML: &str = r#"
                     </span>
                 </li>
             `).join('');
+            clickableItems(container, 'host', hosts.map(item => item.host));
         }
 
         function renderBrowsers(data) {
             const browsers = data.browsers || [];
-            const ctx = document.getElementById('browserChart').getContext('2d');
 
-            new Chart(ctx, {
+            drawChart('browserChart', {
                 type: 'pie',
                 data: {
                     labels: browsers.map(b => b.browser),
                     datasets: [{
                         data: browsers.map(b => b.n),
-                        backgroundColor: [
-                            '#3b82f6',
-                            '#10b981',
-                            '#f59e0b',
-                            '#ef4444',
-                            '#8b5cf6',
-                            '#ec4899',
-                            '#6b7280'
-                        ]
+                        backgroundColor: theme.palette
+                    }]
+                },
+                options: {
+                    responsive: true,
+                    maintainAspectRatio: false,
+                    plugins: {
+                        legend: {
+                            position: 'bottom'
+                        }
+                    }
+                }
+            });
+        }
+
+        function renderReferrers(data) {
+            const systems = data.systems || [];
+
+            drawChart('referrerChart', {
+                type: 'bar',
+                data: {
+                    labels: systems.map(s => s.system),
+                    datasets: [{
+                        label: t('label.requests'),
+                        data: systems.map(s => s.n),
+                        backgroundColor: theme.palette
                     }]
                 },
+                options: {
+                    indexAxis: 'y',
+                    responsive: true,
+                    maintainAspectRatio: false,
+                    plugins: {
+                        legend: {
+                            display: false
+                        }
+                    }
+                }
+            });
+        }
+
+        function renderFederation(data) {
+            const daily = data.daily || [];
+            const card = document.getElementById('federation-card');
+            card.hidden = daily.length === 0;
+            if (card.hidden) return;
+
+            const days = [...new Set(daily.map(d => d.day))];
+            const members = [...new Set(daily.map(d => d.member))];
+            drawChart('federationChart', {
+                type: 'bar',
+                data: {
+                    labels: days,
+                    datasets: members.map((m, i) => ({
+                        label: m,
+                        data: days.map(day => {
+                            const row = daily.find(d => d.day === day && d.member === m);
+                            return row ? row.requests : 0;
+                        }),
+                        backgroundColor: theme.palette[i % theme.palette.length]
+                    }))
+                },
                 options: {
                     responsive: true,
                     maintainAspectRatio: false,
+                    scales: {
+                        x: { stacked: true },
+                        y: { stacked: true, beginAtZero: true }
+                    },
                     plugins: {
                         legend: {
                             position: 'bottom'
@@ -918,7 +3607,7 @@ const INDEX_HTML: &str = r#"
             const paths = data.paths || [];
 
             if (paths.length === 0) {
-                container.innerHTML = '<div class="loading">No data available</div>';
+                container.innerHTML = `<div class="loading">${t('no_data')}</div>`;
                 return;
             }
 
@@ -930,15 +3619,114 @@ const INDEX_HTML: &str = r#"
             `).join('');
         }
 
-        fetchData('/api/top_hosts', 'top-hosts', renderTopHosts);
-        fetchData('/api/requests_over_time', 'timeChart', renderTimeSeries);
-        fetchData('/api/status_codes', 'statusChart', renderStatusCodes);
-        fetchData('/api/top_countries', 'countryChart', renderCountries);
-        fetchData('/api/bandwidth_over_time', 'bandwidthChart', renderBandwidth);
-        fetchData('/api/hourly_heatmap', 'heatmapChart', renderHeatmap);
-        fetchData('/api/error_analysis', 'error-list', renderErrors);
-        fetchData('/api/user_agents', 'browserChart', renderBrowsers);
-        fetchData('/api/top_paths', 'path-list', renderPaths);
+        // Panel name in /api/dashboard, element that shows its errors, renderer
+        const PANELS = [
+            ['summary', 'summary-strip', renderSummary],
+            ['trends', 'kpi-strip', renderTrends],
+            ['top_hosts', 'top-hosts', renderTopHosts],
+            ['forecast', 'timeChart', renderForecast],
+            ['requests_over_time', 'timeChart', renderTimeSeries],
+            ['status_codes', 'statusChart', renderStatusCodes],
+            ['top_countries', 'countryChart', renderCountries],
+            ['bandwidth_over_time', 'bandwidthChart', renderBandwidth],
+            ['hourly_heatmap', 'heatmapChart', renderHeatmap],
+            ['error_analysis', 'error-list', renderErrors],
+            ['user_agents', 'browserChart', renderBrowsers],
+            ['referrer_systems', 'referrerChart', renderReferrers],
+            ['federation', 'federationChart', renderFederation],
+            ['top_paths', 'path-list', renderPaths],
+        ];
+
+        // One request for every panel instead of one per chart.
+        async function loadAll() {
+            const url = new URL('/api/dashboard', location.origin);
+            url.searchParams.set('panels', PANELS.map(([name]) => name).join(','));
+            if (document.getElementById('noise-toggle').checked) {
+                url.searchParams.set('exclude_noise', 'true');
+            }
+            if (document.getElementById('assets-toggle').checked) {
+                url.searchParams.set('include_assets', 'true');
+            }
+            if (document.getElementById('proxy-toggle').checked) {
+                url.searchParams.set('include_proxy', 'true');
+            }
+            activeFilters().forEach(([k, v]) => url.searchParams.set(k, v));
+            if (activeRange()) url.searchParams.set('range', activeRange());
+            renderFilterBar();
+            renderRangePicks();
+            forecast = null;
+            let data, requestId;
+            try {
+                const res = await fetch(url);
+                if (!res.ok) throw new Error(await errorMessage(res));
+                requestId = res.headers.get('X-Request-Id');
+                data = await res.json();
+            } catch (e) {
+                console.error('Error:', e);
+                PANELS.forEach(([, elementId]) => showError(elementId, e.message));
+                return;
+            }
+            PANELS.forEach(([name, elementId, render]) => {
+                try {
+                    if (!(name in data.panels)) throw new Error(data.errors[name]);
+                    render(data.panels[name]);
+                } catch (e) {
+                    console.error(`${name}:`, e);
+                    showError(elementId, name in data.panels ? null : `${data.errors[name]} (${t('request')} ${requestId})`);
+                }
+            });
+        }
+
+        async function init() {
+            await loadStrings(new URLSearchParams(location.search).get('lang'));
+            document.getElementById('lang-select').addEventListener('change', async e => {
+                const url = new URL(location.href);
+                url.searchParams.set('lang', e.target.value);
+                history.replaceState(null, '', url);
+                await loadStrings(e.target.value);
+                loadAll();
+            });
+
+            uiConfig = await (await fetch('/api/ui_config')).json();
+            const select = document.getElementById('theme-select');
+            select.innerHTML = uiConfig.themes
+                .map(t => `<option value="${t.name}">${t.label}</option>`)
+                .join('');
+            select.addEventListener('change', () => {
+                applyTheme(select.value);
+                loadAll();
+            });
+            applyTheme(localStorage.getItem('ezvis-theme') || uiConfig.default_theme);
+            calendar = await (await fetch('/api/calendar_overlay')).json();
+            document.querySelectorAll('#range-picks button').forEach(b => {
+                b.addEventListener('click', () => setFilters({ range: b.dataset.range || null }));
+            });
+            document.querySelector('#range-picks [data-range="semester"]').hidden =
+                !calendar.periods.some(p => p.kind === 'term');
+
+            const noise = document.getElementById('noise-toggle');
+            noise.checked = localStorage.getItem('ezvis-exclude-noise') === 'true';
+            noise.addEventListener('change', () => {
+                localStorage.setItem('ezvis-exclude-noise', noise.checked);
+                loadAll();
+            });
+            const assets = document.getElementById('assets-toggle');
+            assets.checked = localStorage.getItem('ezvis-include-assets') === 'true';
+            assets.addEventListener('change', () => {
+                localStorage.setItem('ezvis-include-assets', assets.checked);
+                loadAll();
+            });
+            const proxy = document.getElementById('proxy-toggle');
+            proxy.checked = localStorage.getItem('ezvis-include-proxy') === 'true';
+            proxy.addEventListener('change', () => {
+                localStorage.setItem('ezvis-include-proxy', proxy.checked);
+                loadAll();
+            });
+            window.addEventListener('popstate', loadAll);
+            loadAll();
+        }
+
+        init();
     </script>
 </body>
 </html>
@@ -0,0 +1,142 @@
+//! Running `serve`, `watch`, or `cron` under the Windows service control
+//! manager, which starts services with `--service` as `install-service
+//! --platform windows` sets them up.
+
+use std::{
+    ptr,
+    sync::{Mutex, mpsc},
+};
+
+use anyhow::{Result, anyhow};
+use windows_sys::Win32::{
+    Foundation::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR},
+    System::Services::{
+        RegisterServiceCtrlHandlerExW, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE,
+        SERVICE_STATUS_HANDLE, SERVICE_STOP_PENDING, SERVICE_STOPPED, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+        SetServiceStatus, StartServiceCtrlDispatcherW,
+    },
+};
+
+/// Ignored for a service in a process of its own, but it can't be empty.
+const NAME: &str = "ezvis";
+
+/// Handed from `start` to the dispatcher thread's callbacks, which the
+/// service API gives no other way to reach.
+struct Session {
+    started: mpsc::Sender<Result<()>>,
+    stop: Option<tokio::sync::oneshot::Sender<()>>,
+    finished: Option<mpsc::Receiver<u32>>,
+    handle: usize,
+}
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain([0]).collect()
+}
+
+fn report(handle: SERVICE_STATUS_HANDLE, state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING { SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN } else { 0 },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        dwWaitHint: if state == SERVICE_STOP_PENDING { 30_000 } else { 0 },
+    };
+    // SAFETY: `handle` came from RegisterServiceCtrlHandlerExW and `status`
+    // outlives the call.
+    unsafe { SetServiceStatus(handle, &status) };
+}
+
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut core::ffi::c_void,
+    _context: *mut core::ffi::c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            let mut session = SESSION.lock().expect("service session poisoned");
+            if let Some(s) = session.as_mut() {
+                report(s.handle as SERVICE_STATUS_HANDLE, SERVICE_STOP_PENDING, NO_ERROR);
+                if let Some(stop) = s.stop.take() {
+                    let _ = stop.send(());
+                }
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut windows_sys::core::PWSTR) {
+    let name = wide(NAME);
+    // SAFETY: `name` is NUL-terminated and lives for the call; the handler
+    // is a plain function and takes no context.
+    let handle = unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), ptr::null()) };
+    let finished = {
+        let mut session = SESSION.lock().expect("service session poisoned");
+        let Some(s) = session.as_mut() else { return };
+        if handle.is_null() {
+            let _ = s.started.send(Err(anyhow!("could not register the service control handler")));
+            return;
+        }
+        s.handle = handle as usize;
+        report(handle, SERVICE_RUNNING, NO_ERROR);
+        let _ = s.started.send(Ok(()));
+        s.finished.take()
+    };
+    // The process exits once this returns, so wait for the command to wind
+    // down before saying it has stopped.
+    let exit_code = finished.and_then(|f| f.recv().ok()).unwrap_or(NO_ERROR);
+    report(handle, SERVICE_STOPPED, exit_code);
+}
+
+/// A connection to the service control manager, open until `finish`.
+pub struct Service {
+    finished: mpsc::Sender<u32>,
+    dispatcher: std::thread::JoinHandle<()>,
+}
+
+impl Service {
+    /// Report the service stopped, as a failure unless `ok`, once the
+    /// command has wound down.
+    pub fn finish(self, ok: bool) {
+        let _ = self.finished.send(if ok { NO_ERROR } else { 1 });
+        let _ = self.dispatcher.join();
+    }
+}
+
+/// Connect to the service control manager on a thread of its own and report
+/// the service running. The receiver fires when Windows asks it to stop.
+pub fn start() -> Result<(Service, tokio::sync::oneshot::Receiver<()>)> {
+    let (started_tx, started_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+    let (finished_tx, finished_rx) = mpsc::channel();
+    *SESSION.lock().expect("service session poisoned") = Some(Session {
+        started: started_tx.clone(),
+        stop: Some(stop_tx),
+        finished: Some(finished_rx),
+        handle: 0,
+    });
+
+    let dispatcher = std::thread::spawn(move || {
+        let mut name = wide(NAME);
+        let table = [
+            SERVICE_TABLE_ENTRYW { lpServiceName: name.as_mut_ptr(), lpServiceProc: Some(service_main) },
+            SERVICE_TABLE_ENTRYW { lpServiceName: ptr::null_mut(), lpServiceProc: None },
+        ];
+        // SAFETY: the table ends with the required null entry, and it and
+        // `name` outlive the call, which returns once the service stops.
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            let e = std::io::Error::last_os_error();
+            let _ = started_tx.send(Err(anyhow!("not started by the service control manager: {}", e)));
+        }
+    });
+    started_rx.recv().map_err(|_| anyhow!("the service dispatcher exited early"))??;
+    Ok((Service { finished: finished_tx, dispatcher }, stop_rx))
+}
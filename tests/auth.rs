@@ -0,0 +1,238 @@
+//! Who may reach what: the role each route needs, and the dashboard server
+//! turning away logins and tokens below it, in `/api/dashboard` too.
+
+use axum::{
+    Router,
+    body::Body,
+    http::{Method, Request, StatusCode, header},
+};
+use base64ct::{Base64, Encoding};
+use pulezviz::{
+    auth::{self, Grant, Role, Scope},
+    config::Config,
+    db, tokens, web,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+
+/// Routes that name users or addresses, open to analysts only.
+const NAMING: &[&str] = &[
+    "/api/policy_violations",
+    "/api/honeytoken_hits",
+    "/api/peak_windows",
+    "/api/login_failures",
+    "/api/turnaways",
+    "/api/client_types",
+    "/api/anomalies",
+    "/api/proxy_abuse",
+];
+
+/// The `/api/dashboard` panels behind those routes.
+const NAMING_PANELS: &[&str] = &["anomalies", "turnaways", "login_failures", "client_types", "proxy_abuse"];
+
+/// Routes for analysts besides `NAMING`.
+const ANALYST: &[(&str, &str)] = &[
+    ("GET", "/api/requests"),
+    ("GET", "/api/requests/export"),
+    ("GET", "/api/requests/export/1/manifest"),
+    ("POST", "/api/query"),
+    ("GET", "/api/schema"),
+    ("GET", "/api/jobs"),
+    ("GET", "/api/jobs/1"),
+];
+
+const ADMIN: &[(&str, &str)] = &[
+    ("POST", "/api/jobs"),
+    ("POST", "/api/costs"),
+    ("POST", "/api/pseudonyms/resolve"),
+    ("GET", "/api/pseudonyms/resolutions"),
+    ("GET", "/api/access_audit"),
+];
+
+const VIEWER: &[(&str, &str)] = &[
+    ("GET", "/api/dashboard"),
+    ("GET", "/api/summary"),
+    ("GET", "/api/top_hosts"),
+    ("GET", "/api/usage_by_department"),
+    ("POST", "/grafana/query"),
+];
+
+fn method(m: &str) -> Method {
+    m.parse().unwrap()
+}
+
+#[test]
+fn each_listed_route_needs_its_role() {
+    for path in NAMING {
+        assert_eq!(auth::required_role(&Method::GET, path), Role::Analyst, "{}", path);
+    }
+    for (routes, role) in [(ANALYST, Role::Analyst), (ADMIN, Role::Admin), (VIEWER, Role::Viewer)] {
+        for (m, path) in routes {
+            assert_eq!(auth::required_role(&method(m), path), role, "{} {}", m, path);
+        }
+    }
+}
+
+#[test]
+fn grants_cover_roles_up_to_their_own_and_scopes_exactly() {
+    let viewer = Grant::Role(Role::Viewer);
+    assert!(viewer.allows(Role::Viewer));
+    assert!(!viewer.allows(Role::Analyst));
+    assert!(!viewer.allows(Role::Admin));
+    let admin = Grant::Role(Role::Admin);
+    assert!([Role::Viewer, Role::Analyst, Role::Admin].into_iter().all(|r| admin.allows(r)));
+
+    let aggregates = Grant::Scopes(vec![Scope::ReadAggregates]);
+    assert!(aggregates.allows(Role::Viewer));
+    assert!(!aggregates.allows(Role::Analyst));
+    // Scopes don't include one another.
+    let requests = Grant::Scopes(vec![Scope::ReadRequests]);
+    assert!(requests.allows(Role::Analyst));
+    assert!(!requests.allows(Role::Viewer));
+}
+
+#[test]
+fn only_state_changing_requests_need_auth() {
+    for (m, path) in [("POST", "/api/jobs"), ("POST", "/api/costs"), ("POST", "/api/query"), ("POST", "/api/pseudonyms/resolve")] {
+        assert!(auth::needs_auth(&method(m), path), "{} {}", m, path);
+    }
+    for (m, path) in [("GET", "/api/jobs"), ("GET", "/api/requests"), ("POST", "/grafana/query"), ("POST", "/grafana/search")] {
+        assert!(!auth::needs_auth(&method(m), path), "{} {}", m, path);
+    }
+}
+
+/// A database with the schema and nothing in it, removed when dropped.
+struct TempDb(std::path::PathBuf);
+
+impl TempDb {
+    fn new(name: &str) -> TempDb {
+        let path = std::env::temp_dir().join(format!("pulezviz-{}-{}.duckdb", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        db::init_schema(&db::open_db(path.to_str().unwrap()).unwrap()).unwrap();
+        TempDb(path)
+    }
+
+    fn path(&self) -> String {
+        self.0.to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_file(self.0.with_extension("duckdb.wal"));
+    }
+}
+
+/// A server with a viewer `v` and an analyst `a`, both with password `pw`.
+fn server(db: &TempDb) -> Router {
+    let hash = auth::hash_password("pw").unwrap();
+    let config: Config = toml::from_str(&format!(
+        r#"
+        [auth]
+        [[auth.users]]
+        name = "v"
+        password_hash = "{hash}"
+        role = "viewer"
+
+        [[auth.users]]
+        name = "a"
+        password_hash = "{hash}"
+        role = "analyst"
+        "#
+    ))
+    .unwrap();
+    web::router(db.path(), config).unwrap()
+}
+
+fn basic(user: &str) -> String {
+    format!("Basic {}", Base64::encode_string(format!("{}:pw", user).as_bytes()))
+}
+
+async fn send(app: &Router, m: &str, path: &str, authorization: &str) -> (StatusCode, Value) {
+    let mut req = Request::builder().method(m).uri(path).header(header::AUTHORIZATION, authorization);
+    let body = if m == "POST" {
+        req = req.header(header::CONTENT_TYPE, "application/json");
+        Body::from("{}")
+    } else {
+        Body::empty()
+    };
+    let resp = app.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+    let status = resp.status();
+    let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+fn error_code(body: &Value) -> &str {
+    body["error"]["code"].as_str().unwrap_or_default()
+}
+
+/// Panels `/api/dashboard` withheld for want of a role, sorted.
+async fn withheld(app: &Router, authorization: &str) -> Vec<String> {
+    let (status, body) = send(app, "GET", "/api/dashboard", authorization).await;
+    assert_eq!(status, StatusCode::OK);
+    let errors = body["errors"].as_object().unwrap();
+    for name in errors.keys() {
+        assert!(body["panels"].get(name).is_none(), "{} both served and withheld", name);
+    }
+    let mut names: Vec<&str> = errors
+        .iter()
+        .filter(|(_, e)| e.as_str().is_some_and(|e| e.starts_with("requires the")))
+        .map(|(n, _)| n.as_str())
+        .collect();
+    names.sort();
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+fn naming_panels() -> Vec<String> {
+    let mut names = NAMING_PANELS.to_vec();
+    names.sort();
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+#[tokio::test]
+async fn viewers_are_refused_analyst_routes() {
+    let db = TempDb::new("auth-viewer");
+    let app = server(&db);
+    let analyst_routes = NAMING.iter().map(|p| ("GET", *p)).chain(ANALYST.iter().copied());
+    for (m, path) in analyst_routes {
+        let (status, body) = send(&app, m, path, &basic("v")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "viewer {} {}", m, path);
+        assert_eq!(error_code(&body), "missing_role", "viewer {} {}", m, path);
+        assert_eq!(body["error"]["details"]["role"], "analyst");
+
+        let (status, _) = send(&app, m, path, &basic("a")).await;
+        assert!(status != StatusCode::FORBIDDEN && status != StatusCode::UNAUTHORIZED, "analyst {} {}: {}", m, path, status);
+    }
+    let (status, _) = send(&app, "GET", "/api/top_hosts", &basic("v")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = send(&app, "GET", "/api/top_hosts", "Basic bm9ib2R5OnB3").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+}
+
+#[tokio::test]
+async fn dashboard_withholds_analyst_panels_from_viewers() {
+    let db = TempDb::new("auth-dashboard");
+    let app = server(&db);
+    assert_eq!(withheld(&app, &basic("v")).await, naming_panels());
+    assert!(withheld(&app, &basic("a")).await.is_empty());
+}
+
+#[tokio::test]
+async fn aggregate_tokens_are_refused_analyst_routes() {
+    let db = TempDb::new("auth-token");
+    let app = server(&db);
+    let (_, secret) = tokens::create(&db::open_db(&db.path()).unwrap(), Some("wallboard"), &[Scope::ReadAggregates]).unwrap();
+    let bearer = format!("Bearer {}", secret);
+
+    let analyst_routes = NAMING.iter().map(|p| ("GET", *p)).chain(ANALYST.iter().copied());
+    for (m, path) in analyst_routes {
+        let (status, body) = send(&app, m, path, &bearer).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", m, path);
+        assert_eq!(error_code(&body), "missing_scope", "{} {}", m, path);
+        assert_eq!(body["error"]["details"]["scope"], "read:requests");
+    }
+    let (status, _) = send(&app, "GET", "/api/top_hosts", &bearer).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(withheld(&app, &bearer).await, naming_panels());
+}
@@ -0,0 +1,155 @@
+//! Anonymized real-world EZproxy lines in `corpus/`: every line in `ok.log`
+//! must parse, and every line in `reject.log` must fail for the reason
+//! given before its tab.
+
+use chrono::{Datelike, FixedOffset, Timelike};
+use pulezviz::{
+    config::ParserConfig,
+    parser::{ParseError, Timestamps, parse_line},
+};
+
+const OK: &str = include_str!("corpus/ok.log");
+const REJECT: &str = include_str!("corpus/reject.log");
+
+fn parse(line: &str) -> Result<pulezviz::parser::LogRow, ParseError> {
+    parse_line(line, &Timestamps::default())
+}
+
+fn ok_line(needle: &str) -> &'static str {
+    OK.lines().find(|l| l.contains(needle)).expect("corpus has the line")
+}
+
+#[test]
+fn every_ok_line_parses() {
+    for (i, line) in OK.lines().enumerate() {
+        let row = parse(line).unwrap_or_else(|e| panic!("ok.log line {}: {}", i + 1, e));
+        assert_eq!(row.raw, line);
+        assert!(row.host.is_some(), "ok.log line {} has no host", i + 1);
+        assert!((100..600).contains(&row.status));
+    }
+}
+
+#[test]
+fn every_reject_line_fails_for_its_reason() {
+    for (i, entry) in REJECT.lines().enumerate() {
+        let (kind, line) = entry.split_once('\t').expect("reason<TAB>line");
+        match parse(line) {
+            Ok(_) => panic!("reject.log line {} parsed", i + 1),
+            Err(e) => assert_eq!(e.kind(), kind, "reject.log line {}: {}", i + 1, e),
+        }
+    }
+}
+
+#[test]
+fn bad_url_keeps_the_rest_of_the_row() {
+    let line = REJECT.lines().find_map(|l| l.strip_prefix("bad_url\t")).expect("corpus has one");
+    let Err(ParseError::BadUrl { row, .. }) = parse(line) else {
+        panic!("expected BadUrl");
+    };
+    assert_eq!(row.url, "/login?url=https://www.jstor.org/");
+    assert_eq!(row.status, 302);
+    assert_eq!(row.host, None);
+    assert_eq!(row.path, None);
+}
+
+#[test]
+fn ipv6_client_addresses() {
+    let row = parse(ok_line("2001:db8::")).unwrap();
+    assert_eq!(row.remote_addr, "2001:db8::8a2e:370:7334");
+    let row = parse(ok_line("::ffff:")).unwrap();
+    assert_eq!(row.remote_addr, "::ffff:198.51.100.7");
+}
+
+#[test]
+fn dash_bytes_are_missing_not_zero() {
+    let row = parse(ok_line("S0140673623012345")).unwrap();
+    assert_eq!(row.bytes, None);
+    let row = parse(ok_line("OPTIONS")).unwrap();
+    assert_eq!(row.bytes, Some(0));
+    assert!(row.is_noise());
+}
+
+#[test]
+fn optional_fields() {
+    let row = parse(ok_line("ident42")).unwrap();
+    assert_eq!(row.identd.as_deref(), Some("ident42"));
+    assert_eq!(row.port, Some(2443));
+
+    let row = parse(ok_line("sessCCC")).unwrap();
+    assert_eq!(row.country, None);
+    assert_eq!(row.user_agent, None);
+    assert_eq!(row.referrer, None);
+
+    let row = parse(ok_line("sessBBB")).unwrap();
+    assert!(row.referrer.unwrap().starts_with("https://princeton.primo."));
+    // A logged "-" is no referrer.
+    assert_eq!(parse(ok_line("sessFFF")).unwrap().referrer, None);
+
+    let row = parse(ok_line("sess7Q2")).unwrap();
+    assert_eq!(row.user_or_session.as_deref(), Some("sess7Q2"));
+    assert_eq!(parse(ok_line("198.51.100.23")).unwrap().user_or_session, None);
+}
+
+#[test]
+fn offsets_are_kept() {
+    let row = parse(ok_line("sessBBB")).unwrap();
+    assert_eq!(row.ts.offset(), &FixedOffset::west_opt(5 * 3600).unwrap());
+    assert_eq!((row.ts.hour(), row.ts.minute()), (6, 30));
+    let row = parse(ok_line("sessCCC")).unwrap();
+    assert_eq!(row.ts.offset(), &FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap());
+}
+
+#[test]
+fn percent_encoding_and_punycode_pass_through() {
+    let row = parse(ok_line("sessFFF")).unwrap();
+    assert_eq!(row.query.as_deref(), Some("query=%22Fr%C3%BChneuzeit%22%20Stadt&lang=de"));
+    let row = parse(ok_line("sessGGG")).unwrap();
+    assert_eq!(row.host.as_deref(), Some("xn--caf-dma.example.org"));
+    assert_eq!(row.path.as_deref(), Some("/r%C3%A9sum%C3%A9/550e8400-e29b-41d4-a716-446655440000"));
+}
+
+#[test]
+fn huge_urls() {
+    // Link resolvers and search forms produce URLs of tens of kilobytes.
+    let query = "q=".to_string() + &"climate%20adaptation%20".repeat(4000);
+    let line = format!(
+        r#"192.0.2.10 - sessAAA [15/Feb/2026:00:00:04 +0000] "GET https://search.example.com/results?{} HTTP/1.1" 200 1 "US" "Mozilla/5.0""#,
+        query
+    );
+    let row = parse(&line).unwrap();
+    assert_eq!(row.query.as_deref(), Some(query.as_str()));
+    assert!(row.url.len() > 90_000);
+}
+
+#[test]
+fn trailing_whitespace_and_carriage_returns() {
+    let line = format!("{}  \r", ok_line("sessHHH"));
+    let row = parse(&line).unwrap();
+    assert_eq!(row.user_agent.as_deref(), Some("Lynx/2.9.0dev.12 libwww-FM/2.14"));
+}
+
+#[test]
+fn configured_timestamp_formats() {
+    let cfg = ParserConfig {
+        month_locales: vec!["de".to_string(), "fr".to_string()],
+        assume_tz: Some("Europe/Berlin".to_string()),
+        ..ParserConfig::default()
+    };
+    let timestamps = Timestamps::from_config(&cfg).unwrap();
+
+    let ts = timestamps.parse("15/Mär/2026:10:00:00 +0100").unwrap();
+    assert_eq!((ts.month(), ts.day()), (3, 15));
+    let ts = timestamps.parse("01/févr./2026:10:00:00 +0100").unwrap();
+    assert_eq!(ts.month(), 2);
+    // Without an offset: Berlin, in summer time by July.
+    let ts = timestamps.parse("15/Jul/2026:10:00:00").unwrap();
+    assert_eq!(ts.offset(), &FixedOffset::east_opt(2 * 3600).unwrap());
+    let ts = timestamps.parse("15/Feb/26:10:00:00 +0000").unwrap();
+    assert_eq!(ts.year(), 2026);
+    let ts = timestamps.parse("2026-02-15T10:00:00Z").unwrap();
+    assert_eq!(ts.hour(), 10);
+    let ts = timestamps.parse("1771149600").unwrap();
+    assert_eq!((ts.year(), ts.month(), ts.day()), (2026, 2, 15));
+
+    assert!(matches!(Timestamps::default().parse("15/Jul/2026:10:00:00"), Err(ParseError::BadTimestamp(_))));
+}